@@ -0,0 +1,63 @@
+// How long the output callback takes to play back loops with more and more
+// layers, adding every layer up as it goes and from the flattened mix (see
+// Looper::flatten()). Run with:
+//
+//   cargo bench
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use looper_proto::{Command, LooperBuilder, MockAudio};
+
+const RATE: u32 = 48000;
+const CHANNELS: u16 = 2;
+// Frames per buffer, and buffers per loop.
+const BUFFER: usize = 256;
+const LOOP_BUFFERS: usize = 16;
+
+fn playback(c: &mut Criterion) {
+    let mut group = c.benchmark_group("playback");
+    for layers in [1, 8, 32] {
+        let mut looper = LooperBuilder::new().sample_rate(RATE).channels(CHANNELS).build();
+        let mut audio = MockAudio::new(&mut looper);
+        let samples = BUFFER * CHANNELS as usize;
+        let input: Vec<f32> = (0..samples).map(|i| (i as f32 * 0.01).sin() * 0.1).collect();
+        let mut output = vec![0.0; samples];
+        // Record the first loop, then carry on recording the rest of the
+        // layers straight after it.
+        looper.run(Command::Tap).unwrap();
+        for _ in 0..LOOP_BUFFERS {
+            audio.run(&input, &mut output);
+        }
+        // The loop closes once the output callback gets to it.
+        looper.run(Command::Tap).unwrap();
+        audio.run(&input, &mut output);
+        for _ in 1..LOOP_BUFFERS * (layers - 1) {
+            audio.run(&input, &mut output);
+            looper.make_room();
+        }
+        looper.run(Command::Tap).unwrap();
+        audio.run(&input, &mut output);
+        while let Ok(event) = looper.events.try_recv() {
+            looper.handle_event(event);
+        }
+        assert_eq!(looper.snapshot().loop_count, layers);
+        let silence = vec![0.0; samples];
+
+        group.bench_with_input(BenchmarkId::new("live", layers), &layers, |b, _| {
+            let mut gain = 1.0;
+            b.iter(|| {
+                // Changing anything about the layers means the flattened mix
+                // can't be used until it's worked out again.
+                gain = if gain == 1.0 { 0.9 } else { 1.0 };
+                looper.set_layer_gain(0, gain);
+                audio.run(&silence, &mut output);
+            })
+        });
+        looper.flatten();
+        group.bench_with_input(BenchmarkId::new("flattened", layers), &layers, |b, _| {
+            b.iter(|| audio.run(&silence, &mut output))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, playback);
+criterion_main!(benches);
@@ -0,0 +1,9308 @@
+// The looper itself: the tracks and their samples, the state shared with the
+// audio callbacks, the callbacks themselves, and the effects they run. The
+// binary opens the devices and runs the UI on top of this.
+use cpal::traits::{DeviceTrait, StreamTrait};
+use ringbuf::RingBuffer;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Where the looper reports what it's done (e.g. "UNDO. 2 layer(s) left."),
+// for the UI to show however it likes. These are tracing events at info,
+// so they can be told apart from the debugging logs.
+pub const STATUS_TARGET: &str = "looper_proto::status";
+
+macro_rules! status {
+    ($($arg:tt)+) => {
+        tracing::info!(target: STATUS_TARGET, $($arg)+)
+    };
+}
+
+// The pair of devices we're looping between, and how we're talking to them.
+// Whatever sample format they each prefer, everything inside the looper is
+// f32.
+pub struct Devices {
+    pub input: cpal::Device,
+    pub input_config: cpal::StreamConfig,
+    pub input_format: cpal::SampleFormat,
+    pub output: cpal::Device,
+    pub config: cpal::StreamConfig,
+    pub format: cpal::SampleFormat,
+}
+
+// Build the input and output streams for looper, sending any errors that
+// come up while they're running to errors. See callbacks() for the rest.
+pub fn build_streams(
+    looper: &Looper,
+    devices: &Devices,
+    preroll_len: usize,
+    fade_len: usize,
+    input_filter: InputFilter,
+    errors: &mpsc::Sender<cpal::StreamError>,
+) -> anyhow::Result<(cpal::Stream, cpal::Stream)> {
+    let (mut input, mut output) =
+        callbacks(looper, &devices.input_config, &devices.config, preroll_len, fade_len, input_filter);
+    let input_stream = input_stream(devices, move |data| input.process(data), errors)?;
+    let output_stream = output_stream(devices, move |data| output.process(data), errors)?;
+    Ok((input_stream, output_stream))
+}
+
+// An input stream from devices that hands process its input as f32, in
+// whatever format the device sends it.
+fn input_stream(
+    devices: &Devices,
+    mut process: impl FnMut(&[f32]) + Send + 'static,
+    errors: &mpsc::Sender<cpal::StreamError>,
+) -> anyhow::Result<cpal::Stream> {
+    let input_errors = errors.clone();
+    let on_input_error = move |err| {
+        let _ = input_errors.send(err);
+    };
+    let stream = match devices.input_format {
+        cpal::SampleFormat::F32 => devices.input.build_input_stream(
+            &devices.input_config,
+            move |data: &[f32], _: &cpal::InputCallbackInfo| process(data),
+            on_input_error,
+        )?,
+        cpal::SampleFormat::I16 => {
+            // Converted a whole number of frames at a time, into room that's
+            // allocated up front, since the input callback can't allocate.
+            let channels = devices.input_config.channels as usize;
+            let mut converted = vec![0.0; CONVERTER_LEN / channels * channels];
+            devices.input.build_input_stream(
+                &devices.input_config,
+                move |data: &[i16], _: &cpal::InputCallbackInfo| {
+                    for chunk in data.chunks(converted.len()) {
+                        let converted = &mut converted[..chunk.len()];
+                        for (sample, raw) in converted.iter_mut().zip(chunk) {
+                            *sample = i16_to_f32(*raw);
+                        }
+                        process(converted);
+                    }
+                },
+                on_input_error,
+            )?
+        },
+        format => anyhow::bail!("input sample format {:?} isn't supported", format),
+    };
+    Ok(stream)
+}
+
+// Likewise for output, with process filling in f32 samples for whatever
+// format the device wants.
+fn output_stream(
+    devices: &Devices,
+    mut process: impl FnMut(&mut [f32]) + Send + 'static,
+    errors: &mpsc::Sender<cpal::StreamError>,
+) -> anyhow::Result<cpal::Stream> {
+    let output_errors = errors.clone();
+    let on_output_error = move |err| {
+        let _ = output_errors.send(err);
+    };
+    let stream = match devices.format {
+        cpal::SampleFormat::F32 => devices.output.build_output_stream(
+            &devices.config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| process(data),
+            on_output_error,
+        )?,
+        cpal::SampleFormat::I16 => {
+            // Like the input, this gets done a piece at a time, in a buffer
+            // allocated up front.
+            let channels = devices.config.channels as usize;
+            let mut mixed = vec![0.0; CONVERTER_LEN / channels * channels];
+            devices.output.build_output_stream(
+                &devices.config,
+                move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+                    for chunk in data.chunks_mut(mixed.len()) {
+                        let mixed = &mut mixed[..chunk.len()];
+                        process(mixed);
+                        for (raw, sample) in chunk.iter_mut().zip(mixed.iter()) {
+                            *raw = f32_to_i16(*sample);
+                        }
+                    }
+                },
+                on_output_error,
+            )?
+        },
+        format => anyhow::bail!("output sample format {:?} isn't supported", format),
+    };
+    Ok(stream)
+}
+
+// How long to wait before clicking when measuring latency, so the streams
+// have settled down, and then how long to listen for it to come back.
+const CLICK_WAIT: Duration = Duration::from_millis(500);
+const CLICK_LISTEN: Duration = Duration::from_secs(1);
+// How many frames of noise the click is. A burst of noise is much easier
+// to pick out of whatever else comes in than a single sample would be.
+const NOISE_CLICK_LEN: usize = 256;
+// The quietest the click can come back (as a fraction of how loud it was
+// sent) and still count as heard.
+const CLICK_THRESHOLD: f32 = 0.05;
+
+// The click to measure latency with: a burst of noise (the same every
+// time) that fades in and out, so it doesn't pop.
+fn click() -> Vec<f32> {
+    let mut seed: u32 = 0x1234_5678;
+    (0..NOISE_CLICK_LEN)
+        .map(|i| {
+            // A plain linear congruential generator is plenty random here.
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let noise = (seed >> 8) as f32 / (1 << 23) as f32 - 1.0;
+            let window = (std::f32::consts::PI * i as f32 / NOISE_CLICK_LEN as f32).sin();
+            noise * window * 0.5
+        })
+        .collect()
+}
+
+// Measure how many frames late input gets to us, compared to what was
+// playing when it was played, for State::set_latency(). This plays a click
+// and listens for it to come back (see find_delay()), so the output has to
+// be looped back into the input (with a cable, or the interface's own
+// monitoring). Input gets lined up with the output the same way the looper
+// does it (see State::next_write_index()), so this is exactly what
+// recordings are off by. Returns None if the click never came back.
+pub fn measure_latency(devices: &Devices) -> anyhow::Result<Option<usize>> {
+    let rate = devices.config.sample_rate.0 as usize;
+    let input_rate = devices.input_config.sample_rate.0 as usize;
+    let channels = devices.config.channels as usize;
+    let input_channels = devices.input_config.channels as usize;
+    let click_at = (CLICK_WAIT.as_secs_f32() * rate as f32) as usize;
+    let len = click_at + (CLICK_LISTEN.as_secs_f32() * rate as f32) as usize;
+    // Frames output so far.
+    let played = Arc::new(AtomicUsize::new(0));
+    // The input at each frame of output, once it's lined up (mixed down to
+    // mono).
+    let heard = Arc::new(Mutex::new(vec![0.0_f32; len]));
+
+    let click = click();
+    let sent = click.clone();
+    let output_played = played.clone();
+    let mut frame_idx: usize = 0;
+    let output = move |data: &mut [f32]| {
+        for frame in data.chunks_mut(channels) {
+            let sample = frame_idx.checked_sub(click_at).and_then(|i| sent.get(i));
+            frame.fill(sample.copied().unwrap_or(0.0));
+            frame_idx += 1;
+        }
+        output_played.store(frame_idx, Ordering::Relaxed);
+    };
+    let input_played = played.clone();
+    let input_heard = heard.clone();
+    let input = move |data: &[f32]| {
+        // In output frames, like everything else.
+        let frames = data.len() / input_channels * rate / input_rate;
+        let start = input_played.load(Ordering::Relaxed).saturating_sub(frames);
+        let Ok(mut heard) = input_heard.try_lock() else {
+            return;
+        };
+        for (i, frame) in data.chunks(input_channels).enumerate() {
+            if let Some(level) = heard.get_mut(start + i * rate / input_rate) {
+                *level = frame.iter().sum::<f32>() / input_channels as f32;
+            }
+        }
+    };
+
+    // Nothing's listening for errors; if the streams fail, the click just
+    // won't be heard.
+    let (errors, _) = mpsc::channel();
+    let input_stream = input_stream(devices, input, &errors)?;
+    let output_stream = output_stream(devices, output, &errors)?;
+    input_stream.play()?;
+    output_stream.play()?;
+    let deadline = Instant::now() + (CLICK_WAIT + CLICK_LISTEN) * 2;
+    while played.load(Ordering::Relaxed) < len && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(input_stream);
+    drop(output_stream);
+
+    let heard = heard.lock().unwrap();
+    Ok(find_delay(&click, &heard[click_at..], CLICK_THRESHOLD))
+}
+
+// How far into received sent turns up, going by where the two line up best
+// (i.e. the peak of their cross-correlation). It only counts if it's at
+// least threshold times as loud as it was sent (or just as loud, but upside
+// down, since some interfaces flip the polarity).
+fn find_delay(sent: &[f32], received: &[f32], threshold: f32) -> Option<usize> {
+    let energy: f32 = sent.iter().map(|sample| sample * sample).sum();
+    if energy == 0.0 || received.len() < sent.len() {
+        return None;
+    }
+    let (delay, peak) = (0..=received.len() - sent.len())
+        .map(|lag| {
+            let correlation: f32 = sent.iter().zip(&received[lag..]).map(|(a, b)| a * b).sum();
+            (lag, correlation.abs() / energy)
+        })
+        .fold((0, 0.0), |best, (lag, level)| if level > best.1 { (lag, level) } else { best });
+    (peak >= threshold).then_some(delay)
+}
+
+// What the input and output streams for looper run, minus the streams, so
+// they can be fed buffers by anything (see MockAudio). Input comes in as
+// input_config and goes out as config. The first loop starts with up to
+// preroll_len frames of whatever came in before it started recording, and
+// playback fades in and out over fade_len frames. Input goes through
+// input_filter before anything else hears it.
+pub fn callbacks(
+    looper: &Looper,
+    input_config: &cpal::StreamConfig,
+    config: &cpal::StreamConfig,
+    preroll_len: usize,
+    fade_len: usize,
+    input_filter: InputFilter,
+) -> (InputCallback, OutputCallback) {
+    // Everything the ring buffer will ever hold is allocated up front, so
+    // neither callback has to allocate to pass clips along. The pre-roll all
+    // gets sent at once, so there has to be room for that on top.
+    let preroll_samples = preroll_len * config.channels as usize;
+    let queue_len = CLIP_QUEUE_LEN + preroll_samples.div_ceil(CLIP_LEN);
+    let (producer, consumer) = RingBuffer::<Clip>::new(queue_len).split();
+
+    // Everything in SampleBank is at the output's sample rate and channel
+    // count, so input gets converted on its way in.
+    let input = InputCallback {
+        tracks: looper.tracks.clone(),
+        dropouts: looper.dropouts.clone(),
+        producer,
+        converter: Converter::new(
+            input_config.channels, input_config.sample_rate.0,
+            config.channels, config.sample_rate.0,
+        ),
+        preroll: RecentSamples::new(preroll_samples),
+        input_filter,
+        // A whole number of frames, so every sample stays on the same
+        // channel.
+        chunk_len: CLIP_LEN / config.channels as usize * config.channels as usize,
+    };
+
+    let channels = config.channels as usize;
+    let sample_rate = config.sample_rate.0;
+    let output = OutputCallback {
+        tracks: looper.tracks.clone(),
+        consumer,
+        channels,
+        sample_rate,
+        metronome: looper.metronome.clone(),
+        meter: looper.meter.clone(),
+        rms: looper.rms.clone(),
+        dropouts: looper.dropouts.clone(),
+        delay: looper.delay.clone(),
+        crush: looper.crush.clone(),
+        eq: looper.eq.clone(),
+        reverb: looper.reverb.clone(),
+        effects: looper.effects.clone(),
+        events: looper.event_sender.clone(),
+        compressors: vec![looper.compressor.build(sample_rate); channels],
+        chains: (0..channels).map(|channel| EffectsChain::new(sample_rate, channel)).collect(),
+        chain_buf: vec![0.0; MIX_LEN],
+        was_recording: looper.tracks.iter().map(|track| track.state.recording()).collect(),
+        monitored: RecentSamples::new(MONITOR_LEN * channels),
+        mix_buf: vec![0.0; MIX_LEN * channels],
+        fades: vec![Fade::new(fade_len); looper.tracks.len()],
+        without_input: 0,
+        held: None,
+    };
+    (input, output)
+}
+
+// The audio callbacks run on realtime threads, where allocating (or
+// anything else that might block) risks a dropout, so neither of them ever
+// allocates; build with --features alloc-check to catch it if one does.
+// Everything they need is allocated up front, apart from room in the
+// SampleBanks, which the UI thread makes ahead of time (see
+// Looper::make_room()). Nor do they wait on the SampleBanks' locks: they
+// only try them, and go without whenever the UI thread has one.
+//
+// Every track's State shares the settings for the whole mix (see
+// State::new_track()), so the first track's will do for those.
+//
+// This one turns whatever the input device sends into Clips for the output
+// callback.
+pub struct InputCallback {
+    tracks: Vec<Track>,
+    dropouts: Dropouts,
+    producer: ringbuf::Producer<Clip>,
+    converter: Converter,
+    preroll: RecentSamples,
+    input_filter: InputFilter,
+    // Input gets filtered in copies of up to this many samples.
+    chunk_len: usize,
+}
+
+impl InputCallback {
+    pub fn process(&mut self, data: &[f32]) {
+        no_alloc(|| self.process_input(data))
+    }
+
+    fn process_input(&mut self, data: &[f32]) {
+        let Self { tracks, dropouts, producer, converter, preroll, input_filter, chunk_len } = self;
+        // Only one track records at a time (see Looper::select_track()).
+        // While its playback is stopped, the loop isn't going anywhere for
+        // new recordings to line up with, so there's nothing to record onto.
+        let recording = tracks.iter().enumerate().find(|(_, track)| {
+            let stopped = !track.state.first_loop() && !track.state.playing();
+            track.state.recording() && !stopped
+        }).map(|(index, track)| (index, &track.state));
+        let monitoring = tracks[0].state.monitoring();
+        // Hang on to the most recent input, in case recording a first loop
+        // starts any moment now.
+        let prerolling = recording.is_none()
+            && tracks.iter().any(|track| track.state.first_loop())
+            && preroll.enabled();
+        match recording {
+            Some((_, state)) if state.first_loop() => {
+                // This only has anything in it right as the first loop starts.
+                preroll.drain(|samples| dropouts.add_overflows(send_clips(producer, samples, recording)));
+            },
+            _ if !prerolling => preroll.clear(),
+            _ => {},
+        }
+        if recording.is_none() && !monitoring && !prerolling {
+            // Nobody wants this input, save nothing.
+            converter.reset();
+            input_filter.reset();
+            return;
+        }
+
+        converter.process(data, |samples| {
+            for chunk in samples.chunks(*chunk_len) {
+                let mut buf = [0.0; CLIP_LEN];
+                let filtered = &mut buf[..chunk.len()];
+                filtered.copy_from_slice(chunk);
+                input_filter.process(filtered);
+                if prerolling {
+                    preroll.push(filtered);
+                }
+                if recording.is_some() || monitoring {
+                    dropouts.add_overflows(send_clips(producer, filtered, recording));
+                }
+            }
+        });
+    }
+}
+
+// Records whatever Clips the input callback sent, and mixes the tracks (and
+// the effects, the metronome, and the monitored input) into the output.
+pub struct OutputCallback {
+    tracks: Vec<Track>,
+    consumer: ringbuf::Consumer<Clip>,
+    channels: usize,
+    sample_rate: u32,
+    metronome: Metronome,
+    meter: PeakMeter,
+    rms: RmsMeter,
+    dropouts: Dropouts,
+    delay: DelaySettings,
+    crush: BitCrushSettings,
+    eq: EqSettings,
+    reverb: ReverbSettings,
+    effects: EffectsSettings,
+    events: mpsc::SyncSender<Event>,
+    // Each channel gets its own of each effect.
+    compressors: Vec<Compressor>,
+    chains: Vec<EffectsChain>,
+    // One channel's worth of the mix at a time, on its way through its
+    // EffectsChain. Up to MIX_LEN frames.
+    chain_buf: Vec<f32>,
+    was_recording: Vec<bool>,
+    // Live input waiting to be played, while monitoring. Anything more than
+    // MONITOR_LEN frames behind gets dropped, to keep the latency down.
+    monitored: RecentSamples,
+    // All the tracks mixed together, before any effects. Up to MIX_LEN
+    // frames; bigger buffers get done a piece at a time.
+    mix_buf: Vec<f32>,
+    fades: Vec<Fade>,
+    // Frames since the last input arrived while recording.
+    without_input: usize,
+    // A Clip that couldn't be recorded yet because the UI thread had its
+    // track's bank. It goes first next time.
+    held: Option<Clip>,
+}
+
+impl OutputCallback {
+    pub fn process(&mut self, data: &mut [f32]) {
+        no_alloc(|| {
+            let len = self.mix_buf.len();
+            if data.len() <= len {
+                return self.process_output(data);
+            }
+            for chunk in data.chunks_mut(len) {
+                self.process_output(chunk);
+            }
+        })
+    }
+
+    fn process_output(&mut self, data: &mut [f32]) {
+        let channels = self.channels;
+        let output_events = &self.events;
+        // Everything in this buffer goes by the clock as of its first frame.
+        let clock = self.tracks[0].state.clock();
+        let monitoring = self.tracks[0].state.monitoring();
+        let mut received = false;
+        let consumer = &mut self.consumer;
+        while let Some(clip) = self.held.take().or_else(|| consumer.pop()) {
+            received = true;
+            let track = &mut self.tracks[clip.track];
+            if track.state.recording() {
+                let Ok(mut bank) = track.bank.try_lock() else {
+                    // Waiting for the UI thread could mean a dropout, so
+                    // this one (and everything after it) waits for the next
+                    // buffer instead.
+                    self.held = Some(clip);
+                    break;
+                };
+                if record_clip(&mut bank, &mut track.state, &clip, output_events) {
+                    send_event(output_events, Event::MaxRecording { track: clip.track });
+                }
+            }
+            if monitoring {
+                self.monitored.push(clip.samples());
+            }
+        }
+        if !monitoring {
+            self.monitored.clear();
+        }
+        let recording = self.tracks.iter().any(|track| track.state.recording());
+        if received || !recording {
+            self.without_input = 0;
+        } else {
+            let before = self.without_input;
+            self.without_input += data.len() / channels;
+            if before <= STARVED_LEN && self.without_input > STARVED_LEN {
+                self.dropouts.add_starved();
+            }
+        }
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            if let Some(len) = track.state.finish_first_loop() {
+                send_event(output_events, Event::LoopClosed { track: index, len });
+                send_event(output_events, Event::LayerAdded { track: index, index: 0 });
+                if track.state.at_max_layers() {
+                    send_event(output_events, Event::MaxLayers { track: index });
+                }
+            }
+            track.state.finish_cancel();
+            let recording = track.state.recording();
+            if recording != self.was_recording[index] {
+                let event = if recording { Event::RecordingStarted } else { Event::RecordingStopped };
+                send_event(output_events, event);
+                self.was_recording[index] = recording;
+            }
+        }
+
+        // No playback until a first loop is done, or while they're all
+        // stopped (and done fading out).
+        let playing = self.tracks.iter().zip(&self.fades).any(|(track, fade)| {
+            !track.state.first_loop() && (track.state.playing() || fade.gain() > 0.0)
+        });
+        let mixed = &mut self.mix_buf[..data.len()];
+        mixed.fill(0.0);
+        for (index, (track, fade)) in self.tracks.iter_mut().zip(self.fades.iter_mut()).enumerate() {
+            mix_track(track, index, clock, fade, mixed, channels, output_events);
+        }
+
+        let state = &self.tracks[0].state;
+        let master_gain = state.master_gain();
+        let monitor_gain = state.monitor_gain();
+        let semitones = state.pitch_semitones();
+        let wet_mix = state.wet_mix();
+        let effects = &self.effects;
+        let on = |effect, enabled: bool| enabled && !effects.bypassed(effect);
+        let delay = &self.delay;
+        let crush = &self.crush;
+        let reverb = &self.reverb;
+        let eq = &self.eq;
+        let sample_rate = self.sample_rate;
+        let settings = ChainSettings {
+            order: effects.order(),
+            cutoff: on(Effect::Filter, true).then(|| state.cutoff()),
+            eq_gains: on(Effect::Eq, true).then(|| EqBand::ALL.map(|band| eq.gain_db(band))),
+            pitch_ratio: on(Effect::PitchShift, semitones != 0.0).then(|| 2.0_f32.powf(semitones / 12.0)),
+            delay: on(Effect::Delay, delay.enabled()).then(|| {
+                ((delay.time_ms() / 1000.0 * sample_rate as f32) as usize, delay.feedback())
+            }),
+            crush: on(Effect::BitCrush, crush.enabled()).then(|| (crush.bits(), crush.downsample())),
+            reverb: on(Effect::Reverb, reverb.enabled()).then(|| (reverb.mix(), reverb.decay_secs())),
+        };
+        if playing {
+            // Each channel goes through its own chain, leaving the mix
+            // blended with whatever came out.
+            let chain_buf = &mut self.chain_buf[..data.len() / channels];
+            for (channel, chain) in self.chains.iter_mut().enumerate() {
+                chain.update(&settings);
+                let dry = mixed.iter().skip(channel).step_by(channels);
+                chain_buf.iter_mut().zip(dry).for_each(|(wet, dry)| *wet = *dry);
+                chain.process(chain_buf, &settings);
+                let dry = mixed.iter_mut().skip(channel).step_by(channels);
+                for (sample, wet) in dry.zip(chain_buf.iter()) {
+                    *sample = *sample * (1.0 - wet_mix) + wet * wet_mix;
+                }
+            }
+            let width = state.width();
+            if channels == 2 && width != 1.0 {
+                for frame in mixed.chunks_mut(2) {
+                    let (left, right) = mid_side(frame[0], frame[1], width);
+                    frame[0] = left;
+                    frame[1] = right;
+                }
+            }
+        }
+        for (i, (frame, mixed)) in data.chunks_mut(channels).zip(mixed.chunks(channels)).enumerate() {
+            // The click is just for monitoring; it never ends up in the bank.
+            let count_in = self.tracks.iter_mut().find_map(|track| {
+                let elapsed = track.state.count_in_elapsed()?;
+                Some((track, elapsed))
+            });
+            let click = match count_in {
+                Some((track, elapsed)) => {
+                    // Count-ins always click, metronome or no metronome.
+                    track.state.advance_count_in();
+                    self.metronome.sound(elapsed)
+                },
+                None => self.metronome.click(clock + i),
+            };
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut out = 0.0;
+                if playing {
+                    out = self.compressors[channel].compress(mixed[channel]) * master_gain;
+                }
+                let live = self.monitored.pop().unwrap_or(0.0) * monitor_gain;
+                *sample = out + live + click;
+            }
+        }
+        self.tracks[0].state.advance_clock(data.len() / channels);
+
+        self.meter.update(data, channels);
+        self.rms.update(data, channels);
+        // Anything out of range at this point is clipping anyway, so make
+        // sure the sound card doesn't get anything it can't handle.
+        self.meter.add_clips(hard_clip(data));
+    }
+}
+
+// Stands in for the sound card, to run a Looper without one: each call to
+// run() hands the callbacks one buffer of input and takes one of output,
+// the same way the real streams would. Input and output are both in the
+// looper's own format, with no pre-roll or fades. A looper can't have both
+// this and real streams.
+pub struct MockAudio {
+    input: InputCallback,
+    output: OutputCallback,
+}
+
+impl MockAudio {
+    pub fn new(looper: &mut Looper) -> Self {
+        looper.mock_audio = true;
+        let config = cpal::StreamConfig {
+            channels: looper.state.channels as u16,
+            sample_rate: cpal::SampleRate(looper.sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let input_filter = InputFilter::new(config.channels as usize, looper.sample_rate, false, None);
+        let (input, output) = callbacks(looper, &config, &config, 0, 0, input_filter);
+        Self { input, output }
+    }
+
+    // Run one buffer through, input first. Both are interleaved, and should
+    // be the same length.
+    pub fn run(&mut self, input: &[f32], output: &mut [f32]) {
+        self.input.process(input);
+        self.output.process(output);
+    }
+}
+
+// Add track's loop (the track-th one) into mix, which is interleaved just
+// like the output buffer and starts at clock, moving its playback along as
+// it goes. fade is the track's own, so it fades in and out as its playback
+// starts and stops.
+fn mix_track(
+    track: &mut Track,
+    index: usize,
+    clock: usize,
+    fade: &mut Fade,
+    mix: &mut [f32],
+    channels: usize,
+    events: &mpsc::SyncSender<Event>,
+) {
+    let state = &mut track.state;
+    // Even a stopped loop starts from the top once it's retriggered.
+    state.finish_retrigger(clock);
+    // No playback until the first loop is done, or while it's stopped. It
+    // carries on just long enough to fade out, though.
+    let playing = state.playing();
+    if state.first_loop() || (!playing && fade.gain() == 0.0) {
+        return;
+    }
+    // Playback moves one frame per frame from here, same as the clock.
+    state.sync_playback(clock);
+
+    let Ok(mut bank) = track.bank.try_lock() else {
+        // The UI thread has the bank, but only ever for a moment (see
+        // SampleBank::copy_into()), so the track goes without for this
+        // buffer rather than waiting for it. Playback carries on regardless,
+        // so it comes back in the right place.
+        for _ in mix.chunks(channels) {
+            if state.advance_playback() {
+                state.count_cycle();
+                send_event(events, Event::PlaybackWrapped { track: index });
+            }
+        }
+        return;
+    };
+    let loop_len = state.get_loop_len();
+    let layer_len = loop_len * channels;
+    let fade_len = state.crossfade_len();
+    let recorded = state.get_total_samples();
+    for frame in mix.chunks_mut(channels) {
+        let pos = state.get_playback();
+        // At anything but normal speed, playback can land between frames,
+        // so interpolate between this one and the next.
+        let frac = state.playback_frac();
+        let next = (pos + 1) % loop_len.max(1);
+        let loop_count = state.get_loop_count();
+        let heard = state.heard_layers(loop_count);
+        let gain = fade.next(playing);
+        // Nothing in the bank changes unless something's recording (or a
+        // layer decays), so there's no need to go through every layer if
+        // it's all been added up already.
+        let flat = match state.recording() {
+            true => None,
+            false => bank.flat(layer_len, heard, recorded),
+        };
+        let mix_at = |idx: usize| match flat {
+            Some(flat) => flat[idx],
+            None => bank.mix(idx, layer_len, heard, recorded, channels),
+        };
+        // The mix at frame pos, crossfading the end of the loop into the
+        // start.
+        let mix_frame = |pos: usize, channel: usize| {
+            let mut sum = mix_at(pos * channels + channel);
+            if let Some((head, weight)) = crossfade(pos, loop_len, fade_len) {
+                let head = mix_at(head * channels + channel);
+                sum = sum * (1.0 - weight) + head * weight;
+            }
+            sum
+        };
+        for (channel, sample) in frame.iter_mut().enumerate() {
+            let mut sum = mix_frame(pos, channel);
+            if frac > 0.0 {
+                sum += (mix_frame(next, channel) - sum) * frac;
+            }
+            *sample += sum * gain;
+        }
+
+        if state.advance_playback() {
+            // Decaying layers get turned down right here, so every pass
+            // around the loop is quieter than the last, even if the UI
+            // misses the event. It only takes the faded ones out.
+            if bank.decay(state.get_loop_count()) {
+                state.count_decay_cycle();
+            }
+            state.count_cycle();
+            send_event(events, Event::PlaybackWrapped { track: index });
+        }
+        if state.get_loop_count() > loop_count {
+            send_event(events, Event::LayerAdded { track: index, index: loop_count });
+            if state.at_max_layers() {
+                send_event(events, Event::MaxLayers { track: index });
+            }
+        }
+    }
+}
+
+// Linear fade in or out over len frames, one frame at a time, so playback
+// doesn't click as it starts and stops. Everything starts out silent, so the
+// first loop fades in too.
+#[derive(Clone)]
+struct Fade {
+    gain: f32,
+    step: f32,
+}
+
+impl Fade {
+    fn new(len: usize) -> Self {
+        Self {
+            gain: 0.0,
+            step: 1.0 / len.max(1) as f32,
+        }
+    }
+
+    fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    // The gain for the next frame, moving towards full volume if on and
+    // silence if not.
+    fn next(&mut self, on: bool) -> f32 {
+        self.gain = if on {
+            (self.gain + self.step).min(1.0)
+        } else {
+            (self.gain - self.step).max(0.0)
+        };
+        self.gain
+    }
+}
+
+// The last fade_len frames of a loop_len frame loop get blended with the
+// first fade_len frames, so the jump from the end back to the start doesn't
+// click. If pos is in that tail, returns which frame of the head to blend in,
+// and how much of it.
+fn crossfade(pos: usize, loop_len: usize, fade_len: usize) -> Option<(usize, f32)> {
+    let fade_start = loop_len.checked_sub(fade_len)?;
+    if fade_len == 0 || pos < fade_start {
+        return None;
+    }
+    let head = pos - fade_start;
+    Some((head, (head + 1) as f32 / (fade_len + 1) as f32))
+}
+
+pub struct SampleBank {
+    samples: Vec<f32>,
+    // Per-layer gain. Layers without an entry play at unity gain.
+    gains: Vec<f32>,
+    // Per-layer mute and solo. Soloing any layer silences the ones that
+    // aren't soloed, whether or not they're muted.
+    muted: Vec<bool>,
+    soloed: Vec<bool>,
+    // Per-layer pan, from -1.0 (left) to 1.0 (right). Only stereo output
+    // gets panned.
+    pans: Vec<f32>,
+    // Per-layer playing backwards, whatever direction the loop as a whole
+    // is going in.
+    reversed: Vec<bool>,
+    // Per-layer factor for the gain to be multiplied by every time playback
+    // comes round. Layers without an entry don't decay.
+    decays: Vec<f32>,
+    // Changes every time anything else here does (and is never the same for
+    // two banks), so flat can tell when it's out of date.
+    generation: usize,
+    // One loop's worth of the mix of every layer, worked out ahead of time
+    // so playback doesn't have to go through every layer for every sample.
+    // See flat().
+    flat: Vec<f32>,
+    flat_key: Option<FlatKey>,
+    // Which samples have changed since copy_into() last looked, so it knows
+    // what to copy again: anywhere in written, and for replace(), which
+    // goes over every layer at once, the same stretch of each layer (given
+    // its length).
+    written: Option<Range<usize>>,
+    replaced: Option<(usize, Range<usize>)>,
+}
+
+// The smallest range covering both range (if there is one) and with.
+fn widen(range: Option<Range<usize>>, with: Range<usize>) -> Range<usize> {
+    match range {
+        Some(range) => range.start.min(with.start)..range.end.max(with.end),
+        None => with,
+    }
+}
+
+// Which samples into a loop len samples long writing count of them from pos
+// goes over, wrapping round at the end: all of them if it wraps.
+fn wrapped_range(pos: usize, count: usize, len: usize) -> Range<usize> {
+    let start = pos % len;
+    match start.checked_add(count) {
+        Some(end) if end <= len => start..end,
+        _ => 0..len,
+    }
+}
+
+// What a SampleBank's flattened mix was worked out from. It's only any good
+// while all of this is still the same.
+#[derive(Clone, Copy, PartialEq)]
+struct FlatKey {
+    generation: usize,
+    layer_len: usize,
+    loop_count: usize,
+    recorded: usize,
+}
+
+// The next SampleBank::generation to hand out.
+static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+impl SampleBank {
+    fn new(samples: Vec<f32>) -> Self {
+        Self {
+            samples,
+            gains: vec![],
+            muted: vec![],
+            soloed: vec![],
+            pans: vec![],
+            reversed: vec![],
+            decays: vec![],
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            flat: vec![],
+            flat_key: None,
+            written: None,
+            replaced: None,
+        }
+    }
+
+    // Note that something's changed, so the flattened mix is out of date.
+    fn touch(&mut self) {
+        self.generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn layer_gain(&self, layer: usize) -> f32 {
+        self.gains.get(layer).copied().unwrap_or(1.0)
+    }
+
+    fn set_layer_gain(&mut self, layer: usize, gain: f32) {
+        *layer_setting(&mut self.gains, layer, 1.0) = gain;
+        self.touch();
+    }
+
+    fn layer_pan(&self, layer: usize) -> f32 {
+        self.pans.get(layer).copied().unwrap_or(0.0)
+    }
+
+    fn set_layer_pan(&mut self, layer: usize, pan: f32) {
+        *layer_setting(&mut self.pans, layer, 0.0) = pan.clamp(-1.0, 1.0);
+        self.touch();
+    }
+
+    fn layer_muted(&self, layer: usize) -> bool {
+        self.muted.get(layer).copied().unwrap_or(false)
+    }
+
+    fn toggle_mute(&mut self, layer: usize) {
+        let muted = layer_setting(&mut self.muted, layer, false);
+        *muted = !*muted;
+        self.touch();
+    }
+
+    fn layer_reversed(&self, layer: usize) -> bool {
+        self.reversed.get(layer).copied().unwrap_or(false)
+    }
+
+    fn toggle_layer_reverse(&mut self, layer: usize) {
+        let reversed = layer_setting(&mut self.reversed, layer, false);
+        *reversed = !*reversed;
+        self.touch();
+    }
+
+    fn layer_decay(&self, layer: usize) -> f32 {
+        self.decays.get(layer).copied().unwrap_or(1.0)
+    }
+
+    fn set_layer_decay(&mut self, layer: usize, factor: f32) {
+        *layer_setting(&mut self.decays, layer, 1.0) = factor.clamp(0.0, 1.0);
+        // Make room for its gain now, so decay() never has to allocate.
+        layer_setting(&mut self.gains, layer, 1.0);
+    }
+
+    // Turn each of the first count layers down by its decay factor, as
+    // playback comes round. This runs on the output thread, so it only
+    // touches gains that are already there (see set_layer_decay()). Returns
+    // whether anything decayed.
+    fn decay(&mut self, count: usize) -> bool {
+        let mut decayed = false;
+        for (gain, &decay) in self.gains.iter_mut().zip(&self.decays).take(count) {
+            if decay < 1.0 {
+                *gain *= decay;
+                decayed = true;
+            }
+        }
+        if decayed {
+            self.touch();
+        }
+        decayed
+    }
+
+    // Which of the first count decaying layers have gone too quiet to
+    // matter, if any.
+    fn faded_layers(&self, count: usize) -> Vec<usize> {
+        let floor = db_to_gain(DECAY_FLOOR_DB);
+        (0..count.min(self.decays.len()))
+            .filter(|&layer| self.layer_decay(layer) < 1.0 && self.layer_gain(layer) < floor)
+            .collect()
+    }
+
+    // Take out a layer layer_len samples long, and its settings, moving the
+    // rest of the first recorded samples down to fill the gap.
+    fn remove_layer_at(&mut self, layer: usize, layer_len: usize, recorded: usize) {
+        let recorded = recorded.min(self.len());
+        let start = (layer * layer_len).min(recorded);
+        let end = ((layer + 1) * layer_len).min(recorded);
+        self.samples.copy_within(end..recorded, start);
+        self.mark_written(start..recorded);
+        self.remove_layer_settings(layer);
+    }
+
+    // Take out a layer's settings, so the ones above it move down one.
+    fn remove_layer_settings(&mut self, layer: usize) {
+        fn remove<T>(settings: &mut Vec<T>, layer: usize) {
+            if layer < settings.len() {
+                settings.remove(layer);
+            }
+        }
+        remove(&mut self.gains, layer);
+        remove(&mut self.muted, layer);
+        remove(&mut self.soloed, layer);
+        remove(&mut self.pans, layer);
+        remove(&mut self.reversed, layer);
+        remove(&mut self.decays, layer);
+        self.touch();
+    }
+
+    // Whether any of the first count layers are reversed.
+    fn any_reversed(&self, count: usize) -> bool {
+        self.reversed.iter().take(count).any(|&reversed| reversed)
+    }
+
+    fn layer_soloed(&self, layer: usize) -> bool {
+        self.soloed.get(layer).copied().unwrap_or(false)
+    }
+
+    fn toggle_solo(&mut self, layer: usize) {
+        let soloed = layer_setting(&mut self.soloed, layer, false);
+        *soloed = !*soloed;
+        self.touch();
+    }
+
+    // Whether a layer should be heard at all, given mutes and solos across
+    // the first loop_count layers.
+    fn layer_audible(&self, layer: usize, loop_count: usize) -> bool {
+        if self.soloed.iter().take(loop_count).any(|&soloed| soloed) {
+            self.layer_soloed(layer)
+        } else {
+            !self.layer_muted(layer)
+        }
+    }
+
+    // The settings for each of the first count layers.
+    fn layer_settings(&self, count: usize) -> Vec<LayerSettings> {
+        (0..count).map(|layer| LayerSettings {
+            gain: self.layer_gain(layer),
+            pan: self.layer_pan(layer),
+            muted: self.layer_muted(layer),
+            soloed: self.layer_soloed(layer),
+            reversed: self.layer_reversed(layer),
+            decay: self.layer_decay(layer),
+        }).collect()
+    }
+
+    // Replace the settings for every layer, with defaults for any past the
+    // end of layers.
+    fn set_layer_settings(&mut self, layers: &[LayerSettings]) {
+        self.gains = layers.iter().map(|layer| layer.gain).collect();
+        self.pans = layers.iter().map(|layer| layer.pan).collect();
+        self.muted = layers.iter().map(|layer| layer.muted).collect();
+        self.soloed = layers.iter().map(|layer| layer.soloed).collect();
+        self.reversed = layers.iter().map(|layer| layer.reversed).collect();
+        self.decays = layers.iter().map(|layer| layer.decay).collect();
+        self.touch();
+    }
+
+    // A copy of the first recorded samples, and every layer's settings,
+    // made without keeping the output callback waiting (see copy_into()). It
+    // has the same generation, so it can be flattened in place of the bank.
+    fn copy_shared(bank: &Mutex<Self>, recorded: usize) -> Self {
+        let mut samples = vec![0.0; recorded.min(bank.lock().unwrap().len())];
+        let bank = Self::copy_into(bank, &mut samples);
+        bank.with_samples(samples)
+    }
+
+    // A bank with samples in place of this one's, and the same settings and
+    // generation.
+    fn with_samples(&self, samples: Vec<f32>) -> Self {
+        Self {
+            samples,
+            gains: self.gains.clone(),
+            muted: self.muted.clone(),
+            soloed: self.soloed.clone(),
+            pans: self.pans.clone(),
+            reversed: self.reversed.clone(),
+            decays: self.decays.clone(),
+            generation: self.generation,
+            flat: vec![],
+            flat_key: None,
+            written: None,
+            replaced: None,
+        }
+    }
+
+    // Copy as many of the bank's samples as fit into to, a chunk at a time,
+    // so the output callback is never kept waiting long for the lock (it
+    // goes without rather than wait; see OutputCallback). Anything written in
+    // the meantime gets copied again at the end, with the lock held, and it
+    // stays held on return so the copy can't go out of date until the
+    // caller lets go. Only the UI thread copies banks, so there's never more
+    // than one of these going at once.
+    fn copy_into<'a>(bank: &'a Mutex<Self>, to: &mut [f32]) -> MutexGuard<'a, Self> {
+        {
+            let mut bank = bank.lock().unwrap();
+            bank.written = None;
+            bank.replaced = None;
+        }
+        for start in (0..to.len()).step_by(COPY_CHUNK_LEN) {
+            let bank = bank.lock().unwrap();
+            let end = (start + COPY_CHUNK_LEN).min(to.len()).min(bank.len());
+            if start < end {
+                to[start..end].copy_from_slice(&bank.samples[start..end]);
+            }
+        }
+
+        let mut bank = bank.lock().unwrap();
+        let written = bank.written.take();
+        let replaced = bank.replaced.take();
+        let len = to.len().min(bank.len());
+        let samples = &bank.samples[..len];
+        let mut copy_again = |range: Range<usize>| {
+            let end = range.end.min(len);
+            let start = range.start.min(end);
+            to[start..end].copy_from_slice(&samples[start..end]);
+        };
+        if let Some(range) = written {
+            copy_again(range);
+        }
+        if let Some((layer_len, offsets)) = replaced.filter(|(layer_len, _)| *layer_len > 0) {
+            for layer_start in (0..len).step_by(layer_len) {
+                copy_again(layer_start + offsets.start..layer_start + offsets.end);
+            }
+        }
+        bank
+    }
+
+    // Note that the samples in range have changed, for copy_into().
+    fn mark_written(&mut self, range: Range<usize>) {
+        self.written = Some(widen(self.written.take(), range));
+    }
+
+    // samples, padded out with silence to at least the bank's length, so
+    // that swapping them in with swap_samples() keeps the room made for
+    // recording (see Looper::make_room()). The padding happens before taking
+    // the lock.
+    fn padded(bank: &Mutex<Self>, mut samples: Vec<f32>) -> Vec<f32> {
+        let len = bank.lock().unwrap().len();
+        if samples.len() < len {
+            samples.resize(len, 0.0);
+        }
+        samples
+    }
+
+    // Put samples (see padded()) in place of the bank's all at once, rather
+    // than copying them in while holding the lock. Returns the old ones, to
+    // be freed once it's been let go.
+    fn swap_samples(&mut self, samples: Vec<f32>) -> Vec<f32> {
+        self.mark_written(0..samples.len());
+        self.touch();
+        std::mem::replace(&mut self.samples, samples)
+    }
+
+    // Put the settings for every layer from first_layer on back to their
+    // defaults.
+    fn reset_layers_from(&mut self, first_layer: usize) {
+        self.gains.truncate(first_layer);
+        self.muted.truncate(first_layer);
+        self.soloed.truncate(first_layer);
+        self.pans.truncate(first_layer);
+        self.reversed.truncate(first_layer);
+        self.decays.truncate(first_layer);
+        self.touch();
+    }
+
+    // Number of samples currently allocated, whether recorded or not.
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    // Write new samples contiguously to this SampleBank, starting at idx,
+    // growing the bank if necessary. Fails without writing anything if the
+    // bank can't grow to fit the samples.
+    fn write_at(&mut self, idx: usize, samples: &[f32]) -> anyhow::Result<()> {
+        let end = idx.checked_add(samples.len())
+            .ok_or_else(|| anyhow::anyhow!("can't write {} samples past sample {}", samples.len(), idx))?;
+        if end > self.len() {
+            self.grow_to(end)?;
+        }
+
+        self.samples[idx..end].copy_from_slice(samples);
+        self.mark_written(idx..end);
+        self.touch();
+        Ok(())
+    }
+
+    // Double the length of the bank until it's at least min_len samples.
+    fn grow_to(&mut self, min_len: usize) -> anyhow::Result<()> {
+        let new_len = self.grown_len(min_len)?;
+        self.samples.try_reserve_exact(new_len - self.len()).map_err(|e| {
+            anyhow::anyhow!("couldn't grow sample bank to {} samples: {}", new_len, e)
+        })?;
+        self.samples.resize(new_len, 0.0);
+        Ok(())
+    }
+
+    // How long grow_to(min_len) would make the bank.
+    fn grown_len(&self, min_len: usize) -> anyhow::Result<usize> {
+        let mut new_len = self.len().max(1);
+        while new_len < min_len {
+            new_len = new_len.saturating_mul(2);
+        }
+        if new_len > isize::MAX as usize / std::mem::size_of::<f32>() {
+            anyhow::bail!("couldn't grow sample bank to {} samples: too big", new_len);
+        }
+        Ok(new_len)
+    }
+
+    // Like grow_to(), but for a bank the output callback might be using,
+    // so it only holds on to the lock long enough to swap the new samples
+    // in. The allocating happens before taking it, along with most of the
+    // copying (see copy_into()), and the freeing after.
+    fn grow_shared(bank: &Mutex<Self>, min_len: usize) -> anyhow::Result<()> {
+        let new_len = {
+            let bank = bank.lock().unwrap();
+            if bank.len() >= min_len {
+                return Ok(());
+            }
+            bank.grown_len(min_len)?
+        };
+        let mut samples = vec![];
+        samples.try_reserve_exact(new_len).map_err(|e| {
+            anyhow::anyhow!("couldn't grow sample bank to {} samples: {}", new_len, e)
+        })?;
+        samples.resize(new_len, 0.0);
+
+        let old = {
+            let mut bank = Self::copy_into(bank, &mut samples);
+            if bank.len() >= new_len {
+                // Something else grew it in the meantime.
+                return Ok(());
+            }
+            std::mem::replace(&mut bank.samples, samples)
+        };
+        drop(old);
+        Ok(())
+    }
+
+    // Mix samples into the loop layer starting at layer_start, beginning pos
+    // samples into the layer and wrapping around at loop_len. Whatever was
+    // already there is scaled by feedback first.
+    fn overdub(
+        &mut self,
+        layer_start: usize,
+        loop_len: usize,
+        pos: usize,
+        samples: &[f32],
+        feedback: f32,
+    ) -> anyhow::Result<()> {
+        if layer_start + loop_len > self.len() {
+            self.grow_to(layer_start + loop_len)?;
+        }
+
+        for (i, sample) in samples.iter().enumerate() {
+            let idx = layer_start + (pos + i) % loop_len;
+            self.samples[idx] = self.samples[idx] * feedback + sample;
+        }
+        if !samples.is_empty() {
+            let offsets = wrapped_range(pos, samples.len(), loop_len);
+            self.mark_written(layer_start + offsets.start..layer_start + offsets.end);
+        }
+        self.touch();
+        Ok(())
+    }
+
+    // Overwrite the loop with samples, beginning pos samples in and
+    // wrapping around at loop_len. The samples go in the top layer, and the
+    // layers underneath are silenced wherever they're replaced.
+    fn replace(
+        &mut self,
+        loop_len: usize,
+        loop_count: usize,
+        pos: usize,
+        samples: &[f32],
+    ) -> anyhow::Result<()> {
+        if loop_count * loop_len > self.len() {
+            self.grow_to(loop_count * loop_len)?;
+        }
+
+        let top = loop_count - 1;
+        for (i, sample) in samples.iter().enumerate() {
+            let offset = (pos + i) % loop_len;
+            for layer in 0..top {
+                self.samples[layer * loop_len + offset] = 0.0;
+            }
+            self.samples[top * loop_len + offset] = *sample;
+        }
+        if !samples.is_empty() {
+            let offsets = wrapped_range(pos, samples.len(), loop_len);
+            self.replaced = Some(match self.replaced.take() {
+                Some((len, replaced)) if len == loop_len => (len, widen(Some(replaced), offsets)),
+                // The layers have changed length since, so there's no
+                // telling which samples those were, short of all of them.
+                Some(_) => (loop_len, 0..loop_len),
+                None => (loop_len, offsets),
+            });
+        }
+        self.touch();
+        Ok(())
+    }
+
+    // Sum up all samples at the given index (in samples, relative to the
+    // start of each layer) across loop layers, each layer_len samples long.
+    // If nothing's changed since the last flatten(), flat() has this already.
+    // Only the first recorded samples in the bank count; anything past that
+    // is either still on its way from the input or left over from a layer
+    // that's been undone. The samples are interleaved across channels, which
+    // matters for panning. This goes through every layer for every sample,
+    // so the more layers there are, the longer the output callback takes;
+    // see State::max_layers().
+    fn mix(&self, idx: usize, layer_len: usize, loop_count: usize, recorded: usize, channels: usize) -> f32 {
+        let recorded = recorded.min(self.len());
+        let mut sum = 0.0;
+        for loop_offset in 0..loop_count {
+            let layer_start = layer_len * loop_offset;
+            if layer_start >= recorded {
+                // Nothing has been recorded yet in this layer, or any of the
+                // ones after it.
+                break;
+            }
+            let mut sample_idx = layer_start + idx;
+            if self.layer_reversed(loop_offset) {
+                // The same channel of the frame as far from the end of the
+                // layer as idx is from the start.
+                let frame = idx / channels;
+                sample_idx = layer_start + (layer_len / channels - 1 - frame) * channels + idx % channels;
+            }
+            if sample_idx >= recorded {
+                continue;
+            }
+            if self.layer_audible(loop_offset, loop_count) {
+                let mut gain = self.layer_gain(loop_offset);
+                if channels == 2 {
+                    gain *= pan_gain(self.layer_pan(loop_offset), idx % 2);
+                }
+                sum += self.samples[sample_idx] * gain;
+            }
+        }
+        sum
+    }
+
+    // The whole mix() for one loop of layer_len samples, worked out in one
+    // go, along with what it was worked out from. This takes a while with a
+    // lot of layers, so it's best done on a copy (see copy_shared()).
+    fn flatten(&self, layer_len: usize, loop_count: usize, recorded: usize, channels: usize) -> (FlatKey, Vec<f32>) {
+        let key = FlatKey {
+            generation: self.generation,
+            layer_len,
+            loop_count,
+            recorded,
+        };
+        let flat = (0..layer_len).map(|idx| self.mix(idx, layer_len, loop_count, recorded, channels)).collect();
+        (key, flat)
+    }
+
+    // Use flat as the flattened mix, if nothing's changed since it was
+    // worked out. Returns whichever one isn't being used anymore, so it
+    // can be freed after letting go of the bank, rather than while the
+    // output callback waits.
+    fn set_flat(&mut self, key: FlatKey, flat: Vec<f32>) -> Vec<f32> {
+        if key.generation != self.generation {
+            return flat;
+        }
+        self.flat_key = Some(key);
+        std::mem::replace(&mut self.flat, flat)
+    }
+
+    // The flattened mix, if it's still what mix() would come up with.
+    fn flat(&self, layer_len: usize, loop_count: usize, recorded: usize) -> Option<&[f32]> {
+        let key = FlatKey {
+            generation: self.generation,
+            layer_len,
+            loop_count,
+            recorded,
+        };
+        (self.flat_key == Some(key)).then_some(&self.flat[..])
+    }
+}
+
+// Equal-power gain for one side (0 for left, 1 for right) of something
+// panned to pan. Centered is unity gain on both sides; hard left is +3 dB on
+// the left and nothing on the right.
+fn pan_gain(pan: f32, side: usize) -> f32 {
+    if pan == 0.0 {
+        return 1.0;
+    }
+    let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+    let gain = if side == 0 { angle.cos() } else { angle.sin() };
+    gain * std::f32::consts::SQRT_2
+}
+
+// Get a mutable per-layer setting, filling in defaults for any layers that
+// don't have one yet.
+fn layer_setting<T: Clone>(settings: &mut Vec<T>, layer: usize, default: T) -> &mut T {
+    if layer >= settings.len() {
+        settings.resize(layer + 1, default);
+    }
+    &mut settings[layer]
+}
+
+// One of the effects in the EffectsChain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    Filter,
+    Eq,
+    PitchShift,
+    Delay,
+    BitCrush,
+    Reverb,
+}
+
+impl Effect {
+    // In the order they run in to start with.
+    pub const ALL: [Effect; EFFECT_COUNT] = [
+        Effect::Filter,
+        Effect::Eq,
+        Effect::PitchShift,
+        Effect::Delay,
+        Effect::BitCrush,
+        Effect::Reverb,
+    ];
+
+    pub fn next(self) -> Self {
+        Effect::ALL[(self as usize + 1) % EFFECT_COUNT]
+    }
+}
+
+impl std::fmt::Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Effect::Filter => "filter",
+            Effect::Eq => "eq",
+            Effect::PitchShift => "pitch",
+            Effect::Delay => "delay",
+            Effect::BitCrush => "crush",
+            Effect::Reverb => "reverb",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+const EFFECT_COUNT: usize = 6;
+
+// Which order the effects run in, and which of them are bypassed (skipped,
+// but keeping their settings), shared with the output callback so the UI can
+// change them as it runs. The whole order is packed into one atomic, four
+// bits per effect, so the output callback never sees it half changed.
+#[derive(Clone)]
+pub struct EffectsSettings {
+    order: Arc<AtomicU32>,
+    // One bit per effect, by its place in Effect::ALL.
+    bypassed: Arc<AtomicU32>,
+}
+
+impl EffectsSettings {
+    fn new() -> Self {
+        let settings = Self {
+            order: Arc::new(0.into()),
+            bypassed: Arc::new(0.into()),
+        };
+        settings.set_order(Effect::ALL);
+        settings
+    }
+
+    pub fn order(&self) -> [Effect; EFFECT_COUNT] {
+        let packed = self.order.load(Ordering::Relaxed);
+        std::array::from_fn(|i| Effect::ALL[((packed >> (i * 4)) & 0xf) as usize])
+    }
+
+    fn set_order(&self, order: [Effect; EFFECT_COUNT]) {
+        let packed = order.iter().enumerate().fold(0, |packed, (i, effect)| packed | ((*effect as u32) << (i * 4)));
+        self.order.store(packed, Ordering::Relaxed);
+    }
+
+    // Swap effect with the one after it (or, if later is false, before it),
+    // unless it's already at that end of the chain.
+    fn move_effect(&self, effect: Effect, later: bool) {
+        let mut order = self.order();
+        let Some(pos) = order.iter().position(|e| *e == effect) else {
+            return;
+        };
+        let other = match later {
+            true if pos + 1 < EFFECT_COUNT => pos + 1,
+            false if pos > 0 => pos - 1,
+            _ => return,
+        };
+        order.swap(pos, other);
+        self.set_order(order);
+    }
+
+    pub fn bypassed(&self, effect: Effect) -> bool {
+        self.bypassed.load(Ordering::Relaxed) & (1 << effect as u32) != 0
+    }
+
+    fn toggle_bypass(&self, effect: Effect) {
+        self.bypassed.fetch_xor(1 << effect as u32, Ordering::Relaxed);
+    }
+}
+
+// Every effect one channel of the mix goes through on its way out, apart
+// from the compressor, in whatever order EffectsSettings says.
+#[derive(Clone)]
+struct EffectsChain {
+    filter: OnePole,
+    eq: Eq,
+    pitch_shift: PitchShift,
+    delay: Delay,
+    crusher: BitCrush,
+    reverb: Reverb,
+    // Whether the last buffer used them, so they don't bring back whatever
+    // was left in them from the last time they were on.
+    was_shifting: bool,
+    delay_was_enabled: bool,
+    reverb_was_enabled: bool,
+}
+
+// How every channel's EffectsChain is set for a buffer, as of the start of
+// it. Effects that are off (or bypassed) are None.
+struct ChainSettings {
+    order: [Effect; EFFECT_COUNT],
+    cutoff: Option<f32>,
+    eq_gains: Option<[f32; 3]>,
+    pitch_ratio: Option<f32>,
+    // Length in frames, and feedback.
+    delay: Option<(usize, f32)>,
+    // Bits, and how many samples to hold each one for.
+    crush: Option<(u32, usize)>,
+    // Mix, and decay time in seconds.
+    reverb: Option<(f32, f32)>,
+}
+
+impl EffectsChain {
+    fn new(sample_rate: u32, channel: usize) -> Self {
+        Self {
+            filter: OnePole::new(MAX_CUTOFF, sample_rate),
+            eq: Eq::new(sample_rate),
+            pitch_shift: PitchShift::new(sample_rate),
+            delay: Delay::new(sample_rate),
+            crusher: BitCrush::new(),
+            // Each channel's reverb is tuned a little differently, so they
+            // don't ring together and the tail spreads out between the
+            // speakers.
+            reverb: Reverb::new(sample_rate, channel),
+            was_shifting: false,
+            delay_was_enabled: false,
+            reverb_was_enabled: false,
+        }
+    }
+
+    // Catch up with any changes to settings since the last buffer.
+    fn update(&mut self, settings: &ChainSettings) {
+        if let Some(cutoff) = settings.cutoff.filter(|cutoff| *cutoff != self.filter.cutoff()) {
+            self.filter.set_cutoff(cutoff);
+        }
+        if let Some(gains) = settings.eq_gains {
+            self.eq.set_gains(gains);
+        }
+        if settings.pitch_ratio.is_some() && !self.was_shifting {
+            self.pitch_shift.clear();
+        }
+        self.was_shifting = settings.pitch_ratio.is_some();
+        if settings.delay.is_some() && !self.delay_was_enabled {
+            // Don't bring back echoes from the last time it was on.
+            self.delay.clear();
+        }
+        self.delay_was_enabled = settings.delay.is_some();
+        if let Some((_, decay)) = settings.reverb {
+            if !self.reverb_was_enabled {
+                self.reverb.clear();
+            }
+            self.reverb.set_decay(decay);
+        }
+        self.reverb_was_enabled = settings.reverb.is_some();
+    }
+
+    // Run samples (all from this chain's channel) through every effect
+    // that's on, in order.
+    fn process(&mut self, samples: &mut [f32], settings: &ChainSettings) {
+        for effect in settings.order {
+            self.process_effect(effect, samples, settings);
+        }
+    }
+
+    fn process_effect(&mut self, effect: Effect, samples: &mut [f32], settings: &ChainSettings) {
+        match effect {
+            Effect::Filter if settings.cutoff.is_some() => {
+                samples.iter_mut().for_each(|sample| *sample = self.filter.process(*sample));
+            },
+            Effect::Eq if settings.eq_gains.is_some() => {
+                samples.iter_mut().for_each(|sample| *sample = self.eq.process(*sample));
+            },
+            Effect::PitchShift => if let Some(ratio) = settings.pitch_ratio {
+                samples.iter_mut().for_each(|sample| *sample = self.pitch_shift.process(*sample, ratio));
+            },
+            Effect::Delay => if let Some((len, feedback)) = settings.delay {
+                samples.iter_mut().for_each(|sample| *sample = self.delay.process(*sample, len, feedback));
+            },
+            Effect::BitCrush => if let Some((bits, hold)) = settings.crush {
+                samples.iter_mut().for_each(|sample| *sample = self.crusher.process(*sample, bits, hold));
+            },
+            Effect::Reverb => if let Some((mix, _)) = settings.reverb {
+                samples.iter_mut().for_each(|sample| *sample = self.reverb.process(*sample, mix));
+            },
+            _ => {},
+        }
+    }
+}
+
+// How the Compressor on the whole mix behaves. The defaults only catch
+// peaks that would otherwise clip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CompressorSettings {
+    // Level (in dBFS) above which we start reducing gain.
+    pub threshold_db: f32,
+    // e.g. 4.0 for 4:1.
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for CompressorSettings {
+    fn default() -> Self {
+        Self {
+            threshold_db: -6.0,
+            ratio: 4.0,
+            attack_ms: 5.0,
+            release_ms: 100.0,
+        }
+    }
+}
+
+impl CompressorSettings {
+    fn build(&self, sample_rate: u32) -> Compressor {
+        Compressor::new(self.threshold_db, self.ratio, self.attack_ms, self.release_ms, sample_rate)
+    }
+}
+
+// Simple feed-forward compressor for taming the summed output once several
+// loop layers stack up. The envelope lives on the struct, so it carries over
+// from one output buffer to the next.
+#[derive(Clone)]
+struct Compressor {
+    // Level (in dBFS) above which we start reducing gain.
+    threshold: f32,
+    // How many dB over the threshold it takes to get 1 dB over it at the
+    // output, e.g. 4.0 for 4:1.
+    ratio: f32,
+    // Per-sample smoothing coefficients for a rising/falling envelope.
+    attack_coeff: f32,
+    release_coeff: f32,
+    // Current (linear) envelope of the input signal.
+    envelope: f32,
+}
+
+impl Compressor {
+    fn new(threshold: f32, ratio: f32, attack_ms: f32, release_ms: f32, sample_rate: u32) -> Self {
+        Self {
+            threshold,
+            ratio: ratio.max(1.0),
+            attack_coeff: time_coeff(attack_ms, sample_rate),
+            release_coeff: time_coeff(release_ms, sample_rate),
+            envelope: 0.0,
+        }
+    }
+
+    fn compress(&mut self, sample: f32) -> f32 {
+        let level = sample.abs();
+        let coeff = if level > self.envelope {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.envelope = coeff * self.envelope + (1.0 - coeff) * level;
+
+        sample * self.gain()
+    }
+
+    // The gain we're currently applying, based on the envelope.
+    fn gain(&self) -> f32 {
+        let level_db = gain_to_db(self.envelope);
+        if level_db <= self.threshold {
+            return 1.0;
+        }
+
+        let over = level_db - self.threshold;
+        db_to_gain(over / self.ratio - over)
+    }
+}
+
+// One-pole low-pass filter, for taking the edge off the whole mix. Like
+// Compressor, it keeps its state from one output buffer to the next.
+#[derive(Clone)]
+struct OnePole {
+    sample_rate: u32,
+    cutoff: f32,
+    // How much of the way to the input each sample moves the output.
+    coeff: f32,
+    last: f32,
+}
+
+impl OnePole {
+    fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let mut filter = Self {
+            sample_rate,
+            cutoff: 0.0,
+            coeff: 1.0,
+            last: 0.0,
+        };
+        filter.set_cutoff(cutoff);
+        filter
+    }
+
+    fn cutoff(&self) -> f32 {
+        self.cutoff
+    }
+
+    // Set the cutoff frequency in Hz. At MAX_CUTOFF or above the filter
+    // passes everything through untouched.
+    fn set_cutoff(&mut self, hz: f32) {
+        self.cutoff = hz;
+        self.coeff = if hz >= MAX_CUTOFF {
+            1.0
+        } else {
+            1.0 - (-2.0 * std::f32::consts::PI * hz / self.sample_rate as f32).exp()
+        };
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.last += self.coeff * (sample - self.last);
+        self.last
+    }
+}
+
+// Feedback delay on the whole mix. The echoes get mixed in on top of the
+// dry signal, so turning it off just leaves the loop as it was.
+#[derive(Clone)]
+struct Delay {
+    // Everything from the last MAX_DELAY_MS, allocated up front.
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Delay {
+    fn new(sample_rate: u32) -> Self {
+        let len = (MAX_DELAY_MS / 1000.0 * sample_rate as f32) as usize;
+        Self {
+            buf: vec![0.0; len.max(1)],
+            pos: 0,
+        }
+    }
+
+    // Run one sample through a delay of delay_len samples, feeding back
+    // feedback of each echo into the next one.
+    fn process(&mut self, sample: f32, delay_len: usize, feedback: f32) -> f32 {
+        let delay_len = delay_len.clamp(1, self.buf.len());
+        let echo = self.buf[(self.pos + self.buf.len() - delay_len) % self.buf.len()];
+        self.buf[self.pos] = sample + echo * feedback;
+        self.pos = (self.pos + 1) % self.buf.len();
+        sample + echo
+    }
+
+    // Forget any echoes still going around.
+    fn clear(&mut self) {
+        self.buf.iter_mut().for_each(|sample| *sample = 0.0);
+    }
+}
+
+// Standard biquad filter (from the Audio EQ Cookbook), keeping its state
+// from one output buffer to the next.
+#[derive(Clone)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    // The last two inputs and outputs.
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    // Passes everything through untouched, until it gets some coefficients.
+    fn new() -> Self {
+        Self {
+            b0: 1.0,
+            b1: 0.0,
+            b2: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0,
+        }
+    }
+
+    // Set the coefficients, normalized so a0 is 1. The state carries on as
+    // it was, so changing them as it runs doesn't click.
+    fn set_coefficients(&mut self, b: [f32; 3], a: [f32; 3]) {
+        self.b0 = b[0] / a[0];
+        self.b1 = b[1] / a[0];
+        self.b2 = b[2] / a[0];
+        self.a1 = a[1] / a[0];
+        self.a2 = a[2] / a[0];
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let out = self.b0 * sample + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = sample;
+        self.y2 = self.y1;
+        self.y1 = out;
+        out
+    }
+}
+
+// The three bands of the master EQ: a low shelf, a peak in the middle, and a
+// high shelf.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EqBand {
+    Low,
+    Mid,
+    High,
+}
+
+impl EqBand {
+    const ALL: [EqBand; 3] = [EqBand::Low, EqBand::Mid, EqBand::High];
+
+    // Where each band's centred (or, for the shelves, where they turn), in Hz.
+    fn frequency(self) -> f32 {
+        match self {
+            EqBand::Low => 200.0,
+            EqBand::Mid => 1000.0,
+            EqBand::High => 5000.0,
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            EqBand::Low => EqBand::Mid,
+            EqBand::Mid => EqBand::High,
+            EqBand::High => EqBand::Low,
+        }
+    }
+}
+
+impl std::fmt::Display for EqBand {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            EqBand::Low => "low",
+            EqBand::Mid => "mid",
+            EqBand::High => "high",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Broad enough for the middle band to cover what the shelves don't.
+const EQ_MID_Q: f32 = 0.7;
+const MAX_EQ_DB: f32 = 12.0;
+
+// Three-band EQ on the whole mix, one biquad per band.
+#[derive(Clone)]
+struct Eq {
+    sample_rate: u32,
+    bands: [Biquad; 3],
+    // Each band's gain in dB, as of the last set_gains().
+    gains: [f32; 3],
+}
+
+impl Eq {
+    // Flat, to start with.
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            bands: [Biquad::new(), Biquad::new(), Biquad::new()],
+            gains: [0.0; 3],
+        }
+    }
+
+    // Set every band's gain in dB, in the order of EqBand::ALL. Only the bands
+    // that changed get their coefficients worked out again.
+    fn set_gains(&mut self, gains: [f32; 3]) {
+        for (i, band) in EqBand::ALL.iter().enumerate() {
+            if gains[i] != self.gains[i] {
+                self.gains[i] = gains[i];
+                let (b, a) = eq_coefficients(*band, gains[i], self.sample_rate);
+                self.bands[i].set_coefficients(b, a);
+            }
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        if self.gains.iter().all(|&gain| gain == 0.0) {
+            // Save the work; it'd come out the same anyway.
+            return sample;
+        }
+        self.bands.iter_mut().fold(sample, |sample, band| band.process(sample))
+    }
+}
+
+// One band's biquad coefficients (b, then a) for a gain of db, straight out
+// of the Audio EQ Cookbook.
+fn eq_coefficients(band: EqBand, db: f32, sample_rate: u32) -> ([f32; 3], [f32; 3]) {
+    let a = 10.0_f32.powf(db / 40.0);
+    let w0 = 2.0 * std::f32::consts::PI * band.frequency() / sample_rate as f32;
+    let (sin, cos) = w0.sin_cos();
+    match band {
+        EqBand::Mid => {
+            let alpha = sin / (2.0 * EQ_MID_Q);
+            (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            )
+        },
+        EqBand::Low | EqBand::High => {
+            // Shelf slope of 1, as steep as it goes without overshooting.
+            let alpha = sin / 2.0 * 2.0_f32.sqrt();
+            let root = 2.0 * a.sqrt() * alpha;
+            // The high shelf is the low one with cos (and the middle terms)
+            // flipped.
+            let (cos, sign) = if band == EqBand::Low { (cos, 1.0) } else { (-cos, -1.0) };
+            (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + root),
+                    sign * 2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - root),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + root,
+                    sign * -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - root,
+                ],
+            )
+        },
+    }
+}
+
+// The EQ's gains, shared with the output callback like the delay's settings.
+#[derive(Clone)]
+pub struct EqSettings {
+    // Each band's gain in dB as f32 bits, in the order of EqBand::ALL.
+    gains: Arc<[AtomicU32; 3]>,
+}
+
+impl EqSettings {
+    fn new() -> Self {
+        Self {
+            gains: Arc::new([0.0_f32.to_bits().into(), 0.0_f32.to_bits().into(), 0.0_f32.to_bits().into()]),
+        }
+    }
+
+    pub fn gain_db(&self, band: EqBand) -> f32 {
+        f32::from_bits(self.gains[band as usize].load(Ordering::Relaxed))
+    }
+
+    fn set_gain_db(&self, band: EqBand, db: f32) {
+        self.gains[band as usize].store(db.clamp(-MAX_EQ_DB, MAX_EQ_DB).to_bits(), Ordering::Relaxed);
+    }
+}
+
+// Schroeder reverb on the whole mix: a few feedback combs in parallel make
+// the echoes, and a couple of allpasses in series smear them out into a tail.
+#[derive(Clone)]
+struct Reverb {
+    sample_rate: u32,
+    combs: Vec<FeedbackLine>,
+    allpasses: Vec<FeedbackLine>,
+    // As of the last set_decay().
+    decay_secs: f32,
+}
+
+// A delay line, with its feedback, for one of the reverb's combs or
+// allpasses. All of it gets allocated up front.
+#[derive(Clone)]
+struct FeedbackLine {
+    buf: Vec<f32>,
+    pos: usize,
+    feedback: f32,
+}
+
+impl FeedbackLine {
+    fn new(len: usize, feedback: f32) -> Self {
+        Self {
+            buf: vec![0.0; len.max(1)],
+            pos: 0,
+            feedback,
+        }
+    }
+
+    fn comb(&mut self, sample: f32) -> f32 {
+        let out = self.buf[self.pos];
+        self.buf[self.pos] = sample + out * self.feedback;
+        self.pos = (self.pos + 1) % self.buf.len();
+        out
+    }
+
+    fn allpass(&mut self, sample: f32) -> f32 {
+        let delayed = self.buf[self.pos];
+        let fed = sample + delayed * self.feedback;
+        self.buf[self.pos] = fed;
+        self.pos = (self.pos + 1) % self.buf.len();
+        delayed - fed * self.feedback
+    }
+}
+
+// Schroeder's own delay times, in ms. They don't share any factors, so the
+// echoes don't pile up on each other.
+const REVERB_COMB_MS: [f32; 4] = [29.7, 37.1, 41.1, 43.7];
+const REVERB_ALLPASS_MS: [f32; 2] = [5.0, 1.7];
+const REVERB_ALLPASS_FEEDBACK: f32 = 0.7;
+// How many samples longer each channel's delays are than the last one's.
+const REVERB_SPREAD: usize = 23;
+// Range of the time it takes the tail to die away by 60 dB, in seconds.
+const MIN_REVERB_DECAY: f32 = 0.1;
+const MAX_REVERB_DECAY: f32 = 10.0;
+
+impl Reverb {
+    fn new(sample_rate: u32, channel: usize) -> Self {
+        let len = |ms: f32| (ms / 1000.0 * sample_rate as f32) as usize + channel * REVERB_SPREAD;
+        let mut reverb = Self {
+            sample_rate,
+            combs: REVERB_COMB_MS.iter().map(|&ms| FeedbackLine::new(len(ms), 0.0)).collect(),
+            allpasses: REVERB_ALLPASS_MS.iter().map(|&ms| FeedbackLine::new(len(ms), REVERB_ALLPASS_FEEDBACK)).collect(),
+            decay_secs: 0.0,
+        };
+        reverb.set_decay(1.0);
+        reverb
+    }
+
+    // Set how long the tail takes to die away by 60 dB. Every comb's
+    // feedback gets set so that its echoes get there in that time, however
+    // long its delay is.
+    fn set_decay(&mut self, secs: f32) {
+        if secs == self.decay_secs {
+            return;
+        }
+        self.decay_secs = secs;
+        for comb in self.combs.iter_mut() {
+            let delay_secs = comb.buf.len() as f32 / self.sample_rate as f32;
+            comb.feedback = db_to_gain(-60.0 * delay_secs / secs);
+        }
+    }
+
+    // Run one sample through, with mix of it (0 to 1) coming out wet.
+    fn process(&mut self, sample: f32, mix: f32) -> f32 {
+        let mut wet = self.combs.iter_mut().map(|comb| comb.comb(sample)).sum::<f32>() / self.combs.len() as f32;
+        for allpass in self.allpasses.iter_mut() {
+            wet = allpass.allpass(wet);
+        }
+        sample * (1.0 - mix) + wet * mix
+    }
+
+    // Forget the tail, so nothing old gets heard when it's turned on.
+    fn clear(&mut self) {
+        for line in self.combs.iter_mut().chain(self.allpasses.iter_mut()) {
+            line.buf.iter_mut().for_each(|sample| *sample = 0.0);
+        }
+    }
+}
+
+// The reverb's settings, shared with the output callback like the delay's.
+#[derive(Clone)]
+pub struct ReverbSettings {
+    enabled: Arc<AtomicBool>,
+    // How much of the output is wet (from 0 to 1), and how long the tail
+    // takes to die away, both as f32 bits.
+    mix: Arc<AtomicU32>,
+    decay_secs: Arc<AtomicU32>,
+}
+
+impl ReverbSettings {
+    fn new(mix: f32, decay_secs: f32) -> Self {
+        let settings = Self {
+            enabled: Arc::new(false.into()),
+            mix: Arc::new(0.into()),
+            decay_secs: Arc::new(0.into()),
+        };
+        settings.set_mix(mix);
+        settings.set_decay_secs(decay_secs);
+        settings
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn toggle(&self) {
+        self.enabled.store(!self.enabled(), Ordering::Relaxed);
+    }
+
+    fn mix(&self) -> f32 {
+        f32::from_bits(self.mix.load(Ordering::Relaxed))
+    }
+
+    fn set_mix(&self, mix: f32) {
+        self.mix.store(mix.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    fn decay_secs(&self) -> f32 {
+        f32::from_bits(self.decay_secs.load(Ordering::Relaxed))
+    }
+
+    fn set_decay_secs(&self, secs: f32) {
+        self.decay_secs.store(secs.clamp(MIN_REVERB_DECAY, MAX_REVERB_DECAY).to_bits(), Ordering::Relaxed);
+    }
+}
+
+// Lo-fi crunch on the whole mix: each sample gets rounded to one of a few
+// levels, and held for a few samples before the next one's taken, which
+// takes out the highs (and aliases them back down) like a cheaper sound card
+// would.
+#[derive(Clone)]
+struct BitCrush {
+    held: f32,
+    // Samples left until the next one's taken.
+    counter: usize,
+}
+
+impl BitCrush {
+    fn new() -> Self {
+        Self {
+            held: 0.0,
+            counter: 0,
+        }
+    }
+
+    // Run one sample through, quantized to bits bits and held for hold
+    // samples.
+    fn process(&mut self, sample: f32, bits: u32, hold: usize) -> f32 {
+        if self.counter == 0 {
+            // One bit goes on the sign, like a real sample.
+            let levels = (1u32 << (bits.clamp(1, MAX_CRUSH_BITS) - 1)) as f32;
+            self.held = (sample * levels).round() / levels;
+            self.counter = hold.max(1);
+        }
+        self.counter -= 1;
+        self.held
+    }
+}
+
+// Shifts the whole mix's pitch without changing its speed. Two taps read
+// back the last PITCH_WINDOW_MS at the new rate, sliding back across the
+// window as they go, and each fades out as it reaches the end and jumps
+// round to start again. That's cheap and never delays anything by more than
+// the window, at the cost of a little warble.
+#[derive(Clone)]
+struct PitchShift {
+    // The last PITCH_WINDOW_MS of input, allocated up front.
+    buf: Vec<f32>,
+    pos: usize,
+    // How far back across the window the first tap is, from 0 to 1. The
+    // second is always half a window away from it.
+    phase: f32,
+}
+
+const PITCH_WINDOW_MS: f32 = 50.0;
+// Up to an octave either way.
+const MAX_PITCH_SEMITONES: f32 = 12.0;
+
+impl PitchShift {
+    fn new(sample_rate: u32) -> Self {
+        let len = (PITCH_WINDOW_MS / 1000.0 * sample_rate as f32) as usize;
+        Self {
+            buf: vec![0.0; len.max(2)],
+            pos: 0,
+            phase: 0.0,
+        }
+    }
+
+    // Run one sample through, shifted up by ratio (e.g. 2.0 for an octave).
+    fn process(&mut self, sample: f32, ratio: f32) -> f32 {
+        let len = self.buf.len();
+        self.buf[self.pos] = sample;
+        let mut out = 0.0;
+        for offset in [0.0, 0.5] {
+            let phase = (self.phase + offset).fract();
+            // Silent at either end of the window, where the tap jumps, and
+            // the two together keep the power steady.
+            let weight = (std::f32::consts::PI * phase).sin();
+            out += self.read(phase * (len - 2) as f32) * weight;
+        }
+        // Reading more slowly than we write (for a lower pitch) means falling
+        // further behind, and vice versa.
+        self.phase = (self.phase + (1.0 - ratio) / len as f32).rem_euclid(1.0);
+        self.pos = (self.pos + 1) % len;
+        out
+    }
+
+    // The input from delay frames ago, interpolating between samples.
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buf.len();
+        let back = delay.floor() as usize;
+        let frac = delay - back as f32;
+        let newer = self.buf[(self.pos + len - back) % len];
+        let older = self.buf[(self.pos + len - back - 1) % len];
+        newer + (older - newer) * frac
+    }
+
+    // Forget everything, so nothing old gets heard when it's turned on.
+    fn clear(&mut self) {
+        self.buf.iter_mut().for_each(|sample| *sample = 0.0);
+        self.phase = 0.0;
+    }
+}
+
+// The delay's settings, shared with the output callback so the UI can change
+// them as it runs.
+#[derive(Clone)]
+pub struct DelaySettings {
+    enabled: Arc<AtomicBool>,
+    // Time between echoes in milliseconds, and how much of each echo comes
+    // back around in the next, both as f32 bits.
+    time_ms: Arc<AtomicU32>,
+    feedback: Arc<AtomicU32>,
+}
+
+// The longest the delay can be, and the most feedback it can have. Any more
+// feedback and the echoes would take forever to die out, or never would.
+const MAX_DELAY_MS: f32 = 2000.0;
+const MAX_DELAY_FEEDBACK: f32 = 0.9;
+
+impl DelaySettings {
+    fn new(time_ms: f32, feedback: f32) -> Self {
+        let settings = Self {
+            enabled: Arc::new(false.into()),
+            time_ms: Arc::new(0.into()),
+            feedback: Arc::new(0.into()),
+        };
+        settings.set_time_ms(time_ms);
+        settings.set_feedback(feedback);
+        settings
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn toggle(&self) {
+        self.enabled.store(!self.enabled(), Ordering::Relaxed);
+    }
+
+    pub fn time_ms(&self) -> f32 {
+        f32::from_bits(self.time_ms.load(Ordering::Relaxed))
+    }
+
+    fn set_time_ms(&self, ms: f32) {
+        self.time_ms.store(ms.clamp(1.0, MAX_DELAY_MS).to_bits(), Ordering::Relaxed);
+    }
+
+    fn feedback(&self) -> f32 {
+        f32::from_bits(self.feedback.load(Ordering::Relaxed))
+    }
+
+    fn set_feedback(&self, feedback: f32) {
+        self.feedback.store(feedback.clamp(0.0, MAX_DELAY_FEEDBACK).to_bits(), Ordering::Relaxed);
+    }
+}
+
+// The bit-crusher's settings, shared with the output callback like the
+// delay's.
+#[derive(Clone)]
+pub struct BitCrushSettings {
+    enabled: Arc<AtomicBool>,
+    // Bits to quantize to, and how many samples to hold each one for.
+    bits: Arc<AtomicU32>,
+    downsample: Arc<AtomicUsize>,
+}
+
+// Any more bits than this and there's nothing left to hear; any more
+// downsampling and there's nothing left of the loop.
+const MAX_CRUSH_BITS: u32 = 16;
+const MAX_CRUSH_DOWNSAMPLE: usize = 32;
+
+impl BitCrushSettings {
+    fn new(bits: u32, downsample: usize) -> Self {
+        let settings = Self {
+            enabled: Arc::new(false.into()),
+            bits: Arc::new(0.into()),
+            downsample: Arc::new(0.into()),
+        };
+        settings.set_bits(bits);
+        settings.set_downsample(downsample);
+        settings
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn toggle(&self) {
+        self.enabled.store(!self.enabled(), Ordering::Relaxed);
+    }
+
+    fn bits(&self) -> u32 {
+        self.bits.load(Ordering::Relaxed)
+    }
+
+    fn set_bits(&self, bits: u32) {
+        self.bits.store(bits.clamp(1, MAX_CRUSH_BITS), Ordering::Relaxed);
+    }
+
+    fn downsample(&self) -> usize {
+        self.downsample.load(Ordering::Relaxed)
+    }
+
+    fn set_downsample(&self, downsample: usize) {
+        self.downsample.store(downsample.clamp(1, MAX_CRUSH_DOWNSAMPLE), Ordering::Relaxed);
+    }
+}
+
+// Range of the master low-pass filter's cutoff, in Hz.
+const MIN_CUTOFF: f32 = 100.0;
+const MAX_CUTOFF: f32 = 20000.0;
+
+// Widest the stereo image can go: the sides at twice what they were.
+const MAX_WIDTH: f32 = 2.0;
+
+// A stereo frame with its sides (whatever's different between left and
+// right) scaled by width: zero is mono, one leaves it as it was, and more
+// than that widens it.
+fn mid_side(left: f32, right: f32, width: f32) -> (f32, f32) {
+    let mid = (left + right) / 2.0;
+    let side = (left - right) / 2.0 * width;
+    (mid + side, mid - side)
+}
+
+// One-pole smoothing coefficient for reaching ~63% of a target in `ms`.
+fn time_coeff(ms: f32, sample_rate: u32) -> f32 {
+    if ms <= 0.0 {
+        return 0.0;
+    }
+    (-1.0 / (ms * 0.001 * sample_rate as f32)).exp()
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-9).log10()
+}
+
+// The gain that takes a peak up or down to target_db, if there's anything
+// there to take.
+fn normalize_gain(peak: f32, target_db: f32) -> Option<f32> {
+    if peak <= 0.0 {
+        return None;
+    }
+    Some(db_to_gain(target_db) / peak)
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+// The frames from the first with any sample louder than threshold up to
+// just past the last, if there are any at all.
+fn loud_frames(samples: &[f32], channels: usize, threshold: f32) -> Option<(usize, usize)> {
+    let loud = |frame: &[f32]| frame.iter().any(|sample| sample.abs() > threshold);
+    let start = samples.chunks(channels).position(loud)?;
+    let end = samples.chunks(channels).rposition(loud)? + 1;
+    Some((start, end))
+}
+
+// The lowest and highest sample (on any channel) in each of width slices of
+// the frames in samples. If there are fewer frames than slices, some frames
+// end up in more than one.
+fn waveform_peaks(samples: &[f32], channels: usize, width: usize) -> Vec<(f32, f32)> {
+    let frames = samples.len() / channels;
+    if frames == 0 {
+        return Vec::new();
+    }
+    (0..width)
+        .map(|bucket| {
+            let start = (bucket * frames / width).min(frames - 1);
+            let end = ((bucket + 1) * frames / width).max(start + 1);
+            samples[start * channels..end * channels].iter()
+                .fold((f32::MAX, f32::MIN), |(min, max), &sample| (min.min(sample), max.max(sample)))
+        })
+        .collect()
+}
+
+// Keeps track of the recent peak output level, for the UI to display. The
+// output callback updates it after each buffer; the peak then falls off
+// gradually until something louder comes along.
+#[derive(Clone)]
+pub struct PeakMeter {
+    // The current peak (absolute) sample value, as f32 bits.
+    peak: Arc<AtomicU32>,
+    // How much the peak falls off with each frame.
+    decay: f32,
+    // How many output samples have clipped so far.
+    clips: Arc<AtomicUsize>,
+}
+
+impl PeakMeter {
+    // The peak falls to about a third of its level after window_ms.
+    fn new(window_ms: f32, sample_rate: u32) -> Self {
+        Self {
+            peak: Arc::new(0.0_f32.to_bits().into()),
+            decay: time_coeff(window_ms, sample_rate),
+            clips: Arc::new(0.into()),
+        }
+    }
+
+    fn peak(&self) -> f32 {
+        f32::from_bits(self.peak.load(Ordering::Relaxed))
+    }
+
+    pub fn peak_dbfs(&self) -> f32 {
+        gain_to_db(self.peak())
+    }
+
+    // Account for a buffer of interleaved output samples.
+    fn update(&self, samples: &[f32], channels: usize) {
+        let frames = (samples.len() / channels) as i32;
+        let decayed = self.peak() * self.decay.powi(frames);
+        let buffer_peak = samples.iter().fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        self.peak.store(decayed.max(buffer_peak).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn clip_count(&self) -> usize {
+        self.clips.load(Ordering::Relaxed)
+    }
+
+    fn add_clips(&self, n: usize) {
+        self.clips.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+// Keeps track of the recent RMS output level, which goes by how loud things
+// sound better than the peak does. Like PeakMeter, the output callback
+// updates it after each buffer.
+#[derive(Clone)]
+pub struct RmsMeter {
+    // Moving average of the squared samples (across all channels), as f32
+    // bits.
+    mean_square: Arc<AtomicU32>,
+    // How much of the average carries over from one frame to the next.
+    coeff: f32,
+}
+
+impl RmsMeter {
+    // Averages over roughly the last window_ms.
+    pub fn new(window_ms: f32, sample_rate: u32) -> Self {
+        Self {
+            mean_square: Arc::new(0.0_f32.to_bits().into()),
+            coeff: time_coeff(window_ms, sample_rate),
+        }
+    }
+
+    fn rms(&self) -> f32 {
+        f32::from_bits(self.mean_square.load(Ordering::Relaxed)).sqrt()
+    }
+
+    pub fn rms_dbfs(&self) -> f32 {
+        gain_to_db(self.rms())
+    }
+
+    // Account for a buffer of interleaved output samples.
+    fn update(&self, samples: &[f32], channels: usize) {
+        let mut mean_square = f32::from_bits(self.mean_square.load(Ordering::Relaxed));
+        for frame in samples.chunks(channels) {
+            let frame_square = frame.iter().map(|sample| sample * sample).sum::<f32>() / channels as f32;
+            mean_square = mean_square * self.coeff + frame_square * (1.0 - self.coeff);
+        }
+        self.mean_square.store(mean_square.to_bits(), Ordering::Relaxed);
+    }
+}
+
+// Clamp samples to -1.0..=1.0, returning how many were out of range.
+fn hard_clip(samples: &mut [f32]) -> usize {
+    let mut clipped = 0;
+    for sample in samples {
+        if sample.abs() > 1.0 {
+            *sample = sample.clamp(-1.0, 1.0);
+            clipped += 1;
+        }
+    }
+    clipped
+}
+
+// Works out a tempo from a series of taps.
+pub struct TapTempo {
+    // The most recent taps, oldest first.
+    taps: Vec<Instant>,
+}
+
+// Most taps TapTempo averages over.
+const TAP_TEMPO_WINDOW: usize = 8;
+// A gap between taps longer than this starts a new tempo.
+const TAP_TEMPO_TIMEOUT: Duration = Duration::from_secs(2);
+// Intervals further than this fraction away from the median are ignored.
+const TAP_TEMPO_TOLERANCE: f64 = 0.25;
+
+impl TapTempo {
+    fn new() -> Self {
+        Self {
+            taps: Vec::with_capacity(TAP_TEMPO_WINDOW),
+        }
+    }
+
+    fn tap(&mut self, at: Instant) {
+        if let Some(last) = self.taps.last() {
+            if at.saturating_duration_since(*last) > TAP_TEMPO_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+        if self.taps.len() == TAP_TEMPO_WINDOW {
+            self.taps.remove(0);
+        }
+        self.taps.push(at);
+    }
+
+    fn reset(&mut self) {
+        self.taps.clear();
+    }
+
+    // The tempo implied by the taps so far, if there have been at least two.
+    fn bpm(&self) -> Option<f32> {
+        let mut intervals: Vec<f64> = self.taps.windows(2)
+            .map(|pair| pair[1].saturating_duration_since(pair[0]).as_secs_f64())
+            .collect();
+        if intervals.is_empty() {
+            return None;
+        }
+
+        intervals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = intervals[intervals.len() / 2];
+        let steady: Vec<f64> = intervals.into_iter()
+            .filter(|interval| (interval - median).abs() <= median * TAP_TEMPO_TOLERANCE)
+            .collect();
+        let average = steady.iter().sum::<f64>() / steady.len() as f64;
+        if average <= 0.0 {
+            return None;
+        }
+        Some((60.0 / average) as f32)
+    }
+
+    // How many frames long a loop of the given number of bars would be at
+    // the tapped tempo.
+    fn loop_len(&self, bars: u32, beats_per_bar: u32, sample_rate: u32) -> Option<usize> {
+        let beats = (bars * beats_per_bar) as f64;
+        Some(beats_to_samples(beats, sample_rate, self.bpm()?))
+    }
+}
+
+// Works out the tempo of an external MIDI clock, and where its beats fall.
+pub struct MidiClock {
+    // When the most recent pulses arrived, oldest first, by the MIDI input's
+    // own clock.
+    pulses: Vec<Duration>,
+    // Pulses since the last Start, if the clock's running.
+    since_start: Option<usize>,
+}
+
+// Most pulses MidiClock averages over: two beats' worth.
+const MIDI_CLOCK_WINDOW: usize = 2 * CLOCK_TICKS_PER_BEAT;
+// A gap between pulses longer than this means the clock stopped for a while
+// (below 3 bpm or so), so its old tempo doesn't count any more.
+const MIDI_CLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl MidiClock {
+    fn new() -> Self {
+        Self {
+            pulses: Vec::with_capacity(MIDI_CLOCK_WINDOW),
+            since_start: None,
+        }
+    }
+
+    // Count a clock pulse that arrived at at, returning whether it's on a
+    // beat since the last Start. The first one after a Start is the
+    // downbeat.
+    pub fn pulse(&mut self, at: Duration) -> bool {
+        if let Some(last) = self.pulses.last() {
+            if at.saturating_sub(*last) > MIDI_CLOCK_TIMEOUT {
+                self.pulses.clear();
+            }
+        }
+        if self.pulses.len() == MIDI_CLOCK_WINDOW {
+            self.pulses.remove(0);
+        }
+        self.pulses.push(at);
+
+        match self.since_start {
+            Some(pulses) => {
+                self.since_start = Some(pulses + 1);
+                pulses.is_multiple_of(CLOCK_TICKS_PER_BEAT)
+            },
+            None => false,
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.since_start = Some(0);
+    }
+
+    // The tempo stays put as long as the clock keeps going, but stopping
+    // means starting over.
+    pub fn stop(&mut self) {
+        self.since_start = None;
+        self.pulses.clear();
+    }
+
+    pub fn running(&self) -> bool {
+        self.since_start.is_some()
+    }
+
+    // How long a beat lasts, in seconds, on average over the last few
+    // pulses, if there have been at least two.
+    fn beat_secs(&self) -> Option<f64> {
+        let (first, last) = (self.pulses.first()?, self.pulses.last()?);
+        if self.pulses.len() < 2 || last <= first {
+            return None;
+        }
+        let pulse = last.saturating_sub(*first).as_secs_f64() / (self.pulses.len() - 1) as f64;
+        Some(pulse * CLOCK_TICKS_PER_BEAT as f64)
+    }
+
+    pub fn bpm(&self) -> Option<f32> {
+        Some((60.0 / self.beat_secs()?) as f32)
+    }
+
+    // How many frames long recorded frames come to, rounded to a whole
+    // number of units beats_per_unit beats long at the clock's tempo (but at
+    // least one). Going straight from the pulses, rather than through bpm(),
+    // keeps this to the nearest frame, however long the loop is.
+    pub fn loop_len(&self, recorded: usize, beats_per_unit: u32, sample_rate: u32) -> Option<usize> {
+        let unit = self.beat_secs()? * beats_per_unit.max(1) as f64 * sample_rate as f64;
+        let units = (recorded as f64 / unit).round().max(1.0);
+        Some((units * unit).round() as usize)
+    }
+}
+
+// What to snap the first loop length to, if anything.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantize {
+    Off,
+    Beat,
+    Bar,
+}
+
+impl Quantize {
+    pub fn next(self) -> Self {
+        match self {
+            Quantize::Off => Quantize::Beat,
+            Quantize::Beat => Quantize::Bar,
+            Quantize::Bar => Quantize::Off,
+        }
+    }
+}
+
+// How many beats (including any fraction of one) frames frames last at bpm.
+pub fn samples_to_beats(frames: usize, sample_rate: u32, bpm: f32) -> f64 {
+    if sample_rate == 0 {
+        return 0.0;
+    }
+    frames as f64 * bpm as f64 / (60.0 * sample_rate as f64)
+}
+
+// How many frames beats beats last at bpm, to the nearest frame. No tempo
+// (or a negative number of beats) means no time at all.
+pub fn beats_to_samples(beats: f64, sample_rate: u32, bpm: f32) -> usize {
+    if bpm <= 0.0 {
+        return 0;
+    }
+    (beats * 60.0 * sample_rate as f64 / bpm as f64).round().max(0.0) as usize
+}
+
+// MIDI clock ticks this many times a beat.
+pub const CLOCK_TICKS_PER_BEAT: usize = 24;
+
+// How many beats a loop of len frames gets clocked out as: however many
+// whole beats at bpm come closest, but at least one, so the clock always
+// lines up with the loop even if it wasn't played quite in time.
+pub fn clock_beats(len: usize, sample_rate: u32, bpm: f32) -> usize {
+    (samples_to_beats(len, sample_rate, bpm).round() as usize).max(1)
+}
+
+// The tempo a loop of len frames clocks out at, if it's beats beats long.
+pub fn clock_bpm(len: usize, sample_rate: u32, beats: usize) -> f32 {
+    if len == 0 {
+        return 0.0;
+    }
+    (beats as f64 * 60.0 * sample_rate as f64 / len as f64) as f32
+}
+
+// Which MIDI clock tick (counting from zero at the start of the loop) phase
+// (see State::phase()) falls in, for a loop beats beats long.
+pub fn clock_tick(phase: f32, beats: usize) -> usize {
+    let ticks = beats * CLOCK_TICKS_PER_BEAT;
+    ((phase as f64 * ticks as f64) as usize).min(ticks.saturating_sub(1))
+}
+
+// Click track for keeping time, mixed straight into the output.
+#[derive(Clone)]
+pub struct Metronome {
+    // Tempo, as f32 bits, so it can be changed while the metronome plays.
+    bpm: Arc<AtomicU32>,
+    pub beats_per_bar: u32,
+    sample_rate: u32,
+    // Shared with the output callback so the UI can turn it on and off.
+    enabled: Arc<AtomicBool>,
+}
+
+// How long each click rings for, in seconds.
+const CLICK_LEN: f64 = 0.02;
+
+impl Metronome {
+    pub fn new(bpm: f32, beats_per_bar: u32, sample_rate: u32) -> Self {
+        Self {
+            bpm: Arc::new(bpm.to_bits().into()),
+            beats_per_bar,
+            sample_rate,
+            enabled: Arc::new(false.into()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    fn toggle(&self) {
+        self.enabled.store(!self.enabled(), Ordering::Relaxed);
+    }
+
+    pub fn bpm(&self) -> f32 {
+        f32::from_bits(self.bpm.load(Ordering::Relaxed))
+    }
+
+    fn set_bpm(&self, bpm: f32) {
+        self.bpm.store(bpm.to_bits(), Ordering::Relaxed);
+    }
+
+    fn samples_per_beat(&self) -> f64 {
+        self.sample_rate as f64 * 60.0 / self.bpm() as f64
+    }
+
+    // How many whole bars, and beats on top of that, frames frames last at
+    // the metronome's tempo. Beats get rounded to a hundredth first, so a
+    // loop that's a rounding error short of a bar still counts as one.
+    pub fn bars_and_beats(&self, frames: usize) -> (usize, f64) {
+        let beats = (samples_to_beats(frames, self.sample_rate, self.bpm()) * 100.0).round() / 100.0;
+        let bars = (beats / self.beats_per_bar as f64).floor();
+        (bars as usize, beats - bars * self.beats_per_bar as f64)
+    }
+
+    // Round len to the nearest whole number of beats or bars (but at least
+    // one).
+    fn quantize(&self, len: usize, grid: Quantize) -> usize {
+        let beats_per_unit = match grid {
+            Quantize::Off => return len,
+            Quantize::Beat => 1.0,
+            Quantize::Bar => self.beats_per_bar as f64,
+        };
+        let beats = samples_to_beats(len, self.sample_rate, self.bpm());
+        let units = (beats / beats_per_unit).round().max(1.0);
+        beats_to_samples(units * beats_per_unit, self.sample_rate, self.bpm())
+    }
+
+    // The click sample to play at the given absolute sample index. Silent
+    // between clicks, and when the metronome is off.
+    fn click(&self, idx: usize) -> f32 {
+        if !self.enabled() {
+            return 0.0;
+        }
+        self.sound(idx)
+    }
+
+    // Like click(), but whether or not the metronome is on. The first beat
+    // of each bar is accented with a higher pitch.
+    fn sound(&self, idx: usize) -> f32 {
+        let beat_len = self.samples_per_beat();
+        let beat = (idx as f64 / beat_len).floor();
+        let t = (idx as f64 - beat * beat_len) / self.sample_rate as f64;
+        if t >= CLICK_LEN {
+            return 0.0;
+        }
+
+        let freq = if (beat as u64).is_multiple_of(self.beats_per_bar as u64) {
+            1760.0
+        } else {
+            880.0
+        };
+        let envelope = 1.0 - t / CLICK_LEN;
+        (0.5 * envelope * (2.0 * std::f64::consts::PI * freq * t).sin()) as f32
+    }
+}
+
+// Memory ordering: the samples themselves only ever cross threads through
+// the Clip ring buffer and the SampleBank mutex, which do their own
+// synchronizing, so none of these atomics protect any other data. Most of
+// them are independent values that are fine to see a little late, and use
+// Relaxed. The exceptions are the ones that get stored last when several
+// values change together, so that whoever sees the new value also sees the
+// rest: loop_count (after loop_len and total_samples), record_mode (after
+// feedback), count_in_remaining (after count_in_len), closing_len (after
+// the first loop's padding), cancelling (after is_recording), and
+// is_recording (after everything that recording depends on, like
+// record_floor). Those are stored with Release and loaded with Acquire.
+#[derive(Clone)]
+pub struct State {
+    // Number of interleaved channels. Playback position and loop length are
+    // counted in frames (one sample for each channel), while everything that
+    // indexes into SampleBank is counted in samples.
+    pub channels: usize,
+    // Where we are in the playback, relative to the start of each loop layer.
+    // This will always be a number between 0 and loop_len.
+    playback: Arc<AtomicUsize>,
+    // Number of frames in the current loop (i.e. in every loop layer).
+    // This determines when playback resets, as well as how far ahead we're
+    // allowed to write into SampleBank. It's zero until the first loop
+    // closes, and only set then.
+    loop_len: Arc<AtomicUsize>,
+    // The number of partially or completely recorded loops.
+    loop_count: Arc<AtomicUsize>,
+    // Total samples across all loop layers.
+    total_samples: Arc<AtomicUsize>,
+    // Where the next Clip sent during the first loop should start. This is
+    // advanced by the input callback as it sends Clips, so it can run ahead of
+    // total_samples.
+    write_cursor: Arc<AtomicUsize>,
+    // Where the layers that were already there when recording last started
+    // end, in samples. Input from just before recording started can be
+    // meant for a little before the new layer (see next_write_index()), but
+    // it mustn't go over what's already been kept.
+    record_floor: Arc<AtomicUsize>,
+    // Whether we're currently recording new samples,
+    // i.e. writing to SampleBank.
+    is_recording: Arc<AtomicBool>,
+    // Frames left to count in before recording actually starts, and how
+    // many there were to begin with.
+    count_in_remaining: Arc<AtomicUsize>,
+    count_in_len: Arc<AtomicUsize>,
+    // Whether the loop is playing. Stopping playback freezes the playback
+    // position where it is, so playing again picks up from the same spot.
+    is_playing: Arc<AtomicBool>,
+    // Whether playback runs backwards through the loop. Nothing can be
+    // recorded while it does, since recordings line up with playback.
+    reversed: Arc<AtomicBool>,
+    // How fast playback goes through the loop (which changes its pitch
+    // too), and how far between frames that leaves it, both as f32 bits.
+    // Like reversing, this rules out recording unless it's 1.0.
+    rate: Arc<AtomicU32>,
+    playback_frac: Arc<AtomicU32>,
+    // Overall output volume, as f32 bits.
+    master_gain: Arc<AtomicU32>,
+    // Whether live input gets played along with the loop, and how loud, as
+    // f32 bits.
+    is_monitoring: Arc<AtomicBool>,
+    monitor_gain: Arc<AtomicU32>,
+    // Cutoff of the low-pass filter on the whole mix, in Hz, as f32 bits.
+    cutoff: Arc<AtomicU32>,
+    // How far to shift the whole mix's pitch, in semitones, as f32 bits.
+    pitch: Arc<AtomicU32>,
+    // How much of the mix goes through the effects, from 0 (none of it) to
+    // 1 (all of it), as f32 bits. See EffectsChain.
+    wet_mix: Arc<AtomicU32>,
+    // How wide the stereo image is, as f32 bits. See mid_side().
+    width: Arc<AtomicU32>,
+    // How recording after the first loop treats what's already there, and
+    // the feedback to use when overdubbing (as f32 bits). Use record_mode()
+    // to get both as a RecordMode.
+    record_mode: Arc<AtomicUsize>,
+    feedback: Arc<AtomicU32>,
+    // Frames at the end of the loop to crossfade into the start. Use
+    // crossfade_len() to keep it in bounds for the current loop.
+    crossfade_len: Arc<AtomicUsize>,
+    // How many frames late input gets to us, compared to what was playing
+    // when it was played, so it can be recorded that much earlier. See
+    // next_write_index().
+    latency: Arc<AtomicUsize>,
+    // Frames output so far, shared by every track, and what it was when
+    // this track's loop started (i.e. when its playback was last at frame 0,
+    // in the same pass as when its first loop started recording). When
+    // synced, playback follows the clock instead of going its own way (see
+    // sync_playback()), which keeps loops in phase with each other even
+    // when they get stopped and started.
+    clock: Arc<AtomicUsize>,
+    clock_offset: Arc<AtomicUsize>,
+    synced: Arc<AtomicBool>,
+    // The length (in frames) the first loop always closes at, or zero to go
+    // by however long it takes to record. See fixed_len().
+    fixed_len: Arc<AtomicUsize>,
+    // The most layers a track can have, or zero for no limit. See
+    // max_layers().
+    max_layers: Arc<AtomicUsize>,
+    // The most frames a track can record, or zero for no limit. See
+    // max_record_samples().
+    max_record_len: Arc<AtomicUsize>,
+    // How many of the layers get played, counting from the first, or zero
+    // for all of them. See heard_layers().
+    playback_layer_limit: Arc<AtomicUsize>,
+    // The length (in frames) the first loop has been asked to close at, or
+    // zero if it hasn't. The output callback does the actual closing, since
+    // it's the one recording; that way loop_count only ever changes while
+    // recording on the output thread.
+    closing_len: Arc<AtomicUsize>,
+    // Whether the layer in progress has been asked to be thrown away. The
+    // output callback does that too, for the same reason.
+    cancelling: Arc<AtomicBool>,
+    // Whether playback has been asked to go back to the start of the loop.
+    // The output callback moves it, since it's the one moving it along.
+    retriggering: Arc<AtomicBool>,
+    // How many times playback's come round with layers decaying. The output
+    // callback turns them down as it wraps; the UI only goes looking for
+    // ones that have faded away once this has moved on (see
+    // Looper::decay_layers()).
+    decay_cycles: Arc<AtomicUsize>,
+    // How many times playback's come round to the start of the loop.
+    cycles: Arc<AtomicUsize>,
+}
+
+// How new recordings combine with existing ones, once the first loop is
+// established.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum RecordMode {
+    // Each pass around the loop records a new layer on top of the others.
+    Layer,
+    // Mix into the top layer, attenuating what's already there by feedback
+    // on each pass, like a tape loop.
+    Overdub { feedback: f32 },
+    // Punch in over the whole loop, so that only the new recording is heard
+    // wherever it lands.
+    Replace,
+}
+
+impl std::str::FromStr for RecordMode {
+    type Err = anyhow::Error;
+
+    // Parse layer, overdub, or replace. Overdubbing always starts out with
+    // the default feedback.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "layer" => Ok(RecordMode::Layer),
+            "overdub" => Ok(RecordMode::Overdub { feedback: DEFAULT_FEEDBACK }),
+            "replace" => Ok(RecordMode::Replace),
+            _ => anyhow::bail!("expected layer, overdub, or replace, not \"{}\"", s),
+        }
+    }
+}
+
+impl std::convert::TryFrom<String> for RecordMode {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+// Loudest we'll let the master volume go.
+const MAX_MASTER_GAIN: f32 = 2.0;
+// Playback can go from an octave down to an octave up.
+const MIN_RATE: f32 = 0.5;
+const MAX_RATE: f32 = 2.0;
+
+// How we store each RecordMode in State.
+const LAYER: usize = 0;
+const OVERDUB: usize = 1;
+const REPLACE: usize = 2;
+
+impl State {
+    fn new() -> Self {
+        Self {
+            channels: 1,
+            playback: Arc::new(0.into()),
+            loop_len: Arc::new(0.into()),
+            loop_count: Arc::new(0.into()),
+            total_samples: Arc::new(0.into()),
+            write_cursor: Arc::new(0.into()),
+            record_floor: Arc::new(0.into()),
+            is_recording: Arc::new(false.into()),
+            count_in_remaining: Arc::new(0.into()),
+            count_in_len: Arc::new(0.into()),
+            is_playing: Arc::new(true.into()),
+            reversed: Arc::new(false.into()),
+            rate: Arc::new(1.0_f32.to_bits().into()),
+            playback_frac: Arc::new(0.0_f32.to_bits().into()),
+            master_gain: Arc::new(1.0_f32.to_bits().into()),
+            is_monitoring: Arc::new(false.into()),
+            monitor_gain: Arc::new(1.0_f32.to_bits().into()),
+            cutoff: Arc::new(MAX_CUTOFF.to_bits().into()),
+            pitch: Arc::new(0.0_f32.to_bits().into()),
+            wet_mix: Arc::new(1.0_f32.to_bits().into()),
+            width: Arc::new(1.0_f32.to_bits().into()),
+            record_mode: Arc::new(LAYER.into()),
+            feedback: Arc::new(0.0_f32.to_bits().into()),
+            crossfade_len: Arc::new(0.into()),
+            latency: Arc::new(0.into()),
+            clock: Arc::new(0.into()),
+            clock_offset: Arc::new(0.into()),
+            synced: Arc::new(false.into()),
+            fixed_len: Arc::new(0.into()),
+            max_layers: Arc::new(0.into()),
+            max_record_len: Arc::new(0.into()),
+            playback_layer_limit: Arc::new(0.into()),
+            closing_len: Arc::new(0.into()),
+            cancelling: Arc::new(false.into()),
+            retriggering: Arc::new(false.into()),
+            decay_cycles: Arc::new(0.into()),
+            cycles: Arc::new(0.into()),
+        }
+    }
+
+    // A State for another track, with nothing recorded, that shares this
+    // one's clock and settings for the whole mix: master gain, monitoring,
+    // the filter, pitch, crossfade, fixed length, limits, and syncing.
+    fn new_track(&self) -> Self {
+        Self {
+            channels: self.channels,
+            clock: self.clock.clone(),
+            synced: self.synced.clone(),
+            master_gain: self.master_gain.clone(),
+            is_monitoring: self.is_monitoring.clone(),
+            monitor_gain: self.monitor_gain.clone(),
+            cutoff: self.cutoff.clone(),
+            pitch: self.pitch.clone(),
+            wet_mix: self.wet_mix.clone(),
+            width: self.width.clone(),
+            crossfade_len: self.crossfade_len.clone(),
+            latency: self.latency.clone(),
+            fixed_len: self.fixed_len.clone(),
+            max_layers: self.max_layers.clone(),
+            max_record_len: self.max_record_len.clone(),
+            ..Self::new()
+        }
+    }
+
+    fn recording(&self) -> bool {
+        self.is_recording.load(Ordering::Acquire)
+    }
+
+    fn toggle_recording(&self) {
+        if self.recording() {
+            self.is_recording.store(false, Ordering::Release);
+        } else {
+            self.begin_recording();
+        }
+    }
+
+    fn begin_recording(&self) {
+        // Nothing else adds layers while recording's off.
+        self.record_floor.store(self.get_loop_count() * self.layer_len(), Ordering::Relaxed);
+        self.is_recording.store(true, Ordering::Release);
+    }
+
+    // Start recording once len frames have been played, instead of right
+    // away.
+    fn start_count_in(&mut self, len: usize) {
+        self.count_in_len.store(len, Ordering::Relaxed);
+        self.count_in_remaining.store(len, Ordering::Release);
+    }
+
+    fn counting_in(&self) -> bool {
+        self.count_in_remaining.load(Ordering::Acquire) > 0
+    }
+
+    // How many frames into the count-in we are, if we're counting in.
+    fn count_in_elapsed(&self) -> Option<usize> {
+        let remaining = self.count_in_remaining.load(Ordering::Acquire);
+        if remaining == 0 {
+            return None;
+        }
+        Some(self.count_in_len.load(Ordering::Relaxed).saturating_sub(remaining))
+    }
+
+    // Count down one frame, starting recording at the end of the count-in.
+    fn advance_count_in(&mut self) {
+        let remaining = self.count_in_remaining.load(Ordering::Acquire);
+        if remaining == 0 {
+            return;
+        }
+
+        self.count_in_remaining.store(remaining - 1, Ordering::Release);
+        if remaining == 1 {
+            self.begin_recording();
+        }
+    }
+
+    pub fn playing(&self) -> bool {
+        self.is_playing.load(Ordering::Relaxed)
+    }
+
+    fn toggle_playing(&self) {
+        self.is_playing.store(!self.playing(), Ordering::Relaxed);
+    }
+
+    fn reversed(&self) -> bool {
+        self.reversed.load(Ordering::Relaxed)
+    }
+
+    fn toggle_reversed(&self) {
+        self.reversed.store(!self.reversed(), Ordering::Relaxed);
+    }
+
+    pub fn rate(&self) -> f32 {
+        f32::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    fn set_rate(&self, rate: f32) {
+        self.rate.store(rate.clamp(MIN_RATE, MAX_RATE).to_bits(), Ordering::Relaxed);
+    }
+
+    // Whether playback is going through the loop any differently than it
+    // was recorded, in which case nothing new can be recorded.
+    fn varispeed(&self) -> bool {
+        self.reversed() || self.rate() != 1.0
+    }
+
+    // How far past get_playback() playback really is, between 0 and 1.
+    fn playback_frac(&self) -> f32 {
+        f32::from_bits(self.playback_frac.load(Ordering::Relaxed))
+    }
+
+    pub fn master_gain(&self) -> f32 {
+        f32::from_bits(self.master_gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set_master_gain(&self, gain: f32) {
+        let gain = gain.clamp(0.0, MAX_MASTER_GAIN);
+        self.master_gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn monitoring(&self) -> bool {
+        self.is_monitoring.load(Ordering::Relaxed)
+    }
+
+    fn toggle_monitoring(&self) {
+        self.is_monitoring.store(!self.monitoring(), Ordering::Relaxed);
+    }
+
+    fn monitor_gain(&self) -> f32 {
+        f32::from_bits(self.monitor_gain.load(Ordering::Relaxed))
+    }
+
+    pub fn set_monitor_gain(&self, gain: f32) {
+        self.monitor_gain.store(gain.clamp(0.0, MAX_MASTER_GAIN).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn cutoff(&self) -> f32 {
+        f32::from_bits(self.cutoff.load(Ordering::Relaxed))
+    }
+
+    fn set_cutoff(&self, hz: f32) {
+        self.cutoff.store(hz.clamp(MIN_CUTOFF, MAX_CUTOFF).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn pitch_semitones(&self) -> f32 {
+        f32::from_bits(self.pitch.load(Ordering::Relaxed))
+    }
+
+    fn set_pitch_semitones(&self, semitones: f32) {
+        let semitones = semitones.clamp(-MAX_PITCH_SEMITONES, MAX_PITCH_SEMITONES);
+        self.pitch.store(semitones.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn wet_mix(&self) -> f32 {
+        f32::from_bits(self.wet_mix.load(Ordering::Relaxed))
+    }
+
+    fn set_wet_mix(&self, mix: f32) {
+        self.wet_mix.store(mix.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn width(&self) -> f32 {
+        f32::from_bits(self.width.load(Ordering::Relaxed))
+    }
+
+    fn set_width(&self, width: f32) {
+        self.width.store(width.clamp(0.0, MAX_WIDTH).to_bits(), Ordering::Relaxed);
+    }
+
+    fn first_loop(&self) -> bool {
+        self.get_loop_count() == 0
+    }
+
+    fn began_recording(&self) -> bool {
+        self.recording() || !self.first_loop()
+    }
+
+    pub fn get_playback(&self) -> usize {
+        self.playback.load(Ordering::Relaxed)
+    }
+
+    pub fn get_loop_len(&self) -> usize {
+        self.loop_len.load(Ordering::Relaxed)
+    }
+
+    // How far through the loop playback is, from 0 at the start up to (but
+    // not including) 1 at the end, e.g. for flashing a light in time. Zero
+    // until there's a loop.
+    pub fn phase(&self) -> f32 {
+        let len = self.get_loop_len();
+        if len == 0 {
+            return 0.0;
+        }
+        let pos = self.get_playback() as f64 + self.playback_frac() as f64;
+        ((pos / len as f64) as f32).clamp(0.0, 1.0 - f32::EPSILON)
+    }
+
+    // Never more than half the loop, so the tail being faded out and the
+    // head being faded in don't overlap.
+    fn crossfade_len(&self) -> usize {
+        self.crossfade_len.load(Ordering::Relaxed).min(self.get_loop_len() / 2)
+    }
+
+    pub fn set_crossfade_len(&self, frames: usize) {
+        self.crossfade_len.store(frames, Ordering::Relaxed);
+    }
+
+    pub fn latency(&self) -> usize {
+        self.latency.load(Ordering::Relaxed)
+    }
+
+    pub fn set_latency(&self, frames: usize) {
+        self.latency.store(frames, Ordering::Relaxed);
+    }
+
+    fn clock(&self) -> usize {
+        self.clock.load(Ordering::Relaxed)
+    }
+
+    fn advance_clock(&self, frames: usize) {
+        self.clock.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    fn synced(&self) -> bool {
+        self.synced.load(Ordering::Relaxed)
+    }
+
+    pub fn set_synced(&self, synced: bool) {
+        self.synced.store(synced, Ordering::Relaxed);
+    }
+
+    // If we're synced, move playback to wherever it should be at clock.
+    // Loops playing at some other speed, or backwards, can't keep in step,
+    // so they go their own way.
+    fn sync_playback(&self, clock: usize) {
+        let len = self.get_loop_len();
+        if !self.synced() || self.varispeed() || self.first_loop() || len == 0 {
+            return;
+        }
+        let pos = clock.wrapping_sub(self.clock_offset.load(Ordering::Relaxed)) % len;
+        self.playback.store(pos, Ordering::Relaxed);
+    }
+
+    // How long the first loop is going to be, if that's been set ahead of
+    // time rather than by recording it.
+    fn fixed_len(&self) -> Option<usize> {
+        match self.fixed_len.load(Ordering::Relaxed) {
+            0 => None,
+            len => Some(len),
+        }
+    }
+
+    pub fn set_fixed_len(&self, frames: usize) {
+        self.fixed_len.store(frames, Ordering::Relaxed);
+    }
+
+    // Every layer makes the bank bigger, and mixing slower (see
+    // SampleBank::mix()), so recording stops once there are this many.
+    fn max_layers(&self) -> Option<usize> {
+        match self.max_layers.load(Ordering::Relaxed) {
+            0 => None,
+            max => Some(max),
+        }
+    }
+
+    fn set_max_layers(&self, max: usize) {
+        self.max_layers.store(max, Ordering::Relaxed);
+    }
+
+    // The most samples a track can record (across every layer and
+    // channel), so the bank doesn't grow without end. Recording stops there.
+    fn max_record_samples(&self) -> Option<usize> {
+        match self.max_record_len.load(Ordering::Relaxed) {
+            0 => None,
+            len => Some(len * self.channels),
+        }
+    }
+
+    pub fn set_max_record_len(&self, frames: usize) {
+        self.max_record_len.store(frames, Ordering::Relaxed);
+    }
+
+    fn at_max_layers(&self) -> bool {
+        self.max_layers().is_some_and(|max| self.get_loop_count() >= max)
+    }
+
+    // How many of the layers get played, counting from the first, or None
+    // for all of them.
+    pub fn playback_layer_limit(&self) -> Option<usize> {
+        match self.playback_layer_limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    fn set_playback_layer_limit(&self, limit: usize) {
+        self.playback_layer_limit.store(limit, Ordering::Relaxed);
+    }
+
+    // How many of loop_count layers actually get played, given the limit.
+    fn heard_layers(&self, loop_count: usize) -> usize {
+        self.playback_layer_limit().map_or(loop_count, |limit| limit.min(loop_count))
+    }
+
+    // Number of samples in each loop layer, across all channels.
+    fn layer_len(&self) -> usize {
+        self.get_loop_len() * self.channels
+    }
+
+    pub fn get_loop_count(&self) -> usize {
+        self.loop_count.load(Ordering::Acquire)
+    }
+
+    fn get_total_samples(&self) -> usize {
+        self.total_samples.load(Ordering::Relaxed)
+    }
+
+    // How many frames have been recorded, in all. While the first loop's
+    // recording, that's how long it'd be if it closed now; loop_len only
+    // gets set once it does (see finish_first_loop()).
+    fn recorded_len(&self) -> usize {
+        self.get_total_samples() / self.channels
+    }
+
+    // Get the index at which we should start writing the next Clip(s).
+    // Takes an offset (number of samples) to subtract from the playback idx
+    // on subsequent loops. The first time through the loop, we don't need to
+    // account for playback: we're just tacking onto the end of whatever's
+    // been sent so far. We can't go by total_samples for that, since it lags
+    // behind until the output callback receives each Clip; instead we claim
+    // the next offset samples from write_cursor.
+    //
+    // After that, what was played along to was heard a while before it got
+    // back to us, so it goes latency() frames earlier still, to line up with
+    // what was playing. The first loop isn't playing along to anything.
+    fn next_write_index(&self, offset: usize) -> usize {
+        if self.first_loop() {
+            self.write_cursor.fetch_add(offset, Ordering::Relaxed)
+        } else {
+            ((self.get_loop_count() * self.get_loop_len() + self.get_playback()) * self.channels)
+                .saturating_sub(offset + self.latency() * self.channels)
+        }
+    }
+
+    fn record_mode(&self) -> RecordMode {
+        match self.record_mode.load(Ordering::Acquire) {
+            OVERDUB => RecordMode::Overdub {
+                feedback: f32::from_bits(self.feedback.load(Ordering::Relaxed)),
+            },
+            REPLACE => RecordMode::Replace,
+            _ => RecordMode::Layer,
+        }
+    }
+
+    fn set_record_mode(&mut self, mode: RecordMode) {
+        let tag = match mode {
+            RecordMode::Layer => LAYER,
+            RecordMode::Overdub { feedback } => {
+                let feedback = feedback.clamp(0.0, 1.0);
+                self.feedback.store(feedback.to_bits(), Ordering::Relaxed);
+                OVERDUB
+            },
+            RecordMode::Replace => REPLACE,
+        };
+        self.record_mode.store(tag, Ordering::Release);
+    }
+
+    // Go back to an empty looper, with nothing recorded.
+    fn reset(&mut self) {
+        self.is_recording.store(false, Ordering::Release);
+        self.count_in_remaining.store(0, Ordering::Release);
+        self.is_playing.store(true, Ordering::Relaxed);
+        self.reversed.store(false, Ordering::Relaxed);
+        self.rate.store(1.0_f32.to_bits(), Ordering::Relaxed);
+        self.playback.store(0, Ordering::Relaxed);
+        self.playback_frac.store(0.0_f32.to_bits(), Ordering::Relaxed);
+        self.loop_len.store(0, Ordering::Relaxed);
+        self.loop_count.store(0, Ordering::Release);
+        self.total_samples.store(0, Ordering::Relaxed);
+        self.write_cursor.store(0, Ordering::Relaxed);
+        self.closing_len.store(0, Ordering::Release);
+        self.cancelling.store(false, Ordering::Release);
+        self.retriggering.store(false, Ordering::Relaxed);
+        self.playback_layer_limit.store(0, Ordering::Relaxed);
+    }
+
+    // Establish a first loop of len frames that's already in the
+    // SampleBank, starting at index 0.
+    fn load_first_loop(&mut self, len: usize) {
+        self.load_layers(len, 1, len * self.channels, 0);
+    }
+
+    // Establish count layers of len frames each (total_samples in all) that
+    // are already in the SampleBank, starting at index 0, with playback
+    // picking up from frame playback.
+    fn load_layers(&mut self, len: usize, count: usize, total_samples: usize, playback: usize) {
+        let playback = playback % len.max(1);
+        self.loop_len.store(len, Ordering::Relaxed);
+        self.playback.store(playback, Ordering::Relaxed);
+        self.clock_offset.store(self.clock().wrapping_sub(playback), Ordering::Relaxed);
+        self.total_samples.store(total_samples, Ordering::Relaxed);
+        self.write_cursor.store(total_samples, Ordering::Relaxed);
+        self.loop_count.store(count, Ordering::Release);
+    }
+
+    // Ask for the first loop to be closed at len frames, next time
+    // finish_first_loop() gets called.
+    fn close_first_loop(&self, len: usize) {
+        self.closing_len.store(len.max(1), Ordering::Release);
+    }
+
+    // Close the first loop, if that's been asked for or it's reached its
+    // fixed_len(): set its length and count it as the first layer. This has
+    // to happen on the same thread that's recording, or it can race with
+    // add_sample_count() and advance_playback(). Returns the loop's length
+    // if it closed.
+    fn finish_first_loop(&mut self) -> Option<usize> {
+        let mut len = self.closing_len.swap(0, Ordering::AcqRel);
+        if let Some(fixed_len) = self.fixed_len() {
+            if len == 0 && self.recording() && self.recorded_len() >= fixed_len {
+                len = fixed_len;
+            }
+        }
+        if len == 0 || !self.first_loop() {
+            return None;
+        }
+
+        // The loop started as far back as it's been recording.
+        let recorded = self.recorded_len();
+        self.clock_offset.store(self.clock().wrapping_sub(recorded), Ordering::Relaxed);
+        self.loop_len.store(len, Ordering::Relaxed);
+        self.total_samples.store(len * self.channels, Ordering::Relaxed);
+        self.inc_loop_count();
+        self.stop_at_max_layers();
+        Some(len)
+    }
+
+    // Stop recording, and ask for whatever's been recorded of the layer in
+    // progress to be thrown away next time finish_cancel() gets called.
+    fn cancel_layer(&self) {
+        self.is_recording.store(false, Ordering::Release);
+        self.cancelling.store(true, Ordering::Release);
+    }
+
+    // Throw away the layer in progress, if that's been asked for, going back
+    // to the end of the last whole layer. Nothing gets recorded once
+    // recording's off, so nothing can end up past that afterwards. Like
+    // finish_first_loop(), this has to happen on the thread that's
+    // recording. Returns whether it threw anything away.
+    fn finish_cancel(&mut self) -> bool {
+        if !self.cancelling.swap(false, Ordering::AcqRel) {
+            return false;
+        }
+        let total = self.get_loop_count() * self.layer_len();
+        self.total_samples.store(total, Ordering::Relaxed);
+        self.write_cursor.store(total, Ordering::Relaxed);
+        true
+    }
+
+    // Ask for playback to go back to the start of the loop next time
+    // finish_retrigger() gets called.
+    fn retrigger(&self) {
+        self.retriggering.store(true, Ordering::Relaxed);
+    }
+
+    fn decay_cycles(&self) -> usize {
+        self.decay_cycles.load(Ordering::Relaxed)
+    }
+
+    fn count_decay_cycle(&self) {
+        self.decay_cycles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // How many times playback's come round to the start of the loop, e.g.
+    // for flashing a light on the downbeat without missing any.
+    pub fn cycles(&self) -> usize {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    fn count_cycle(&self) {
+        self.cycles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Move playback back to the start of the loop, if that's been asked
+    // for, as of clock. When synced, the loop starts over from here on. This
+    // has to happen on the output thread, or it can race with
+    // advance_playback().
+    fn finish_retrigger(&mut self, clock: usize) {
+        if !self.retriggering.swap(false, Ordering::Relaxed) || self.first_loop() {
+            return;
+        }
+        self.playback.store(0, Ordering::Relaxed);
+        self.playback_frac.store(0.0_f32.to_bits(), Ordering::Relaxed);
+        self.clock_offset.store(clock, Ordering::Relaxed);
+    }
+
+    // Logically discard the most recent loop layer. Its samples stay in the
+    // SampleBank, but they're past total_samples and outside loop_count, so
+    // nothing reads them anymore. Returns false if there was nothing to
+    // discard.
+    fn remove_layer(&mut self) -> bool {
+        let count = self.get_loop_count();
+        if count == 0 {
+            return false;
+        }
+
+        self.loop_count.store(count - 1, Ordering::Release);
+        let total = self.get_total_samples().saturating_sub(self.layer_len());
+        self.total_samples.store(total, Ordering::Relaxed);
+        true
+    }
+
+    // Bring back the layer most recently discarded by remove_layer().
+    fn restore_layer(&mut self) {
+        self.inc_loop_count();
+        let total = self.get_total_samples() + self.layer_len();
+        self.total_samples.store(total, Ordering::Relaxed);
+    }
+
+    // Stop recording, if the layer that's just been added was the last one
+    // there's room for.
+    fn stop_at_max_layers(&self) {
+        if self.at_max_layers() {
+            self.is_recording.store(false, Ordering::Release);
+        }
+    }
+
+    fn inc_loop_count(&mut self) {
+        let count = self.get_loop_count();
+        self.loop_count.store(count + 1, Ordering::Release);
+    }
+
+    fn add_sample_count(&mut self, n: usize) {
+        let new_total = self.get_total_samples() + n;
+        self.total_samples.store(new_total, Ordering::Relaxed);
+    }
+
+    // Move playback along by a frame. Returns whether that took it around
+    // to the other end of the loop.
+    fn advance_playback(&mut self) -> bool {
+        if !self.began_recording() {
+            return false;
+        }
+
+        let mut playback = self.get_playback();
+        if self.varispeed() {
+            // Recording is off, so there's no new layer to count when we
+            // wrap.
+            let reversed = self.reversed();
+            let old = playback as f64 + self.playback_frac() as f64;
+            let step = if reversed { -self.rate() } else { self.rate() };
+            let pos = (old + step as f64).rem_euclid(self.get_loop_len() as f64);
+            // Rounding can land pos right on the end of the loop.
+            self.playback.store(pos as usize % self.get_loop_len(), Ordering::Relaxed);
+            self.playback_frac.store((pos.fract() as f32).to_bits(), Ordering::Relaxed);
+            return if reversed { pos > old } else { pos < old };
+        }
+        playback += 1;
+
+        let wrapped = playback >= self.get_loop_len();
+        if wrapped {
+            playback = 0;
+            if self.recording() && self.record_mode() == RecordMode::Layer {
+                // We went past the end of the current loop while recording.
+                self.inc_loop_count();
+                self.stop_at_max_layers();
+            }
+        }
+
+        self.playback.store(playback, Ordering::Relaxed);
+        wrapped
+    }
+}
+
+// Write a newly arrived clip to the bank, according to the current
+// RecordMode. Returns true if that filled up all the recording time there
+// is (see State::max_record_samples()), in which case recording stops, with
+// the first loop closing if that's what was recording. If it can't be
+// written at all, that goes out on events, for the UI to log.
+fn record_clip(bank: &mut SampleBank, state: &mut State, clip: &Clip, events: &mpsc::SyncSender<Event>) -> bool {
+    let Some(start) = clip.start else {
+        // This one's only for monitoring.
+        return false;
+    };
+    let mode = if state.first_loop() {
+        // There's nothing to overdub onto yet.
+        RecordMode::Layer
+    } else {
+        state.record_mode()
+    };
+
+    let len = state.layer_len();
+    let mut full = false;
+    // Growing the bank would mean allocating, so it doesn't happen here;
+    // the UI thread makes room ahead of time instead (see
+    // Looper::make_room()). Anything that doesn't fit gets dropped.
+    let fits = |end: Option<usize>| end.is_some_and(|end| end <= bank.len());
+    let written = match mode {
+        RecordMode::Layer => {
+            // Only layering records anything new; the others go over what's
+            // already there.
+            let mut samples = clip.samples();
+            // Anything meant for before where this recording started still
+            // counts towards the layer, it just doesn't get written.
+            let floor = state.record_floor.load(Ordering::Relaxed);
+            let skipped = floor.saturating_sub(start).min(samples.len());
+            samples = &samples[skipped..];
+            let start = start.max(floor);
+            if let Some(max) = state.max_record_samples() {
+                // Input sent from part way through the loop lands further
+                // along than it counts for, and input from before recording
+                // started counts for more than it lands, so go by whichever
+                // is further along.
+                let counted = state.get_total_samples() + skipped;
+                let room = max.saturating_sub(start.max(counted));
+                if samples.len() >= room {
+                    samples = &samples[..room];
+                    full = true;
+                }
+            }
+            let written = fits(start.checked_add(samples.len())) && bank.write_at(start, samples).is_ok();
+            if written {
+                // Update state to account for newly recorded samples.
+                state.add_sample_count(skipped + samples.len());
+            }
+            written
+        },
+        RecordMode::Overdub { feedback } => {
+            let layer = state.get_loop_count() - 1;
+            fits(Some((layer + 1) * len)) && bank.overdub(layer * len, len, start % len, clip.samples(), feedback).is_ok()
+        },
+        RecordMode::Replace => {
+            fits(Some(state.get_loop_count() * len))
+                && bank.replace(len, state.get_loop_count(), start % len, clip.samples()).is_ok()
+        },
+    };
+    if !written {
+        send_event(events, Event::ClipDropped { track: clip.track });
+    }
+    if full {
+        if state.first_loop() {
+            state.close_first_loop(state.recorded_len());
+        }
+        state.is_recording.store(false, Ordering::Release);
+    }
+    full
+}
+
+// Send samples to the output callback, as however many Clips it takes. They
+// only get recorded if record says which track (and its State) to record
+// them onto; otherwise they're just for monitoring. Returns how many Clips
+// didn't fit.
+fn send_clips(producer: &mut ringbuf::Producer<Clip>, samples: &[f32], record: Option<(usize, &State)>) -> usize {
+    let (track, start) = match record {
+        Some((track, state)) => (track, Some(state.next_write_index(samples.len()))),
+        None => (0, None),
+    };
+    let clips = samples.len().div_ceil(CLIP_LEN);
+    for (i, chunk) in samples.chunks(CLIP_LEN).enumerate() {
+        if producer.push(Clip::new(chunk, track, start.map(|start| start + i * CLIP_LEN))).is_err() {
+            // The output side has fallen behind. There's nothing useful
+            // we can do about it here, so the rest of the buffer is lost.
+            return clips - i;
+        }
+    }
+    0
+}
+
+// Counts of the ways audio can go missing between the two callbacks, for
+// the UI to report.
+#[derive(Clone)]
+pub struct Dropouts {
+    // Clips the input callback couldn't fit in the queue, because the output
+    // callback wasn't keeping up.
+    overflows: Arc<AtomicUsize>,
+    // Times the output callback went more than STARVED_LEN frames without
+    // any input while recording, because the input callback wasn't keeping
+    // up.
+    starved: Arc<AtomicUsize>,
+}
+
+// How long the output callback can go without input while recording before
+// it counts as starved. Different buffer sizes on either side mean it often
+// goes a buffer or two without.
+const STARVED_LEN: usize = MONITOR_LEN;
+
+impl Dropouts {
+    fn new() -> Self {
+        Self {
+            overflows: Arc::new(0.into()),
+            starved: Arc::new(0.into()),
+        }
+    }
+
+    fn overflows(&self) -> usize {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    fn add_overflows(&self, n: usize) {
+        if n > 0 {
+            self.overflows.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    fn starved(&self) -> usize {
+        self.starved.load(Ordering::Relaxed)
+    }
+
+    fn add_starved(&self) {
+        self.starved.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> usize {
+        self.overflows() + self.starved()
+    }
+
+    // A warning about any dropouts since there were last_total of them.
+    pub fn warning(&self, last_total: usize) -> Option<String> {
+        if self.total() <= last_total {
+            return None;
+        }
+        Some(format!(
+            "WARNING: {} input overflow(s) and {} starved buffer(s) so far! Try a bigger --buffer-size.",
+            self.overflows(), self.starved()
+        ))
+    }
+}
+
+// Most samples a single Clip can carry. Input buffers bigger than this are
+// split across several Clips.
+const CLIP_LEN: usize = 512;
+// How many Clips can be waiting for the output callback at once.
+const CLIP_QUEUE_LEN: usize = 64;
+// Frames the output callback mixes at once.
+const MIX_LEN: usize = 4096;
+// Most samples the UI thread copies out of a bank each time it takes the
+// lock, so the output callback never goes without it for long (see
+// SampleBank::copy_into()).
+const COPY_CHUNK_LEN: usize = 4096;
+// How many seconds' room make_room() keeps in each bank beyond what's been
+// recorded (or the next layer, whichever's further), so the output
+// callback never runs out between calls.
+const BANK_HEADROOM_SECS: usize = 10;
+// Most frames of input to keep around for monitoring.
+const MONITOR_LEN: usize = 2048;
+
+// A chunk of input on its way from the input callback to the output
+// callback. Clips are fixed-size so that they can be passed through the ring
+// buffer without allocating.
+#[derive(Clone, Copy)]
+struct Clip {
+    buf: [f32; CLIP_LEN],
+    len: usize,
+    // Which track's SampleBank this goes in, and where, or None if it isn't
+    // being recorded and is only on its way to be monitored.
+    track: usize,
+    start: Option<usize>,
+}
+
+impl Clip {
+    // Copy up to CLIP_LEN samples into a new Clip.
+    fn new(samples: &[f32], track: usize, start: Option<usize>) -> Self {
+        let len = samples.len().min(CLIP_LEN);
+        let mut buf = [0.0; CLIP_LEN];
+        buf[..len].copy_from_slice(&samples[..len]);
+        Self {
+            buf,
+            len,
+            track,
+            start,
+        }
+    }
+
+    fn samples(&self) -> &[f32] {
+        &self.buf[..self.len]
+    }
+}
+
+// Something the looper can be told to do, whether from the keyboard or from
+// somewhere else (like a MIDI controller) by way of Looper::command_sender().
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Tap,
+    Undo,
+    Clear,
+    TogglePlayback,
+    SetMasterGain(f32),
+    // Counting from zero.
+    SelectTrack(usize),
+    // MIDI clock and transport coming in, for following another device's
+    // tempo. Pulses come with when they arrived, by the MIDI input's clock.
+    ClockPulse(Duration),
+    ClockStart,
+    ClockStop,
+}
+
+// Something that happened to the looper, for a UI to show. The audio
+// threads send these too, so the UI finds out about things like the loop
+// wrapping around without having to keep checking.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    RecordingStarted,
+    RecordingStopped,
+    // A track's first loop is len frames long.
+    LoopClosed { track: usize, len: usize },
+    // A track's layer (counting from zero) has finished recording.
+    LayerAdded { track: usize, index: usize },
+    // A track's playback has come round to the start of its loop again.
+    // Like any event, this can get dropped if the UI falls behind, so
+    // anything that has to see every one should watch State::cycles()
+    // instead.
+    PlaybackWrapped { track: usize },
+    // A track has as many layers as it can have, so it's stopped recording.
+    MaxLayers { track: usize },
+    // Likewise for recording time.
+    MaxRecording { track: usize },
+    // Some input for a track couldn't be written to its bank, so it's gone.
+    ClipDropped { track: usize },
+    // A track's layer decayed until it was too quiet to keep, so it's been
+    // taken out, and the ones after it moved down.
+    LayerFaded { track: usize, index: usize },
+}
+
+// Most events that can be waiting for the UI. It doesn't matter much if the
+// UI misses some, so more than this just get dropped.
+const EVENT_QUEUE_LEN: usize = 256;
+
+// Send an event without waiting around (or allocating). If the queue's
+// full, the event's lost, so nothing the UI has to know about can rely on
+// events alone.
+fn send_event(events: &mpsc::SyncSender<Event>, event: Event) {
+    let _ = events.try_send(event);
+}
+
+// Something a tap can do.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TapAction {
+    // Start recording the first loop (and the audio streams).
+    Record,
+    // Set the first loop's length, and keep recording the next layer.
+    SetLength,
+    // Start recording onto the loop, or stop if we already are.
+    Overdub,
+    // Stop recording, closing the first loop if that's what's recording.
+    // If nothing is, stop or restart playback instead.
+    Stop,
+    // Take back the last layer.
+    Undo,
+}
+
+// What each tap does. The first few taps go through taps in order, and
+// every tap after that goes round and round then.
+pub struct TapConfig {
+    pub taps: Vec<TapAction>,
+    pub then: Vec<TapAction>,
+}
+
+impl TapConfig {
+    pub fn new(taps: Vec<TapAction>, then: Vec<TapAction>) -> Self {
+        Self {
+            taps,
+            then,
+        }
+    }
+
+    // What the nth tap (counting from zero) does, if anything.
+    fn action(&self, n: usize) -> Option<TapAction> {
+        match self.taps.get(n) {
+            Some(&action) => Some(action),
+            None if self.then.is_empty() => None,
+            None => Some(self.then[(n - self.taps.len()) % self.then.len()]),
+        }
+    }
+}
+
+impl Default for TapConfig {
+    // Record, close the loop, then go back and forth between overdubbing
+    // and just listening.
+    fn default() -> Self {
+        Self::new(vec![TapAction::Record, TapAction::SetLength], vec![TapAction::Overdub])
+    }
+}
+
+// An empty SampleBank for a new track, with room for capacity samples to
+// start with. It grows as needed.
+fn new_bank(capacity: usize) -> Arc<Mutex<SampleBank>> {
+    Arc::new(Mutex::new(SampleBank::new(vec![0.0; capacity])))
+}
+
+// Ten seconds' worth, at 44.1 kHz.
+const DEFAULT_BANK_CAPACITY: usize = 44100 * 10;
+
+// What gets saved in a session file, besides the samples themselves.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SessionManifest {
+    // Bumped whenever the format changes, so old files don't get misread.
+    version: u32,
+    sample_rate: u32,
+    channels: usize,
+    // Every track, in order, including any with nothing on them.
+    tracks: Vec<TrackManifest>,
+    master_gain: f32,
+    cutoff: f32,
+    bpm: f32,
+}
+
+// One track's part of a session file. Its samples are in
+// session_samples() for its index.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TrackManifest {
+    loop_len: usize,
+    loop_count: usize,
+    total_samples: usize,
+    playback: usize,
+    layers: Vec<LayerSettings>,
+}
+
+// Everything SampleBank keeps track of for a single layer.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LayerSettings {
+    pub gain: f32,
+    pub pan: f32,
+    pub muted: bool,
+    pub soloed: bool,
+    // Sessions saved before layers could be reversed don't have this.
+    #[serde(default)]
+    pub reversed: bool,
+    // Or this, from before they could decay.
+    #[serde(default = "no_decay")]
+    pub decay: f32,
+}
+
+fn no_decay() -> f32 {
+    1.0
+}
+
+// Once a decaying layer's this quiet, it's taken out altogether.
+const DECAY_FLOOR_DB: f32 = -60.0;
+// The decay factors to go through, after none at all.
+const LAYER_DECAYS: [f32; 3] = [0.9, 0.75, 0.5];
+
+// Version 1 only had the selected track.
+const SESSION_VERSION: u32 = 2;
+// Where things go inside a session file.
+const SESSION_MANIFEST: &str = "manifest.json";
+
+// Where a track's layers go inside a session file, counting tracks from
+// one like everywhere the user sees them.
+fn session_samples(track: usize) -> String {
+    format!("track{}.wav", track + 1)
+}
+
+// Loops can be stretched to anywhere from a quarter to four times as long.
+const MIN_STRETCH: f32 = 0.25;
+const MAX_STRETCH: f32 = 4.0;
+
+// What happens to the second half of the loop when it's halved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HalveMode {
+    // It's thrown away.
+    KeepFirst,
+    // It's added onto the first half.
+    Mix,
+}
+
+// How much of the existing loop survives each pass in RecordMode::Overdub.
+const DEFAULT_FEEDBACK: f32 = 0.5;
+
+// One of several independent loops, each with its own length and layers.
+// The audio threads mix them all together.
+#[derive(Clone)]
+pub struct Track {
+    pub state: State,
+    bank: Arc<Mutex<SampleBank>>,
+    // Looper's taps, redo_depth, and frozen for this track, while it isn't
+    // the selected one.
+    taps: usize,
+    redo_depth: usize,
+    frozen: Option<Frozen>,
+    // The state's decay_cycles as of the last time faded layers were taken
+    // out.
+    decay_cycles: usize,
+}
+
+impl Track {
+    fn new(state: State, bank: Arc<Mutex<SampleBank>>) -> Self {
+        Self {
+            state,
+            bank,
+            taps: 0,
+            redo_depth: 0,
+            frozen: None,
+            decay_cycles: 0,
+        }
+    }
+}
+
+// A track's layers (and their settings) from just before they were frozen
+// into one (see Looper::freeze()), each len frames long.
+#[derive(Clone)]
+struct Frozen {
+    len: usize,
+    layers: Vec<Vec<f32>>,
+    settings: Vec<LayerSettings>,
+}
+
+// A plain copy of what the selected track's up to, all as of the same
+// moment (as near as can be; see Looper::snapshot()).
+#[derive(Clone, Debug, PartialEq)]
+pub struct LooperState {
+    pub playback: usize,
+    pub loop_len: usize,
+    pub loop_count: usize,
+    pub total_samples: usize,
+    pub recording: bool,
+    pub counting_in: bool,
+    pub playing: bool,
+    // The settings for each of the loop_count layers.
+    pub layers: Vec<LayerSettings>,
+}
+
+pub struct Looper {
+    // The selected track's State and SampleBank, which is what everything
+    // but the audio threads works on.
+    pub state: State,
+    pub bank: Arc<Mutex<SampleBank>>,
+    // Every track, including the selected one.
+    pub tracks: Vec<Track>,
+    pub track: usize,
+    pub sample_rate: u32,
+    pub metronome: Metronome,
+    pub delay: DelaySettings,
+    pub crush: BitCrushSettings,
+    pub eq: EqSettings,
+    pub reverb: ReverbSettings,
+    pub effects: EffectsSettings,
+    // Only read when the callbacks get built, so changing it afterwards does
+    // nothing until the streams are rebuilt.
+    pub compressor: CompressorSettings,
+    pub quantize: Quantize,
+    // Once a tempo's been tapped, the first loop is this many bars long,
+    // regardless of how long it takes to tap the loop closed.
+    pub tap_tempo: TapTempo,
+    pub tempo_bars: u32,
+    // Beats to click before the first loop starts recording.
+    pub count_in_beats: u32,
+    // Once this has a tempo, the first loop gets snapped to whole beats (or
+    // bars) of it, and the metronome follows it.
+    pub midi_clock: MidiClock,
+    // Whether the clock's been started with nothing recorded yet, so the
+    // next beat should start recording.
+    record_on_beat: bool,
+    pub meter: PeakMeter,
+    pub rms: RmsMeter,
+    pub dropouts: Dropouts,
+    pub input: Option<cpal::Stream>,
+    pub output: Option<cpal::Stream>,
+    // Whether the callbacks are being run by a MockAudio instead of any
+    // streams, so there's nothing to play.
+    pub mock_audio: bool,
+
+    pub tap_config: TapConfig,
+    // Everything that happens to the looper gets sent to events, for
+    // whatever UI is running to keep up.
+    pub events: mpsc::Receiver<Event>,
+    event_sender: mpsc::SyncSender<Event>,
+    // Commands sent from other threads, for the UI to run.
+    pub commands: mpsc::Receiver<Command>,
+    command_sender: mpsc::Sender<Command>,
+    // How many taps have done something since the looper was last cleared.
+    pub taps: usize,
+    // Whether to turn the mix up or down to peak at normalize_db (in dBFS)
+    // whenever a layer gets added.
+    pub normalize: bool,
+    pub normalize_db: f32,
+    // How many samples each track's bank starts out with room for.
+    pub bank_capacity: usize,
+    // How many undone layers we can still bring back. Recording anything new
+    // overwrites them, so this goes back to zero whenever recording starts.
+    pub redo_depth: usize,
+    // The layers from before the last freeze(), until undo() brings them
+    // back or anything else changes them.
+    frozen: Option<Frozen>,
+    // Everything logged while recording goes in here, from when recording
+    // starts until it stops.
+    recording_span: tracing::Span,
+    // How much had been recorded the last time log_recording() checked.
+    logged_samples: usize,
+}
+
+// Sets up a Looper with something other than the defaults. Anything not
+// set here can still be changed on the Looper afterwards.
+pub struct LooperBuilder {
+    sample_rate: u32,
+    channels: u16,
+    bank_capacity: usize,
+    record_mode: RecordMode,
+    max_layers: Option<usize>,
+    compressor: CompressorSettings,
+}
+
+impl Default for LooperBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LooperBuilder {
+    // Starting from the same defaults as Looper::new().
+    pub fn new() -> Self {
+        let looper = Looper::new();
+        Self {
+            sample_rate: looper.sample_rate,
+            channels: looper.state.channels as u16,
+            bank_capacity: looper.bank_capacity,
+            record_mode: looper.state.record_mode(),
+            max_layers: looper.state.max_layers(),
+            compressor: looper.compressor,
+        }
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn channels(mut self, channels: u16) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    // How many samples each track's bank starts out with room for.
+    pub fn bank_capacity(mut self, samples: usize) -> Self {
+        self.bank_capacity = samples;
+        self
+    }
+
+    pub fn record_mode(mut self, mode: RecordMode) -> Self {
+        self.record_mode = mode;
+        self
+    }
+
+    pub fn max_layers(mut self, max: usize) -> Self {
+        self.max_layers = Some(max);
+        self
+    }
+
+    // How the compressor on the whole mix behaves.
+    pub fn compressor(mut self, settings: CompressorSettings) -> Self {
+        self.compressor = settings;
+        self
+    }
+
+    pub fn build(self) -> Looper {
+        let mut looper = Looper::new();
+        let rate = self.sample_rate;
+        looper.sample_rate = rate;
+        looper.metronome = Metronome::new(looper.metronome.bpm(), looper.metronome.beats_per_bar, rate);
+        looper.meter = PeakMeter::new(300.0, rate);
+        looper.rms = RmsMeter::new(300.0, rate);
+        looper.state.channels = self.channels as usize;
+        looper.state.set_record_mode(self.record_mode);
+        looper.state.set_max_layers(self.max_layers.unwrap_or(0));
+        looper.bank_capacity = self.bank_capacity;
+        looper.compressor = self.compressor;
+        looper.bank = new_bank(self.bank_capacity);
+        looper.set_track_count(1);
+        looper
+    }
+}
+
+impl Default for Looper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Looper {
+    // A Looper with all the defaults; see LooperBuilder for anything else.
+    pub fn new() -> Self {
+        let (event_sender, events) = mpsc::sync_channel(EVENT_QUEUE_LEN);
+        let (command_sender, commands) = mpsc::channel();
+        let state = State::new();
+        let bank = new_bank(DEFAULT_BANK_CAPACITY);
+        Self {
+            tracks: vec![Track::new(state.clone(), bank.clone())],
+            track: 0,
+            state,
+            bank,
+            sample_rate: 44100,
+            metronome: Metronome::new(120.0, 4, 44100),
+            delay: DelaySettings::new(300.0, 0.4),
+            crush: BitCrushSettings::new(8, 4),
+            eq: EqSettings::new(),
+            reverb: ReverbSettings::new(0.25, 1.5),
+            effects: EffectsSettings::new(),
+            compressor: CompressorSettings::default(),
+            quantize: Quantize::Off,
+            tap_tempo: TapTempo::new(),
+            tempo_bars: 1,
+            count_in_beats: 0,
+            midi_clock: MidiClock::new(),
+            record_on_beat: false,
+            meter: PeakMeter::new(300.0, 44100),
+            rms: RmsMeter::new(300.0, 44100),
+            dropouts: Dropouts::new(),
+            input: None,
+            output: None,
+            mock_audio: false,
+            recording_span: tracing::Span::none(),
+            logged_samples: 0,
+            tap_config: TapConfig::default(),
+            events,
+            event_sender,
+            commands,
+            command_sender,
+            taps: 0,
+            redo_depth: 0,
+            frozen: None,
+            normalize: false,
+            normalize_db: -1.0,
+            bank_capacity: DEFAULT_BANK_CAPACITY,
+        }
+    }
+
+    // Play input/output streams.
+    pub fn play(&self) -> anyhow::Result<()> {
+        if self.mock_audio {
+            return Ok(());
+        }
+        let output = self.output.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("There's no output stream to play."))?;
+        let input = self.input.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("There's no input stream to record from."))?;
+        output.play()?;
+        input.play()?;
+        Ok(())
+    }
+
+    // Start or stop playing live input along with the loop.
+    pub fn toggle_monitoring(&self) -> anyhow::Result<()> {
+        self.state.toggle_monitoring();
+        if self.state.monitoring() {
+            // Like the metronome, this should work before anything's
+            // recorded.
+            self.play()?;
+        }
+        status!("monitoring={}", self.state.monitoring());
+        Ok(())
+    }
+
+    pub fn toggle_delay(&self) {
+        self.delay.toggle();
+        status!("delay={}", self.delay.enabled());
+    }
+
+    // Go to the next of a few delay feedback settings.
+    pub fn next_delay_feedback(&self) {
+        let next = match self.delay.feedback() {
+            feedback if feedback < 0.25 => 0.25,
+            feedback if feedback < 0.5 => 0.5,
+            feedback if feedback < 0.75 => 0.75,
+            _ => 0.0,
+        };
+        self.delay.set_feedback(next);
+        status!("delay_feedback={:.2}", self.delay.feedback());
+    }
+
+    pub fn set_delay_time(&self, ms: f32) {
+        self.delay.set_time_ms(ms);
+        status!("delay_time={:.0} ms", self.delay.time_ms());
+    }
+
+    // Cut or boost one band of the EQ on the whole mix.
+    pub fn set_band_gain(&self, band: EqBand, db: f32) {
+        self.eq.set_gain_db(band, db);
+        status!("eq_{}={:+.1} dB", band, self.eq.gain_db(band));
+    }
+
+    // Skip effect, or stop skipping it, keeping its settings either way.
+    pub fn toggle_bypass(&self, effect: Effect) {
+        self.effects.toggle_bypass(effect);
+        self.print_effects();
+    }
+
+    // Move effect one later in the chain (or, if later is false, one
+    // earlier).
+    pub fn move_effect(&self, effect: Effect, later: bool) {
+        self.effects.move_effect(effect, later);
+        self.print_effects();
+    }
+
+    // The effects in the order they run in, with the bypassed ones in
+    // brackets.
+    fn print_effects(&self) {
+        let names: Vec<String> = self.effects.order().iter().map(|effect| match self.effects.bypassed(*effect) {
+            true => format!("({})", effect),
+            false => effect.to_string(),
+        }).collect();
+        status!("effects={}", names.join(" > "));
+    }
+
+    pub fn toggle_reverb(&self) {
+        self.reverb.toggle();
+        status!("reverb={}", self.reverb.enabled());
+    }
+
+    // Go to the next of a few wet/dry mixes for the reverb.
+    pub fn next_reverb_mix(&self) {
+        let next = match self.reverb.mix() {
+            mix if mix < 0.25 => 0.25,
+            mix if mix < 0.5 => 0.5,
+            mix if mix < 0.75 => 0.75,
+            _ => 0.1,
+        };
+        self.reverb.set_mix(next);
+        status!("reverb_mix={:.2}", self.reverb.mix());
+    }
+
+    // Likewise for its decay time.
+    pub fn next_reverb_decay(&self) {
+        let next = match self.reverb.decay_secs() {
+            secs if secs < 0.75 => 0.75,
+            secs if secs < 1.5 => 1.5,
+            secs if secs < 3.0 => 3.0,
+            secs if secs < 6.0 => 6.0,
+            _ => 0.5,
+        };
+        self.reverb.set_decay_secs(next);
+        status!("reverb_decay={:.2} s", self.reverb.decay_secs());
+    }
+
+    pub fn toggle_crush(&self) {
+        self.crush.toggle();
+        status!("crush={}", self.crush.enabled());
+    }
+
+    // Go to the next of a few bit depths, crunchier each time.
+    pub fn next_crush_bits(&self) {
+        let next = match self.crush.bits() {
+            bits if bits > 12 => 12,
+            bits if bits > 8 => 8,
+            bits if bits > 6 => 6,
+            bits if bits > 4 => 4,
+            _ => MAX_CRUSH_BITS,
+        };
+        self.crush.set_bits(next);
+        status!("crush_bits={}", self.crush.bits());
+    }
+
+    // Go to the next of a few downsample factors, likewise.
+    pub fn next_crush_downsample(&self) {
+        let next = match self.crush.downsample() {
+            downsample if downsample < 2 => 2,
+            downsample if downsample < 4 => 4,
+            downsample if downsample < 8 => 8,
+            downsample if downsample < 16 => 16,
+            _ => 1,
+        };
+        self.crush.set_downsample(next);
+        status!("crush_downsample={}", self.crush.downsample());
+    }
+
+    pub fn toggle_metronome(&self) -> anyhow::Result<()> {
+        self.metronome.toggle();
+        if self.metronome.enabled() {
+            // We might not have started recording yet, so make sure the
+            // click can actually be heard.
+            self.play()?;
+        }
+        status!("metronome={}", self.metronome.enabled());
+        Ok(())
+    }
+
+    // Start over with count empty tracks (at least one), all sharing the
+    // mix settings in state. This has to happen before the streams get
+    // built, and after state.channels is set.
+    pub fn set_track_count(&mut self, count: usize) {
+        self.tracks = vec![Track::new(self.state.clone(), self.bank.clone())];
+        for _ in 1..count {
+            self.tracks.push(Track::new(self.state.new_track(), new_bank(self.bank_capacity)));
+        }
+        self.track = 0;
+    }
+
+    // Switch which track tapping, undoing, layer settings, and so on apply
+    // to. The others carry on as they were.
+    fn select_track(&mut self, track: usize) -> anyhow::Result<()> {
+        if track >= self.tracks.len() {
+            anyhow::bail!("There's no track {}.", track + 1);
+        }
+        if self.state.recording() || self.state.counting_in() {
+            // This way, only one track ever records at a time.
+            anyhow::bail!("Can't switch tracks while recording.");
+        }
+
+        let current = &mut self.tracks[self.track];
+        current.taps = self.taps;
+        current.redo_depth = self.redo_depth;
+        current.frozen = self.frozen.take();
+        let next = &mut self.tracks[track];
+        self.state = next.state.clone();
+        self.bank = next.bank.clone();
+        self.taps = next.taps;
+        self.redo_depth = next.redo_depth;
+        self.frozen = next.frozen.take();
+        self.track = track;
+        status!("TRACK {}/{}.", track + 1, self.tracks.len());
+        Ok(())
+    }
+
+    // Keep up with whatever the audio threads have been up to.
+    pub fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::RecordingStarted => {
+                self.recording_span = tracing::info_span!("recording", track = self.track);
+                self.logged_samples = 0;
+                tracing::debug!(parent: &self.recording_span, "recording started");
+            },
+            Event::RecordingStopped => {
+                tracing::debug!(parent: &self.recording_span, "recording stopped");
+                self.recording_span = tracing::Span::none();
+            },
+            Event::LoopClosed { track, len } => {
+                tracing::debug!(parent: &self.recording_span, track, len, "loop closed");
+            },
+            Event::LayerAdded { track, index } => {
+                tracing::debug!(parent: &self.recording_span, track, layer = index, "layer added");
+                self.normalize_mix();
+            },
+            Event::PlaybackWrapped { track } => {
+                tracing::trace!(track, "playback wrapped");
+                self.decay_layers(track);
+            },
+            Event::MaxLayers { track } => tracing::info!(track, "reached the most layers"),
+            Event::MaxRecording { track } => {
+                tracing::warn!(parent: &self.recording_span, track, "ran out of recording time");
+            },
+            Event::ClipDropped { track } => {
+                tracing::warn!(parent: &self.recording_span, track, "dropped a clip that didn't fit in the bank");
+            },
+            Event::LayerFaded { track, index } => tracing::debug!(track, layer = index, "layer faded away"),
+        }
+    }
+
+    // Grow any track's bank that's getting close to running out of room to
+    // record into, since the output callback can't (see record_clip()). The
+    // UI calls this every so often, along with log_recording().
+    pub fn make_room(&self) {
+        let headroom = BANK_HEADROOM_SECS * self.sample_rate as usize * self.state.channels;
+        for (index, track) in self.tracks.iter().enumerate() {
+            let state = &track.state;
+            let next_layer = (state.get_loop_count() + 1) * state.layer_len();
+            let cursor = state.write_cursor.load(Ordering::Relaxed).max(state.get_total_samples());
+            if let Err(e) = SampleBank::grow_shared(&track.bank, cursor.max(next_layer).saturating_add(headroom)) {
+                tracing::error!(error = %e, track = index, "couldn't make room in the bank");
+            }
+        }
+    }
+
+    // Log how much input has come in since last time, if anything's
+    // recording. The output callback can't log anything itself, so the UI
+    // calls this every so often instead.
+    pub fn log_recording(&mut self) {
+        let recorded = self.state.get_total_samples();
+        if self.state.recording() && recorded != self.logged_samples {
+            let frames = recorded.saturating_sub(self.logged_samples) / self.state.channels;
+            tracing::trace!(parent: &self.recording_span, frames, total = recorded, "clips arrived");
+        }
+        self.logged_samples = recorded;
+    }
+
+    pub fn toggle_normalize(&mut self) {
+        self.normalize = !self.normalize;
+        status!("normalize={} ({:.1} dBFS)", self.normalize, self.normalize_db);
+        self.normalize_mix();
+    }
+
+    // If normalizing's on, set the master gain so that the loudest sample
+    // across every track's loop (each starting from the top, as they do when
+    // synced) hits normalize_db. This works on copies of the banks, so the
+    // output callback isn't kept waiting for them.
+    fn normalize_mix(&mut self) {
+        if !self.normalize {
+            return;
+        }
+
+        let channels = self.state.channels;
+        let loops: Vec<(SampleBank, usize, usize)> = self.tracks.iter()
+            .filter(|track| !track.state.first_loop())
+            .map(|track| {
+                let recorded = track.state.get_total_samples();
+                let bank = SampleBank::copy_shared(&track.bank, recorded);
+                (bank, track.state.layer_len(), track.state.heard_layers(track.state.get_loop_count()))
+            })
+            .collect();
+        let len = loops.iter().map(|(_, layer_len, _)| *layer_len).max().unwrap_or(0);
+        let peak = (0..len)
+            .map(|idx| {
+                loops.iter()
+                    .map(|(bank, layer_len, count)| {
+                        bank.mix(idx % layer_len, *layer_len, *count, bank.len(), channels)
+                    })
+                    .sum::<f32>()
+                    .abs()
+            })
+            .fold(0.0, f32::max);
+        if let Some(gain) = normalize_gain(peak, self.normalize_db) {
+            self.state.set_master_gain(gain);
+        }
+    }
+
+    // Add up every layer of any track whose flattened mix (see
+    // SampleBank::flat()) is out of date, so playback can use that instead
+    // of adding them up for every sample. That's done on a copy of the bank,
+    // like normalize_mix(), and only used if nothing's changed in the
+    // meantime. Tracks that are recording change too often to bother.
+    pub fn flatten(&self) {
+        let channels = self.state.channels;
+        for track in &self.tracks {
+            let state = &track.state;
+            if state.first_loop() || state.recording() {
+                continue;
+            }
+            let layer_len = state.layer_len();
+            let count = state.heard_layers(state.get_loop_count());
+            let recorded = state.get_total_samples();
+            if track.bank.lock().unwrap().flat(layer_len, count, recorded).is_some() {
+                continue;
+            }
+            let copy = SampleBank::copy_shared(&track.bank, recorded);
+            let (key, flat) = copy.flatten(layer_len, count, recorded, channels);
+            let old = track.bank.lock().unwrap().set_flat(key, flat);
+            drop(old);
+        }
+    }
+
+    // When synced, what every track's loop length should be a multiple of:
+    // the length of the first other track to have a loop, if there is one.
+    fn base_len(&self) -> Option<usize> {
+        if !self.state.synced() {
+            return None;
+        }
+        self.tracks.iter().enumerate()
+            .find(|(index, track)| *index != self.track && !track.state.first_loop())
+            .map(|(_, track)| track.state.get_loop_len())
+    }
+
+    // Whether any track has been tapped since it was last cleared.
+    pub fn tapped(&self) -> bool {
+        self.taps > 0 || self.tracks.iter().any(|track| track.taps > 0)
+    }
+
+    // Somewhere to send Commands from other threads.
+    pub fn command_sender(&self) -> mpsc::Sender<Command> {
+        self.command_sender.clone()
+    }
+
+    pub fn run(&mut self, command: Command) -> anyhow::Result<()> {
+        match command {
+            Command::Tap => self.tap(),
+            Command::Undo => self.undo(),
+            Command::Clear => {
+                self.clear();
+                Ok(())
+            },
+            Command::TogglePlayback => {
+                self.toggle_playback();
+                Ok(())
+            },
+            Command::SetMasterGain(gain) => {
+                self.set_master_gain(gain);
+                Ok(())
+            },
+            Command::SelectTrack(track) => self.select_track(track),
+            Command::ClockPulse(at) => self.clock_pulse(at),
+            Command::ClockStart => self.clock_start(),
+            Command::ClockStop => {
+                self.clock_stop();
+                Ok(())
+            },
+        }
+    }
+
+    // Follow the clock's tempo, and start recording on the first beat after
+    // a Start if that's what it's waiting for. That only happens once the UI
+    // gets round to running the command, so it can be a few milliseconds
+    // late, but the loop's length still comes out at whole beats.
+    fn clock_pulse(&mut self, at: Duration) -> anyhow::Result<()> {
+        let on_beat = self.midi_clock.pulse(at);
+        if let Some(bpm) = self.midi_clock.bpm() {
+            self.metronome.set_bpm(bpm);
+        }
+        if on_beat && self.record_on_beat {
+            self.record_on_beat = false;
+            if let Some(bpm) = self.midi_clock.bpm() {
+                status!("bpm={:.1}", bpm);
+            }
+            return self.tap();
+        }
+        Ok(())
+    }
+
+    // Start from the top of the loop along with the clock, or if there's no
+    // loop yet, start recording one on the next beat.
+    fn clock_start(&mut self) -> anyhow::Result<()> {
+        self.midi_clock.start();
+        if self.state.recording() || self.state.counting_in() {
+            return Ok(());
+        }
+        if self.state.first_loop() {
+            if self.taps == 0 {
+                self.record_on_beat = true;
+                status!("Waiting for the clock's next beat to start recording...");
+            }
+            return Ok(());
+        }
+        self.retrigger()?;
+        if !self.state.playing() {
+            self.toggle_playback();
+        }
+        Ok(())
+    }
+
+    // Stop playback along with the clock, unless something's recording.
+    fn clock_stop(&mut self) {
+        self.midi_clock.stop();
+        self.record_on_beat = false;
+        if self.state.playing() && !self.state.first_loop() && !self.state.recording() {
+            self.toggle_playback();
+        }
+    }
+
+    // Do whatever the next tap does, according to tap_config.
+    fn tap(&mut self) -> anyhow::Result<()> {
+        if self.state.counting_in() {
+            // We haven't really started recording yet.
+            status!("Still counting in...");
+            return Ok(());
+        }
+        if self.tap_config.action(self.taps) == Some(TapAction::SetLength) && !self.state.first_loop() {
+            // The first loop closed itself (at its fixed length, or when it
+            // ran out of recording time) before this tap got the chance to.
+            self.taps += 1;
+        }
+        let action = match self.tap_config.action(self.taps) {
+            Some(action) => action,
+            None => return Ok(()),
+        };
+        tracing::debug!(tap = self.taps, ?action, track = self.track, "tap");
+
+        match action {
+            TapAction::Record => {
+                if self.state.began_recording() {
+                    anyhow::bail!("Already recording.");
+                }
+                self.start_recording()?;
+            },
+            TapAction::SetLength => {
+                if !self.state.first_loop() || !self.state.recording() {
+                    anyhow::bail!("There's no first loop being recorded to set the length of.");
+                }
+                if !self.close_first_loop() {
+                    return Ok(());
+                }
+            },
+            TapAction::Overdub => {
+                if self.state.first_loop() {
+                    anyhow::bail!("There's no loop to overdub onto yet.");
+                }
+                if !self.state.recording() && self.state.varispeed() {
+                    anyhow::bail!("Can't record unless the loop's playing forwards at normal speed.");
+                }
+                if !self.state.recording() && self.state.at_max_layers() && self.state.record_mode() == RecordMode::Layer {
+                    anyhow::bail!("Already got the most layers there's room for; undo one to make room.");
+                }
+                if !self.state.recording()
+                    && self.state.record_mode() != RecordMode::Layer
+                    && self.bank.lock().unwrap().any_reversed(self.state.get_loop_count())
+                {
+                    // Those record onto the layers as they're stored, not as
+                    // they're heard, which would put everything in the wrong
+                    // place.
+                    anyhow::bail!("Can't overdub or replace onto reversed layers.");
+                }
+                self.state.toggle_recording();
+                if self.state.recording() {
+                    self.discard_redo();
+                }
+            },
+            TapAction::Stop => {
+                if !self.state.recording() {
+                    self.toggle_playback();
+                } else {
+                    if self.state.first_loop() && !self.close_first_loop() {
+                        return Ok(());
+                    }
+                    self.state.toggle_recording();
+                }
+            },
+            TapAction::Undo => {
+                self.undo()?;
+                if self.state.first_loop() {
+                    // That was the last layer, so we're back to the start.
+                    return Ok(());
+                }
+            },
+        }
+        self.taps += 1;
+        Ok(())
+    }
+
+    // Start recording the first loop, after a count-in if there is one.
+    fn start_recording(&mut self) -> anyhow::Result<()> {
+        if self.count_in_beats > 0 {
+            // The output callback starts recording when it's done
+            // counting in.
+            let len = self.metronome.samples_per_beat() * self.count_in_beats as f64;
+            self.state.start_count_in(len.round() as usize);
+            status!("COUNTING IN...");
+        } else {
+            self.state.toggle_recording();
+        }
+        self.discard_redo();
+        self.play()
+    }
+
+    // Set the length of the first loop, based on how much has been recorded
+    // so far (or the fixed length, or the tapped tempo). Recording carries on
+    // into the next layer. If nothing's been recorded at all, there's no
+    // loop to speak of (just silence), so this stops recording and goes back
+    // to the start instead, returning false.
+    fn close_first_loop(&mut self) -> bool {
+        let recorded = self.state.recorded_len();
+        if recorded == 0 {
+            // Any input still on its way won't be recorded now.
+            self.state.reset();
+            self.taps = 0;
+            status!("NOTHING RECORDED. Hit SPACE to start recording.");
+            return false;
+        }
+        let tempo_len = self.tap_tempo.loop_len(
+            self.tempo_bars, self.metronome.beats_per_bar, self.sample_rate
+        );
+        let clock_beats = match self.quantize {
+            Quantize::Bar => self.metronome.beats_per_bar,
+            Quantize::Off | Quantize::Beat => 1,
+        };
+        let clock_len = self.midi_clock.loop_len(recorded, clock_beats, self.sample_rate);
+        let mut len = match (self.state.fixed_len(), tempo_len, clock_len) {
+            (Some(len), _, _) | (None, Some(len), _) | (None, None, Some(len)) => len,
+            (None, None, None) => self.metronome.quantize(recorded, self.quantize),
+        };
+        if let Some(base) = self.base_len() {
+            // Make it fit a whole number of times around the other loops.
+            let repeats = ((len as f64 / base as f64).round() as usize).max(1);
+            len = repeats * base;
+        }
+        if len > recorded {
+            // Pad the end of the loop with silence, in case there's
+            // something left over in the bank from before a clear.
+            let channels = self.state.channels;
+            let padding = vec![0.0; (len - recorded) * channels];
+            let mut bank = self.bank.lock().unwrap();
+            if let Err(e) = bank.write_at(recorded * channels, &padding) {
+                tracing::error!(error = %e, "couldn't pad loop");
+            }
+        }
+        self.state.close_first_loop(len);
+        true
+    }
+
+    // Stop recording the layer in progress, and throw it away, leaving the
+    // layers that were already there as they were. With no layers yet, that
+    // means starting over.
+    pub fn cancel_layer(&mut self) -> anyhow::Result<()> {
+        if !self.state.recording() {
+            anyhow::bail!("There's no layer being recorded to cancel.");
+        }
+        if self.state.first_loop() {
+            self.state.reset();
+            self.taps = 0;
+            status!("CANCELLED. Hit SPACE to start recording.");
+            return Ok(());
+        }
+        if self.state.record_mode() != RecordMode::Layer {
+            // Those go straight onto the layers that are already there, so
+            // there's nothing separate to throw away.
+            anyhow::bail!("Can only cancel a layer while layering.");
+        }
+        self.state.cancel_layer();
+        status!("CANCELLED. {} layer(s) left.", self.state.get_loop_count());
+        Ok(())
+    }
+
+    // Remove the most recently recorded loop layer, or if the last thing to
+    // happen was a freeze(), bring back the layers from before it.
+    fn undo(&mut self) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't undo while recording.");
+        }
+
+        if let Some(frozen) = self.frozen.take() {
+            let playback = self.state.get_playback();
+            self.replace_layers(frozen.len, &frozen.layers, Some(&frozen.settings), playback)?;
+            status!("UNFROZEN. {} layer(s).", frozen.layers.len());
+            return Ok(());
+        }
+
+        if !self.state.remove_layer() {
+            status!("Nothing to undo.");
+            return Ok(());
+        }
+
+        if self.state.first_loop() {
+            // That was the first loop, so there's no loop length anymore
+            // either. Start over from scratch.
+            self.clear();
+        } else {
+            self.redo_depth += 1;
+            status!("UNDO. {} layer(s) left.", self.state.get_loop_count());
+        }
+        Ok(())
+    }
+
+    // Throw away everything recorded so far. The next tap starts recording
+    // a brand new first loop.
+    fn clear(&mut self) {
+        self.state.reset();
+        self.taps = 0;
+        self.tap_tempo.reset();
+        self.discard_redo();
+        status!("CLEARED. Hit SPACE to start recording.");
+    }
+
+    // Render one loop's worth of the mix across all layers, just like the
+    // output callback hears it, and save it as a 32-bit float WAV.
+    pub fn export_wav(&self, path: &Path) -> anyhow::Result<()> {
+        if self.state.first_loop() {
+            anyhow::bail!("Nothing to export yet.");
+        }
+
+        let spec = hound::WavSpec {
+            channels: self.state.channels as u16,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let len = self.state.layer_len();
+        let count = self.state.heard_layers(self.state.get_loop_count());
+        let recorded = self.state.get_total_samples();
+        // Working on a copy keeps the output callback from waiting on the
+        // file.
+        let bank = SampleBank::copy_shared(&self.bank, recorded);
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for idx in 0..len {
+            writer.write_sample(bank.mix(idx, len, count, bank.len(), self.state.channels))?;
+        }
+        writer.finalize()?;
+
+        status!("EXPORTED {}.", path.display());
+        Ok(())
+    }
+
+    // The lowest and highest sample of the current track's mix in each of
+    // width equal slices of the loop, e.g. one for each pixel (or column) of
+    // a waveform display. Like normalize_mix(), this works on a copy of the
+    // bank, so it's fine to call from the UI as often as it redraws.
+    pub fn waveform(&self, width: usize) -> Vec<(f32, f32)> {
+        if self.state.first_loop() || width == 0 {
+            return Vec::new();
+        }
+
+        let channels = self.state.channels;
+        let len = self.state.layer_len();
+        let count = self.state.heard_layers(self.state.get_loop_count());
+        let recorded = self.state.get_total_samples();
+        let bank = SampleBank::copy_shared(&self.bank, recorded);
+        let mix: Vec<f32> = (0..len).map(|idx| bank.mix(idx, len, count, bank.len(), channels)).collect();
+        waveform_peaks(&mix, channels, width)
+    }
+
+    // Stop the streams, waiting for the audio threads to finish with
+    // everything, then export the loop to export_path if there is one.
+    pub fn shutdown(&mut self, export_path: Option<&Path>) -> anyhow::Result<()> {
+        // Dropping a stream doesn't return until its callback has stopped
+        // running for good.
+        self.input = None;
+        self.output = None;
+
+        match export_path {
+            Some(path) if !self.state.first_loop() => self.export_wav(path),
+            _ => Ok(()),
+        }
+    }
+
+    // Make the loop ratio times as long (slowing it down, for a ratio over
+    // 1) without changing its pitch, layers and all. The stretching happens
+    // on copies of the layers, and the new ones go in all at once.
+    pub fn stretch(&mut self, ratio: f32) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't stretch while recording.");
+        }
+        if self.state.first_loop() {
+            anyhow::bail!("There's no loop to stretch yet.");
+        }
+        if !(MIN_STRETCH..=MAX_STRETCH).contains(&ratio) {
+            anyhow::bail!("The loop can only be stretched by {} to {} times.", MIN_STRETCH, MAX_STRETCH);
+        }
+
+        let channels = self.state.channels;
+        let len = self.state.get_loop_len();
+        let count = self.state.get_loop_count();
+        let recorded = self.state.get_total_samples();
+        let new_len = ((len as f32 * ratio).round() as usize).max(1);
+        let mut samples = Vec::with_capacity(count * new_len * channels);
+        for layer in self.copy_layers() {
+            samples.extend(time_stretch(&layer, channels, new_len, self.sample_rate));
+        }
+        let new_recorded = (((recorded / channels) as f32 * ratio).round() as usize * channels).min(samples.len());
+        let playback = (self.state.get_playback() as f32 * ratio) as usize;
+
+        let samples = SampleBank::padded(&self.bank, samples);
+        let old = {
+            // The output callback reads the loop length once it has the bank,
+            // so holding on to it keeps the two in step.
+            let mut bank = self.bank.lock().unwrap();
+            let old = bank.swap_samples(samples);
+            self.state.load_layers(new_len, count, new_recorded, playback);
+            old
+        };
+        drop(old);
+        // Anything undone was at the old length.
+        self.discard_redo();
+        status!("STRETCHED to {:.2}s.", new_len as f32 / self.sample_rate as f32);
+        Ok(())
+    }
+
+    // Cut any near-silence (below threshold_db dBFS) off the start and end of
+    // the first layer, and the same stretch off every other layer, so the
+    // loop's only as long as what's actually in it.
+    pub fn trim_silence(&mut self, threshold_db: f32) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't trim while recording.");
+        }
+        if self.state.first_loop() {
+            anyhow::bail!("There's no loop to trim yet.");
+        }
+
+        let channels = self.state.channels;
+        let len = self.state.get_loop_len();
+        let layers = self.copy_layers();
+        let Some((start, end)) = loud_frames(&layers[0], channels, db_to_gain(threshold_db)) else {
+            anyhow::bail!("The first layer's all silence, so there'd be nothing left.");
+        };
+        if start == 0 && end == len {
+            status!("Nothing to trim.");
+            return Ok(());
+        }
+        let trimmed: Vec<Vec<f32>> = layers.iter()
+            .map(|layer| layer[start * channels..end * channels].to_vec())
+            .collect();
+        let playback = self.state.get_playback().clamp(start, end - 1) - start;
+        self.replace_layers(end - start, &trimmed, None, playback)?;
+        status!(
+            "TRIMMED {:.2}s off the start and {:.2}s off the end.",
+            start as f32 / self.sample_rate as f32, (len - end) as f32 / self.sample_rate as f32
+        );
+        Ok(())
+    }
+
+    // Make the loop twice as long, with every layer playing twice over in
+    // it, so there's room to record something longer on top.
+    pub fn double_length(&mut self) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't double the loop while recording.");
+        }
+        if self.state.first_loop() {
+            anyhow::bail!("There's no loop to double yet.");
+        }
+        let len = self.state.get_loop_len() * 2;
+        let samples = self.state.get_loop_count() * len * self.state.channels;
+        if self.state.max_record_samples().is_some_and(|max| samples > max) {
+            anyhow::bail!("There isn't room to record a loop twice as long.");
+        }
+
+        let doubled: Vec<Vec<f32>> = self.copy_layers().iter().map(|layer| layer.repeat(2)).collect();
+        let playback = self.state.get_playback();
+        self.replace_layers(len, &doubled, None, playback)?;
+        status!("DOUBLED to {:.2}s.", len as f32 / self.sample_rate as f32);
+        Ok(())
+    }
+
+    // Make the loop half as long, keeping just the first half of every layer
+    // or mixing the two halves together, depending on mode. With an odd
+    // number of frames, the last one goes.
+    pub fn halve_length(&mut self, mode: HalveMode) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't halve the loop while recording.");
+        }
+        if self.state.first_loop() {
+            anyhow::bail!("There's no loop to halve yet.");
+        }
+        let len = self.state.get_loop_len() / 2;
+        if len == 0 {
+            anyhow::bail!("The loop's too short to halve.");
+        }
+
+        let half = len * self.state.channels;
+        let halved: Vec<Vec<f32>> = self.copy_layers().iter()
+            .map(|layer| match mode {
+                HalveMode::KeepFirst => layer[..half].to_vec(),
+                HalveMode::Mix => layer[..half].iter().zip(&layer[half..half * 2]).map(|(a, b)| a + b).collect(),
+            })
+            .collect();
+        let playback = self.state.get_playback() % len;
+        self.replace_layers(len, &halved, None, playback)?;
+        status!("HALVED to {:.2}s.", len as f32 / self.sample_rate as f32);
+        Ok(())
+    }
+
+    // Mix all of the current track's layers (or as many as are being played;
+    // see set_playback_layers()) down into one, just as they sound, so
+    // playback has less to add up and anything recorded from then on goes
+    // on top of just the one. Undoing straight afterwards splits them up
+    // again.
+    pub fn freeze(&mut self) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't freeze while recording.");
+        }
+        if self.state.first_loop() {
+            anyhow::bail!("There's no loop to freeze yet.");
+        }
+
+        let len = self.state.get_loop_len();
+        let count = self.state.get_loop_count();
+        let recorded = self.state.get_total_samples();
+        let copy = SampleBank::copy_shared(&self.bank, recorded);
+        let (_, mix) = copy.flatten(self.state.layer_len(), self.state.heard_layers(count), recorded, self.state.channels);
+        let frozen = Frozen {
+            len,
+            layers: self.copy_layers(),
+            settings: copy.layer_settings(count),
+        };
+        drop(copy);
+        let playback = self.state.get_playback();
+        self.replace_layers(len, &[mix], Some(&[]), playback)?;
+        self.frozen = Some(frozen);
+        status!("FROZEN {} layer(s) into 1.", count);
+        Ok(())
+    }
+
+    // A copy of each of the current track's layers, padded out with silence
+    // to a whole loop, since the last one might not have been recorded all
+    // the way round.
+    fn copy_layers(&self) -> Vec<Vec<f32>> {
+        let layer_len = self.state.layer_len();
+        let old = SampleBank::copy_shared(&self.bank, self.state.get_total_samples());
+        (0..self.state.get_loop_count())
+            .map(|layer| {
+                let mut samples = vec![0.0; layer_len];
+                let start = layer * layer_len;
+                let end = ((layer + 1) * layer_len).min(old.len());
+                samples[..end.saturating_sub(start)].copy_from_slice(&old.samples[start.min(end)..end]);
+                samples
+            })
+            .collect()
+    }
+
+    // Swap the current track's layers for new ones len frames long, all at
+    // once, with playback picking up from frame playback. The layers keep
+    // their settings, unless there are new ones for them too.
+    fn replace_layers(
+        &mut self,
+        len: usize,
+        layers: &[Vec<f32>],
+        settings: Option<&[LayerSettings]>,
+        playback: usize,
+    ) -> anyhow::Result<()> {
+        let samples = layers.concat();
+        let recorded = samples.len();
+        let samples = SampleBank::padded(&self.bank, samples);
+        let old = {
+            // The output callback reads the loop length once it has the bank,
+            // so holding on to it keeps the two in step.
+            let mut bank = self.bank.lock().unwrap();
+            let old = bank.swap_samples(samples);
+            if let Some(settings) = settings {
+                bank.set_layer_settings(settings);
+            }
+            self.state.load_layers(len, layers.len(), recorded, playback);
+            old
+        };
+        drop(old);
+        // Anything undone was at the old length.
+        self.discard_redo();
+        Ok(())
+    }
+
+    // Replace everything with the contents of a WAV file, as if it had just
+    // been recorded as the first loop. The file is converted to our sample
+    // rate and channel count as needed.
+    pub fn import_wav(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't import while recording.");
+        }
+
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => {
+                reader.samples::<f32>().collect::<Result<Vec<_>, _>>()?
+            },
+            hound::SampleFormat::Int => {
+                let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+                reader.samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 / scale))
+                    .collect::<Result<Vec<_>, _>>()?
+            },
+        };
+        let channels = self.state.channels as u16;
+        let samples = remix(&samples, spec.channels, channels);
+        let samples = resample(&samples, channels, spec.sample_rate, self.sample_rate);
+        if samples.is_empty() {
+            anyhow::bail!("{} has no samples.", path.display());
+        }
+
+        let frames = samples.len() / self.state.channels;
+        let samples = SampleBank::padded(&self.bank, samples);
+        let old = self.bank.lock().unwrap().swap_samples(samples);
+        drop(old);
+        self.state.reset();
+        self.discard_redo();
+        self.state.load_first_loop(frames);
+        // Skip past the taps for recording the first loop.
+        self.taps = self.tap_config.taps.len();
+        self.play()?;
+
+        status!("IMPORTED {}. Hit SPACE to overdub.", path.display());
+        Ok(())
+    }
+
+    // Save everything recorded so far on every track, along with each
+    // layer's settings and the rest of the mix, as a zip of each track's raw
+    // layers (one after another, as a WAV) and a JSON manifest describing
+    // them.
+    pub fn save_session(&self, path: &Path) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't save while recording.");
+        }
+        if self.tracks.iter().all(|track| track.state.first_loop()) {
+            anyhow::bail!("Nothing to save yet.");
+        }
+
+        let spec = hound::WavSpec {
+            channels: self.state.channels as u16,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut tracks = vec![];
+        let mut wavs = vec![];
+        for track in &self.tracks {
+            let state = &track.state;
+            let count = state.get_loop_count();
+            // Anything on its way to being a first loop doesn't count yet.
+            let total_samples = if count == 0 { 0 } else { state.get_total_samples() };
+            // Everything from here on works on a copy, so the output
+            // callback doesn't have to wait for it.
+            let bank = SampleBank::copy_shared(&track.bank, total_samples);
+            tracks.push(TrackManifest {
+                loop_len: if count == 0 { 0 } else { state.get_loop_len() },
+                loop_count: count,
+                total_samples,
+                playback: state.get_playback(),
+                layers: bank.layer_settings(count),
+            });
+
+            let mut wav = std::io::Cursor::new(vec![]);
+            let mut writer = hound::WavWriter::new(&mut wav, spec)?;
+            for &sample in &bank.samples {
+                writer.write_sample(sample)?;
+            }
+            writer.finalize()?;
+            wavs.push(wav.into_inner());
+        }
+        let manifest = SessionManifest {
+            version: SESSION_VERSION,
+            sample_rate: self.sample_rate,
+            channels: self.state.channels,
+            tracks,
+            master_gain: self.state.master_gain(),
+            cutoff: self.state.cutoff(),
+            bpm: self.metronome.bpm(),
+        };
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(path)?);
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        zip.start_file(SESSION_MANIFEST, options)?;
+        serde_json::to_writer_pretty(&mut zip, &manifest)?;
+        for (track, wav) in wavs.iter().enumerate() {
+            zip.start_file(session_samples(track), options)?;
+            zip.write_all(wav)?;
+        }
+        zip.finish()?;
+
+        status!("SAVED {}.", path.display());
+        Ok(())
+    }
+
+    // Replace everything on every track with a session saved by
+    // save_session(), picking up playback right where it was. The session
+    // has to match our sample rate and channel count, since the layers have
+    // to line up exactly, and can't have more tracks than we do. Any tracks
+    // past the session's are left empty.
+    pub fn load_session(&mut self, path: &Path) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't load while recording.");
+        }
+
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+        let manifest: SessionManifest = serde_json::from_reader(zip.by_name(SESSION_MANIFEST)?)?;
+        if manifest.version != SESSION_VERSION {
+            anyhow::bail!(
+                "{} is a version {} session, but only version {} is supported.",
+                path.display(), manifest.version, SESSION_VERSION
+            );
+        }
+        if manifest.sample_rate != self.sample_rate || manifest.channels != self.state.channels {
+            anyhow::bail!(
+                "{} was saved at {} Hz with {} channel(s), but we're running at {} Hz with {}.",
+                path.display(), manifest.sample_rate, manifest.channels,
+                self.sample_rate, self.state.channels
+            );
+        }
+
+        if manifest.tracks.len() > self.tracks.len() {
+            anyhow::bail!(
+                "{} has {} tracks, but there are only {}.",
+                path.display(), manifest.tracks.len(), self.tracks.len()
+            );
+        }
+
+        // Read everything before changing anything, so a damaged file
+        // doesn't leave only some of the tracks loaded.
+        let mut samples = vec![];
+        for (index, track) in manifest.tracks.iter().enumerate() {
+            let mut wav = vec![];
+            zip.by_name(&session_samples(index))?.read_to_end(&mut wav)?;
+            let track_samples = hound::WavReader::new(std::io::Cursor::new(wav))?
+                .samples::<f32>()
+                .collect::<Result<Vec<_>, _>>()?;
+            if track_samples.len() != track.total_samples || (track.loop_len == 0) != (track.loop_count == 0) {
+                anyhow::bail!("{} is damaged: its samples don't match its manifest.", path.display());
+            }
+            samples.push(track_samples);
+        }
+        if manifest.tracks.iter().all(|track| track.loop_count == 0) {
+            anyhow::bail!("{} is damaged: it doesn't have any loops.", path.display());
+        }
+
+        let taps = self.tap_config.taps.len();
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            let mut state = track.state.clone();
+            state.reset();
+            track.redo_depth = 0;
+            track.frozen = None;
+            track.taps = 0;
+            let Some(saved) = manifest.tracks.get(index).filter(|saved| saved.loop_count > 0) else {
+                track.bank.lock().unwrap().reset_layers_from(0);
+                continue;
+            };
+            let samples = SampleBank::padded(&track.bank, std::mem::take(&mut samples[index]));
+            let old = {
+                let mut bank = track.bank.lock().unwrap();
+                let old = bank.swap_samples(samples);
+                bank.set_layer_settings(&saved.layers);
+                state.load_layers(saved.loop_len, saved.loop_count, saved.total_samples, saved.playback);
+                old
+            };
+            drop(old);
+            // Skip past the taps for recording the first loop.
+            track.taps = taps;
+        }
+        let selected = &self.tracks[self.track];
+        self.taps = selected.taps;
+        self.discard_redo();
+        self.state.set_master_gain(manifest.master_gain);
+        self.state.set_cutoff(manifest.cutoff);
+        self.metronome.set_bpm(manifest.bpm);
+        self.play()?;
+
+        let layers: usize = manifest.tracks.iter().map(|track| track.loop_count).sum();
+        status!("LOADED {}. {} layer(s) on {} track(s).", path.display(), layers, manifest.tracks.len());
+        Ok(())
+    }
+
+    // Undone layers get recorded over once we start recording again, so
+    // there's no bringing them (or their settings) back after that. The same
+    // goes for layers from before a freeze.
+    fn discard_redo(&mut self) {
+        self.redo_depth = 0;
+        self.frozen = None;
+        self.bank.lock().unwrap().reset_layers_from(self.state.get_loop_count());
+    }
+
+    // Everything about the selected track at once, so callers don't have to
+    // load each value separately and risk some of them changing in between
+    // (e.g. loop_count going up after playback's wrapped). The output
+    // callback holds on to the bank whenever it's changing any of this, so
+    // holding on to it here keeps it all in step with that. That's only
+    // best-effort, though: anything else changing the state (i.e. this
+    // thread) can still get in between.
+    pub fn snapshot(&self) -> LooperState {
+        let bank = self.bank.lock().unwrap();
+        let state = &self.state;
+        let loop_count = state.get_loop_count();
+        LooperState {
+            playback: state.get_playback(),
+            loop_len: state.get_loop_len(),
+            loop_count,
+            total_samples: state.get_total_samples(),
+            recording: state.recording(),
+            counting_in: state.counting_in(),
+            playing: state.playing(),
+            layers: bank.layer_settings(loop_count),
+        }
+    }
+
+    pub fn layer_gain(&self, layer: usize) -> f32 {
+        self.bank.lock().unwrap().layer_gain(layer)
+    }
+
+    pub fn set_layer_gain(&mut self, layer: usize, gain: f32) {
+        self.bank.lock().unwrap().set_layer_gain(layer, gain.max(0.0));
+    }
+
+    pub fn layer_pan(&self, layer: usize) -> f32 {
+        self.bank.lock().unwrap().layer_pan(layer)
+    }
+
+    pub fn set_layer_pan(&mut self, layer: usize, pan: f32) {
+        self.bank.lock().unwrap().set_layer_pan(layer, pan);
+    }
+
+    // Tap out a tempo for the metronome and the first loop.
+    pub fn tap_tempo(&mut self, at: Instant) {
+        self.tap_tempo.tap(at);
+        match self.tap_tempo.bpm() {
+            Some(bpm) => {
+                self.metronome.set_bpm(bpm);
+                status!("bpm={:.1}", bpm);
+            },
+            None => {
+                status!("Keep tapping...");
+            },
+        }
+    }
+
+    // Stop or restart playback, keeping everything recorded so far.
+    fn toggle_playback(&mut self) {
+        self.state.toggle_playing();
+        if self.state.playing() {
+            status!("PLAYING.");
+        } else {
+            status!("STOPPED.");
+        }
+    }
+
+    // Go back to the start of the loop, keeping everything recorded. When
+    // synced, every track goes back to the start together, so they stay in
+    // phase with each other.
+    pub fn retrigger(&mut self) -> anyhow::Result<()> {
+        if self.state.first_loop() {
+            anyhow::bail!("There's no loop to retrigger yet.");
+        }
+        if self.state.recording() {
+            anyhow::bail!("Can't retrigger while recording.");
+        }
+        if self.state.synced() {
+            self.tracks.iter().for_each(|track| track.state.retrigger());
+        } else {
+            self.state.retrigger();
+        }
+        status!("RETRIGGERED.");
+        Ok(())
+    }
+
+    // Halve or double the playback speed, within MIN_RATE and MAX_RATE.
+    pub fn set_rate(&mut self, rate: f32) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't change speed while recording.");
+        }
+        self.state.set_rate(rate);
+        status!("rate={}", self.state.rate());
+        Ok(())
+    }
+
+    // Play the loop backwards, or forwards again.
+    pub fn toggle_reverse(&mut self) -> anyhow::Result<()> {
+        if self.state.first_loop() {
+            anyhow::bail!("There's no loop to reverse yet.");
+        }
+        if self.state.recording() {
+            anyhow::bail!("Can't reverse while recording.");
+        }
+        self.state.toggle_reversed();
+        status!("reversed={}", self.state.reversed());
+        Ok(())
+    }
+
+    // Transpose the whole mix, without changing its speed. Zero turns it
+    // off.
+    pub fn set_pitch_semitones(&mut self, semitones: f32) {
+        self.state.set_pitch_semitones(semitones);
+        status!("pitch={:+} semitone(s)", self.state.pitch_semitones());
+    }
+
+    // How much of the mix goes through the effects, from 0 (none of it) to
+    // 1 (all of it).
+    pub fn set_wet_mix(&mut self, mix: f32) {
+        self.state.set_wet_mix(mix);
+        status!("wet_mix={:.2}", self.state.wet_mix());
+    }
+
+    // Go to the next of a few wet mix settings.
+    pub fn next_wet_mix(&mut self) {
+        let next = match self.state.wet_mix() {
+            mix if mix < 0.25 => 0.25,
+            mix if mix < 0.5 => 0.5,
+            mix if mix < 0.75 => 0.75,
+            mix if mix < 1.0 => 1.0,
+            _ => 0.0,
+        };
+        self.set_wet_mix(next);
+    }
+
+    // Narrow or widen the stereo image, from 0 (mono) up to MAX_WIDTH. It
+    // only makes a difference in stereo.
+    pub fn set_width(&mut self, width: f32) {
+        self.state.set_width(width);
+        status!("width={:.2}", self.state.width());
+    }
+
+    // Go to the next of a few widths, from mono up to the widest.
+    pub fn next_width(&mut self) {
+        let width = self.state.width();
+        let next = if width >= MAX_WIDTH { 0.0 } else { (width * 2.0).floor() / 2.0 + 0.5 };
+        self.set_width(next);
+    }
+
+    // Move the low-pass filter's cutoff, or switch it off by moving it all
+    // the way up.
+    pub fn set_cutoff(&mut self, hz: f32) {
+        self.state.set_cutoff(hz);
+        if self.state.cutoff() >= MAX_CUTOFF {
+            status!("cutoff=off");
+        } else {
+            status!("cutoff={:.0} Hz", self.state.cutoff());
+        }
+    }
+
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.state.set_master_gain(gain);
+        status!("master_gain={:.1}", self.state.master_gain());
+    }
+
+    // Cycle through the recording modes.
+    pub fn next_record_mode(&mut self) {
+        let mode = match self.state.record_mode() {
+            RecordMode::Layer => RecordMode::Overdub { feedback: DEFAULT_FEEDBACK },
+            RecordMode::Overdub { .. } => RecordMode::Replace,
+            RecordMode::Replace => RecordMode::Layer,
+        };
+        self.state.set_record_mode(mode);
+        status!("mode={:?}", self.state.record_mode());
+    }
+
+    pub fn toggle_mute(&mut self, layer: usize) {
+        let mut bank = self.bank.lock().unwrap();
+        bank.toggle_mute(layer);
+        status!("layer={} muted={}", layer + 1, bank.layer_muted(layer));
+    }
+
+    pub fn toggle_solo(&mut self, layer: usize) {
+        let mut bank = self.bank.lock().unwrap();
+        bank.toggle_solo(layer);
+        status!("layer={} soloed={}", layer + 1, bank.layer_soloed(layer));
+    }
+
+    // Only play the first n layers, leaving the rest recorded (and their
+    // mutes and solos alone) but unheard. Zero plays all of them again.
+    pub fn set_playback_layers(&mut self, n: usize) {
+        self.state.set_playback_layer_limit(n);
+        match self.state.playback_layer_limit() {
+            Some(limit) => status!("playback_layers={}", limit),
+            None => status!("playback_layers=all"),
+        }
+    }
+
+    // Play one more of the layers, going back to all of them after the last.
+    pub fn next_playback_layers(&mut self) {
+        let count = self.state.get_loop_count();
+        let next = match self.state.playback_layer_limit() {
+            None if count > 1 => 1,
+            Some(limit) if limit + 1 < count => limit + 1,
+            _ => 0,
+        };
+        self.set_playback_layers(next);
+    }
+
+    // Play one layer backwards (or forwards again), leaving the rest alone.
+    pub fn toggle_layer_reverse(&mut self, layer: usize) -> anyhow::Result<()> {
+        if self.state.recording() && self.state.record_mode() != RecordMode::Layer {
+            anyhow::bail!("Can't reverse a layer while recording onto it.");
+        }
+        let mut bank = self.bank.lock().unwrap();
+        bank.toggle_layer_reverse(layer);
+        status!("layer={} reversed={}", layer + 1, bank.layer_reversed(layer));
+        Ok(())
+    }
+
+    // Have a layer fade away, its gain multiplied by factor every time
+    // playback comes round, until it's quiet enough to be taken out. A
+    // factor of 1 stops it decaying any further.
+    pub fn set_layer_decay(&mut self, layer: usize, factor: f32) {
+        let mut bank = self.bank.lock().unwrap();
+        bank.set_layer_decay(layer, factor);
+        status!("layer={} decay={:.2}", layer + 1, bank.layer_decay(layer));
+    }
+
+    // Go to the next of a few decay factors for a layer (see LAYER_DECAYS),
+    // from none to the quickest.
+    pub fn next_layer_decay(&mut self, layer: usize) {
+        let decay = self.bank.lock().unwrap().layer_decay(layer);
+        let next = LAYER_DECAYS.iter().copied().find(|&next| next < decay).unwrap_or(1.0);
+        self.set_layer_decay(layer, next);
+    }
+
+    // Take out any of track's decaying layers that have faded away, now
+    // that its playback's come round and turned them down (leaving at least
+    // one, so there's still a loop). Nothing gets taken out while recording,
+    // since that would move the layer being recorded out from under it.
+    fn decay_layers(&mut self, track: usize) {
+        let Track { state, bank, decay_cycles, .. } = &mut self.tracks[track];
+        if state.recording() || state.decay_cycles() == *decay_cycles {
+            return;
+        }
+        *decay_cycles = state.decay_cycles();
+        let count = state.get_loop_count();
+        let layer_len = state.layer_len();
+        let faded = bank.lock().unwrap().faded_layers(count);
+        // From the top down, so the ones still to go don't move, and
+        // leaving at least one.
+        let gone: Vec<usize> = faded.into_iter().rev().take(count.saturating_sub(1)).collect();
+        if gone.is_empty() {
+            return;
+        }
+        // The samples move down on a copy, which then goes in all at once.
+        // That leaves the last layer's worth of samples past the end, which
+        // is what removing the last layer does anyway.
+        let recorded = state.get_total_samples();
+        let mut copy = SampleBank::copy_shared(bank, recorded);
+        for &layer in &gone {
+            copy.remove_layer_at(layer, layer_len, recorded);
+        }
+        let samples = SampleBank::padded(bank, copy.samples);
+        let old = {
+            let mut bank = bank.lock().unwrap();
+            for &layer in &gone {
+                bank.remove_layer_settings(layer);
+                state.remove_layer();
+            }
+            bank.swap_samples(samples)
+        };
+        drop(old);
+        for &layer in &gone {
+            send_event(&self.event_sender, Event::LayerFaded { track, index: layer });
+        }
+        // Anything undone would come back in the wrong place now.
+        if track == self.track {
+            self.discard_redo();
+        } else {
+            self.tracks[track].redo_depth = 0;
+            self.tracks[track].frozen = None;
+            self.tracks[track].bank.lock().unwrap().reset_layers_from(count - gone.len());
+        }
+    }
+
+    // Bring back the most recently undone loop layer.
+    pub fn redo(&mut self) -> anyhow::Result<()> {
+        if self.state.recording() {
+            anyhow::bail!("Can't redo while recording.");
+        }
+        if self.redo_depth == 0 {
+            anyhow::bail!("Nothing to redo.");
+        }
+
+        self.state.restore_layer();
+        self.redo_depth -= 1;
+        status!("REDO. {} layer(s).", self.state.get_loop_count());
+        Ok(())
+    }
+}
+
+// A sample from a device that talks in i16, as an f32 between -1 and 1.
+pub fn i16_to_f32(sample: i16) -> f32 {
+    sample as f32 / 32768.0
+}
+
+// An f32 sample for a device that talks in i16. Anything louder than full
+// scale gets clipped, rather than wrapping around.
+pub fn f32_to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16
+}
+
+// Convert interleaved samples from one channel count to another. Extra
+// channels are folded down by averaging; missing ones are filled by repeating
+// what we have.
+fn remix(samples: &[f32], from: u16, to: u16) -> Vec<f32> {
+    let (from, to) = (from as usize, to as usize);
+    if from == to {
+        return samples.to_vec();
+    }
+
+    let mut remixed = Vec::with_capacity(samples.len() / from * to);
+    for frame in samples.chunks_exact(from) {
+        for channel in 0..to {
+            remixed.push(remix_channel(frame, channel, to));
+        }
+    }
+    remixed
+}
+
+// The sample for one channel of a frame remixed to `to` channels, as in
+// remix().
+fn remix_channel(frame: &[f32], channel: usize, to: usize) -> f32 {
+    let from = frame.len();
+    if from <= to {
+        return frame[channel % from];
+    }
+
+    let (sum, n) = frame.iter()
+        .skip(channel)
+        .step_by(to)
+        .fold((0.0, 0), |(sum, n), sample| (sum + sample, n + 1));
+    sum / n as f32
+}
+
+// Frames of output the Converter can hold at once.
+const CONVERTER_LEN: usize = 4096;
+
+// The most recent samples, kept in a fixed-size circular buffer: input to
+// tack onto the start of a recording after the fact, or input on its way to
+// being monitored. Like Converter, it never allocates after it's been
+// created.
+struct RecentSamples {
+    buf: Vec<f32>,
+    // Where the next sample goes, and how many of buf's samples are real.
+    pos: usize,
+    len: usize,
+}
+
+impl RecentSamples {
+    // Keep up to len samples.
+    fn new(len: usize) -> Self {
+        Self {
+            buf: vec![0.0; len],
+            pos: 0,
+            len: 0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    // Add samples, forgetting the oldest ones to make room.
+    fn push(&mut self, samples: &[f32]) {
+        if !self.enabled() {
+            return;
+        }
+        // Only the last buf.len() samples could possibly survive.
+        let samples = &samples[samples.len().saturating_sub(self.buf.len())..];
+        for &sample in samples {
+            self.buf[self.pos] = sample;
+            self.pos = (self.pos + 1) % self.buf.len();
+        }
+        self.len = (self.len + samples.len()).min(self.buf.len());
+    }
+
+    // Take out the oldest sample.
+    fn pop(&mut self) -> Option<f32> {
+        if self.len == 0 {
+            return None;
+        }
+        let sample = self.buf[(self.pos + self.buf.len() - self.len) % self.buf.len()];
+        self.len -= 1;
+        Some(sample)
+    }
+
+    // Hand everything over to emit, oldest first, and start over empty.
+    fn drain(&mut self, mut emit: impl FnMut(&[f32])) {
+        if self.len == 0 {
+            return;
+        }
+        // The oldest sample is len samples behind pos, wrapping around.
+        let start = (self.pos + self.buf.len() - self.len) % self.buf.len();
+        if start + self.len <= self.buf.len() {
+            emit(&self.buf[start..start + self.len]);
+        } else {
+            emit(&self.buf[start..]);
+            emit(&self.buf[..self.pos]);
+        }
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+// Converts a stream of input buffers to a different sample rate and/or
+// channel count as they arrive, like resample() and remix() but keeping its
+// place from one buffer to the next. It works in a fixed-size buffer, so it
+// never allocates after it's been created.
+struct Converter {
+    in_channels: usize,
+    out_channels: usize,
+    // Input frames per output frame.
+    step: f64,
+    // Where the next output frame falls, in input frames relative to the
+    // start of the next buffer. Between -1 and 0 means it falls between the
+    // last frame of the previous buffer and the first of the next.
+    pos: f64,
+    // The last frame of the previous buffer, already remixed.
+    prev: Vec<f32>,
+    out: Vec<f32>,
+}
+
+impl Converter {
+    fn new(in_channels: u16, in_rate: u32, out_channels: u16, out_rate: u32) -> Self {
+        Self {
+            in_channels: in_channels as usize,
+            out_channels: out_channels as usize,
+            step: in_rate as f64 / out_rate as f64,
+            pos: 0.0,
+            prev: vec![0.0; out_channels as usize],
+            out: Vec::with_capacity(CONVERTER_LEN * out_channels as usize),
+        }
+    }
+
+    fn passthrough(&self) -> bool {
+        self.step == 1.0 && self.in_channels == self.out_channels
+    }
+
+    // Forget about the previous buffer, e.g. because there was a gap since.
+    fn reset(&mut self) {
+        self.pos = 0.0;
+        self.prev.iter_mut().for_each(|sample| *sample = 0.0);
+    }
+
+    // Convert a buffer of input, handing the converted samples to emit in
+    // one or more pieces.
+    fn process(&mut self, input: &[f32], mut emit: impl FnMut(&[f32])) {
+        if self.passthrough() {
+            emit(input);
+            return;
+        }
+
+        let frames = input.len() / self.in_channels;
+        if frames == 0 {
+            return;
+        }
+
+        self.out.clear();
+        // We need the frame after pos to interpolate, so stop one short.
+        while self.pos < (frames - 1) as f64 {
+            let before = self.pos.floor();
+            let frac = (self.pos - before) as f32;
+            for channel in 0..self.out_channels {
+                let a = self.sample(input, before as isize, channel);
+                let b = self.sample(input, before as isize + 1, channel);
+                self.out.push(a + (b - a) * frac);
+            }
+            self.pos += self.step;
+
+            if self.out.len() == self.out.capacity() {
+                emit(&self.out);
+                self.out.clear();
+            }
+        }
+        if !self.out.is_empty() {
+            emit(&self.out);
+        }
+
+        self.pos -= frames as f64;
+        for channel in 0..self.out_channels {
+            self.prev[channel] = self.sample(input, frames as isize - 1, channel);
+        }
+    }
+
+    // One channel of the given input frame, remixed. Frame -1 is the last
+    // frame of the previous buffer.
+    fn sample(&self, input: &[f32], frame: isize, channel: usize) -> f32 {
+        if frame < 0 {
+            return self.prev[channel];
+        }
+
+        let start = frame as usize * self.in_channels;
+        remix_channel(&input[start..start + self.in_channels], channel, self.out_channels)
+    }
+}
+
+// Whatever gets done to input as it comes in, before it's monitored or
+// recorded. It keeps its state from one input buffer to the next.
+pub struct InputFilter {
+    channels: usize,
+    // One for each channel, if taking out DC offset.
+    dc_blockers: Vec<DcBlocker>,
+    // Just the one for every channel, so they all open and close together.
+    gate: Option<NoiseGate>,
+}
+
+impl InputFilter {
+    pub fn new(channels: usize, sample_rate: u32, dc_block: bool, gate: Option<NoiseGate>) -> Self {
+        let dc_blockers = match dc_block {
+            true => vec![DcBlocker::new(sample_rate); channels],
+            false => vec![],
+        };
+        Self {
+            channels,
+            dc_blockers,
+            gate,
+        }
+    }
+
+    // Filter interleaved samples in place. There have to be a whole number
+    // of frames.
+    fn process(&mut self, samples: &mut [f32]) {
+        if self.dc_blockers.is_empty() && self.gate.is_none() {
+            return;
+        }
+        for frame in samples.chunks_mut(self.channels) {
+            for (sample, blocker) in frame.iter_mut().zip(self.dc_blockers.iter_mut()) {
+                *sample = blocker.process(*sample);
+            }
+            if let Some(gate) = &mut self.gate {
+                let gain = gate.gain(frame);
+                frame.iter_mut().for_each(|sample| *sample *= gain);
+            }
+        }
+    }
+
+    // Forget about previous input, e.g. because there was a gap since.
+    fn reset(&mut self) {
+        self.dc_blockers.iter_mut().for_each(DcBlocker::reset);
+        if let Some(gate) = &mut self.gate {
+            gate.reset();
+        }
+    }
+}
+
+// Silences the input while it's quieter than a threshold, so whatever noise
+// there is between notes doesn't get recorded. It opens over attack and
+// closes over release, fading linearly so it doesn't click.
+pub struct NoiseGate {
+    threshold: f32,
+    // How much the gain moves per frame when opening and closing.
+    attack_step: f32,
+    release_step: f32,
+    // How much the level drops per frame if nothing louder comes along.
+    decay: f32,
+    level: f32,
+    gain: f32,
+}
+
+// How long the gate's level takes to fall by 60 dB. Any quicker and it'd
+// drop below the threshold between every cycle of a low note.
+const GATE_DETECT_MS: f32 = 50.0;
+
+impl NoiseGate {
+    pub fn new(threshold_db: f32, attack_ms: f32, release_ms: f32, sample_rate: u32) -> Self {
+        let frames = |ms: f32| (ms / 1000.0 * sample_rate as f32).max(1.0);
+        Self {
+            threshold: db_to_gain(threshold_db),
+            attack_step: 1.0 / frames(attack_ms),
+            release_step: 1.0 / frames(release_ms),
+            decay: db_to_gain(-60.0 / frames(GATE_DETECT_MS)),
+            level: 0.0,
+            gain: 0.0,
+        }
+    }
+
+    // The gain for the next frame of input.
+    fn gain(&mut self, frame: &[f32]) -> f32 {
+        let peak = frame.iter().fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        self.level = peak.max(self.level * self.decay);
+        self.gain = if self.level >= self.threshold {
+            (self.gain + self.attack_step).min(1.0)
+        } else {
+            (self.gain - self.release_step).max(0.0)
+        };
+        self.gain
+    }
+
+    fn reset(&mut self) {
+        self.level = 0.0;
+        self.gain = 0.0;
+    }
+}
+
+// High-pass filter low enough to leave everything audible alone, but still
+// take out any DC offset, which would otherwise pile up with every layer.
+#[derive(Clone)]
+struct DcBlocker {
+    coeff: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+// Where the DC blocker cuts off, in Hz.
+const DC_BLOCK_HZ: f32 = 10.0;
+
+impl DcBlocker {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            coeff: 1.0 - 2.0 * std::f32::consts::PI * DC_BLOCK_HZ / sample_rate as f32,
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        self.last_out = sample - self.last_in + self.coeff * self.last_out;
+        self.last_in = sample;
+        self.last_out
+    }
+
+    fn reset(&mut self) {
+        self.last_in = 0.0;
+        self.last_out = 0.0;
+    }
+}
+
+// Convert interleaved samples from one sample rate to another, using linear
+// interpolation between neighbouring frames.
+fn resample(samples: &[f32], channels: u16, from: u32, to: u32) -> Vec<f32> {
+    let channels = channels as usize;
+    if from == to || samples.len() < channels {
+        return samples.to_vec();
+    }
+
+    let frames = samples.len() / channels;
+    let new_frames = (frames as u64 * to as u64 / from as u64) as usize;
+    let step = from as f64 / to as f64;
+
+    let mut resampled = Vec::with_capacity(new_frames * channels);
+    for frame in 0..new_frames {
+        let pos = frame as f64 * step;
+        let before = (pos as usize).min(frames - 1);
+        let after = (before + 1).min(frames - 1);
+        let frac = (pos - before as f64) as f32;
+        for channel in 0..channels {
+            let a = samples[before * channels + channel];
+            let b = samples[after * channels + channel];
+            resampled.push(a + (b - a) * frac);
+        }
+    }
+    resampled
+}
+
+// How long each grain of a time stretch is, and how far either side of
+// where it ought to come from it can be moved to line up with the last one.
+const STRETCH_WINDOW_MS: f32 = 40.0;
+const STRETCH_TOLERANCE_MS: f32 = 10.0;
+// Only every few samples get compared when lining grains up; that's plenty
+// to find a good fit, and far quicker.
+const STRETCH_SEARCH_STEP: usize = 4;
+
+// Change the length of a loop (interleaved, frames long) to new_len frames
+// without changing its pitch, by overlapping short windowed grains of it at
+// a different spacing than they came from (WSOLA). Each grain gets nudged to
+// wherever best continues the one before it, so waveforms line up rather
+// than cancelling out. The loop is treated as going round and round, so the
+// result loops just as smoothly.
+fn time_stretch(samples: &[f32], channels: usize, new_len: usize, sample_rate: u32) -> Vec<f32> {
+    let len = samples.len() / channels;
+    if len == 0 || new_len == 0 {
+        return vec![0.0; new_len * channels];
+    }
+
+    let window = ((STRETCH_WINDOW_MS / 1000.0 * sample_rate as f32) as usize).clamp(4, len.max(4));
+    let hop = window / 2;
+    let tolerance = (STRETCH_TOLERANCE_MS / 1000.0 * sample_rate as f32) as isize;
+    let hann: Vec<f32> = (0..window)
+        .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / window as f32).cos())
+        .collect();
+    let at = |frame: isize, channel: usize| samples[frame.rem_euclid(len as isize) as usize * channels + channel];
+    // How alike the grains starting at a and b are, across every channel.
+    let likeness = |a: isize, b: isize| -> f32 {
+        (0..window).step_by(STRETCH_SEARCH_STEP)
+            .map(|i| (0..channels).map(|c| at(a + i as isize, c) * at(b + i as isize, c)).sum::<f32>())
+            .sum()
+    };
+
+    let ratio = new_len as f64 / len as f64;
+    let mut stretched = vec![0.0; new_len * channels];
+    let mut weights = vec![0.0; new_len];
+    let mut last: Option<isize> = None;
+    for out_start in (0..new_len).step_by(hop) {
+        let target = (out_start as f64 / ratio).round() as isize;
+        let start = match last {
+            None => target,
+            Some(last) => {
+                // What would have come straight after the last grain.
+                let natural = last + hop as isize;
+                (target - tolerance..=target + tolerance)
+                    .max_by(|&a, &b| likeness(a, natural).total_cmp(&likeness(b, natural)))
+                    .unwrap_or(target)
+            },
+        };
+        for (i, weight) in hann.iter().enumerate() {
+            let out = (out_start + i) % new_len;
+            for channel in 0..channels {
+                stretched[out * channels + channel] += at(start + i as isize, channel) * weight;
+            }
+            weights[out] += weight;
+        }
+        last = Some(start);
+    }
+
+    // The grains don't overlap evenly everywhere (like where the end wraps
+    // round onto the start), so even out the level.
+    for (frame, weight) in stretched.chunks_mut(channels).zip(weights) {
+        if weight > 1e-3 {
+            frame.iter_mut().for_each(|sample| *sample /= weight);
+        }
+    }
+    stretched
+}
+
+// Run f, which must not allocate. With the alloc-check feature, any
+// allocation in f aborts the process; otherwise this just calls f.
+#[cfg(not(feature = "alloc-check"))]
+fn no_alloc<R>(f: impl FnOnce() -> R) -> R {
+    f()
+}
+
+#[cfg(feature = "alloc-check")]
+fn no_alloc<R>(f: impl FnOnce() -> R) -> R {
+    NO_ALLOC.with(|no_alloc| no_alloc.set(true));
+    let result = f();
+    NO_ALLOC.with(|no_alloc| no_alloc.set(false));
+    result
+}
+
+#[cfg(feature = "alloc-check")]
+thread_local! {
+    // Whether the current thread is inside no_alloc().
+    static NO_ALLOC: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+// The system allocator, but refusing to allocate inside no_alloc().
+#[cfg(feature = "alloc-check")]
+struct CheckedAlloc;
+
+#[cfg(feature = "alloc-check")]
+#[global_allocator]
+static ALLOC: CheckedAlloc = CheckedAlloc;
+
+#[cfg(feature = "alloc-check")]
+unsafe impl std::alloc::GlobalAlloc for CheckedAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        if NO_ALLOC.with(|no_alloc| no_alloc.replace(false)) {
+            // Allocators aren't allowed to unwind, so we can't panic here.
+            eprintln!("allocated {} bytes in an audio callback!", layout.size());
+            std::process::abort();
+        }
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+// Models of how the threads hand off State's atomics while recording, which
+// loom runs through every interleaving (and every reordering the orderings
+// allow). State itself uses std's atomics, so these mirror its loads and
+// stores, orderings and all, and need keeping in step with it. Run them with:
+//
+//   RUSTFLAGS="--cfg loom" cargo test --release --lib loom
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::{LAYER, OVERDUB};
+    use loom::sync::Arc;
+    use loom::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+    use loom::thread;
+
+    const LEN: usize = 4;
+    const CHANNELS: usize = 2;
+    const LAYER_LEN: usize = LEN * CHANNELS;
+
+    // The parts of State the recording handoff goes through.
+    #[derive(Clone)]
+    struct Recording {
+        is_recording: Arc<AtomicBool>,
+        cancelling: Arc<AtomicBool>,
+        loop_len: Arc<AtomicUsize>,
+        total_samples: Arc<AtomicUsize>,
+        loop_count: Arc<AtomicUsize>,
+    }
+
+    impl Recording {
+        fn new() -> Self {
+            Self {
+                is_recording: Arc::new(AtomicBool::new(true)),
+                cancelling: Arc::new(AtomicBool::new(false)),
+                loop_len: Arc::new(AtomicUsize::new(0)),
+                total_samples: Arc::new(AtomicUsize::new(0)),
+                loop_count: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        // Like a Recording whose first loop has closed, and which is
+        // recording the next layer.
+        fn looping() -> Self {
+            let recording = Self::new();
+            recording.loop_len.store(LEN, Ordering::Relaxed);
+            recording.total_samples.store(LAYER_LEN, Ordering::Relaxed);
+            recording.loop_count.store(1, Ordering::Release);
+            recording
+        }
+
+        // State::recording()
+        fn recording(&self) -> bool {
+            self.is_recording.load(Ordering::Acquire)
+        }
+
+        // State::add_sample_count(). Only the output thread writes
+        // total_samples, so it doesn't need a read-modify-write.
+        fn add_sample_count(&self, n: usize) {
+            let total = self.total_samples.load(Ordering::Relaxed);
+            self.total_samples.store(total + n, Ordering::Relaxed);
+        }
+
+        // State::inc_loop_count()
+        fn inc_loop_count(&self) {
+            let count = self.loop_count.load(Ordering::Acquire);
+            self.loop_count.store(count + 1, Ordering::Release);
+        }
+
+        // State::finish_first_loop(), with the loop exactly as long as what's
+        // been recorded.
+        fn finish_first_loop(&self) {
+            self.loop_len.store(LEN, Ordering::Relaxed);
+            self.total_samples.store(LAYER_LEN, Ordering::Relaxed);
+            self.inc_loop_count();
+        }
+
+        // State::cancel_layer()
+        fn cancel_layer(&self) {
+            self.is_recording.store(false, Ordering::Release);
+            self.cancelling.store(true, Ordering::Release);
+        }
+
+        // State::finish_cancel(). That swaps in false, but loom can lose
+        // track of a store that's already happened behind a swap that
+        // doesn't change anything, so this only swaps if it does.
+        fn finish_cancel(&self) -> bool {
+            if self.cancelling.compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                return false;
+            }
+            let total = self.loop_count.load(Ordering::Acquire) * LAYER_LEN;
+            self.total_samples.store(total, Ordering::Relaxed);
+            true
+        }
+    }
+
+    // The UI only sees the first loop counted once its length and samples
+    // are there too.
+    #[test]
+    fn closing_the_first_loop() {
+        loom::model(|| {
+            let recording = Recording::new();
+            let output = recording.clone();
+            let output = thread::spawn(move || {
+                output.add_sample_count(LAYER_LEN / 2);
+                output.add_sample_count(LAYER_LEN / 2);
+                output.finish_first_loop();
+            });
+
+            if recording.loop_count.load(Ordering::Acquire) == 1 {
+                assert_eq!(recording.loop_len.load(Ordering::Relaxed), LEN);
+                assert_eq!(recording.total_samples.load(Ordering::Relaxed), LAYER_LEN);
+            }
+            output.join().unwrap();
+        });
+    }
+
+    // Same for every layer after that, which gets counted when playback
+    // wraps.
+    #[test]
+    fn adding_a_layer() {
+        loom::model(|| {
+            let recording = Recording::looping();
+            let output = recording.clone();
+            let output = thread::spawn(move || {
+                output.add_sample_count(LAYER_LEN / 2);
+                output.add_sample_count(LAYER_LEN / 2);
+                output.inc_loop_count();
+            });
+
+            let count = recording.loop_count.load(Ordering::Acquire);
+            let total = recording.total_samples.load(Ordering::Relaxed);
+            assert!(total >= count * LAYER_LEN, "{} layers but only {} samples", count, total);
+            output.join().unwrap();
+        });
+    }
+
+    // Once the output thread has thrown away a cancelled layer, it doesn't
+    // record any more of it.
+    #[test]
+    fn cancelling_a_layer() {
+        loom::model(|| {
+            let recording = Recording::looping();
+            let output = recording.clone();
+            let output = thread::spawn(move || {
+                let mut cancelled = false;
+                for _ in 0..2 {
+                    if output.recording() {
+                        assert!(!cancelled, "recorded after the layer was thrown away");
+                        output.add_sample_count(CHANNELS);
+                    }
+                    cancelled |= output.finish_cancel();
+                }
+            });
+
+            recording.cancel_layer();
+            output.join().unwrap();
+            // The next callback gets to it, if that one didn't.
+            recording.finish_cancel();
+            assert!(!recording.recording());
+            assert_eq!(recording.total_samples.load(Ordering::Relaxed), LAYER_LEN);
+        });
+    }
+
+    // set_record_mode() stores the feedback before the mode, so whatever
+    // sees the new mode gets the new feedback with it.
+    #[test]
+    fn switching_to_overdub() {
+        loom::model(|| {
+            let record_mode = Arc::new(AtomicUsize::new(LAYER));
+            let feedback = Arc::new(AtomicU32::new(0.0_f32.to_bits()));
+            let (ui_mode, ui_feedback) = (record_mode.clone(), feedback.clone());
+            let ui = thread::spawn(move || {
+                ui_feedback.store(0.5_f32.to_bits(), Ordering::Relaxed);
+                ui_mode.store(OVERDUB, Ordering::Release);
+            });
+
+            // What State::record_mode() reads on the output thread.
+            if record_mode.load(Ordering::Acquire) == OVERDUB {
+                assert_eq!(f32::from_bits(feedback.load(Ordering::Relaxed)), 0.5);
+            }
+            ui.join().unwrap();
+        });
+    }
+
+    // The count-in's length is there before it starts counting down, and
+    // recording only starts once it's finished.
+    #[test]
+    fn counting_in() {
+        loom::model(|| {
+            let count_in_len = Arc::new(AtomicUsize::new(0));
+            let count_in_remaining = Arc::new(AtomicUsize::new(0));
+            let is_recording = Arc::new(AtomicBool::new(false));
+            let (len, remaining, recording) = (count_in_len.clone(), count_in_remaining.clone(), is_recording.clone());
+            let output = thread::spawn(move || {
+                for _ in 0..3 {
+                    // State::count_in_elapsed()
+                    let left = remaining.load(Ordering::Acquire);
+                    if left > 0 {
+                        assert!(len.load(Ordering::Relaxed) >= left, "counting in before the length's set");
+                    }
+                    // State::advance_count_in()
+                    let left = remaining.load(Ordering::Acquire);
+                    if left > 0 {
+                        remaining.store(left - 1, Ordering::Release);
+                        if left == 1 {
+                            recording.store(true, Ordering::Release);
+                        }
+                    }
+                }
+            });
+
+            // State::start_count_in()
+            count_in_len.store(2, Ordering::Relaxed);
+            count_in_remaining.store(2, Ordering::Release);
+            if is_recording.load(Ordering::Acquire) {
+                assert_eq!(count_in_remaining.load(Ordering::Acquire), 0, "recording while counting in");
+            }
+            output.join().unwrap();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+    // Frames per buffer, as MockAudio gets them.
+    const BUFFER: usize = 64;
+
+    // Somewhere for a test to write name to, that no other test uses.
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("looper_proto-{}-{}", std::process::id(), name))
+    }
+
+    // A Looper at RATE, run by a MockAudio instead of a sound card.
+    struct Rig {
+        looper: Looper,
+        audio: MockAudio,
+    }
+
+    impl Rig {
+        fn new(channels: u16) -> Self {
+            Self::build(LooperBuilder::new().sample_rate(RATE).channels(channels))
+        }
+
+        fn build(builder: LooperBuilder) -> Self {
+            Self::with_tracks(builder, 1)
+        }
+
+        fn with_tracks(builder: LooperBuilder, tracks: usize) -> Self {
+            let mut looper = builder.build();
+            looper.set_track_count(tracks);
+            let audio = MockAudio::new(&mut looper);
+            Self { looper, audio }
+        }
+
+        fn tap(&mut self) {
+            self.looper.run(Command::Tap).unwrap();
+        }
+
+        // Run input through BUFFER frames at a time, and return whatever
+        // came out, handling any events that came up along the way.
+        fn run(&mut self, input: &[f32]) -> Vec<f32> {
+            let channels = self.looper.state.channels;
+            let mut output = vec![0.0; input.len()];
+            for (input, output) in input.chunks(BUFFER * channels).zip(output.chunks_mut(BUFFER * channels)) {
+                self.audio.run(input, output);
+            }
+            if input.is_empty() {
+                self.audio.run(&[], &mut []);
+            }
+            while let Ok(event) = self.looper.events.try_recv() {
+                self.looper.handle_event(event);
+            }
+            self.looper.make_room();
+            output
+        }
+
+        // Record input as the first loop, and stop recording there.
+        fn record_loop(&mut self, input: &[f32]) {
+            self.tap();
+            self.run(input);
+            self.tap();
+            // The loop closes once the output callback gets to it, so
+            // there has to be a run in between for the next tap to stop
+            // recording the next layer.
+            self.run(&[]);
+            self.tap();
+            self.run(&[]);
+        }
+
+        // Record another whole layer of input, which should be one loop
+        // long.
+        fn record_layer(&mut self, input: &[f32]) {
+            self.tap();
+            self.run(input);
+            self.tap();
+            self.run(&[]);
+        }
+
+        fn silence(&mut self, frames: usize) -> Vec<f32> {
+            self.run(&vec![0.0; frames * self.looper.state.channels])
+        }
+
+        fn loop_count(&self) -> usize {
+            self.looper.state.get_loop_count()
+        }
+
+        // Put layers (each a whole loop long) straight into the bank, as if
+        // they'd been recorded, with playback at frame playback.
+        fn load(&mut self, layers: &[Vec<f32>], playback: usize) {
+            let layer_len = layers[0].len();
+            let mut bank = self.looper.bank.lock().unwrap();
+            for (layer, samples) in layers.iter().enumerate() {
+                bank.write_at(layer * layer_len, samples).unwrap();
+            }
+            drop(bank);
+            let len = layer_len / self.looper.state.channels;
+            self.looper.state.load_layers(len, layers.len(), layer_len * layers.len(), playback);
+        }
+    }
+
+    #[test]
+    fn compressor_gain_reduction_curve() {
+        let rate = 48000;
+        let settings = CompressorSettings::default();
+        let mut compressor = settings.build(rate);
+
+        // Quiet input is left alone.
+        for _ in 0..rate {
+            assert_eq!(compressor.compress(0.1), 0.1);
+        }
+
+        // A full-scale burst is 6 dB over the threshold, so at 4:1 it should
+        // settle at 4.5 dB of gain reduction, getting there gradually over
+        // the attack rather than all at once.
+        let mut gains = vec![];
+        for _ in 0..rate / 2 {
+            compressor.compress(1.0);
+            gains.push(compressor.gain());
+        }
+        assert!(gains.windows(2).all(|pair| pair[1] <= pair[0]));
+        let attack = (settings.attack_ms / 1000.0 * rate as f32) as usize;
+        assert!(gains[0] > 0.9);
+        assert!(gains[attack / 4] > gains[attack * 4]);
+        let settled = gain_to_db(*gains.last().unwrap());
+        assert!((settled + 4.5).abs() < 0.05, "settled at {} dB", settled);
+
+        // Once it's over, the gain comes back up to unity over the release.
+        let mut gains = vec![];
+        for _ in 0..rate {
+            compressor.compress(0.0);
+            gains.push(compressor.gain());
+        }
+        assert!(gains.windows(2).all(|pair| pair[1] >= pair[0]));
+        let release = (settings.release_ms / 1000.0 * rate as f32) as usize;
+        assert!(gains[release / 10] < 1.0);
+        assert_eq!(*gains.last().unwrap(), 1.0);
+    }
+
+    #[test]
+    fn compressor_settings_change_the_curve() {
+        let rate = 48000;
+        let reduction = |settings: CompressorSettings| {
+            let mut compressor = settings.build(rate);
+            for _ in 0..rate {
+                compressor.compress(1.0);
+            }
+            gain_to_db(compressor.gain())
+        };
+        let default = CompressorSettings::default();
+        assert!((reduction(CompressorSettings { threshold_db: -12.0, ..default }) + 9.0).abs() < 0.05);
+        assert!((reduction(CompressorSettings { ratio: 2.0, ..default }) + 3.0).abs() < 0.05);
+        // Anything under 1:1 would be expansion, so it's left alone.
+        assert_eq!(reduction(CompressorSettings { ratio: 0.5, ..default }), 0.0);
+        assert_eq!(reduction(CompressorSettings { threshold_db: 0.0, ..default }), 0.0);
+
+        let settings = CompressorSettings { threshold_db: -20.0, ratio: 8.0, attack_ms: 1.0, release_ms: 50.0 };
+        let looper = LooperBuilder::new().compressor(settings).build();
+        assert_eq!(looper.compressor, settings);
+    }
+
+    #[test]
+    fn write_at_reports_overflow() {
+        let mut bank = SampleBank::new(vec![0.0; 8]);
+        bank.write_at(6, &[1.0, 2.0]).unwrap();
+        assert_eq!(&bank.samples[6..], &[1.0, 2.0]);
+
+        // Past the end of what could ever be allocated, it fails without
+        // touching anything.
+        assert!(bank.write_at(usize::MAX / 2, &[3.0; 4]).is_err());
+        assert!(bank.write_at(usize::MAX - 1, &[3.0; 4]).is_err());
+        assert_eq!(bank.len(), 8);
+        assert_eq!(bank.samples, [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0]);
+
+        // The output callback tells the UI about it instead of panicking.
+        let mut state = State::new();
+        let (events, received) = mpsc::sync_channel(EVENT_QUEUE_LEN);
+        let clip = Clip::new(&[3.0; 4], 0, Some(usize::MAX - 1));
+        record_clip(&mut bank, &mut state, &clip, &events);
+        assert!(matches!(received.try_recv(), Ok(Event::ClipDropped { track: 0 })));
+        assert_eq!(state.recorded_len(), 0);
+    }
+
+    #[test]
+    fn write_at_grows_the_bank() {
+        let mut bank = SampleBank::new(vec![0.0; 4]);
+        bank.write_at(0, &[1.0; 4]).unwrap();
+        assert_eq!(bank.len(), 4);
+
+        // Doubling until it fits, keeping what was already there.
+        bank.write_at(4, &[2.0; 2]).unwrap();
+        assert_eq!(bank.len(), 8);
+        bank.write_at(20, &[3.0; 3]).unwrap();
+        assert_eq!(bank.len(), 32);
+        assert_eq!(&bank.samples[..6], &[1.0, 1.0, 1.0, 1.0, 2.0, 2.0]);
+        assert!(bank.samples[6..20].iter().all(|&sample| sample == 0.0));
+        assert_eq!(&bank.samples[20..23], &[3.0; 3]);
+
+        // Even an empty one.
+        let mut bank = SampleBank::new(vec![]);
+        bank.write_at(0, &[1.0; 5]).unwrap();
+        assert_eq!(bank.len(), 8);
+
+        // Mixing never reads past what's allocated, however much is said
+        // to be recorded.
+        assert_eq!(bank.mix(0, 4, 3, 100, 1), 2.0);
+        assert_eq!(bank.mix(1, 4, 3, 100, 1), 1.0);
+    }
+
+    #[test]
+    fn state_undo_bookkeeping() {
+        let mut state = State::new();
+        state.channels = 2;
+        state.load_layers(100, 3, 600, 0);
+
+        assert!(state.remove_layer());
+        assert_eq!((state.get_loop_count(), state.get_total_samples()), (2, 400));
+        assert!(state.remove_layer());
+        assert!(state.remove_layer());
+        assert_eq!((state.get_loop_count(), state.get_total_samples()), (0, 0));
+        // With nothing left, there's nothing to do.
+        assert!(!state.remove_layer());
+        assert_eq!((state.get_loop_count(), state.get_total_samples()), (0, 0));
+    }
+
+    #[test]
+    fn undo_removes_the_last_layer() {
+        let mut rig = Rig::new(1);
+        // Nothing to undo yet.
+        rig.looper.run(Command::Undo).unwrap();
+        assert!(rig.looper.state.first_loop());
+
+        rig.record_loop(&[0.1; 400]);
+        rig.record_layer(&[0.1; 400]);
+        assert_eq!(rig.loop_count(), 2);
+        assert_eq!(rig.looper.state.get_total_samples(), 800);
+
+        rig.looper.run(Command::Undo).unwrap();
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.looper.state.get_total_samples(), 400);
+        assert_eq!(rig.looper.state.get_loop_len(), 400);
+
+        // Not while recording, though.
+        rig.tap();
+        rig.run(&[0.1; 64]);
+        assert!(rig.looper.run(Command::Undo).is_err());
+        assert_eq!(rig.loop_count(), 1);
+        rig.tap();
+        rig.run(&[]);
+
+        // Undoing the first loop starts over.
+        rig.looper.run(Command::Undo).unwrap();
+        assert!(rig.looper.state.first_loop());
+        assert_eq!(rig.looper.taps, 0);
+        assert_eq!(rig.looper.state.get_loop_len(), 0);
+    }
+
+    #[test]
+    fn recording_invalidates_redo() {
+        let mut rig = Rig::new(1);
+        assert!(rig.looper.redo().is_err());
+        rig.record_loop(&[0.1; 400]);
+        rig.record_layer(&[0.1; 400]);
+        rig.record_layer(&[0.1; 400]);
+        assert_eq!(rig.loop_count(), 3);
+
+        rig.looper.run(Command::Undo).unwrap();
+        rig.looper.run(Command::Undo).unwrap();
+        assert_eq!((rig.loop_count(), rig.looper.redo_depth), (1, 2));
+        rig.looper.redo().unwrap();
+        assert_eq!((rig.loop_count(), rig.looper.redo_depth), (2, 1));
+        assert_eq!(rig.looper.state.get_total_samples(), 800);
+
+        // Recording goes over the one that's still undone, so it's gone.
+        rig.record_layer(&[0.1; 400]);
+        assert_eq!(rig.loop_count(), 3);
+        assert_eq!(rig.looper.redo_depth, 0);
+        assert!(rig.looper.redo().is_err());
+        assert_eq!(rig.loop_count(), 3);
+    }
+
+    #[test]
+    fn clear_resets_everything() {
+        let mut rig = Rig::new(1);
+        rig.record_loop(&[0.1; 400]);
+        rig.record_layer(&[0.1; 400]);
+        rig.tap();
+        rig.run(&[0.1; 100]);
+        assert!(rig.looper.state.recording());
+
+        rig.looper.run(Command::Clear).unwrap();
+        let state = &rig.looper.state;
+        assert!(!state.recording());
+        assert_eq!(state.get_playback(), 0);
+        assert_eq!(state.get_loop_len(), 0);
+        assert_eq!(state.get_loop_count(), 0);
+        assert_eq!(state.get_total_samples(), 0);
+        assert_eq!(state.write_cursor.load(Ordering::Relaxed), 0);
+        assert_eq!(rig.looper.taps, 0);
+
+        // The next tap starts a brand new first loop, without any of the
+        // old one in it.
+        rig.record_loop(&[0.2; 200]);
+        assert_eq!((rig.loop_count(), rig.looper.state.get_loop_len()), (1, 200));
+        let bank = rig.looper.bank.lock().unwrap();
+        assert_eq!(bank.mix(0, 200, 1, 200, 1), 0.2);
+    }
+
+    #[test]
+    fn export_wav_writes_the_mix() {
+        let mut rig = Rig::new(2);
+        let path = temp_path("export.wav");
+        assert!(rig.looper.export_wav(&path).is_err());
+        assert!(!path.exists());
+
+        // Two layers' worth, as clips straight into the bank.
+        let first: Vec<f32> = (0..400).map(|idx| idx as f32 / 1000.0).collect();
+        let second = vec![0.25; 400];
+        {
+            let mut bank = rig.looper.bank.lock().unwrap();
+            bank.write_at(0, &first).unwrap();
+            bank.write_at(400, &second).unwrap();
+            bank.set_layer_gain(1, 0.5);
+        }
+        rig.looper.state.load_layers(200, 2, 800, 0);
+
+        rig.looper.export_wav(&path).unwrap();
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, RATE);
+        let samples: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(samples.len(), 400);
+        for (idx, sample) in samples.iter().enumerate() {
+            assert_eq!(*sample, first[idx] + 0.125);
+        }
+    }
+
+    #[test]
+    fn import_wav_sets_up_the_first_loop() {
+        // A stereo 16-bit file, with the left channel a ramp and the right
+        // one a constant, to be downmixed.
+        let path = temp_path("import.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for frame in 0..300 {
+            writer.write_sample(frame as i16 * 64).unwrap();
+            writer.write_sample(8192_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut rig = Rig::new(1);
+        rig.looper.import_wav(&path).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 300);
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.looper.state.get_total_samples(), 300);
+        {
+            let bank = rig.looper.bank.lock().unwrap();
+            for frame in 0..4 {
+                let expected = (frame as f32 * 64.0 / 32768.0 + 0.25) / 2.0;
+                assert!((bank.samples[frame] - expected).abs() < 1e-6);
+            }
+        }
+
+        // At twice the sample rate, it comes out half as long.
+        let spec = hound::WavSpec { sample_rate: RATE * 2, ..spec };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..600 {
+            writer.write_sample(0_i16).unwrap();
+            writer.write_sample(0_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        rig.looper.import_wav(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 300);
+        assert_eq!(rig.loop_count(), 1);
+    }
+
+    // A ramp from 0 up to (but not including) top, frames long.
+    fn ramp(frames: usize, top: f32) -> Vec<f32> {
+        (0..frames).map(|frame| frame as f32 / frames as f32 * top).collect()
+    }
+
+    // The frequency (in whole Hz, at RATE, up to 2 kHz) with the most energy
+    // in samples, by running a Goertzel filter for each one.
+    fn dominant_freq(samples: &[f32]) -> f32 {
+        let power = |freq: f32| {
+            let coeff = 2.0 * (std::f32::consts::TAU * freq / RATE as f32).cos();
+            let (mut prev, mut before) = (0.0, 0.0);
+            for &sample in samples {
+                let next = sample + coeff * prev - before;
+                before = prev;
+                prev = next;
+            }
+            prev * prev + before * before - coeff * prev * before
+        };
+        (20..2000)
+            .map(|freq| freq as f32)
+            .max_by(|&a, &b| power(a).total_cmp(&power(b)))
+            .unwrap()
+    }
+
+    // A sine at freq Hz (at RATE), peaking at amplitude.
+    fn sine(frames: usize, freq: f32, amplitude: f32) -> Vec<f32> {
+        (0..frames)
+            .map(|frame| amplitude * (std::f32::consts::TAU * freq * frame as f32 / RATE as f32).sin())
+            .collect()
+    }
+
+    // Whether there's anything at all in each window samples of samples.
+    fn sounding(samples: &[f32], window: usize) -> Vec<bool> {
+        samples.chunks(window).map(|chunk| chunk.iter().any(|&sample| sample != 0.0)).collect()
+    }
+
+    #[test]
+    fn metronome_clicks_on_the_beat() {
+        // Four beats a second, so a beat every 2000 samples, each click
+        // lasting 160.
+        let metronome = Metronome::new(240.0, 4, RATE);
+        assert_eq!(metronome.samples_per_beat(), 2000.0);
+        let clicks: Vec<f32> = (0..8000).map(|idx| metronome.sound(idx)).collect();
+        for (window, sounding) in sounding(&clicks, 80).into_iter().enumerate() {
+            assert_eq!(sounding, window % 25 < 2, "window {}", window);
+        }
+        // The first beat of the bar is accented.
+        assert_ne!(&clicks[..160], &clicks[2000..2160]);
+        assert_eq!(&clicks[2000..2160], &clicks[4000..4160]);
+        // And nothing plays while it's off.
+        assert!((0..8000).all(|idx| metronome.click(idx) == 0.0));
+    }
+
+    #[test]
+    fn metronome_only_plays_in_the_output() {
+        let mut rig = Rig::new(1);
+        rig.looper.metronome.set_bpm(240.0);
+        rig.looper.toggle_metronome().unwrap();
+        let output = rig.silence(8000);
+        for (window, sounding) in sounding(&output, 80).into_iter().enumerate() {
+            assert_eq!(sounding, window % 25 < 2, "window {}", window);
+        }
+
+        // Clicking away while recording silence records silence.
+        rig.record_loop(&[0.0; 4000]);
+        let bank = rig.looper.bank.lock().unwrap();
+        assert!(bank.samples[..4000].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn quantize_rounds_to_beats_and_bars() {
+        // A beat every 2000 samples, and a bar every 8000.
+        let metronome = Metronome::new(240.0, 4, RATE);
+        let cases = [
+            (Quantize::Off, 4999, 4999),
+            (Quantize::Beat, 4999, 4000),
+            (Quantize::Beat, 5001, 6000),
+            (Quantize::Beat, 10, 2000),
+            (Quantize::Bar, 11999, 8000),
+            (Quantize::Bar, 12001, 16000),
+            (Quantize::Bar, 100, 8000),
+        ];
+        for (grid, raw, quantized) in cases {
+            assert_eq!(metronome.quantize(raw, grid), quantized, "{:?} {}", grid, raw);
+        }
+    }
+
+    #[test]
+    fn quantize_the_first_loop() {
+        let mut rig = Rig::new(1);
+        rig.looper.metronome.set_bpm(240.0);
+        rig.looper.quantize = Quantize::Beat;
+        rig.record_loop(&[0.1; 4600]);
+        assert_eq!(rig.looper.state.get_loop_len(), 4000);
+
+        rig.looper.run(Command::Clear).unwrap();
+        rig.looper.quantize = Quantize::Bar;
+        rig.record_loop(&[0.1; 4600]);
+        assert_eq!(rig.looper.state.get_loop_len(), 8000);
+    }
+
+    #[test]
+    fn layer_gain_scales_its_contribution() {
+        let mut bank = SampleBank::new(vec![0.0; 8]);
+        bank.write_at(0, &[0.4, 0.4, 0.2, 0.2]).unwrap();
+        assert_eq!(bank.layer_gain(1), 1.0);
+        assert!((bank.mix(0, 2, 2, 4, 1) - 0.6).abs() < 1e-6);
+
+        bank.set_layer_gain(1, 0.5);
+        assert_eq!(bank.layer_gain(1), 0.5);
+        assert!((bank.mix(0, 2, 2, 4, 1) - 0.5).abs() < 1e-6);
+        bank.set_layer_gain(0, 0.5);
+        assert!((bank.mix(1, 2, 2, 4, 1) - 0.3).abs() < 1e-6);
+
+        // Through the Looper too, which won't go below silence.
+        let mut rig = Rig::new(1);
+        rig.record_loop(&[0.2; 400]);
+        rig.looper.set_layer_gain(0, 0.5);
+        assert_eq!(rig.looper.layer_gain(0), 0.5);
+        let output = rig.silence(400);
+        assert!(output.iter().all(|&sample| (sample - 0.1).abs() < 1e-6));
+        rig.looper.set_layer_gain(0, -1.0);
+        assert_eq!(rig.looper.layer_gain(0), 0.0);
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        // Three layers of one sample each, easy to tell apart in the mix.
+        let mut bank = SampleBank::new(vec![]);
+        bank.write_at(0, &[1.0, 10.0, 100.0]).unwrap();
+        let mix = |bank: &SampleBank| bank.mix(0, 1, 3, 3, 1);
+        assert_eq!(mix(&bank), 111.0);
+
+        bank.toggle_mute(1);
+        assert_eq!(mix(&bank), 101.0);
+        // Soloing a muted layer plays it anyway, and nothing else.
+        bank.toggle_solo(1);
+        assert_eq!(mix(&bank), 10.0);
+        bank.toggle_solo(2);
+        assert_eq!(mix(&bank), 110.0);
+        // Once nothing's soloed, mutes are back in charge.
+        bank.toggle_solo(1);
+        bank.toggle_solo(2);
+        assert_eq!(mix(&bank), 101.0);
+        bank.toggle_mute(1);
+        assert_eq!(mix(&bank), 111.0);
+
+        // A solo on a layer past loop_count doesn't silence the others.
+        bank.toggle_solo(2);
+        assert_eq!(bank.mix(0, 1, 2, 3, 1), 11.0);
+
+        let mut rig = Rig::new(1);
+        rig.record_loop(&[0.2; 400]);
+        rig.looper.toggle_mute(0);
+        assert!(rig.silence(400).iter().all(|&sample| sample == 0.0));
+        rig.looper.toggle_solo(0);
+        assert!(rig.silence(400).iter().all(|&sample| (sample - 0.2).abs() < 1e-6));
+    }
+
+    #[test]
+    fn overdub_feedback_decays_geometrically() {
+        let mut bank = SampleBank::new(vec![]);
+        bank.write_at(0, &[1.0; 4]).unwrap();
+        for pass in 1..=5 {
+            // Overdubbing silence all the way round, starting partway in so
+            // it wraps.
+            bank.overdub(0, 4, 3, &[0.0; 4], 0.5).unwrap();
+            let expected = 0.5_f32.powi(pass);
+            assert!(bank.samples[..4].iter().all(|&sample| sample == expected), "pass {}", pass);
+        }
+        // What's overdubbed gets added on after.
+        bank.overdub(0, 4, 0, &[0.25; 2], 0.5).unwrap();
+        assert_eq!(&bank.samples[..4], &[0.265625, 0.265625, 0.03125, 0.03125]);
+
+        // Going through record_clip(), overdubs go onto the top layer.
+        let mut state = State::new();
+        state.load_layers(4, 1, 4, 0);
+        state.set_record_mode(RecordMode::Overdub { feedback: 0.5 });
+        state.is_recording.store(true, Ordering::Release);
+        let (events, _received) = mpsc::sync_channel(EVENT_QUEUE_LEN);
+        let mut bank = SampleBank::new(vec![]);
+        bank.write_at(0, &[1.0; 4]).unwrap();
+        for _ in 0..3 {
+            record_clip(&mut bank, &mut state, &Clip::new(&[0.0; 4], 0, Some(4)), &events);
+        }
+        assert!(bank.samples[..4].iter().all(|&sample| sample == 0.125));
+        // Nothing new got recorded.
+        assert_eq!((state.get_loop_count(), state.get_total_samples()), (1, 4));
+    }
+
+    #[test]
+    fn replace_overwrites_the_loop() {
+        // Two layers of four, with the new samples going on the top one and
+        // wrapping round past the end.
+        let mut bank = SampleBank::new(vec![]);
+        bank.write_at(0, &[1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 2.0]).unwrap();
+        bank.replace(4, 2, 3, &[5.0, 6.0]).unwrap();
+        assert_eq!(bank.samples, [0.0, 1.0, 1.0, 0.0, 6.0, 2.0, 2.0, 5.0]);
+        // So only the new samples are heard there.
+        let mix: Vec<f32> = (0..4).map(|idx| bank.mix(idx, 4, 2, 8, 1)).collect();
+        assert_eq!(mix, [6.0, 3.0, 3.0, 5.0]);
+
+        // Through record_clip(), it lands where playback was.
+        let mut state = State::new();
+        state.load_layers(4, 2, 8, 0);
+        state.set_record_mode(RecordMode::Replace);
+        state.is_recording.store(true, Ordering::Release);
+        let (events, _received) = mpsc::sync_channel(EVENT_QUEUE_LEN);
+        record_clip(&mut bank, &mut state, &Clip::new(&[7.0; 3], 0, Some(8)), &events);
+        let mix: Vec<f32> = (0..4).map(|idx| bank.mix(idx, 4, 2, 8, 1)).collect();
+        assert_eq!(mix, [7.0, 7.0, 7.0, 5.0]);
+        assert_eq!(state.get_total_samples(), 8);
+    }
+
+    #[test]
+    fn clip_queue_interleaved() {
+        let (mut producer, mut consumer) = RingBuffer::<Clip>::new(4).split();
+        let state = State::new();
+
+        // A buffer longer than a Clip gets split up, each piece starting
+        // where the last left off.
+        let samples: Vec<f32> = (0..CLIP_LEN + 10).map(|idx| idx as f32).collect();
+        assert_eq!(send_clips(&mut producer, &samples, Some((0, &state))), 0);
+        let first = consumer.pop().unwrap();
+        assert_eq!((first.start, first.samples().len()), (Some(0), CLIP_LEN));
+        assert_eq!(send_clips(&mut producer, &[1.0; 3], None), 0);
+        let second = consumer.pop().unwrap();
+        assert_eq!((second.start, second.samples()), (Some(CLIP_LEN), &samples[CLIP_LEN..]));
+        assert_eq!(consumer.pop().unwrap().start, None);
+        assert!(consumer.pop().is_none());
+
+        // Once it's full, whatever doesn't fit is counted, and the rest
+        // still comes through once there's room.
+        assert_eq!(send_clips(&mut producer, &[0.0; CLIP_LEN * 6], None), 2);
+        assert_eq!(std::iter::from_fn(|| consumer.pop()).count(), 4);
+
+        // Across threads, everything arrives in order.
+        let (mut producer, mut consumer) = RingBuffer::<Clip>::new(CLIP_QUEUE_LEN).split();
+        let sender = std::thread::spawn(move || {
+            for idx in 0..2000 {
+                while producer.push(Clip::new(&[idx as f32; 7], 0, Some(idx))).is_err() {
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut next = 0;
+        while next < 2000 {
+            match consumer.pop() {
+                Some(clip) => {
+                    assert_eq!(clip.start, Some(next));
+                    assert_eq!(clip.samples(), &[next as f32; 7]);
+                    next += 1;
+                },
+                None => std::thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn many_small_buffers() {
+        let builder = LooperBuilder::new().sample_rate(RATE).channels(2).bank_capacity(256);
+        let mut rig = Rig::build(builder);
+        let input: Vec<f32> = (0..RATE as usize * 6).map(|idx| (idx % 1000) as f32 / 1000.0).collect();
+        rig.tap();
+
+        // Buffers of anything from 1 to 13 frames, with the UI only making
+        // room in the bank every so often.
+        let mut output = [0.0; 26];
+        let mut sent = 0;
+        for (idx, frames) in (1..=13).cycle().enumerate() {
+            if sent == input.len() {
+                break;
+            }
+            let len = (frames * 2).min(input.len() - sent);
+            rig.audio.run(&input[sent..sent + len], &mut output[..len]);
+            sent += len;
+            if idx % 100 == 0 {
+                rig.looper.make_room();
+            }
+        }
+        rig.run(&[]);
+        assert_eq!(rig.looper.dropouts.total(), 0);
+        assert_eq!(rig.looper.state.get_total_samples(), input.len());
+        let bank = rig.looper.bank.lock().unwrap();
+        assert!(bank.len() >= input.len() + BANK_HEADROOM_SECS * RATE as usize * 2);
+        assert!(bank.samples[..input.len()] == input[..]);
+    }
+
+    #[test]
+    fn output_callback_never_grows_the_bank() {
+        let builder = LooperBuilder::new().sample_rate(RATE).channels(1).bank_capacity(256);
+        let mut rig = Rig::build(builder);
+        rig.tap();
+        // Without anything making room, only what fits gets recorded.
+        let mut output = vec![0.0; 64];
+        for _ in 0..8 {
+            rig.audio.run(&[0.5; 64], &mut output);
+        }
+        assert_eq!(rig.looper.bank.lock().unwrap().len(), 256);
+        assert_eq!(rig.looper.state.get_total_samples(), 256);
+        let dropped = std::iter::from_fn(|| rig.looper.events.try_recv().ok())
+            .filter(|event| matches!(event, Event::ClipDropped { .. }))
+            .count();
+        assert_eq!(dropped, 4);
+
+        // Once there's room, recording picks up again.
+        rig.looper.make_room();
+        rig.audio.run(&[0.5; 64], &mut output);
+        assert_eq!(rig.looper.state.get_total_samples(), 320);
+    }
+
+    #[test]
+    fn back_to_back_input_gets_its_own_room() {
+        let mut rig = Rig::new(2);
+        rig.tap();
+        // Several input callbacks in a row, before the output callback gets
+        // round to any of them (or to moving total_samples along).
+        for frames in [64, 1, 300, 7, 256, 33] {
+            rig.audio.input.process(&vec![0.1; frames * 2]);
+        }
+        let clips: Vec<Clip> = std::iter::from_fn(|| rig.audio.output.consumer.pop()).collect();
+        assert!(clips.len() >= 6);
+        let mut next = 0;
+        for clip in &clips {
+            assert_eq!(clip.start, Some(next));
+            next += clip.samples().len();
+        }
+        assert_eq!(next, 661 * 2);
+        assert_eq!(rig.looper.state.get_total_samples(), 0);
+    }
+
+    #[test]
+    fn stereo_channels_stay_apart() {
+        let mut rig = Rig::new(2);
+        let input: Vec<f32> = (0..400).flat_map(|frame| [frame as f32 / 4000.0, -0.3]).collect();
+        rig.record_loop(&input);
+        assert_eq!(rig.looper.state.get_loop_len(), 400);
+        assert_eq!(rig.looper.state.get_total_samples(), 800);
+
+        // Round the loop a couple of times, from partway through.
+        let playback = rig.looper.state.get_playback();
+        let output = rig.silence(1000);
+        for (idx, frame) in output.chunks(2).enumerate() {
+            let pos = (playback + idx) % 400;
+            assert!((frame[0] - pos as f32 / 4000.0).abs() < 1e-6, "frame {}", idx);
+            assert!((frame[1] + 0.3).abs() < 1e-6, "frame {}", idx);
+        }
+        assert_eq!(rig.looper.state.get_playback(), (playback + 1000) % 400);
+    }
+
+    #[test]
+    fn resample_48k_to_44k1() {
+        // A second of stereo at 48 kHz.
+        let input: Vec<f32> = (0..48000).flat_map(|frame| [frame as f32 / 48000.0, 0.5]).collect();
+        let resampled = resample(&input, 2, 48000, 44100);
+        assert_eq!(resampled.len(), 44100 * 2);
+        // The ramp still goes at the same speed, in seconds.
+        for (frame, pair) in resampled.chunks(2).enumerate().step_by(997) {
+            assert!((pair[0] - frame as f32 / 44100.0).abs() < 1e-4, "frame {}", frame);
+            assert_eq!(pair[1], 0.5);
+        }
+        assert_eq!(resample(&input, 2, 48000, 48000), input);
+
+        // The input callback's Converter comes out the same length, a
+        // buffer at a time.
+        let mut converter = Converter::new(2, 48000, 2, 44100);
+        let mut converted = vec![];
+        for buffer in input.chunks(480 * 2) {
+            converter.process(buffer, |samples| converted.extend_from_slice(samples));
+        }
+        let frames = converted.len() / 2;
+        assert!(frames.abs_diff(44100) <= 1, "{} frames", frames);
+        for (frame, pair) in converted.chunks(2).enumerate().step_by(997) {
+            assert!((pair[0] - frame as f32 / 44100.0).abs() < 1e-4, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn toggle_playback_freezes_the_loop() {
+        let state = State::new();
+        assert!(state.playing());
+        state.toggle_playing();
+        assert!(!state.playing());
+        state.toggle_playing();
+        assert!(state.playing());
+
+        let mut rig = Rig::new(1);
+        rig.record_loop(&ramp(400, 0.4));
+        rig.silence(100);
+        rig.looper.run(Command::TogglePlayback).unwrap();
+        // It carries on for as long as it takes to fade out, which is no
+        // time at all with a MockAudio.
+        assert!(rig.silence(BUFFER).iter().all(|&sample| sample == 0.0));
+        let playback = rig.looper.state.get_playback();
+        assert!(rig.silence(1000).iter().all(|&sample| sample == 0.0));
+        assert_eq!(rig.looper.state.get_playback(), playback);
+
+        // Picking up from the same spot.
+        rig.looper.run(Command::TogglePlayback).unwrap();
+        let output = rig.silence(10);
+        assert!((output[0] - playback as f32 / 1000.0).abs() < 1e-6);
+        assert_eq!(rig.looper.state.get_playback(), playback + 10);
+    }
+
+    #[test]
+    fn master_gain_scales_the_output() {
+        let state = State::new();
+        assert_eq!(state.master_gain(), 1.0);
+        // It goes through the atomic as bits, so it comes back exactly.
+        for gain in [0.0, 0.1, 0.333, 1.0, 1.75] {
+            state.set_master_gain(gain);
+            assert_eq!(state.master_gain(), gain);
+        }
+        state.set_master_gain(-1.0);
+        assert_eq!(state.master_gain(), 0.0);
+        state.set_master_gain(5.0);
+        assert_eq!(state.master_gain(), MAX_MASTER_GAIN);
+
+        let mut rig = Rig::new(1);
+        rig.record_loop(&[0.2; 400]);
+        rig.looper.run(Command::SetMasterGain(0.5)).unwrap();
+        assert!(rig.silence(400).iter().all(|&sample| (sample - 0.1).abs() < 1e-6));
+        rig.looper.run(Command::SetMasterGain(2.0)).unwrap();
+        assert!(rig.silence(400).iter().all(|&sample| (sample - 0.4).abs() < 1e-6));
+        rig.looper.run(Command::SetMasterGain(0.0)).unwrap();
+        assert!(rig.silence(400).iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn peak_meter_reads_dbfs() {
+        let meter = PeakMeter::new(300.0, RATE);
+        assert!(meter.peak_dbfs() < -100.0);
+        meter.update(&[0.1, -0.5, 0.25, 0.0], 2);
+        assert!((meter.peak_dbfs() - gain_to_db(0.5)).abs() < 1e-4);
+        assert!((meter.peak_dbfs() + 6.02).abs() < 0.01);
+
+        // Something louder takes over straight away.
+        meter.update(&[1.0; 2], 2);
+        assert!(meter.peak_dbfs().abs() < 1e-4);
+        // Quieter buffers let it fall, by about a third after 300 ms.
+        meter.update(&vec![0.0; RATE as usize * 3 / 10], 1);
+        assert!((meter.peak() - (-1.0_f32).exp()).abs() < 1e-3);
+        for _ in 0..100 {
+            meter.update(&[0.125; 800], 1);
+        }
+        assert!((meter.peak_dbfs() - gain_to_db(0.125)).abs() < 1e-4);
+
+        // The output callback keeps it up to date.
+        let mut rig = Rig::new(1);
+        rig.record_loop(&[0.25; 400]);
+        rig.silence(400);
+        assert!((rig.looper.meter.peak_dbfs() - gain_to_db(0.25)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn clipping_gets_counted_and_clamped() {
+        let mut samples = [0.5, 1.0, 1.5, -1.0, -3.0, 0.0];
+        assert_eq!(hard_clip(&mut samples), 2);
+        assert_eq!(samples, [0.5, 1.0, 1.0, -1.0, -1.0, 0.0]);
+
+        // Monitored input skips the compressor, so it's the easiest way to
+        // get something over unity out.
+        let mut rig = Rig::new(1);
+        rig.looper.toggle_monitoring().unwrap();
+        let input: Vec<f32> = (0..256).map(|idx| if idx % 4 == 0 { 1.5 } else { -0.5 }).collect();
+        let output = rig.run(&input);
+        assert!(output.iter().all(|sample| sample.abs() <= 1.0));
+        assert!(output.contains(&1.0));
+        assert_eq!(rig.looper.meter.clip_count(), 64);
+    }
+
+    #[test]
+    fn tap_tempo_from_timestamps() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut tempo = TapTempo::new();
+        assert_eq!(tempo.bpm(), None);
+        tempo.tap(at(0));
+        // It takes two.
+        assert_eq!(tempo.bpm(), None);
+        assert_eq!(tempo.loop_len(1, 4, RATE), None);
+        tempo.tap(at(500));
+        assert!((tempo.bpm().unwrap() - 120.0).abs() < 0.01);
+
+        // A hesitant tap doesn't throw the rest off.
+        for ms in [1000, 1500, 2300, 2800, 3300] {
+            tempo.tap(at(ms));
+        }
+        assert!((tempo.bpm().unwrap() - 120.0).abs() < 0.01);
+        // A bar of four beats at 120 bpm is two seconds.
+        assert_eq!(tempo.loop_len(1, 4, RATE), Some(2 * RATE as usize));
+        assert_eq!(tempo.loop_len(2, 3, RATE), Some(3 * RATE as usize));
+
+        // Only the last few taps count, so it follows a change of tempo.
+        for beat in 1..=TAP_TEMPO_WINDOW as u64 {
+            tempo.tap(at(3300 + beat * 400));
+        }
+        assert!((tempo.bpm().unwrap() - 150.0).abs() < 0.01);
+
+        // After a long enough gap, it starts over.
+        tempo.tap(at(20000));
+        assert_eq!(tempo.bpm(), None);
+        tempo.tap(at(21000));
+        assert!((tempo.bpm().unwrap() - 60.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn tapped_tempo_sets_the_first_loop() {
+        let mut rig = Rig::new(1);
+        let start = Instant::now();
+        for beat in 0..4 {
+            rig.looper.tap_tempo(start + Duration::from_millis(beat * 250));
+        }
+        assert!((rig.looper.metronome.bpm() - 240.0).abs() < 0.01);
+        // However long it takes to tap it closed, it's a bar long.
+        rig.record_loop(&[0.1; 3000]);
+        assert_eq!(rig.looper.state.get_loop_len(), RATE as usize);
+    }
+
+    #[test]
+    fn count_in_starts_recording_on_time() {
+        let mut rig = Rig::new(1);
+        // Two beats at 240 bpm is 4000 frames.
+        rig.looper.metronome.set_bpm(240.0);
+        rig.looper.count_in_beats = 2;
+        rig.tap();
+        assert!(rig.looper.state.counting_in());
+        assert!(!rig.looper.state.recording());
+
+        // Taps in the meantime don't do anything.
+        rig.tap();
+        let mut clicks = vec![0.0; 4000];
+        for frame in 0..4000 {
+            assert!(!rig.looper.state.recording(), "frame {}", frame);
+            rig.audio.run(&[0.0], &mut clicks[frame..frame + 1]);
+        }
+        assert!(rig.looper.state.recording());
+        assert!(!rig.looper.state.counting_in());
+        // Clicking on every beat, metronome or not.
+        for (window, sounding) in sounding(&clicks, 80).into_iter().enumerate() {
+            assert_eq!(sounding, window % 25 < 2, "window {}", window);
+        }
+    }
+
+    #[test]
+    fn tap_config_tables() {
+        use TapAction::*;
+        let cases: [(TapConfig, &[Option<TapAction>]); 4] = [
+            (TapConfig::default(), &[Some(Record), Some(SetLength), Some(Overdub), Some(Overdub), Some(Overdub)]),
+            // Stopping after every overdub, instead of toggling it.
+            (
+                TapConfig::new(vec![Record, SetLength], vec![Stop, Overdub]),
+                &[Some(Record), Some(SetLength), Some(Stop), Some(Overdub), Some(Stop), Some(Overdub)],
+            ),
+            // One tap for the first loop, which closes with a stop, then
+            // nothing but undoing.
+            (
+                TapConfig::new(vec![Record, Stop], vec![Undo]),
+                &[Some(Record), Some(Stop), Some(Undo), Some(Undo)],
+            ),
+            // Just recording the first loop, with nothing after.
+            (TapConfig::new(vec![Record, SetLength], vec![]), &[Some(Record), Some(SetLength), None, None]),
+        ];
+        for (config, actions) in cases {
+            for (n, action) in actions.iter().enumerate() {
+                assert_eq!(config.action(n), *action, "tap {} of {:?} then {:?}", n, config.taps, config.then);
+            }
+        }
+    }
+
+    #[test]
+    fn other_tap_mappings_drive_the_looper() {
+        use TapAction::*;
+        let input = ramp(BUFFER * 4, 0.25);
+
+        // Stopping after every overdub, instead of toggling it.
+        let mut rig = Rig::new(1);
+        rig.looper.tap_config = TapConfig::new(vec![Record, SetLength], vec![Stop, Overdub]);
+        rig.tap();
+        rig.run(&input);
+        rig.tap();
+        rig.run(&input);
+        assert!(rig.looper.state.recording(), "the second layer should still be recording");
+        rig.tap();
+        rig.run(&[]);
+        assert!(!rig.looper.state.recording());
+        assert_eq!(rig.loop_count(), 2);
+        rig.tap();
+        assert!(rig.looper.state.recording(), "the fourth tap should overdub");
+        rig.run(&input);
+        rig.tap();
+        rig.run(&[]);
+        assert!(!rig.looper.state.recording());
+        assert_eq!(rig.loop_count(), 3);
+        assert_eq!(rig.looper.taps, 5);
+        rig.tap();
+        assert!(rig.looper.state.recording(), "the sixth tap should overdub again");
+
+        // One loop and done, then undoing back to the start.
+        let mut rig = Rig::new(1);
+        rig.looper.tap_config = TapConfig::new(vec![Record, Stop], vec![Undo]);
+        rig.tap();
+        rig.run(&input);
+        rig.tap();
+        rig.run(&[]);
+        assert!(!rig.looper.state.recording());
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.looper.state.get_loop_len(), input.len());
+        rig.tap();
+        assert!(rig.looper.state.first_loop());
+        assert_eq!(rig.looper.taps, 0, "undoing the first loop should start over");
+        rig.tap();
+        assert!(rig.looper.state.recording(), "the next tap should record again");
+    }
+
+    #[test]
+    fn tap_without_streams_is_an_error() {
+        let mut looper = LooperBuilder::new().sample_rate(RATE).channels(1).build();
+        assert!(looper.input.is_none() && looper.output.is_none());
+        let err = looper.run(Command::Tap).unwrap_err();
+        assert_eq!(err.to_string(), "There's no output stream to play.");
+        assert_eq!(looper.taps, 0);
+        assert!(looper.play().is_err());
+    }
+
+    #[test]
+    fn crossfade_blends_the_tail_into_the_head() {
+        // Only the last fade_len frames, with the head coming in linearly.
+        assert_eq!(crossfade(5, 10, 0), None);
+        assert_eq!(crossfade(5, 10, 4), None);
+        assert_eq!(crossfade(6, 10, 4), Some((0, 0.2)));
+        assert_eq!(crossfade(7, 10, 4), Some((1, 0.4)));
+        assert_eq!(crossfade(9, 10, 4), Some((3, 0.8)));
+        assert_eq!(crossfade(0, 2, 4), None);
+
+        let len = BUFFER * 4;
+        let fade_len = 8;
+        let input = ramp(len, 0.25);
+        let mut rig = Rig::new(1);
+        rig.looper.state.set_crossfade_len(fade_len);
+        rig.record_loop(&input);
+        let start = rig.looper.state.get_playback();
+        let output = rig.silence(len);
+        for (i, sample) in output.iter().enumerate() {
+            let pos = (start + i) % len;
+            let expected = match pos.checked_sub(len - fade_len) {
+                Some(head) => {
+                    let weight = (head + 1) as f32 / (fade_len + 1) as f32;
+                    input[pos] * (1.0 - weight) + input[head] * weight
+                },
+                None => input[pos],
+            };
+            assert!((sample - expected).abs() < 1e-6, "frame {}: {} != {}", pos, sample, expected);
+        }
+
+        // It can't be more than half the loop.
+        rig.looper.state.set_crossfade_len(len);
+        assert_eq!(rig.looper.state.crossfade_len(), len / 2);
+    }
+
+    #[test]
+    fn two_layers_count_as_two() {
+        let mut rig = Rig::new(1);
+        // Closing the first loop counts it, once.
+        rig.tap();
+        rig.run(&[0.1; 256]);
+        rig.tap();
+        rig.run(&[]);
+        assert_eq!(rig.loop_count(), 1);
+        // Carrying on recording past the wrap counts the second.
+        rig.run(&[0.1; 256]);
+        rig.tap();
+        rig.run(&[]);
+        assert!(!rig.looper.state.recording());
+        assert_eq!(rig.loop_count(), 2);
+        assert_eq!(rig.looper.state.get_total_samples(), 512);
+        // Playing it back, wraps and all, doesn't count anything.
+        rig.silence(256 * 3);
+        assert_eq!(rig.loop_count(), 2);
+    }
+
+    #[test]
+    fn unrecorded_samples_dont_play() {
+        // One whole layer, and whatever happens to be left in the bank where
+        // the next one's only part way recorded.
+        let mut samples = vec![0.1; 8];
+        samples.extend([9.0; 8]);
+        let bank = SampleBank::new(samples);
+        let recorded = 8 + 3;
+        for idx in 0..8 {
+            let expected = if idx < 3 { 9.1 } else { 0.1 };
+            assert!((bank.mix(idx, 8, 2, recorded, 1) - expected).abs() < 1e-6, "sample {}", idx);
+            // Counting a layer that hasn't started doesn't change anything.
+            assert_eq!(bank.mix(idx, 8, 3, recorded, 1), bank.mix(idx, 8, 2, recorded, 1));
+        }
+        // Even if recorded runs off the end of the bank.
+        assert!((bank.mix(7, 8, 3, 100, 1) - 9.1).abs() < 1e-6);
+
+        // And through the callbacks: half a layer of overdub only gets
+        // heard where it's been recorded so far.
+        let len = BUFFER * 4;
+        let mut rig = Rig::new(1);
+        rig.record_loop(&vec![0.1; len]);
+        rig.silence(len - rig.looper.state.get_playback());
+        assert_eq!(rig.looper.state.get_playback(), 0);
+        rig.tap();
+        rig.run(&vec![0.2; len / 2]);
+        assert!(rig.looper.state.recording());
+        let recorded = rig.looper.state.get_total_samples() - len;
+        assert!(recorded > 0 && recorded < len);
+        let bank = rig.looper.bank.lock().unwrap();
+        for idx in 0..len {
+            let mut expected = bank.samples[idx];
+            if idx < recorded {
+                expected += bank.samples[len + idx];
+            }
+            assert!((bank.mix(idx, len, 2, len + recorded, 1) - expected).abs() < 1e-6, "sample {}", idx);
+        }
+    }
+
+    #[test]
+    fn preroll_goes_before_the_first_loop() {
+        let mut recent = RecentSamples::new(4);
+        recent.push(&[1.0, 2.0, 3.0]);
+        recent.push(&[4.0, 5.0, 6.0]);
+        let mut drained = Vec::new();
+        recent.drain(|samples| drained.extend_from_slice(samples));
+        assert_eq!(drained, [3.0, 4.0, 5.0, 6.0]);
+        recent.drain(|_| panic!("should be empty after draining"));
+
+        let preroll = BUFFER / 2;
+        let mut looper = LooperBuilder::new().sample_rate(RATE).channels(1).build();
+        looper.mock_audio = true;
+        let config = cpal::StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(RATE),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let (input, output) = callbacks(&looper, &config, &config, preroll, 0, InputFilter::new(1, RATE, false, None));
+        let mut rig = Rig { looper, audio: MockAudio { input, output } };
+
+        // Only the last preroll frames of this should make it in.
+        let before = ramp(BUFFER * 3, 0.25);
+        rig.run(&before);
+        let during = vec![0.2; BUFFER * 2];
+        rig.tap();
+        rig.run(&during);
+        rig.tap();
+        rig.run(&[]);
+
+        let len = rig.looper.state.get_loop_len();
+        assert_eq!(len, preroll + during.len());
+        let bank = rig.looper.bank.lock().unwrap();
+        assert_eq!(&bank.samples[..preroll], &before[before.len() - preroll..]);
+        assert_eq!(&bank.samples[preroll..len], &during[..]);
+    }
+
+    #[test]
+    fn reverse_plays_the_loop_backwards() {
+        let len = BUFFER * 4;
+        let input = ramp(len, 0.25);
+        let mut rig = Rig::new(1);
+        // Not before there's anything to reverse.
+        assert!(rig.looper.toggle_reverse().is_err());
+        rig.record_loop(&input);
+
+        let start = rig.looper.state.get_playback();
+        let forward = rig.silence(len);
+        for (i, &sample) in forward.iter().enumerate() {
+            assert_eq!(sample, input[(start + i) % len]);
+        }
+
+        rig.looper.toggle_reverse().unwrap();
+        let start = rig.looper.state.get_playback();
+        let backward = rig.silence(len);
+        for (i, &sample) in backward.iter().enumerate() {
+            assert_eq!(sample, input[(start + len - i) % len], "frame {}", i);
+        }
+
+        // And not while recording.
+        rig.looper.toggle_reverse().unwrap();
+        rig.tap();
+        assert!(rig.looper.toggle_reverse().is_err());
+    }
+
+    #[test]
+    fn half_speed_interpolates() {
+        let len = BUFFER * 4;
+        let input = ramp(len, 0.25);
+        let mut rig = Rig::new(1);
+        rig.record_loop(&input);
+        // Wait for the start of the loop, so nothing wraps.
+        rig.silence(len - rig.looper.state.get_playback());
+        assert_eq!(rig.looper.state.get_playback(), 0);
+
+        rig.looper.set_rate(0.5).unwrap();
+        let output = rig.silence(len);
+        for (i, &sample) in output.iter().enumerate() {
+            // Every other frame lands halfway between two of the ramp's.
+            let pos = i / 2;
+            let expected = match i % 2 {
+                0 => input[pos],
+                _ => (input[pos] + input[pos + 1]) / 2.0,
+            };
+            assert!((sample - expected).abs() < 1e-6, "frame {}: {} != {}", i, sample, expected);
+        }
+        assert_eq!(rig.looper.state.get_playback(), len / 2);
+    }
+
+    #[test]
+    fn low_pass_step_response() {
+        let cutoff = 100.0;
+        let mut filter = OnePole::new(cutoff, RATE);
+        // The time constant, in samples.
+        let tau = RATE as f32 / (2.0 * std::f32::consts::PI * cutoff);
+        let output: Vec<f32> = (0..200).map(|_| filter.process(1.0)).collect();
+        for (n, &sample) in output.iter().enumerate() {
+            let expected = 1.0 - (-((n + 1) as f32) / tau).exp();
+            assert!((sample - expected).abs() < 1e-4, "sample {}: {} != {}", n, sample, expected);
+        }
+        // About 63% of the way there after one time constant.
+        assert!((output[tau.round() as usize - 1] - 0.632).abs() < 0.02);
+        assert!(output[199] > 0.999);
+
+        // All the way up, it's not there at all.
+        let mut filter = OnePole::new(MAX_CUTOFF, RATE);
+        assert_eq!(filter.process(0.5), 0.5);
+        assert_eq!(filter.process(-0.25), -0.25);
+    }
+
+    #[test]
+    fn delay_echoes_an_impulse() {
+        let delay_len = 10;
+        let feedback = 0.5;
+        let mut delay = Delay::new(RATE);
+        let output: Vec<f32> = (0..45)
+            .map(|n| delay.process(if n == 0 { 1.0 } else { 0.0 }, delay_len, feedback))
+            .collect();
+        for (n, &sample) in output.iter().enumerate() {
+            // The impulse itself, then an echo every delay_len samples, each
+            // feedback times the last.
+            let expected = match n {
+                0 => 1.0,
+                n if n % delay_len == 0 => feedback.powi((n / delay_len) as i32 - 1),
+                _ => 0.0,
+            };
+            assert_eq!(sample, expected, "sample {}", n);
+        }
+
+        delay.clear();
+        assert_eq!(delay.process(0.0, delay_len, feedback), 0.0);
+        // It can't feed back enough to run away.
+        let settings = DelaySettings::new(100.0, 1.5);
+        assert_eq!(settings.feedback(), MAX_DELAY_FEEDBACK);
+    }
+
+    #[test]
+    fn hard_left_only_plays_on_the_left() {
+        // Equal power all the way across.
+        for pan in [-1.0, -0.5, 0.0, 0.3, 1.0] {
+            let power = pan_gain(pan, 0).powi(2) + pan_gain(pan, 1).powi(2);
+            assert!((power - 2.0).abs() < 1e-5, "pan {}", pan);
+        }
+
+        let mut rig = Rig::new(2);
+        rig.record_loop(&[0.1; 400 * 2]);
+        rig.looper.set_layer_pan(0, -1.0);
+        assert_eq!(rig.looper.layer_pan(0), -1.0);
+        let output = rig.silence(400);
+        for frame in output.chunks(2) {
+            assert!((frame[0] - 0.1 * std::f32::consts::SQRT_2).abs() < 1e-6);
+            assert!(frame[1].abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn monitoring_plays_but_doesnt_record() {
+        let mut rig = Rig::new(1);
+        rig.looper.toggle_monitoring().unwrap();
+        rig.looper.state.set_monitor_gain(0.5);
+        let input = ramp(BUFFER * 4, 0.25);
+        let output = rig.run(&input);
+        // It comes out at monitor_gain, in the same buffer it came in.
+        for (i, (&sample, &input)) in output.iter().zip(&input).enumerate() {
+            assert!((sample - input * 0.5).abs() < 1e-6, "frame {}: {} != {}", i, sample, input * 0.5);
+        }
+        // But none of it goes in the bank.
+        assert!(rig.looper.state.first_loop());
+        assert_eq!(rig.looper.state.get_total_samples(), 0);
+        assert!(rig.looper.bank.lock().unwrap().samples.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn taps_produce_events() {
+        let len = BUFFER * 4;
+        let mut rig = Rig::new(1);
+        // Like Rig::run(), but keeping the events instead of handling them.
+        let mut events = Vec::new();
+        let mut run = |rig: &mut Rig, input: &[f32]| {
+            let mut output = vec![0.0; input.len()];
+            for (input, output) in input.chunks(BUFFER).zip(output.chunks_mut(BUFFER)) {
+                rig.audio.run(input, output);
+            }
+            if input.is_empty() {
+                rig.audio.run(&[], &mut []);
+            }
+            events.extend(rig.looper.events.try_iter());
+        };
+        rig.tap();
+        run(&mut rig, &vec![0.1; len]);
+        rig.tap();
+        run(&mut rig, &[]);
+        run(&mut rig, &vec![0.1; len]);
+        rig.tap();
+        run(&mut rig, &[]);
+        assert_eq!(events, [
+            Event::RecordingStarted,
+            Event::LoopClosed { track: 0, len },
+            Event::LayerAdded { track: 0, index: 0 },
+            Event::PlaybackWrapped { track: 0 },
+            Event::LayerAdded { track: 0, index: 1 },
+            Event::RecordingStopped,
+        ]);
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let len = BUFFER * 4;
+        let mut rig = Rig::new(1);
+        rig.record_loop(&ramp(len, 0.25));
+        rig.record_layer(&vec![0.1; len]);
+        rig.looper.set_layer_gain(0, 0.5);
+        rig.looper.set_layer_pan(1, 0.25);
+        rig.looper.toggle_mute(1);
+        rig.looper.set_master_gain(0.8);
+        rig.looper.set_cutoff(2000.0);
+        rig.looper.metronome.set_bpm(96.0);
+        let path = temp_path("round-trip.zip");
+        rig.looper.save_session(&path).unwrap();
+
+        let mut loaded = Rig::new(1);
+        loaded.looper.load_session(&path).unwrap();
+        assert_eq!(loaded.looper.snapshot(), rig.looper.snapshot());
+        let total = rig.looper.state.get_total_samples();
+        assert_eq!(
+            &loaded.looper.bank.lock().unwrap().samples[..total],
+            &rig.looper.bank.lock().unwrap().samples[..total],
+        );
+        assert_eq!(loaded.looper.state.master_gain(), 0.8);
+        assert_eq!(loaded.looper.state.cutoff(), 2000.0);
+        assert_eq!(loaded.looper.metronome.bpm(), 96.0);
+        // It plays on from the same place, too, once the new filter's caught
+        // up with the old one.
+        assert_eq!(loaded.looper.state.get_playback(), rig.looper.state.get_playback());
+        loaded.silence(len);
+        rig.silence(len);
+        assert_eq!(loaded.silence(len * 2), rig.silence(len * 2));
+
+        // Only at the same rate and channel count.
+        let mut stereo = Rig::new(2);
+        assert!(stereo.looper.load_session(&path).is_err());
+        assert!(stereo.looper.state.first_loop());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fixed_length_loops() {
+        let len = BUFFER * 4;
+        // Recording past the fixed length closes the loop right there, and
+        // carries on with the next layer.
+        let mut rig = Rig::new(1);
+        rig.looper.state.set_fixed_len(len);
+        rig.tap();
+        let input = ramp(len + BUFFER * 2, 0.25);
+        rig.run(&input);
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.looper.state.get_loop_len(), len);
+        assert!(rig.looper.state.recording());
+        assert_eq!(&rig.looper.bank.lock().unwrap().samples[..len], &input[..len]);
+        // So the next tap skips closing it, and goes straight on to
+        // stopping the next layer.
+        rig.tap();
+        assert_eq!(rig.looper.taps, 3);
+        assert!(!rig.looper.state.recording());
+
+        // Closing it early pads it out with silence.
+        let mut rig = Rig::new(1);
+        rig.looper.state.set_fixed_len(len);
+        rig.tap();
+        rig.run(&vec![0.1; len / 2]);
+        rig.tap();
+        rig.run(&[]);
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.looper.state.get_loop_len(), len);
+        let bank = rig.looper.bank.lock().unwrap();
+        assert!(bank.samples[..len / 2].iter().all(|&sample| sample == 0.1));
+        assert!(bank.samples[len / 2..len].iter().all(|&sample| sample == 0.0));
+    }
+
+    // Two tracks, recorded one after the other with loops of different
+    // lengths.
+    fn two_tracks(short: &[f32], long: &[f32]) -> Rig {
+        let mut rig = Rig::with_tracks(LooperBuilder::new().sample_rate(RATE).channels(1), 2);
+        rig.record_loop(short);
+        rig.looper.run(Command::SelectTrack(1)).unwrap();
+        rig.record_loop(long);
+        rig
+    }
+
+    #[test]
+    fn tracks_play_at_once() {
+        let short = ramp(BUFFER * 4, 0.1);
+        let long: Vec<f32> = ramp(BUFFER * 6, 0.1).into_iter().rev().collect();
+        let mut rig = two_tracks(&short, &long);
+        assert_eq!(rig.looper.tracks[0].state.get_loop_len(), short.len());
+        assert_eq!(rig.looper.tracks[1].state.get_loop_len(), long.len());
+
+        // Each one carries on around its own loop, and they're mixed
+        // together.
+        let first = rig.looper.tracks[0].state.get_playback();
+        let second = rig.looper.tracks[1].state.get_playback();
+        let output = rig.silence(BUFFER * 12);
+        for (i, &sample) in output.iter().enumerate() {
+            let expected = short[(first + i) % short.len()] + long[(second + i) % long.len()];
+            assert!((sample - expected).abs() < 1e-6, "{}: {} != {}", i, sample, expected);
+        }
+    }
+
+    #[test]
+    fn sessions_save_every_track() {
+        let short = ramp(BUFFER * 4, 0.1);
+        let long = vec![0.05; BUFFER * 6];
+        let mut rig = two_tracks(&short, &long);
+        rig.looper.set_layer_gain(0, 0.5);
+        let path = temp_path("tracks.zip");
+        rig.looper.save_session(&path).unwrap();
+
+        let mut loaded = Rig::with_tracks(LooperBuilder::new().sample_rate(RATE).channels(1), 3);
+        loaded.looper.load_session(&path).unwrap();
+        for (saved, track) in rig.looper.tracks.iter().zip(&loaded.looper.tracks) {
+            assert_eq!(track.state.get_loop_len(), saved.state.get_loop_len());
+            assert_eq!(track.state.get_loop_count(), saved.state.get_loop_count());
+            assert_eq!(track.state.get_playback(), saved.state.get_playback());
+            let total = saved.state.get_total_samples();
+            assert_eq!(&track.bank.lock().unwrap().samples[..total], &saved.bank.lock().unwrap().samples[..total]);
+        }
+        assert_eq!(loaded.looper.tracks[1].bank.lock().unwrap().layer_settings(1)[0].gain, 0.5);
+        // The extra track's left empty, and ready to record.
+        assert!(loaded.looper.tracks[2].state.first_loop());
+        assert_eq!(loaded.looper.tracks[2].taps, 0);
+        // Each track plays on from where it was.
+        loaded.silence(BUFFER * 12);
+        rig.silence(BUFFER * 12);
+        assert_eq!(loaded.silence(BUFFER * 12), rig.silence(BUFFER * 12));
+
+        // But they don't fit on fewer tracks than they were saved from.
+        let mut single = Rig::new(1);
+        assert!(single.looper.load_session(&path).is_err());
+        assert!(single.looper.state.first_loop());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn synced_tracks_stay_in_phase() {
+        let len = BUFFER * 4;
+        let mut rig = Rig::with_tracks(LooperBuilder::new().sample_rate(RATE).channels(1), 2);
+        rig.looper.state.set_synced(true);
+        rig.record_loop(&ramp(len, 0.1));
+        rig.looper.run(Command::SelectTrack(1)).unwrap();
+        // A little over twice as long gets rounded to exactly twice.
+        rig.record_loop(&vec![0.05; len * 2 + BUFFER]);
+        let first = rig.looper.tracks[0].state.clone();
+        let second = rig.looper.tracks[1].state.clone();
+        assert_eq!(second.get_loop_len(), len * 2);
+        let in_phase = |rig: &mut Rig| {
+            for _ in 0..len * 8 / BUFFER {
+                rig.silence(BUFFER);
+                assert_eq!(second.get_playback() % len, first.get_playback());
+            }
+        };
+        in_phase(&mut rig);
+
+        // Even after the longer one's been stopped for a while.
+        rig.looper.run(Command::TogglePlayback).unwrap();
+        rig.silence(BUFFER * 3);
+        rig.looper.run(Command::TogglePlayback).unwrap();
+        in_phase(&mut rig);
+    }
+
+    #[test]
+    fn closing_with_nothing_recorded_starts_over() {
+        let len = BUFFER * 4;
+        let mut rig = Rig::new(1);
+        rig.tap();
+        rig.tap();
+        assert_eq!(rig.looper.taps, 0);
+        assert!(!rig.looper.state.recording());
+        assert!(rig.looper.state.first_loop());
+        assert_eq!(rig.looper.state.get_loop_len(), 0);
+        assert_eq!(rig.looper.state.get_total_samples(), 0);
+        // Nothing that comes in afterwards gets recorded.
+        rig.run(&vec![0.1; len]);
+        assert_eq!(rig.looper.state.get_total_samples(), 0);
+
+        // And the next tap starts recording the first loop all over again.
+        let input = ramp(len, 0.25);
+        rig.record_loop(&input);
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.looper.state.get_loop_len(), len);
+        assert_eq!(&rig.looper.bank.lock().unwrap().samples[..len], &input[..]);
+    }
+
+    #[test]
+    fn normalizing_the_mix() {
+        assert_eq!(normalize_gain(0.5, 0.0), Some(2.0));
+        assert!((normalize_gain(1.0, -6.0).unwrap() - 0.501).abs() < 0.001);
+        assert_eq!(normalize_gain(0.0, -1.0), None);
+
+        let len = BUFFER * 4;
+        let mut rig = Rig::new(1);
+        rig.looper.normalize = true;
+        rig.record_loop(&vec![0.5; len]);
+        let target = db_to_gain(-1.0);
+        assert!((rig.looper.state.master_gain() - target / 0.5).abs() < 1e-4);
+        // Two layers peak where they add up.
+        rig.record_layer(&vec![0.25; len]);
+        assert_eq!(rig.loop_count(), 2);
+        assert!((rig.looper.state.master_gain() - target / 0.75).abs() < 1e-4);
+
+        // Turning it off leaves the gain alone from then on.
+        rig.looper.toggle_normalize();
+        rig.record_layer(&vec![0.25; len]);
+        assert!((rig.looper.state.master_gain() - target / 0.75).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rms_meter_reads_a_sine() {
+        let meter = RmsMeter::new(300.0, RATE);
+        assert!(meter.rms_dbfs() < -100.0);
+        // A sine's RMS is its peak over root two, or 3 dB down.
+        meter.update(&sine(RATE as usize * 2, 440.0, 0.5), 1);
+        assert!((meter.rms() - 0.5 / 2.0_f32.sqrt()).abs() < 0.005);
+        assert!((meter.rms_dbfs() - (gain_to_db(0.5) - 3.01)).abs() < 0.1);
+        // In stereo, it's the average across both channels: one silent
+        // channel takes it down by another 3 dB.
+        let stereo: Vec<f32> = sine(RATE as usize * 2, 440.0, 0.5).into_iter()
+            .flat_map(|sample| [sample, 0.0])
+            .collect();
+        meter.update(&stereo, 2);
+        assert!((meter.rms_dbfs() - (gain_to_db(0.5) - 6.02)).abs() < 0.1);
+
+        // The output callback keeps it up to date.
+        let mut rig = Rig::new(1);
+        rig.record_loop(&sine(BUFFER * 10, 400.0, 0.25));
+        rig.silence(RATE as usize * 2);
+        assert!((rig.looper.rms.rms_dbfs() - (gain_to_db(0.25) - 3.01)).abs() < 0.1);
+    }
+
+    #[test]
+    fn dropouts_get_counted() {
+        let mut rig = Rig::new(1);
+        rig.tap();
+        let dropouts = rig.looper.dropouts.clone();
+        assert_eq!(dropouts.warning(0), None);
+
+        // Input with nothing taking it off the queue fills it up, and
+        // anything after that overflows.
+        let input = vec![0.1; BUFFER];
+        for _ in 0..CLIP_QUEUE_LEN {
+            rig.audio.input.process(&input);
+        }
+        assert_eq!(dropouts.overflows(), 0);
+        rig.audio.input.process(&input);
+        rig.audio.input.process(&input);
+        assert_eq!(dropouts.overflows(), 2);
+        assert_eq!(dropouts.starved(), 0);
+
+        // Output with no input coming in while recording is starved, once
+        // it's gone STARVED_LEN frames without, and only once until the
+        // input comes back.
+        let mut output = vec![0.0; BUFFER];
+        rig.audio.output.process(&mut output);
+        for _ in 0..STARVED_LEN * 3 / BUFFER {
+            rig.audio.output.process(&mut output);
+        }
+        assert_eq!(dropouts.starved(), 1);
+        rig.run(&input);
+        for _ in 0..STARVED_LEN * 3 / BUFFER {
+            rig.audio.output.process(&mut output);
+        }
+        assert_eq!(dropouts.starved(), 2);
+        assert_eq!(dropouts.total(), 4);
+        assert!(dropouts.warning(0).unwrap().contains("2 input overflow(s) and 2 starved"));
+        assert_eq!(dropouts.warning(4), None);
+
+        // None of that counts while nothing's recording.
+        rig.tap();
+        rig.run(&[]);
+        rig.tap();
+        rig.run(&[]);
+        assert!(!rig.looper.state.recording());
+        for _ in 0..STARVED_LEN * 3 / BUFFER {
+            rig.audio.output.process(&mut output);
+        }
+        assert_eq!(dropouts.total(), 4);
+    }
+
+    #[test]
+    fn pitch_shift_changes_the_frequency() {
+        let input = sine(RATE as usize * 2, 400.0, 0.5);
+        assert_eq!(dominant_freq(&input[RATE as usize..]), 400.0);
+        for semitones in [12.0, 7.0, -12.0] {
+            let ratio = 2.0_f32.powf(semitones / 12.0);
+            let mut shift = PitchShift::new(RATE);
+            let output: Vec<f32> = input.iter().map(|&sample| shift.process(sample, ratio)).collect();
+            let freq = dominant_freq(&output[RATE as usize..]);
+            assert!((freq / (400.0 * ratio) - 1.0).abs() < 0.01, "{} semitones: {} Hz", semitones, freq);
+        }
+
+        // Only the pitch changes on the way out of the looper, not the loop.
+        let mut rig = Rig::new(1);
+        let len = BUFFER * 25;
+        rig.record_loop(&sine(len, 400.0, 0.25));
+        rig.looper.set_pitch_semitones(12.0);
+        let output = rig.silence(RATE as usize * 2);
+        assert_eq!(rig.looper.state.get_loop_len(), len);
+        let freq = dominant_freq(&output[RATE as usize..]);
+        assert!((freq / 800.0 - 1.0).abs() < 0.01, "{} Hz", freq);
+    }
+
+    #[test]
+    fn stretching_keeps_the_pitch() {
+        let len = BUFFER * 25;
+        let input = sine(len, 400.0, 0.5);
+        for ratio in [0.5, 1.5, 2.0] {
+            let new_len = (len as f32 * ratio) as usize;
+            let stretched = time_stretch(&input, 1, new_len, RATE);
+            assert_eq!(stretched.len(), new_len);
+            let freq = dominant_freq(&stretched);
+            assert!((freq / 400.0 - 1.0).abs() < 0.01, "{}x: {} Hz", ratio, freq);
+        }
+        // Each channel gets stretched the same.
+        let stereo: Vec<f32> = input.iter().flat_map(|&sample| [sample, -sample]).collect();
+        let stretched = time_stretch(&stereo, 2, len * 2, RATE);
+        assert_eq!(stretched.len(), len * 4);
+        assert!(stretched.chunks(2).all(|frame| frame[0] == -frame[1]));
+
+        // Stretching the looper's loop stretches every layer.
+        let mut rig = Rig::new(1);
+        rig.record_loop(&input);
+        rig.record_layer(&vec![0.0; len]);
+        let total = rig.looper.state.get_total_samples();
+        rig.looper.stretch(1.5).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), len * 3 / 2);
+        assert_eq!(rig.looper.state.get_total_samples(), total * 3 / 2);
+        assert_eq!(rig.loop_count(), 2);
+        let output = rig.silence(len * 3);
+        let freq = dominant_freq(&output);
+        assert!((freq / 400.0 - 1.0).abs() < 0.01, "{} Hz", freq);
+        assert!(rig.looper.stretch(MAX_STRETCH * 2.0).is_err());
+    }
+
+    #[test]
+    fn bit_crush_quantizes_and_holds() {
+        // Three bits is a sign and four levels either way.
+        let mut crush = BitCrush::new();
+        let levels: Vec<f32> = [0.0, 0.1, 0.13, 0.2, 0.5, 0.9, -0.3, -1.0]
+            .iter()
+            .map(|&sample| crush.process(sample, 3, 1))
+            .collect();
+        assert_eq!(levels, [0.0, 0.0, 0.25, 0.25, 0.5, 1.0, -0.25, -1.0]);
+
+        // Each sample taken is held for the next few, carrying on from one
+        // buffer to the next.
+        let mut crush = BitCrush::new();
+        let input = ramp(10, 1.0);
+        let mut held: Vec<f32> = input[..4].iter().map(|&sample| crush.process(sample, 16, 3)).collect();
+        held.extend(input[4..].iter().map(|&sample| crush.process(sample, 16, 3)));
+        let expected: Vec<f32> = (0..10).map(|i| input[i / 3 * 3]).collect();
+        for (held, expected) in held.iter().zip(&expected) {
+            assert!((held - expected).abs() < 1.0 / 32768.0);
+        }
+
+        // The settings stay in range.
+        let settings = BitCrushSettings::new(0, 0);
+        assert_eq!((settings.bits(), settings.downsample()), (1, 1));
+        settings.set_bits(64);
+        settings.set_downsample(1000);
+        assert_eq!((settings.bits(), settings.downsample()), (MAX_CRUSH_BITS, MAX_CRUSH_DOWNSAMPLE));
+    }
+
+    #[test]
+    fn eq_band_responses() {
+        let rate = 48000;
+        // How much the EQ turns a sine at freq up or down, in dB, once it's
+        // settled.
+        let response = |gains: [f32; 3], freq: f32| {
+            let mut eq = Eq::new(rate);
+            eq.set_gains(gains);
+            let input: Vec<f32> = (0..rate as usize / 2)
+                .map(|i| 0.5 * (std::f32::consts::TAU * freq * i as f32 / rate as f32).sin())
+                .collect();
+            let output: Vec<f32> = input.iter().map(|&sample| eq.process(sample)).collect();
+            let peak = |samples: &[f32]| samples[samples.len() / 2..].iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+            gain_to_db(peak(&output) / peak(&input))
+        };
+
+        // Each band turns up (or down) what it covers, and leaves the rest.
+        for (band, freq) in [(EqBand::Low, 40.0), (EqBand::Mid, 1000.0), (EqBand::High, 16000.0)] {
+            for db in [6.0, -6.0] {
+                let mut gains = [0.0; 3];
+                gains[band as usize] = db;
+                let heard = response(gains, freq);
+                assert!((heard - db).abs() < 0.5, "{} at {} dB: {} dB", band, db, heard);
+                let others: Vec<f32> = [40.0, 1000.0, 16000.0].iter().copied()
+                    .filter(|&other| other != freq)
+                    .map(|other| response(gains, other))
+                    .collect();
+                assert!(others.iter().all(|heard| heard.abs() < 1.5), "{} at {} dB: {:?}", band, db, others);
+            }
+        }
+        // Flat is untouched.
+        assert!(response([0.0; 3], 1000.0).abs() < 1e-3);
+
+        // The gains stay in range.
+        let settings = EqSettings::new();
+        settings.set_gain_db(EqBand::Mid, 30.0);
+        assert_eq!(settings.gain_db(EqBand::Mid), MAX_EQ_DB);
+    }
+
+    #[test]
+    fn reverb_tail_decays() {
+        // How loud (RMS) the tail is in each tenth of a second after an
+        // impulse, in dBFS.
+        let tail = |decay_secs: f32| -> Vec<f32> {
+            let mut reverb = Reverb::new(RATE, 0);
+            reverb.set_decay(decay_secs);
+            let output: Vec<f32> = (0..RATE as usize * 2)
+                .map(|i| reverb.process(if i == 0 { 1.0 } else { 0.0 }, 1.0))
+                .collect();
+            output.chunks(RATE as usize / 10)
+                .map(|window| gain_to_db((window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32).sqrt()))
+                .collect()
+        };
+
+        for decay_secs in [0.5, 1.0, 2.0] {
+            let tail = tail(decay_secs);
+            // It only ever dies away (until it's too quiet to tell)...
+            let audible: Vec<f32> = tail.iter().copied().take_while(|&db| db > -120.0).collect();
+            assert!(audible.windows(2).all(|pair| pair[1] < pair[0]), "{}s: {:?}", decay_secs, tail);
+            // ...by about 60 dB over the decay time.
+            let windows = (decay_secs * 10.0) as usize;
+            let drop = tail[0] - tail[windows.min(tail.len() - 1)];
+            let expected = 60.0 * windows.min(tail.len() - 1) as f32 / 10.0 / decay_secs;
+            assert!((drop - expected).abs() < 10.0, "{}s: down {} dB, not {}", decay_secs, drop, expected);
+        }
+
+        // All dry, it's untouched.
+        let mut reverb = Reverb::new(RATE, 0);
+        assert_eq!(reverb.process(0.5, 0.0), 0.5);
+    }
+
+    #[test]
+    fn playback_fades_in_and_out() {
+        let mut fade = Fade::new(4);
+        let gains: Vec<f32> = (0..6).map(|_| fade.next(true)).collect();
+        assert_eq!(gains, [0.25, 0.5, 0.75, 1.0, 1.0, 1.0]);
+        let gains: Vec<f32> = (0..6).map(|_| fade.next(false)).collect();
+        assert_eq!(gains, [0.75, 0.5, 0.25, 0.0, 0.0, 0.0]);
+
+        let fade_len = BUFFER * 2;
+        let mut looper = LooperBuilder::new().sample_rate(RATE).channels(1).build();
+        looper.mock_audio = true;
+        let config = cpal::StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(RATE),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let (input, output) = callbacks(&looper, &config, &config, 0, fade_len, InputFilter::new(1, RATE, false, None));
+        let mut rig = Rig { looper, audio: MockAudio { input, output } };
+        // The loop's the same all the way through, so only the fades change
+        // how loud it is.
+        let level = 0.2;
+        let envelope = |output: &[f32]| -> Vec<f32> { output.iter().map(|sample| sample / level).collect() };
+        let ramp_up: Vec<f32> = (1..=fade_len).map(|i| i as f32 / fade_len as f32).collect();
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-4);
+
+        // It fades in when the first loop starts playing...
+        rig.record_loop(&vec![level; BUFFER * 4]);
+        let output = envelope(&rig.silence(fade_len * 2));
+        assert!(close(&output[..fade_len], &ramp_up), "{:?}", output);
+        assert!(output[fade_len..].iter().all(|&gain| (gain - 1.0).abs() < 1e-4));
+
+        // ...and out when it's stopped, and back in when it starts again.
+        rig.looper.run(Command::TogglePlayback).unwrap();
+        let output = envelope(&rig.silence(fade_len * 2));
+        let ramp_down: Vec<f32> = ramp_up.iter().rev().skip(1).copied().chain([0.0]).collect();
+        assert!(close(&output[..fade_len], &ramp_down), "{:?}", output);
+        assert!(output[fade_len..].iter().all(|&gain| gain == 0.0));
+        rig.looper.run(Command::TogglePlayback).unwrap();
+        let output = envelope(&rig.silence(fade_len));
+        assert!(close(&output, &ramp_up), "{:?}", output);
+    }
+
+    #[test]
+    fn cancelling_a_layer_keeps_the_rest() {
+        let len = BUFFER * 4;
+        let input = ramp(len, 0.25);
+        let mut rig = Rig::new(1);
+        rig.record_loop(&input);
+        let before = rig.looper.snapshot();
+        let taps = rig.looper.taps;
+        let expected = rig.silence(len);
+
+        // Halfway through the next layer, think better of it.
+        rig.tap();
+        rig.run(&vec![0.1; len / 2]);
+        assert!(rig.looper.state.recording());
+        rig.looper.cancel_layer().unwrap();
+        rig.run(&[]);
+        assert!(!rig.looper.state.recording());
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.looper.state.get_total_samples(), len);
+        assert_eq!(rig.looper.taps, taps + 1);
+        // Which leaves everything just like it was with the one layer,
+        // other than where playback's got to.
+        let mut after = rig.looper.snapshot();
+        after.playback = before.playback;
+        assert_eq!(after, before);
+        let start = rig.looper.state.get_playback();
+        let output = rig.silence(len);
+        let shifted: Vec<f32> = (0..len).map(|i| expected[(start + i) % len]).collect();
+        assert_eq!(output, shifted);
+        // And nothing to cancel now.
+        assert!(rig.looper.cancel_layer().is_err());
+
+        // Cancelling the first loop starts over.
+        let mut rig = Rig::new(1);
+        rig.tap();
+        rig.run(&input);
+        rig.looper.cancel_layer().unwrap();
+        assert_eq!(rig.looper.taps, 0);
+        assert!(rig.looper.state.first_loop());
+        assert!(!rig.looper.state.recording());
+    }
+
+    #[test]
+    fn layers_stop_at_the_limit() {
+        let len = BUFFER * 4;
+        let mut rig = Rig::build(LooperBuilder::new().sample_rate(RATE).channels(1).max_layers(2));
+        rig.record_loop(&vec![0.1; len]);
+        assert_eq!(rig.loop_count(), 1);
+
+        // Carrying on recording for three more loops only gets one of them.
+        rig.tap();
+        rig.looper.events.try_iter().for_each(drop);
+        let input = vec![0.1; len * 3];
+        let mut output = vec![0.0; len * 3];
+        for (input, output) in input.chunks(BUFFER).zip(output.chunks_mut(BUFFER)) {
+            rig.audio.run(input, output);
+        }
+        let events: Vec<Event> = rig.looper.events.try_iter().collect();
+        assert_eq!(rig.loop_count(), 2);
+        assert!(!rig.looper.state.recording());
+        assert!(rig.looper.state.get_total_samples() <= len * 2);
+        assert!(events.contains(&Event::MaxLayers { track: 0 }), "{:?}", events);
+
+        // And there's no starting another.
+        assert!(rig.looper.run(Command::Tap).is_err());
+        rig.run(&vec![0.1; len * 2]);
+        assert_eq!(rig.loop_count(), 2);
+        // Until one's undone.
+        rig.looper.run(Command::Undo).unwrap();
+        rig.tap();
+        rig.run(&vec![0.1; len * 2]);
+        assert_eq!(rig.loop_count(), 2);
+        assert!(!rig.looper.state.recording());
+    }
+
+    #[test]
+    fn recording_stops_at_the_longest() {
+        let len = BUFFER * 4;
+        let record = |rig: &mut Rig, input: &[f32]| -> Vec<Event> {
+            let mut output = vec![0.0; input.len()];
+            for (input, output) in input.chunks(BUFFER * 2).zip(output.chunks_mut(BUFFER * 2)) {
+                rig.audio.run(input, output);
+            }
+            rig.looper.events.try_iter().collect()
+        };
+
+        // Partway through the first loop, which closes right there.
+        let mut rig = Rig::new(2);
+        rig.looper.state.set_max_record_len(len + 10);
+        rig.tap();
+        let events = record(&mut rig, &vec![0.1; len * 2 * 2]);
+        assert!(events.contains(&Event::MaxRecording { track: 0 }), "{:?}", events);
+        assert!(!rig.looper.state.recording());
+        assert_eq!(rig.looper.state.get_loop_len(), len + 10);
+        assert_eq!(rig.looper.state.get_total_samples(), (len + 10) * 2);
+
+        // Or partway through a layer.
+        let mut rig = Rig::new(1);
+        rig.looper.state.set_max_record_len(len * 5 / 2);
+        rig.record_loop(&vec![0.1; len]);
+        rig.tap();
+        let events = record(&mut rig, &vec![0.1; len * 3]);
+        assert!(events.contains(&Event::MaxRecording { track: 0 }), "{:?}", events);
+        assert!(!rig.looper.state.recording());
+        assert_eq!(rig.looper.state.get_total_samples(), len * 5 / 2);
+        assert!(rig.looper.bank.lock().unwrap().samples[len * 5 / 2..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn flattened_mix_matches_the_live_one() {
+        // Anything that isn't the same from one sample (or layer) to the
+        // next.
+        let noise = |n: usize| -> Vec<f32> {
+            let mut seed = 12345_u32;
+            (0..n).map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 * 0.5 - 0.25
+            }).collect()
+        };
+        for channels in [1, 2] {
+            let layer_len = 16 * channels;
+            let mut bank = SampleBank::new(noise(layer_len * 4));
+            let recordings = [layer_len * 4, layer_len * 3 + 5 * channels];
+            let settings: [fn(&mut SampleBank); 5] = [
+                |_| {},
+                |bank| bank.set_layer_gain(0, 0.5),
+                |bank| bank.toggle_mute(1),
+                |bank| bank.toggle_layer_reverse(2),
+                |bank| bank.set_layer_pan(3, -0.5),
+            ];
+            for change in settings {
+                change(&mut bank);
+                for loop_count in 1..=4 {
+                    for recorded in recordings {
+                        let (key, flat) = bank.flatten(layer_len, loop_count, recorded, channels);
+                        for (idx, &sample) in flat.iter().enumerate() {
+                            assert_eq!(sample, bank.mix(idx, layer_len, loop_count, recorded, channels));
+                        }
+                        bank.set_flat(key, flat);
+                        assert!(bank.flat(layer_len, loop_count, recorded).is_some());
+                        assert!(bank.flat(layer_len, loop_count + 1, recorded).is_none());
+                    }
+                }
+            }
+            // Once anything changes, it's out of date.
+            bank.toggle_mute(0);
+            assert!(bank.flat(layer_len, 4, recordings[1]).is_none());
+        }
+
+        // And through the callbacks, playing from the flattened mix sounds
+        // just like adding up every layer as it goes.
+        let len = BUFFER * 4;
+        let record = || {
+            let mut rig = Rig::new(2);
+            rig.record_loop(&noise(len * 2));
+            rig.record_layer(&noise(len * 2));
+            rig.record_layer(&ramp(len * 2, 0.25));
+            rig.looper.set_layer_gain(1, 0.5);
+            rig.looper.set_layer_pan(2, 0.5);
+            rig.looper.toggle_layer_reverse(0).unwrap();
+            rig
+        };
+        let (mut flattened, mut live) = (record(), record());
+        for _ in 0..3 {
+            flattened.looper.flatten();
+            let state = &flattened.looper.state;
+            let key = (state.layer_len(), state.heard_layers(state.get_loop_count()), state.get_total_samples());
+            assert!(flattened.looper.bank.lock().unwrap().flat(key.0, key.1, key.2).is_some());
+            assert_eq!(flattened.silence(len / 2), live.silence(len / 2));
+            flattened.looper.toggle_mute(1);
+            live.looper.toggle_mute(1);
+        }
+    }
+
+    #[test]
+    fn dc_blocker_takes_out_offsets() {
+        let frames = RATE as usize;
+        let ac = sine(frames, 400.0, 0.25);
+        // Different offsets on each channel.
+        let mut samples: Vec<f32> = ac.iter().flat_map(|&sample| [sample + 0.3, sample - 0.2]).collect();
+        let mut filter = InputFilter::new(2, RATE, true, None);
+        filter.process(&mut samples);
+
+        // Once it's settled (after half a second), the offsets are gone and
+        // the sine's still there.
+        let settled = &samples[frames..];
+        for channel in 0..2 {
+            let channel: Vec<f32> = settled.iter().skip(channel).step_by(2).copied().collect();
+            let mean = channel.iter().sum::<f32>() / channel.len() as f32;
+            assert!(mean.abs() < 0.005, "{}", mean);
+            for (filtered, original) in channel.iter().zip(&ac[frames / 2..]) {
+                assert!((filtered - original).abs() < 0.01, "{} != {}", filtered, original);
+            }
+        }
+
+        // Without it, nothing changes.
+        let mut samples: Vec<f32> = ac.iter().map(|sample| sample + 0.3).collect();
+        let original = samples.clone();
+        InputFilter::new(1, RATE, false, None).process(&mut samples);
+        assert_eq!(samples, original);
+    }
+
+    #[test]
+    fn noise_gate_opens_and_closes_on_time() {
+        // -20 dBFS is 0.1; 10 ms is 80 frames, and 50 ms 400.
+        let (attack, release): (usize, usize) = (80, 400);
+        let mut gate = NoiseGate::new(-20.0, 10.0, 50.0, RATE);
+        let mut gains = |level: f32, frames: usize| -> Vec<f32> {
+            (0..frames).map(|_| gate.gain(&[level, -level])).collect()
+        };
+
+        // Quiet stuff stays shut out.
+        assert!(gains(0.05, 1000).iter().all(|&gain| gain == 0.0));
+        // Anything louder opens it, a step at a time over the attack.
+        let opening = gains(0.5, 200);
+        for (frame, &gain) in opening.iter().enumerate().take(attack) {
+            assert!((gain - (frame + 1) as f32 / attack as f32).abs() < 1e-4, "frame {}: {}", frame, gain);
+        }
+        assert!(opening[attack..].iter().all(|&gain| gain == 1.0));
+
+        // Once it goes quiet, it stays open until the level it's detected
+        // has fallen below the threshold...
+        let closing = gains(0.01, 1000);
+        let hold = (0.1_f32 / 0.5).ln() / db_to_gain(-60.0 / 400.0).ln();
+        let held = closing.iter().take_while(|&&gain| gain == 1.0).count();
+        assert!((held as f32 - hold).abs() <= 1.0, "held for {} frames, not {}", held, hold);
+        // ...and then closes over the release.
+        let released = closing[held..].iter().take_while(|&&gain| gain > 0.0).count();
+        assert!((released as i32 - release as i32).abs() <= 1, "released over {} frames", released);
+
+        // Which is what happens to the input.
+        let mut filter = InputFilter::new(1, RATE, false, Some(NoiseGate::new(-20.0, 10.0, 50.0, RATE)));
+        let mut samples = vec![0.05; 100];
+        samples.extend(vec![0.5; 200]);
+        filter.process(&mut samples);
+        assert!(samples[..100].iter().all(|&sample| sample == 0.0));
+        assert!((samples[100 + attack / 2 - 1] - 0.25).abs() < 1e-4);
+        assert_eq!(samples[299], 0.5);
+    }
+
+    #[test]
+    fn reversed_layers_play_backwards() {
+        // Two stereo layers of four frames, the second one reversed.
+        let forward = [0.1, -0.1, 0.2, -0.2, 0.3, -0.3, 0.4, -0.4];
+        let backward = [1.0, -1.0, 2.0, -2.0, 3.0, -3.0, 4.0, -4.0];
+        let mut bank = SampleBank::new(forward.iter().chain(&backward).copied().collect());
+        bank.toggle_layer_reverse(1);
+        let mixed: Vec<f32> = (0..8).map(|idx| bank.mix(idx, 8, 2, 16, 2)).collect();
+        // Each channel stays where it is.
+        let expected = [4.1, -4.1, 3.2, -3.2, 2.3, -2.3, 1.4, -1.4];
+        for (mixed, expected) in mixed.iter().zip(expected) {
+            assert!((mixed - expected).abs() < 1e-6, "{:?}", mixed);
+        }
+        // Part way through recording it, only what's been recorded plays,
+        // which for a reversed layer is at the end.
+        assert!((bank.mix(0, 8, 2, 12, 2) - 0.1).abs() < 1e-6);
+        assert!((bank.mix(6, 8, 2, 12, 2) - 1.4).abs() < 1e-6);
+
+        // And through the callbacks.
+        let len = BUFFER * 4;
+        let mut rig = Rig::new(1);
+        rig.record_loop(&ramp(len, 0.1));
+        rig.record_layer(&ramp(len, 0.1));
+        rig.looper.toggle_layer_reverse(1).unwrap();
+        let samples = rig.looper.bank.lock().unwrap().samples[..len * 2].to_vec();
+        let start = rig.looper.state.get_playback();
+        let output = rig.silence(len);
+        for (i, &sample) in output.iter().enumerate() {
+            let pos = (start + i) % len;
+            let expected = samples[pos] + samples[len + len - 1 - pos];
+            assert!((sample - expected).abs() < 1e-6, "{}: {} != {}", i, sample, expected);
+        }
+        // Reversing it again puts it back.
+        rig.looper.toggle_layer_reverse(1).unwrap();
+        let start = rig.looper.state.get_playback();
+        let output = rig.silence(len);
+        for (i, &sample) in output.iter().enumerate() {
+            let pos = (start + i) % len;
+            assert!((sample - (samples[pos] + samples[len + pos])).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn snapshot_matches_the_state() {
+        let len = BUFFER * 4;
+        let mut rig = Rig::new(1);
+        let empty = rig.looper.snapshot();
+        assert_eq!(empty, LooperState {
+            playback: 0,
+            loop_len: 0,
+            loop_count: 0,
+            total_samples: 0,
+            recording: false,
+            counting_in: false,
+            playing: true,
+            layers: vec![],
+        });
+
+        rig.record_loop(&vec![0.1; len]);
+        rig.record_layer(&vec![0.1; len]);
+        rig.looper.set_layer_gain(1, 0.5);
+        rig.looper.set_layer_pan(1, -1.0);
+        rig.looper.toggle_mute(0);
+        let before = rig.looper.snapshot();
+        assert_eq!(before.loop_len, len);
+        assert_eq!(before.loop_count, 2);
+        assert_eq!(before.total_samples, len * 2);
+        assert!(!before.recording);
+        assert!(before.playing);
+        assert_eq!(before.layers.len(), 2);
+        assert!(before.layers[0].muted);
+        assert_eq!(before.layers[0].gain, 1.0);
+        assert!(!before.layers[1].muted);
+        assert_eq!(before.layers[1].gain, 0.5);
+        assert_eq!(before.layers[1].pan, -1.0);
+        assert_eq!(before.playback, rig.looper.state.get_playback());
+
+        // It's a copy, so it stays put while the looper carries on.
+        let copy = before.clone();
+        rig.silence(BUFFER);
+        assert_eq!(before, copy);
+        let after = rig.looper.snapshot();
+        assert_eq!(after.playback, (before.playback + BUFFER) % len);
+        assert_eq!(after.layers, before.layers);
+
+        rig.tap();
+        rig.run(&[]);
+        assert!(rig.looper.snapshot().recording);
+    }
+
+    #[test]
+    fn builder_sets_each_option() {
+        // Without anything set, it's the same as the defaults.
+        let defaults = Looper::new();
+        let looper = LooperBuilder::default().build();
+        assert_eq!(looper.sample_rate, defaults.sample_rate);
+        assert_eq!(looper.state.channels, defaults.state.channels);
+        assert_eq!(looper.bank_capacity, DEFAULT_BANK_CAPACITY);
+        assert_eq!(looper.bank.lock().unwrap().len(), DEFAULT_BANK_CAPACITY);
+        assert_eq!(looper.state.record_mode(), RecordMode::Layer);
+        assert_eq!(looper.state.max_layers(), None);
+        assert_eq!(looper.compressor, CompressorSettings::default());
+        assert_eq!(looper.tracks.len(), 1);
+
+        let looper = LooperBuilder::new().sample_rate(RATE).build();
+        assert_eq!(looper.sample_rate, RATE);
+        assert_eq!(looper.metronome.sample_rate, RATE);
+
+        let mut looper = LooperBuilder::new().channels(1).build();
+        assert_eq!(looper.state.channels, 1);
+        looper.set_track_count(2);
+        assert_eq!(looper.tracks[1].state.channels, 1);
+
+        let mut looper = LooperBuilder::new().bank_capacity(1000).build();
+        assert_eq!(looper.bank_capacity, 1000);
+        assert_eq!(looper.bank.lock().unwrap().len(), 1000);
+        looper.set_track_count(2);
+        assert_eq!(looper.tracks[1].bank.lock().unwrap().len(), 1000);
+
+        let mode = RecordMode::Overdub { feedback: 0.5 };
+        let looper = LooperBuilder::new().record_mode(mode).build();
+        assert_eq!(looper.state.record_mode(), mode);
+
+        let mut looper = LooperBuilder::new().max_layers(3).build();
+        assert_eq!(looper.state.max_layers(), Some(3));
+        looper.set_track_count(2);
+        assert_eq!(looper.tracks[1].state.max_layers(), Some(3));
+
+        let compressor = CompressorSettings { threshold_db: -12.0, ratio: 2.0, attack_ms: 10.0, release_ms: 200.0 };
+        let looper = LooperBuilder::new().compressor(compressor).build();
+        assert_eq!(looper.compressor, compressor);
+    }
+
+    #[test]
+    fn recorded_ramp_replays() {
+        let len = BUFFER * 8;
+        let mut rig = Rig::new(2);
+        // Left going up, right coming down, so they can't get swapped.
+        let input: Vec<f32> = ramp(len, 0.25).into_iter()
+            .flat_map(|sample| vec![sample, 0.25 - sample])
+            .collect();
+        rig.record_loop(&input);
+        assert_eq!(rig.looper.state.get_loop_len(), len);
+        assert_eq!(rig.loop_count(), 1);
+
+        // Every buffer after that, of whatever size, carries on through the
+        // ramp from wherever playback was, around and around.
+        let frames = 37;
+        let mut pos = rig.looper.state.get_playback();
+        let mut output = vec![0.0; frames * 2];
+        for _ in 0..len * 3 / frames {
+            rig.audio.run(&vec![0.0; frames * 2], &mut output);
+            for (frame, sample) in output.chunks(2).enumerate() {
+                let expected = &input[(pos + frame) % len * 2..][..2];
+                assert_eq!(sample, expected, "frame {}", (pos + frame) % len);
+            }
+            pos = (pos + frames) % len;
+        }
+        assert_eq!(rig.looper.state.get_playback(), pos);
+    }
+
+    #[test]
+    fn first_loop_is_as_long_as_its_clips() {
+        let mut rig = Rig::new(2);
+        rig.tap();
+        // Clips of all sorts of sizes, none of which count towards the loop's
+        // length until it's closed.
+        let mut frames = 0;
+        for size in (1..=13).chain([64, 100, 7]) {
+            let mut output = vec![0.0; size * 2];
+            rig.audio.run(&vec![0.1; size * 2], &mut output);
+            frames += size;
+            assert_eq!(rig.looper.state.get_loop_len(), 0);
+            assert_eq!(rig.looper.state.get_total_samples(), frames * 2);
+        }
+        rig.tap();
+        rig.audio.run(&[], &mut []);
+        let closed: Vec<Event> = std::iter::from_fn(|| rig.looper.events.try_recv().ok())
+            .filter(|event| matches!(event, Event::LoopClosed { .. }))
+            .collect();
+        assert_eq!(closed, vec![Event::LoopClosed { track: 0, len: frames }]);
+        assert_eq!(rig.looper.state.get_loop_len(), frames);
+        assert_eq!(rig.looper.state.get_total_samples(), frames * 2);
+        assert_eq!(rig.looper.state.layer_len(), frames * 2);
+        assert_eq!(rig.loop_count(), 1);
+    }
+
+    #[test]
+    fn retrigger_starts_from_the_top() {
+        let len = BUFFER * 4;
+        let input = ramp(len, 0.25);
+        let mut rig = Rig::new(1);
+        assert!(rig.looper.retrigger().is_err(), "there's no loop yet");
+        rig.record_loop(&input);
+        rig.silence(BUFFER + 10);
+        assert_ne!(rig.looper.state.get_playback(), 0);
+
+        // Playback goes back to 0 as soon as the output callback gets to it,
+        // and carries on from there, keeping what's been recorded.
+        rig.looper.retrigger().unwrap();
+        rig.run(&[]);
+        assert_eq!(rig.looper.state.get_playback(), 0);
+        let output = rig.silence(BUFFER);
+        assert_eq!(output, input[..BUFFER]);
+        assert_eq!(rig.looper.state.get_playback(), BUFFER);
+        let output = rig.silence(len);
+        assert_eq!(output[..len - BUFFER], input[BUFFER..]);
+        assert_eq!(output[len - BUFFER..], input[..BUFFER]);
+        assert_eq!(rig.looper.state.get_playback(), BUFFER);
+        assert_eq!(rig.loop_count(), 1);
+
+        // Not while recording, though.
+        rig.tap();
+        assert!(rig.looper.retrigger().is_err());
+    }
+
+    #[test]
+    fn musical_units_at_any_tempo() {
+        // (sample rate, bpm, frames a beat)
+        let tempos = [
+            (44100, 120.0, 22050.0),
+            (48000, 90.0, 32000.0),
+            (44100, 100.0, 26460.0),
+            (96000, 140.0, 96000.0 * 60.0 / 140.0),
+            (RATE, 133.0, RATE as f64 * 60.0 / 133.0),
+        ];
+        for &(rate, bpm, per_beat) in &tempos {
+            let what = format!("{} Hz at {} bpm", rate, bpm);
+            for beats in [0.0, 0.5, 1.0, 1.25, 3.0, 16.0, 33.75] {
+                let frames = beats_to_samples(beats, rate, bpm);
+                assert_eq!(frames, (beats * per_beat).round() as usize, "{} beats, {}", beats, what);
+                // Going back only loses what rounding to the nearest frame
+                // did.
+                let back = samples_to_beats(frames, rate, bpm);
+                assert!((back - beats).abs() <= 0.5 / per_beat + 1e-12, "{} beats came back as {}, {}", beats, back, what);
+            }
+        }
+        // Half a frame rounds up, anything less rounds down.
+        assert_eq!(beats_to_samples(0.5, RATE, 133.0), 1805);
+        assert_eq!(beats_to_samples(1.0, RATE, 133.0), 3609);
+        assert_eq!(beats_to_samples(2.0, RATE, 133.0), 7218);
+        assert_eq!(beats_to_samples(1.5 / 1024.0, 1024, 60.0), 2);
+        assert_eq!(beats_to_samples(1.49 / 1024.0, 1024, 60.0), 1);
+        assert_eq!(samples_to_beats(11025, 44100, 120.0), 0.5);
+        // Nothing to go by means no time at all.
+        assert_eq!(samples_to_beats(1000, 0, 120.0), 0.0);
+        assert_eq!(samples_to_beats(1000, 44100, 0.0), 0.0);
+        assert_eq!(beats_to_samples(4.0, 44100, 0.0), 0);
+        assert_eq!(beats_to_samples(-4.0, 44100, 120.0), 0);
+
+        // Bars and beats, to the nearest hundredth of a beat.
+        let metronome = Metronome::new(120.0, 4, 44100);
+        assert_eq!(metronome.bars_and_beats(0), (0, 0.0));
+        assert_eq!(metronome.bars_and_beats(22050 * 16), (4, 0.0));
+        assert_eq!(metronome.bars_and_beats(22050 * 16 + 11025), (4, 0.5));
+        // A frame short of four bars is still four bars, not three and a bit.
+        assert_eq!(metronome.bars_and_beats(22050 * 16 - 1), (4, 0.0));
+        let (bars, beats) = metronome.bars_and_beats(beats_to_samples(15.994, 44100, 120.0));
+        assert_eq!(bars, 3);
+        assert!((beats - 3.99).abs() < 1e-9, "{}", beats);
+        // In three, a bar's three beats.
+        let metronome = Metronome::new(90.0, 3, 48000);
+        assert_eq!(metronome.bars_and_beats(32000 * 7), (2, 1.0));
+    }
+
+    #[test]
+    fn i16_samples_convert_both_ways() {
+        assert_eq!(i16_to_f32(0), 0.0);
+        assert_eq!(i16_to_f32(i16::MIN), -1.0);
+        assert_eq!(i16_to_f32(16384), 0.5);
+        assert!(i16_to_f32(i16::MAX) < 1.0);
+
+        assert_eq!(f32_to_i16(0.0), 0);
+        assert_eq!(f32_to_i16(1.0), i16::MAX);
+        assert_eq!(f32_to_i16(-1.0), -i16::MAX);
+        assert_eq!(f32_to_i16(0.5), 16384);
+        assert_eq!(f32_to_i16(-0.5), -16384);
+        // Anything past full scale clips, however far past.
+        assert_eq!(f32_to_i16(1.5), i16::MAX);
+        assert_eq!(f32_to_i16(-1.5), -i16::MAX);
+        assert_eq!(f32_to_i16(f32::INFINITY), i16::MAX);
+        assert_eq!(f32_to_i16(f32::NEG_INFINITY), -i16::MAX);
+        assert_eq!(f32_to_i16(f32::NAN), 0);
+
+        // There and back again lands on the same sample, or within one of it.
+        for sample in (i16::MIN..=i16::MAX).step_by(7) {
+            let back = f32_to_i16(i16_to_f32(sample));
+            assert!((back as i32 - sample as i32).abs() <= 1, "{} came back as {}", sample, back);
+        }
+    }
+
+    #[test]
+    fn wet_mix_blends_in_the_effects() {
+        let len = BUFFER * 4;
+        let input = ramp(len, 0.25);
+        let mut rig = Rig::with_tracks(LooperBuilder::new().sample_rate(RATE).channels(1), 2);
+        rig.record_loop(&input);
+        // Three bits, every sample, so what comes out is easy to work out.
+        rig.looper.crush.set_bits(3);
+        rig.looper.crush.set_downsample(1);
+        rig.looper.crush.toggle();
+        let mut crush = BitCrush::new();
+        let crushed: Vec<f32> = input.iter().map(|&sample| crush.process(sample, 3, 1)).collect();
+        assert_ne!(crushed, input);
+
+        // It's the same knob whichever track's selected.
+        rig.looper.run(Command::SelectTrack(1)).unwrap();
+        for (mix, expected) in [(1.0, &crushed), (0.0, &input)] {
+            rig.looper.set_wet_mix(mix);
+            assert_eq!(rig.looper.tracks[0].state.wet_mix(), mix);
+            let start = rig.looper.tracks[0].state.get_playback();
+            let output = rig.silence(len);
+            for (i, sample) in output.iter().enumerate() {
+                let pos = (start + i) % len;
+                assert!((sample - expected[pos]).abs() < 1e-6, "mix {}, frame {}: {} != {}", mix, pos, sample, expected[pos]);
+            }
+        }
+
+        // And anything in between blends the two.
+        rig.looper.set_wet_mix(0.25);
+        let start = rig.looper.tracks[0].state.get_playback();
+        let output = rig.silence(len);
+        for (i, sample) in output.iter().enumerate() {
+            let pos = (start + i) % len;
+            let expected = input[pos] * 0.75 + crushed[pos] * 0.25;
+            assert!((sample - expected).abs() < 1e-6, "frame {}: {} != {}", pos, sample, expected);
+        }
+    }
+
+    #[test]
+    fn effects_run_in_order() {
+        let effects = EffectsSettings::new();
+        assert_eq!(effects.order(), Effect::ALL);
+        // Bringing the crusher forward until it's ahead of the filter.
+        for _ in 0..Effect::BitCrush as usize {
+            effects.move_effect(Effect::BitCrush, false);
+        }
+        assert_eq!(effects.order()[..2], [Effect::BitCrush, Effect::Filter]);
+        // It can't go any further than the front.
+        effects.move_effect(Effect::BitCrush, false);
+        assert_eq!(effects.order()[0], Effect::BitCrush);
+        let crush_first = effects.order();
+
+        // A step, through a filter slow enough to make a ramp out of it and a
+        // crusher coarse enough to make steps out of that.
+        let input: Vec<f32> = (0..200).map(|i| if i < 10 { 0.0 } else { 0.9 }).collect();
+        let settings = |order| ChainSettings {
+            order,
+            cutoff: Some(100.0),
+            eq_gains: None,
+            pitch_ratio: None,
+            delay: None,
+            crush: Some((3, 1)),
+            reverb: None,
+        };
+        let run = |order| {
+            let settings = settings(order);
+            let mut chain = EffectsChain::new(RATE, 0);
+            chain.update(&settings);
+            let mut samples = input.clone();
+            chain.process(&mut samples, &settings);
+            samples
+        };
+
+        let (mut filter, mut crush) = (OnePole::new(100.0, RATE), BitCrush::new());
+        let filter_first: Vec<f32> = input.iter().map(|&sample| crush.process(filter.process(sample), 3, 1)).collect();
+        let (mut filter, mut crush) = (OnePole::new(100.0, RATE), BitCrush::new());
+        let crushed_first: Vec<f32> = input.iter().map(|&sample| filter.process(crush.process(sample, 3, 1))).collect();
+        assert_eq!(run(Effect::ALL), filter_first);
+        assert_eq!(run(crush_first), crushed_first);
+        // Crushing last leaves nothing but its levels, but filtering last
+        // smooths them out.
+        assert!(filter_first.iter().all(|sample| (sample * 4.0).fract() == 0.0));
+        assert!(crushed_first.iter().any(|sample| (sample * 4.0).fract() != 0.0));
+    }
+
+    #[test]
+    fn width_narrows_and_widens() {
+        assert_eq!(mid_side(0.5, -0.25, 1.0), (0.5, -0.25));
+        assert_eq!(mid_side(0.5, -0.25, 0.0), (0.125, 0.125));
+        assert_eq!(mid_side(0.5, -0.25, 2.0), (0.875, -0.625));
+        // Mono stays mono, however wide.
+        assert_eq!(mid_side(0.25, 0.25, 2.0), (0.25, 0.25));
+
+        // Left going up and right coming down, through the whole output.
+        let len = BUFFER * 4;
+        let input: Vec<f32> = ramp(len, 0.2).into_iter()
+            .flat_map(|sample| vec![sample, 0.1 - sample])
+            .collect();
+        let mut rig = Rig::with_tracks(LooperBuilder::new().sample_rate(RATE).channels(2), 2);
+        rig.record_loop(&input);
+        // It's the same knob whichever track's selected.
+        rig.looper.run(Command::SelectTrack(1)).unwrap();
+        for width in [1.0, 0.0] {
+            rig.looper.set_width(width);
+            assert_eq!(rig.looper.tracks[0].state.width(), width);
+            let start = rig.looper.tracks[0].state.get_playback();
+            let output = rig.silence(len);
+            for (i, frame) in output.chunks(2).enumerate() {
+                let pos = (start + i) % len;
+                let (left, right) = (input[pos * 2], input[pos * 2 + 1]);
+                let expected = match width == 1.0 {
+                    true => [left, right],
+                    false => [(left + right) / 2.0; 2],
+                };
+                for (sample, expected) in frame.iter().zip(expected) {
+                    assert!((sample - expected).abs() < 1e-6, "width {}, frame {}: {:?} != {:?}", width, pos, frame, expected);
+                }
+            }
+        }
+
+        // It only goes so wide.
+        rig.looper.set_width(10.0);
+        assert_eq!(rig.looper.state.width(), MAX_WIDTH);
+        rig.looper.set_width(-1.0);
+        assert_eq!(rig.looper.state.width(), 0.0);
+    }
+
+    #[test]
+    fn playing_only_the_first_layers() {
+        let mut rig = Rig::new(1);
+        let len = BUFFER * 2;
+        let levels = [0.25, 0.125, 0.0625];
+        {
+            let mut bank = rig.looper.bank.lock().unwrap();
+            for (layer, &level) in levels.iter().enumerate() {
+                bank.write_at(layer * len, &vec![level; len]).unwrap();
+            }
+        }
+        rig.looper.state.load_layers(len, 3, len * 3, 0);
+        let exported = |looper: &Looper| {
+            let path = temp_path("first-layers.wav");
+            looper.export_wav(&path).unwrap();
+            let samples: Vec<f32> = hound::WavReader::open(&path).unwrap().samples::<f32>().map(Result::unwrap).collect();
+            std::fs::remove_file(&path).unwrap();
+            samples
+        };
+        assert_eq!(exported(&rig.looper), vec![0.4375; len]);
+
+        // Only the first two get added up, in playback, exports, and
+        // normalizing alike, whether or not they've been flattened.
+        rig.looper.set_playback_layers(2);
+        assert_eq!(rig.looper.state.heard_layers(3), 2);
+        assert_eq!(rig.silence(len), vec![0.375; len]);
+        rig.looper.flatten();
+        assert_eq!(rig.silence(len), vec![0.375; len]);
+        assert_eq!(rig.loop_count(), 3);
+        rig.looper.set_playback_layers(1);
+        assert_eq!(rig.silence(len), vec![0.25; len]);
+        assert_eq!(exported(&rig.looper), vec![0.25; len]);
+        rig.looper.normalize = true;
+        rig.looper.normalize_db = -12.0;
+        rig.looper.normalize_mix();
+        assert!((rig.looper.state.master_gain() - db_to_gain(-12.0) / 0.25).abs() < 1e-6);
+
+        // More than there are is all of them, and so's none.
+        rig.looper.set_playback_layers(5);
+        assert_eq!(rig.looper.state.heard_layers(3), 3);
+        rig.looper.set_playback_layers(0);
+        assert_eq!(rig.looper.state.playback_layer_limit(), None);
+        assert_eq!(rig.looper.state.heard_layers(3), 3);
+    }
+
+    #[test]
+    fn waveform_buckets_hold_their_peaks() {
+        // Stereo, eight frames into four buckets of two.
+        let samples = [
+            0.1, -0.2, 0.3, 0.0,
+            0.5, 0.4, -0.6, 0.2,
+            0.0, 0.0, 0.0, 0.0,
+            -0.9, 0.8, 0.7, -0.1,
+        ];
+        assert_eq!(waveform_peaks(&samples, 2, 4), [(-0.2, 0.3), (-0.6, 0.5), (0.0, 0.0), (-0.9, 0.8)]);
+        // Fewer buckets, more in each.
+        assert_eq!(waveform_peaks(&samples, 2, 2), [(-0.6, 0.5), (-0.9, 0.8)]);
+        assert_eq!(waveform_peaks(&samples, 2, 1), [(-0.9, 0.8)]);
+        // More buckets than frames, and some frames go in more than one.
+        let peaks = waveform_peaks(&samples, 2, 16);
+        assert_eq!(peaks.len(), 16);
+        assert_eq!(peaks[0], (-0.2, 0.1));
+        assert_eq!(peaks[1], (-0.2, 0.1));
+        assert_eq!(peaks[15], (-0.1, 0.7));
+        assert!(waveform_peaks(&[], 2, 4).is_empty());
+
+        // From a looper, the buckets are of what's heard.
+        let mut rig = Rig::new(1);
+        assert!(rig.looper.waveform(4).is_empty());
+        let input: Vec<f32> = (0..BUFFER * 4).map(|idx| match idx / BUFFER {
+            0 => 0.1,
+            1 => -0.2,
+            2 => 0.0,
+            _ => if idx % 2 == 0 { 0.25 } else { -0.25 },
+        }).collect();
+        rig.record_loop(&input);
+        assert_eq!(rig.looper.waveform(4), [(0.1, 0.1), (-0.2, -0.2), (0.0, 0.0), (-0.25, 0.25)]);
+        rig.looper.set_layer_gain(0, 0.5);
+        assert_eq!(rig.looper.waveform(2), [(-0.1, 0.05), (-0.125, 0.125)]);
+        assert!(rig.looper.waveform(0).is_empty());
+    }
+
+    // Every event logged while it's the default subscriber.
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<Logged>>>);
+
+    struct Logged {
+        level: tracing::Level,
+        target: String,
+        // The span it was logged in, if any.
+        span: Option<String>,
+        message: String,
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for Logs
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+            struct Message(String);
+
+            impl tracing::field::Visit for Message {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    if field.name() == "message" {
+                        self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            let metadata = event.metadata();
+            let span = ctx.event_span(event).map(|span| span.name().to_string());
+            self.0.lock().unwrap().push(Logged {
+                level: *metadata.level(),
+                target: metadata.target().to_string(),
+                span,
+                message: message.0,
+            });
+        }
+    }
+
+    impl Logs {
+        fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+    }
+
+    #[test]
+    fn recording_gets_logged() {
+        use tracing_subscriber::layer::SubscriberExt;
+        let logs = Logs::default();
+        let subscriber = tracing_subscriber::registry().with(logs.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let mut rig = Rig::new(1);
+            rig.tap();
+            // The audio callbacks never log anything themselves; it all
+            // waits for the UI to get the events they send.
+            let logged = logs.len();
+            let mut output = [0.0; BUFFER];
+            for _ in 0..4 {
+                rig.audio.run(&[0.1; BUFFER], &mut output);
+            }
+            assert_eq!(logs.len(), logged);
+            rig.run(&[]);
+            rig.looper.log_recording();
+            rig.tap();
+            rig.run(&[]);
+            rig.tap();
+            rig.run(&[]);
+            rig.looper.run(Command::TogglePlayback).unwrap();
+        });
+
+        let logs = logs.0.lock().unwrap();
+        let lifecycle: Vec<(&str, Option<&str>)> = logs.iter()
+            .filter(|logged| logged.target == "looper_proto")
+            .map(|logged| (logged.message.as_str(), logged.span.as_deref()))
+            .collect();
+        assert_eq!(lifecycle, [
+            ("tap", None),
+            ("recording started", Some("recording")),
+            ("clips arrived", Some("recording")),
+            ("tap", None),
+            ("loop closed", Some("recording")),
+            ("layer added", Some("recording")),
+            ("tap", None),
+            ("recording stopped", Some("recording")),
+        ]);
+        // Along with what the UI should show.
+        let status: Vec<&str> = logs.iter()
+            .filter(|logged| logged.level == tracing::Level::INFO && logged.target == STATUS_TARGET)
+            .map(|logged| logged.message.as_str())
+            .collect();
+        assert_eq!(status, ["STOPPED."]);
+    }
+
+    #[test]
+    fn latency_moves_overdubs_earlier() {
+        let len = BUFFER * 8;
+        let at = 200;
+        let impulse = |frames: usize| -> Vec<f32> {
+            (0..frames).map(|i| if i == at { 0.5 } else { 0.0 }).collect()
+        };
+        // Where the impulse ends up in each layer, with latency frames made
+        // up for.
+        let recorded = |latency: usize| -> Vec<usize> {
+            let mut rig = Rig::new(1);
+            rig.looper.state.set_latency(latency);
+            rig.record_loop(&impulse(len));
+            // Each buffer of input goes with the output before it (see
+            // next_write_index()), so it takes one more to fill the layer.
+            rig.record_layer(&impulse(len + BUFFER));
+            let bank = rig.looper.bank.lock().unwrap();
+            bank.samples[..len * 2].chunks(len)
+                .map(|layer| layer.iter().position(|&sample| sample == 0.5).unwrap())
+                .collect()
+        };
+        // The first loop isn't played along to, so it stays put; what's
+        // played over it goes as much earlier as the latency.
+        assert_eq!(recorded(0), [at, at - BUFFER]);
+        assert_eq!(recorded(20), [at, at - BUFFER - 20]);
+        assert_eq!(recorded(100), [at, at - BUFFER - 100]);
+    }
+
+    #[test]
+    fn calibration_needs_a_loud_enough_click() {
+        let sent = click();
+        let mut received = vec![0.0; 1000];
+        received[300..300 + sent.len()].copy_from_slice(&sent);
+        assert_eq!(find_delay(&sent, &received, CLICK_THRESHOLD), Some(300));
+        // Upside down still counts.
+        let flipped: Vec<f32> = received.iter().map(|sample| -sample).collect();
+        assert_eq!(find_delay(&sent, &flipped, CLICK_THRESHOLD), Some(300));
+        // Quieter still counts, down to the threshold.
+        let quiet: Vec<f32> = received.iter().map(|sample| sample * 0.06).collect();
+        assert_eq!(find_delay(&sent, &quiet, CLICK_THRESHOLD), Some(300));
+        let too_quiet: Vec<f32> = received.iter().map(|sample| sample * 0.04).collect();
+        assert_eq!(find_delay(&sent, &too_quiet, CLICK_THRESHOLD), None);
+        // Nothing coming back at all is nothing heard.
+        assert_eq!(find_delay(&sent, &[0.0; 1000], CLICK_THRESHOLD), None);
+        // Nor is too little to hold the whole click, or no click to find.
+        assert_eq!(find_delay(&sent, &received[..sent.len() - 1], CLICK_THRESHOLD), None);
+        assert_eq!(find_delay(&[0.0; 16], &received, CLICK_THRESHOLD), None);
+    }
+
+    #[test]
+    fn cross_correlation_finds_the_delay() {
+        // Anything sent, received after delay frames (and half as loud), on
+        // top of some quiet noise.
+        let delayed = |sent: &[f32], delay: usize, len: usize| -> Vec<f32> {
+            let mut seed: u32 = 42;
+            let mut received: Vec<f32> = (0..len).map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                ((seed >> 8) as f32 / (1 << 23) as f32 - 1.0) * 0.01
+            }).collect();
+            for (sample, sent) in received[delay..].iter_mut().zip(sent) {
+                *sample += sent * 0.5;
+            }
+            received
+        };
+        let click = click();
+        let len = 2000;
+        for delay in [0, 1, 37, 256, 999, len - click.len()] {
+            let received = delayed(&click, delay, len);
+            assert_eq!(find_delay(&click, &received, CLICK_THRESHOLD), Some(delay), "noise click");
+            let received = delayed(&[1.0], delay, len);
+            assert_eq!(find_delay(&[1.0], &received, CLICK_THRESHOLD), Some(delay), "impulse");
+        }
+
+        // An echo of the click after the real thing doesn't throw it off,
+        // as long as it's quieter.
+        let mut received = delayed(&click, 300, len);
+        for (sample, echo) in received[900..].iter_mut().zip(&click) {
+            *sample += echo * 0.3;
+        }
+        assert_eq!(find_delay(&click, &received, CLICK_THRESHOLD), Some(300));
+    }
+
+    #[test]
+    fn trimming_silence_off_the_ends() {
+        let threshold = db_to_gain(-40.0);
+        // Stereo: frames 1 and 3 are loud, on one channel or the other.
+        let samples = [0.0, 0.001, 0.5, 0.0, 0.0, 0.0, 0.0, -0.5, 0.001, 0.0];
+        assert_eq!(loud_frames(&samples, 2, threshold), Some((1, 4)));
+        assert_eq!(loud_frames(&samples, 1, threshold), Some((2, 8)));
+        // Right at the threshold is still silence.
+        assert_eq!(loud_frames(&[threshold, -threshold], 1, threshold), None);
+        assert_eq!(loud_frames(&[0.0; 8], 2, threshold), None);
+        assert_eq!(loud_frames(&[0.9; 4], 2, threshold), Some((0, 2)));
+
+        let mut rig = Rig::new(1);
+        assert!(rig.looper.trim_silence(-40.0).is_err(), "there's no loop yet");
+        // The first layer's quiet for 100 frames, then loud (with a quiet
+        // bit in the middle that stays), then quiet again for the last 56.
+        let len = 400;
+        let first: Vec<f32> = (0..len).map(|i| match i {
+            100..=199 | 250..=343 => 0.25,
+            _ => 0.0001,
+        }).collect();
+        let second: Vec<f32> = (0..len).map(|i| i as f32 / len as f32).collect();
+        {
+            let mut bank = rig.looper.bank.lock().unwrap();
+            bank.write_at(0, &first).unwrap();
+            bank.write_at(len, &second).unwrap();
+        }
+        rig.looper.state.load_layers(len, 2, len * 2, 50);
+        rig.looper.trim_silence(-40.0).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 244);
+        assert_eq!(rig.loop_count(), 2);
+        assert_eq!(rig.looper.state.get_total_samples(), 244 * 2);
+        // Every layer loses the same stretch.
+        let layers = rig.looper.copy_layers();
+        assert_eq!(layers[0], first[100..344]);
+        assert_eq!(layers[1], second[100..344]);
+        // Playback was in what got cut off, so it's at the start of what's
+        // left.
+        assert_eq!(rig.looper.state.get_playback(), 0);
+
+        // Once it's trimmed, there's nothing more to do.
+        rig.looper.trim_silence(-40.0).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 244);
+        // A lower threshold counts the quiet bits as loud.
+        rig.looper.trim_silence(-90.0).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 244);
+
+        // And it won't leave nothing at all.
+        let mut rig = Rig::new(1);
+        rig.looper.bank.lock().unwrap().write_at(0, &[0.0001; 100]).unwrap();
+        rig.looper.state.load_layers(100, 1, 100, 0);
+        assert!(rig.looper.trim_silence(-40.0).is_err());
+        assert_eq!(rig.looper.state.get_loop_len(), 100);
+    }
+
+    #[test]
+    fn doubling_repeats_every_layer() {
+        let mut rig = Rig::new(2);
+        assert!(rig.looper.double_length().is_err(), "there's no loop yet");
+        let first = ramp(300 * 2, 0.25);
+        let second = vec![0.05; 300 * 2];
+        rig.load(&[first.clone(), second.clone()], 120);
+
+        rig.looper.double_length().unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 600);
+        assert_eq!(rig.loop_count(), 2);
+        assert_eq!(rig.looper.state.get_total_samples(), 600 * 2 * 2);
+        // Each layer's two copies of itself, back to back.
+        let layers = rig.looper.copy_layers();
+        assert_eq!(layers[0], [&first[..], &first[..]].concat());
+        assert_eq!(layers[1], [&second[..], &second[..]].concat());
+        // Playback carries on from the same place, in the first copy.
+        assert_eq!(rig.looper.state.get_playback(), 120);
+        // So it sounds just the same as before, but takes twice as long to
+        // come back round.
+        let output = rig.silence(600);
+        for (i, sample) in output.iter().enumerate() {
+            let expected = first[(120 * 2 + i) % first.len()] + 0.05;
+            assert!((sample - expected).abs() < 1e-6, "sample {}: {} != {}", i, sample, expected);
+        }
+        assert_eq!(rig.looper.state.get_playback(), 120);
+
+        // Not if there isn't room for it, though: two layers of 1200 frames
+        // would be 2400 frames in all.
+        rig.looper.state.set_max_record_len(2399);
+        assert!(rig.looper.double_length().is_err());
+        assert_eq!(rig.looper.state.get_loop_len(), 600);
+    }
+
+    #[test]
+    fn halving_keeps_or_mixes_the_second_half() {
+        let mut rig = Rig::new(1);
+        assert!(rig.looper.halve_length(HalveMode::KeepFirst).is_err(), "there's no loop yet");
+        // An odd number of frames, so the last one goes either way.
+        let first = ramp(301, 0.25);
+        let second: Vec<f32> = (0..301).map(|i| if i < 150 { 0.01 } else { 0.02 }).collect();
+        let load = |rig: &mut Rig| rig.load(&[first.clone(), second.clone()], 200);
+
+        load(&mut rig);
+        rig.looper.halve_length(HalveMode::KeepFirst).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 150);
+        assert_eq!(rig.loop_count(), 2);
+        assert_eq!(rig.looper.state.get_total_samples(), 300);
+        let layers = rig.looper.copy_layers();
+        assert_eq!(layers[0], first[..150]);
+        assert_eq!(layers[1], second[..150]);
+        // Playback stays at the same place in the half that's left.
+        assert_eq!(rig.looper.state.get_playback(), 50);
+
+        let mut rig = Rig::new(1);
+        load(&mut rig);
+        rig.looper.halve_length(HalveMode::Mix).unwrap();
+        assert_eq!(rig.looper.state.get_loop_len(), 150);
+        assert_eq!(rig.loop_count(), 2);
+        let layers = rig.looper.copy_layers();
+        let mixed: Vec<f32> = (0..150).map(|i| first[i] + first[150 + i]).collect();
+        assert_eq!(layers[0], mixed);
+        assert_eq!(layers[1], [0.03; 150]);
+        assert_eq!(rig.looper.state.get_playback(), 50);
+
+        // It has to leave something.
+        let mut rig = Rig::new(1);
+        rig.load(&[vec![0.1]], 0);
+        for mode in [HalveMode::KeepFirst, HalveMode::Mix] {
+            assert!(rig.looper.halve_length(mode).is_err());
+            assert_eq!(rig.looper.state.get_loop_len(), 1);
+        }
+    }
+
+    #[test]
+    fn decaying_layers_fade_each_time_round() {
+        let mut rig = Rig::new(1);
+        let len = BUFFER * 2;
+        rig.load(&[vec![0.25; len], vec![0.125; len]], 0);
+        rig.looper.set_layer_decay(1, 0.5);
+        // Each pass around the loop plays the second layer at half what it
+        // was the time before.
+        for pass in 0..10 {
+            assert_eq!(rig.loop_count(), 2);
+            let output = rig.silence(len);
+            assert_eq!(output[len - 1], 0.25 + 0.125 * 0.5_f32.powi(pass), "pass {}", pass);
+        }
+        // The tenth time round took it below DECAY_FLOOR_DB, so it's gone.
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.silence(len)[len - 1], 0.25);
+    }
+
+    #[test]
+    fn phase_goes_from_zero_to_one() {
+        let mut rig = Rig::new(1);
+        // Without a loop there's nothing to divide by.
+        assert_eq!(rig.looper.state.phase(), 0.0);
+
+        let len = BUFFER * 2;
+        for (playback, phase) in [(0, 0.0), (BUFFER / 2, 0.25), (BUFFER, 0.5), (len - BUFFER / 2, 0.75)] {
+            rig.load(&[vec![0.0; len]], playback);
+            assert_eq!(rig.looper.state.phase(), phase);
+        }
+
+        // Every time round gets counted, and an event for each one.
+        rig.load(&[vec![0.0; len]], 0);
+        for _ in 0..6 {
+            rig.audio.run(&[0.0; BUFFER], &mut [0.0; BUFFER]);
+        }
+        assert_eq!(rig.looper.state.cycles(), 3);
+        let wraps = std::iter::from_fn(|| rig.looper.events.try_recv().ok())
+            .filter(|&event| event == Event::PlaybackWrapped { track: 0 })
+            .count();
+        assert_eq!(wraps, 3);
+    }
+
+    #[test]
+    fn clock_ticks_fit_the_loop() {
+        // Two seconds at 120 bpm is four beats.
+        assert_eq!(clock_beats(88200, 44100, 120.0), 4);
+        assert_eq!(clock_bpm(88200, 44100, 4), 120.0);
+        // A loop that's a bit long still gets four beats, just slower ones,
+        // so they line up with it.
+        assert_eq!(clock_beats(92610, 44100, 120.0), 4);
+        assert!((clock_bpm(92610, 44100, 4) - 4.0 * 60.0 / 2.1).abs() < 1e-3);
+        // Even a very short loop gets a beat.
+        assert_eq!(clock_beats(100, 44100, 120.0), 1);
+        assert_eq!(clock_bpm(0, 44100, 1), 0.0);
+
+        // 24 ticks to a beat, starting from the top of the loop.
+        assert_eq!(clock_tick(0.0, 4), 0);
+        assert_eq!(clock_tick(0.25, 4), 24);
+        assert_eq!(clock_tick(0.5, 4), 48);
+        assert_eq!(clock_tick(0.75 - f32::EPSILON, 4), 71);
+        // The last tick lasts right up to the end.
+        assert_eq!(clock_tick(1.0 - f32::EPSILON, 4), 95);
+        assert_eq!(clock_tick(1.0, 4), 95);
+        assert_eq!(clock_tick(0.5, 0), 0);
+    }
+
+    #[test]
+    fn clock_pulses_set_the_tempo_and_loop_length() {
+        // 125 bpm is a pulse every 20 ms.
+        let pulse = |n: u64| Duration::from_millis(20 * n);
+        let mut clock = MidiClock::new();
+        assert!(!clock.pulse(pulse(0)), "there's been no Start");
+        assert_eq!(clock.bpm(), None, "one pulse isn't a tempo");
+        clock.start();
+        let beats: Vec<u64> = (1..=100).filter(|&n| clock.pulse(pulse(n))).collect();
+        // The first pulse after Start is the downbeat.
+        assert_eq!(beats, [1, 25, 49, 73, 97]);
+        assert!((clock.bpm().unwrap() - 125.0).abs() < 1e-3);
+
+        // A bar at 125 bpm is 1.92 s, or 92160 frames at 48 kHz. Loops get
+        // rounded to whole bars of that, to the frame.
+        assert_eq!(clock.loop_len(95000, 4, 48000), Some(92160));
+        assert_eq!(clock.loop_len(200000, 4, 48000), Some(184320));
+        assert_eq!(clock.loop_len(100, 4, 48000), Some(92160));
+        // Or beats.
+        assert_eq!(clock.loop_len(50000, 1, 48000), Some(46080));
+
+        // It keeps up when the tempo changes: 150 bpm, a pulse every
+        // 16 2/3 ms, once the old pulses are out of the window.
+        let start = pulse(100);
+        for n in 1..=MIDI_CLOCK_WINDOW as u32 {
+            clock.pulse(start + Duration::from_micros(16667) * n);
+        }
+        assert!((clock.bpm().unwrap() - 150.0).abs() < 0.01);
+
+        // A long gap means it stopped, so the old tempo's gone.
+        clock.pulse(start + Duration::from_secs(10));
+        assert_eq!(clock.bpm(), None);
+        clock.stop();
+        assert!(!clock.running());
+        assert_eq!(clock.loop_len(95000, 4, 48000), None);
+    }
+
+    #[test]
+    fn freezing_sounds_the_same() {
+        let len = BUFFER * 2;
+        let mut rig = Rig::new(2);
+        rig.load(&[ramp(len * 2, 0.25), vec![0.125; len * 2], vec![0.25; len * 2]], 0);
+        rig.looper.set_layer_gain(0, 0.5);
+        rig.looper.set_layer_pan(1, 0.5);
+        rig.looper.toggle_mute(2);
+        let path = temp_path("before-freeze.zip");
+        rig.looper.save_session(&path).unwrap();
+
+        let before = rig.silence(len);
+        rig.looper.freeze().unwrap();
+        assert_eq!(rig.loop_count(), 1);
+        assert_eq!(rig.silence(len), before);
+        // Undoing splits them up again.
+        rig.looper.undo().unwrap();
+        assert_eq!(rig.loop_count(), 3);
+        assert_eq!(rig.silence(len), before);
+
+        // Loading a session leaves nothing to unfreeze, so undoing after
+        // that just undoes its top layer.
+        rig.looper.freeze().unwrap();
+        rig.looper.load_session(&path).unwrap();
+        assert_eq!(rig.loop_count(), 3);
+        rig.looper.undo().unwrap();
+        assert_eq!(rig.loop_count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn callbacks_go_without_the_bank_while_the_ui_has_it() {
+        let mut rig = Rig::new(1);
+        rig.tap();
+        rig.run(&[0.25; BUFFER]);
+        let recorded = rig.looper.state.get_total_samples();
+        assert_eq!(recorded, BUFFER);
+
+        // Input that comes in meanwhile waits for the bank, rather than
+        // being lost.
+        let shared = Arc::clone(&rig.looper.bank);
+        let held = shared.lock().unwrap();
+        rig.audio.run(&[0.5; BUFFER], &mut [0.0; BUFFER]);
+        assert_eq!(rig.looper.state.get_total_samples(), recorded);
+        drop(held);
+        rig.run(&[0.75; BUFFER]);
+        assert_eq!(rig.looper.state.get_total_samples(), BUFFER * 3);
+        let bank = rig.looper.bank.lock().unwrap();
+        assert!(bank.samples[..BUFFER].iter().all(|&sample| sample == 0.25));
+        assert!(bank.samples[BUFFER..BUFFER * 2].iter().all(|&sample| sample == 0.5));
+        assert!(bank.samples[BUFFER * 2..BUFFER * 3].iter().all(|&sample| sample == 0.75));
+        drop(bank);
+        rig.tap();
+        rig.run(&[]);
+        rig.tap();
+        rig.run(&[]);
+        assert!(!rig.looper.state.recording());
+
+        // Playback goes silent, but keeps its place.
+        let len = rig.looper.state.get_loop_len();
+        let playback = rig.looper.state.get_playback();
+        let bank = Arc::clone(&rig.looper.bank);
+        let held = bank.lock().unwrap();
+        let mut output = [0.0; BUFFER];
+        rig.audio.run(&[0.0; BUFFER], &mut output);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+        assert_eq!(rig.looper.state.get_playback(), (playback + BUFFER) % len);
+        drop(held);
+        assert!(rig.silence(BUFFER).iter().all(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn copying_a_bank_keeps_up_with_writes() {
+        // Long enough to take a good while to copy, with something standing
+        // in for the output callback writing all over it in between.
+        let len = COPY_CHUNK_LEN * 256;
+        let layer_len = len / 4;
+        let bank = Arc::new(Mutex::new(SampleBank::new(vec![0.0; len])));
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let bank = Arc::clone(&bank);
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                let mut count = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let mut bank = bank.lock().unwrap();
+                    count += 1;
+                    let value = count as f32;
+                    // Near the start, which gets copied first, so it's sure
+                    // to change again afterwards.
+                    bank.write_at(count % 32, &[value; 16]).unwrap();
+                    // Overdubbing right up to the end of the layer, and
+                    // wrapping round.
+                    let pos = layer_len - 32 + count % 32;
+                    bank.overdub(layer_len, layer_len, pos, &[value; 16], 0.5).unwrap();
+                    bank.replace(layer_len, 4, 100, &[value; 16]).unwrap();
+                }
+            })
+        };
+        for _ in 0..20 {
+            let mut copy = vec![0.0; len];
+            let held = SampleBank::copy_into(&bank, &mut copy);
+            assert!(copy == held.samples);
+        }
+        done.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+
+        // Growing the bank copies it over the same way.
+        let before = bank.lock().unwrap().samples.clone();
+        SampleBank::grow_shared(&bank, len + 1).unwrap();
+        let bank = bank.lock().unwrap();
+        assert_eq!(bank.len(), len * 2);
+        assert!(bank.samples[..len] == before[..]);
+        assert!(bank.samples[len..].iter().all(|&sample| sample == 0.0));
+    }
+}
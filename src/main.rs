@@ -1,21 +1,480 @@
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::mpsc;
+use clap::Parser;
+use cpal::traits::{DeviceTrait, HostTrait};
+use crossterm::event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use looper_proto::{
+    build_streams, clock_beats, clock_bpm, clock_tick, measure_latency, Command, CompressorSettings, Devices, Effect,
+    EqBand, Event, HalveMode, InputFilter, Looper, LooperBuilder, Metronome, NoiseGate, RecordMode, RmsMeter, State,
+    TapAction, TapConfig, CLOCK_TICKS_PER_BEAT, STATUS_TARGET,
+};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
 
-fn main() -> anyhow::Result<()> {
-    // Set up an audio Device.
-    let host = cpal::default_host();
+// Command-line options. Most of them can go in the config file instead (see
+// Config), so they only override it when given.
+#[derive(Parser)]
+#[command(about = "Looper that records onto several layers at once.")]
+struct Args {
+    /// Config file to read (default: looper_proto/config.toml in the user's
+    /// config directory, if it's there)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Name of the device to record from (default: the system default)
+    #[arg(long)]
+    input: Option<String>,
+    /// Name of the device to play back on (default: the system default)
+    #[arg(long)]
+    output: Option<String>,
+    /// Audio buffer size, in frames (default: whatever the device prefers)
+    #[arg(long, value_name = "FRAMES")]
+    buffer_size: Option<u32>,
+    /// Audio system to use, as default or jack (default: default)
+    #[arg(long)]
+    backend: Option<Backend>,
+    /// List the available audio devices and exit
+    #[arg(long)]
+    list_devices: bool,
+    /// Measure the round-trip latency (with the output looped back to the
+    /// input), save it for --latency to default to, and exit
+    #[arg(long)]
+    calibrate: bool,
+    /// Metronome tempo to start with (default: 120)
+    #[arg(long)]
+    bpm: Option<f32>,
+    /// How loud to play the whole mix (default: 1)
+    #[arg(long, value_name = "GAIN")]
+    master_gain: Option<f32>,
+    /// How many independent loops to have, each with its own length (default:
+    /// 1)
+    #[arg(long)]
+    tracks: Option<usize>,
+    /// Keep every track in phase, with each one's length a whole multiple of
+    /// the first one recorded
+    #[arg(long)]
+    sync: bool,
+    /// Turn the whole mix up or down every time a layer's recorded, so its
+    /// peak stays at --normalize-db (toggle with a)
+    #[arg(long)]
+    normalize: bool,
+    /// Peak level to normalize the mix to, in dBFS (default: -1)
+    #[arg(long, value_name = "DB", allow_negative_numbers = true)]
+    normalize_db: Option<f32>,
+    /// How far back the RMS level meter averages over, in milliseconds
+    /// (default: 300)
+    #[arg(long, value_name = "MS")]
+    rms_window_ms: Option<f32>,
+    /// Make the first loop exactly this long, in seconds, closing it as soon
+    /// as that much is recorded (or padding it with silence if it's closed
+    /// early)
+    #[arg(long, value_name = "SECS")]
+    loop_secs: Option<f32>,
+    /// Stop recording once a track has this many layers (default: no limit)
+    #[arg(long, value_name = "N")]
+    max_layers: Option<usize>,
+    /// Stop recording once a track has this many seconds recorded, across
+    /// all its layers (default: no limit)
+    #[arg(long, value_name = "SECS")]
+    max_record_secs: Option<f32>,
+    /// How long to crossfade the end of the loop into its start, in
+    /// milliseconds (default: 5)
+    #[arg(long, value_name = "MS")]
+    crossfade_ms: Option<f32>,
+    /// How late input arrives, in frames, so overdubs can be recorded that
+    /// much earlier to line up with the loop (default: whatever --calibrate
+    /// measured last, if anything, or 0)
+    #[arg(long, value_name = "FRAMES")]
+    latency: Option<usize>,
+    /// How much input from just before recording starts to keep, in
+    /// milliseconds, in case recording starts late (default: 0)
+    #[arg(long, value_name = "MS")]
+    preroll_ms: Option<f32>,
+    /// How long to fade in and out when playback starts and stops, in
+    /// milliseconds (default: 10)
+    #[arg(long, value_name = "MS")]
+    fade_ms: Option<f32>,
+    /// Take out any DC offset in the input before recording it
+    #[arg(long)]
+    dc_block: bool,
+    /// Gate the input, silencing it whenever it's quieter than this, in
+    /// dBFS (default: no gate)
+    #[arg(long, value_name = "DB", allow_negative_numbers = true)]
+    gate_db: Option<f32>,
+    /// How quickly the gate opens once the input gets loud enough, in
+    /// milliseconds (default: 1)
+    #[arg(long, value_name = "MS")]
+    gate_attack_ms: Option<f32>,
+    /// How quickly it closes again, in milliseconds (default: 100)
+    #[arg(long, value_name = "MS")]
+    gate_release_ms: Option<f32>,
+    /// Level above which the compressor on the whole mix starts turning it
+    /// down, in dBFS (default: -6)
+    #[arg(long, value_name = "DB", allow_negative_numbers = true)]
+    compress_threshold_db: Option<f32>,
+    /// How hard it turns it down, e.g. 4 for 4:1 (default: 4)
+    #[arg(long)]
+    compress_ratio: Option<f32>,
+    /// How quickly it reacts to the mix getting louder, in milliseconds
+    /// (default: 5)
+    #[arg(long, value_name = "MS")]
+    compress_attack_ms: Option<f32>,
+    /// How quickly it lets go once it's quieter again, in milliseconds
+    /// (default: 100)
+    #[arg(long, value_name = "MS")]
+    compress_release_ms: Option<f32>,
+    /// How loud to play the input when monitoring it (hit n) (default: 1)
+    #[arg(long, value_name = "GAIN")]
+    monitor_gain: Option<f32>,
+    /// What the first few taps do, in order (default: record,set-length)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    taps: Option<Vec<TapAction>>,
+    /// What the taps after those do, over and over (default: overdub)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    then_taps: Option<Vec<TapAction>>,
+    /// How overdubs go onto the loop, as layer, overdub, or replace (toggle
+    /// with o) (default: layer)
+    #[arg(long, value_name = "MODE")]
+    record_mode: Option<RecordMode>,
+    /// Name of a MIDI input to take commands from, e.g. a footswitch
+    #[arg(long, value_name = "PORT")]
+    midi_port: Option<String>,
+    /// MIDI message that taps, as note:N or cc:N (default: cc:80)
+    #[arg(long, value_name = "MESSAGE")]
+    midi_tap: Option<MidiTrigger>,
+    /// MIDI message that undoes the last layer (default: cc:81)
+    #[arg(long, value_name = "MESSAGE")]
+    midi_undo: Option<MidiTrigger>,
+    /// MIDI message that clears everything (default: cc:82)
+    #[arg(long, value_name = "MESSAGE")]
+    midi_clear: Option<MidiTrigger>,
+    /// MIDI message that stops or restarts playback (default: cc:83)
+    #[arg(long, value_name = "MESSAGE")]
+    midi_play: Option<MidiTrigger>,
+    /// Follow the MIDI clock and start/stop coming in on the MIDI input
+    #[arg(long)]
+    midi_clock_in: bool,
+    /// Name of a MIDI output to send clock and start/stop to, so other gear
+    /// follows the loop
+    #[arg(long, value_name = "PORT")]
+    midi_clock_port: Option<String>,
+    /// Follow the tempo, bars, and start/stop of an Ableton Link session
+    /// (needs building with --features link)
+    #[arg(long)]
+    link: bool,
+    /// UDP port to listen for OSC commands on (/looper/tap, /looper/undo,
+    /// /looper/clear, /looper/play, and /looper/gain)
+    #[arg(long, value_name = "PORT")]
+    osc_port: Option<u16>,
+    /// Where to send OSC status updates (default: wherever the last OSC
+    /// command came from)
+    #[arg(long, value_name = "HOST:PORT")]
+    osc_reply: Option<std::net::SocketAddr>,
+    /// Use the full-screen UI, which only has the basic controls
+    #[arg(long)]
+    tui: bool,
+    /// Don't read the keyboard at all, just take commands over MIDI and OSC
+    /// until killed
+    #[arg(long, conflicts_with = "tui")]
+    headless: bool,
+    /// Export the loop to this WAV file when quitting
+    #[arg(long, value_name = "PATH")]
+    export_on_exit: Option<PathBuf>,
+}
+
+// Everything that can be set in the config file. Whatever the file leaves
+// out comes from Config::default(), and whatever's given on the command line
+// overrides both (see Config::merge()).
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    input: Option<String>,
+    output: Option<String>,
+    buffer_size: Option<u32>,
+    backend: Backend,
+    bpm: f32,
+    master_gain: f32,
+    tracks: usize,
+    sync: bool,
+    normalize: bool,
+    normalize_db: f32,
+    rms_window_ms: f32,
+    loop_secs: Option<f32>,
+    max_layers: Option<usize>,
+    max_record_secs: Option<f32>,
+    crossfade_ms: f32,
+    latency: Option<usize>,
+    preroll_ms: f32,
+    fade_ms: f32,
+    dc_block: bool,
+    gate_db: Option<f32>,
+    gate_attack_ms: f32,
+    gate_release_ms: f32,
+    compress_threshold_db: f32,
+    compress_ratio: f32,
+    compress_attack_ms: f32,
+    compress_release_ms: f32,
+    monitor_gain: f32,
+    taps: Vec<TapAction>,
+    then_taps: Vec<TapAction>,
+    record_mode: RecordMode,
+    midi_port: Option<String>,
+    midi_tap: MidiTrigger,
+    midi_undo: MidiTrigger,
+    midi_clear: MidiTrigger,
+    midi_play: MidiTrigger,
+    midi_clock_in: bool,
+    midi_clock_port: Option<String>,
+    link: bool,
+    osc_port: Option<u16>,
+    osc_reply: Option<std::net::SocketAddr>,
+    keys: KeyBindings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let taps = TapConfig::default();
+        let compressor = CompressorSettings::default();
+        Self {
+            input: None,
+            output: None,
+            buffer_size: None,
+            backend: Backend::Default,
+            bpm: 120.0,
+            master_gain: 1.0,
+            tracks: 1,
+            sync: false,
+            normalize: false,
+            normalize_db: -1.0,
+            rms_window_ms: 300.0,
+            loop_secs: None,
+            max_layers: None,
+            max_record_secs: None,
+            crossfade_ms: 5.0,
+            latency: None,
+            preroll_ms: 0.0,
+            fade_ms: 10.0,
+            dc_block: false,
+            gate_db: None,
+            gate_attack_ms: 1.0,
+            gate_release_ms: 100.0,
+            compress_threshold_db: compressor.threshold_db,
+            compress_ratio: compressor.ratio,
+            compress_attack_ms: compressor.attack_ms,
+            compress_release_ms: compressor.release_ms,
+            monitor_gain: 1.0,
+            taps: taps.taps,
+            then_taps: taps.then,
+            record_mode: RecordMode::Layer,
+            midi_port: None,
+            midi_tap: MidiTrigger::Cc(80),
+            midi_undo: MidiTrigger::Cc(81),
+            midi_clear: MidiTrigger::Cc(82),
+            midi_play: MidiTrigger::Cc(83),
+            midi_clock_in: false,
+            midi_clock_port: None,
+            link: false,
+            osc_port: None,
+            osc_reply: None,
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+impl Config {
+    // Read the config file at path, or at default_config_path() if there's
+    // one there. With neither, it's all defaults.
+    fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_config_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| anyhow::anyhow!("couldn't read {}: {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    // Override whatever was given on the command line.
+    fn merge(mut self, args: &Args) -> Self {
+        fn set<T: Clone>(setting: &mut T, arg: &Option<T>) {
+            if let Some(value) = arg {
+                *setting = value.clone();
+            }
+        }
+        fn set_option<T: Clone>(setting: &mut Option<T>, arg: &Option<T>) {
+            if arg.is_some() {
+                *setting = arg.clone();
+            }
+        }
+
+        set_option(&mut self.input, &args.input);
+        set_option(&mut self.output, &args.output);
+        set_option(&mut self.buffer_size, &args.buffer_size);
+        set(&mut self.backend, &args.backend);
+        set(&mut self.bpm, &args.bpm);
+        set(&mut self.master_gain, &args.master_gain);
+        set(&mut self.tracks, &args.tracks);
+        self.sync |= args.sync;
+        self.normalize |= args.normalize;
+        set(&mut self.normalize_db, &args.normalize_db);
+        set(&mut self.rms_window_ms, &args.rms_window_ms);
+        set_option(&mut self.loop_secs, &args.loop_secs);
+        set_option(&mut self.max_layers, &args.max_layers);
+        set_option(&mut self.max_record_secs, &args.max_record_secs);
+        set(&mut self.crossfade_ms, &args.crossfade_ms);
+        set_option(&mut self.latency, &args.latency);
+        set(&mut self.preroll_ms, &args.preroll_ms);
+        set(&mut self.fade_ms, &args.fade_ms);
+        self.dc_block |= args.dc_block;
+        set_option(&mut self.gate_db, &args.gate_db);
+        set(&mut self.gate_attack_ms, &args.gate_attack_ms);
+        set(&mut self.gate_release_ms, &args.gate_release_ms);
+        set(&mut self.compress_threshold_db, &args.compress_threshold_db);
+        set(&mut self.compress_ratio, &args.compress_ratio);
+        set(&mut self.compress_attack_ms, &args.compress_attack_ms);
+        set(&mut self.compress_release_ms, &args.compress_release_ms);
+        set(&mut self.monitor_gain, &args.monitor_gain);
+        set(&mut self.taps, &args.taps);
+        set(&mut self.then_taps, &args.then_taps);
+        set(&mut self.record_mode, &args.record_mode);
+        set_option(&mut self.midi_port, &args.midi_port);
+        set(&mut self.midi_tap, &args.midi_tap);
+        set(&mut self.midi_undo, &args.midi_undo);
+        set(&mut self.midi_clear, &args.midi_clear);
+        set(&mut self.midi_play, &args.midi_play);
+        self.midi_clock_in |= args.midi_clock_in;
+        set_option(&mut self.midi_clock_port, &args.midi_clock_port);
+        self.link |= args.link;
+        set_option(&mut self.osc_port, &args.osc_port);
+        set_option(&mut self.osc_reply, &args.osc_reply);
+        self
+    }
+}
+
+// How many frames long secs seconds is, to the nearest frame. Frames are
+// the same length whatever the channel count.
+fn secs_to_frames(secs: f32, sample_rate: u32) -> usize {
+    (secs.max(0.0) * sample_rate as f32).round() as usize
+}
+
+// Where the config file (and anything else we keep between runs) goes.
+fn config_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("looper_proto"))
+}
+
+// Where to look for the config file when there's no --config.
+fn default_config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
+
+// Where --calibrate saves the latency it measured.
+fn latency_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("latency"))
+}
+
+// The latency --calibrate measured last, if it's measured anything.
+fn load_latency() -> Option<usize> {
+    std::fs::read_to_string(latency_path()?).ok()?.trim().parse().ok()
+}
+
+// Measure the round-trip latency between the devices, and save it for next
+// time.
+fn calibrate(audio: &Audio) -> anyhow::Result<()> {
+    let devices = audio.open_devices(false)?;
+    prompt("Loop the output back into the input, then hit ENTER to play a click. ")?;
+    let frames = match measure_latency(&devices)? {
+        Some(frames) => frames,
+        None => anyhow::bail!("Didn't hear the click come back. Is the output looped back into the input, and loud enough?"),
+    };
+    let ms = frames as f32 / devices.config.sample_rate.0 as f32 * 1000.0;
+    println!("Round-trip latency: {} frames ({:.2} ms).", frames, ms);
+
+    let path = latency_path().ok_or_else(|| anyhow::anyhow!("Nowhere to save the latency (no $HOME)."))?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, format!("{}\n", frames))?;
+    println!("Saved to {}; --latency overrides it.", path.display());
+    Ok(())
+}
 
-    let input = host.default_input_device()
-        .expect("no input device available.");
-    let output = host.default_output_device()
-        .expect("no output device available.");
-    println!("Input device: {}", input.name()?);
-    println!("Output device: {}", output.name()?);
+// Which keys run the basic Commands, in either UI.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct KeyBindings {
+    tap: char,
+    undo: char,
+    clear: char,
+    play: char,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            tap: ' ',
+            undo: 'u',
+            clear: 'c',
+            play: 'p',
+        }
+    }
+}
+
+impl KeyBindings {
+    // The Command bound to code, if any. Enter always taps too.
+    fn command(&self, code: KeyCode) -> Option<Command> {
+        match code {
+            KeyCode::Enter => Some(Command::Tap),
+            KeyCode::Char(c) if c == self.tap => Some(Command::Tap),
+            KeyCode::Char(c) if c == self.undo => Some(Command::Undo),
+            KeyCode::Char(c) if c == self.clear => Some(Command::Clear),
+            KeyCode::Char(c) if c == self.play => Some(Command::TogglePlayback),
+            _ => None,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // Nothing gets logged unless RUST_LOG asks for it (e.g. RUST_LOG=debug,
+    // or RUST_LOG=looper_proto=trace for everything, clips and all). Logs go
+    // to stderr, so they can be sent somewhere other than the UI. What the
+    // looper says it's done (see STATUS_TARGET) always goes to stdout, as
+    // it is.
+    let logs = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off")));
+    tracing_subscriber::registry().with(logs).with(status_layer(std::io::stdout)).init();
+    if args.list_devices {
+        return list_devices();
+    }
+    let config = Config::load(args.config.as_deref())?.merge(&args);
 
-    let config: cpal::StreamConfig = output.default_input_config()?.into();
-    println!("Output config:  {:?}", config);
+    let audio = Audio::new(&config);
+    if args.calibrate {
+        return calibrate(&audio);
+    }
+    let devices = audio.open_devices(false)?;
+    if devices.input_config.sample_rate != devices.config.sample_rate {
+        println!(
+            "Resampling input from {} Hz to {} Hz.",
+            devices.input_config.sample_rate.0, devices.config.sample_rate.0
+        );
+    }
 
     // Design notes:
     //
@@ -44,268 +503,2027 @@ fn main() -> anyhow::Result<()> {
     // playback:
     // sample_idx = 0..loop_len-1
 
-    let mut looper = Looper::new();
-    let input_state = looper.state.clone();
-    let mut output_state = looper.state.clone();
+    let rate = devices.config.sample_rate.0;
+    let channels = devices.config.channels;
+    let mut builder = LooperBuilder::new()
+        .sample_rate(rate)
+        .channels(channels)
+        // Start with ten seconds' worth of room; the bank grows as needed.
+        .bank_capacity(rate as usize * channels as usize * 10)
+        .record_mode(config.record_mode)
+        .compressor(CompressorSettings {
+            threshold_db: config.compress_threshold_db,
+            ratio: config.compress_ratio,
+            attack_ms: config.compress_attack_ms.max(0.0),
+            release_ms: config.compress_release_ms.max(0.0),
+        });
+    if let Some(max) = config.max_layers {
+        builder = builder.max_layers(max);
+    }
+    let mut looper = builder.build();
+    looper.tap_config = TapConfig::new(config.taps.clone(), config.then_taps.clone());
+    looper.state.set_master_gain(config.master_gain);
+    looper.state.set_monitor_gain(config.monitor_gain);
+    if let Some(secs) = config.loop_secs {
+        looper.state.set_fixed_len(secs_to_frames(secs, looper.sample_rate));
+    }
+    if let Some(secs) = config.max_record_secs {
+        looper.state.set_max_record_len(secs_to_frames(secs, looper.sample_rate));
+    }
+    looper.state.set_crossfade_len((config.crossfade_ms.max(0.0) / 1000.0 * looper.sample_rate as f32) as usize);
+    looper.state.set_latency(config.latency.or_else(load_latency).unwrap_or(0));
+    looper.state.set_synced(config.sync);
+    looper.normalize = config.normalize;
+    looper.normalize_db = config.normalize_db;
+    looper.set_track_count(config.tracks);
+    looper.metronome = Metronome::new(config.bpm, 4, devices.config.sample_rate.0);
+    looper.rms = RmsMeter::new(config.rms_window_ms.max(1.0), devices.config.sample_rate.0);
+    audio.start(&mut looper, &devices)?;
+
+    // Ctrl-C only reaches us as a signal outside of raw mode (e.g. while
+    // prompting for a file name, or running headless), but it should still
+    // get us out cleanly. So should SIGTERM, e.g. from systemd.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let handler_shutdown = shutdown.clone();
+    ctrlc::set_handler(move || handler_shutdown.store(true, Ordering::Relaxed))?;
+    let panicked = Arc::new(AtomicBool::new(false));
+    set_panic_hook(shutdown.clone(), panicked.clone());
+
+    // The connection has to stay open for as long as the UI runs.
+    let _midi = match &config.midi_port {
+        Some(port) => {
+            let map = MidiMap {
+                tap: config.midi_tap,
+                undo: config.midi_undo,
+                clear: config.midi_clear,
+                play: config.midi_play,
+                clock: config.midi_clock_in,
+            };
+            Some(connect_midi(port, map, looper.command_sender())?)
+        },
+        None => None,
+    };
+
+    if let Some(port) = &config.midi_clock_port {
+        // Other tracks either follow the first one or go their own way, so
+        // there's only one loop worth following.
+        let state = looper.tracks[0].state.clone();
+        send_midi_clock(port, state, looper.metronome.clone(), looper.sample_rate)?;
+    }
+
+    if config.link {
+        join_link(&mut looper);
+    }
+
+    if let Some(port) = config.osc_port {
+        listen_osc(port, config.osc_reply, looper.command_sender(), looper.state.clone())?;
+    }
+
+    let ui = if args.headless {
+        if config.midi_port.is_none() && config.osc_port.is_none() {
+            println!("WARNING: running headless without --midi-port or --osc-port, so nothing can control the looper.");
+        }
+        Ui::Headless
+    } else if args.tui {
+        Ui::Tui
+    } else {
+        Ui::Text
+    };
+    init_ui(&mut looper, audio, &config.keys, &shutdown, ui)?;
+    finish(&mut looper, panicked.load(Ordering::Relaxed), args.export_on_exit.as_deref())
+}
+
+// Writes each of the looper's status messages on a line of its own, and
+// nothing else.
+fn status_layer<S, W>(writer: W) -> impl tracing_subscriber::Layer<S>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    W: for<'a> tracing_subscriber::fmt::MakeWriter<'a> + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .without_time()
+        .with_level(false)
+        .with_target(false)
+        .with_filter(Targets::new().with_target(STATUS_TARGET, tracing::Level::INFO))
+}
+
+// Once the UI's done, stop the streams, then export the loop to export_path
+// if there is one.
+fn finish(looper: &mut Looper, panicked: bool, export_path: Option<&Path>) -> anyhow::Result<()> {
+    if panicked {
+        // Whatever panicked might have been holding a bank, so don't try to
+        // export it; just stop the streams.
+        looper.shutdown(None)?;
+        anyhow::bail!("Stopped after a panic.");
+    }
+    looper.shutdown(export_path)
+}
+
+// If anything panics, on any thread, put the terminal back to normal before
+// the panic gets printed, so it's readable (and the shell's usable again
+// afterwards), then shut down as if Ctrl-C had been hit, which stops the
+// streams. Without this, a panic in an audio callback just quietly takes
+// its stream down with it. The full-screen UI adds its own hook on top of
+// this one to leave the alternate screen (see ratatui::try_init()).
+fn set_panic_hook(shutdown: Arc<AtomicBool>, panicked: Arc<AtomicBool>) {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // This does nothing if raw mode isn't on.
+        let _ = terminal::disable_raw_mode();
+        default_hook(info);
+        panicked.store(true, Ordering::Relaxed);
+        shutdown.store(true, Ordering::Relaxed);
+    }));
+}
+
+// How long to wait before trying to reopen the streams after an error,
+// doubling on every failed attempt up to RECONNECT_MAX_DELAY.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(8);
+
+// Decides when to reopen the audio streams, given the errors coming out of
+// them. Errors tend to come in bursts (and from both streams at once) when a
+// device goes away, so they all get folded into a single attempt.
+struct Reconnect {
+    // When to make the next attempt, if one's needed.
+    next_attempt: Option<Instant>,
+    delay: Duration,
+}
+
+impl Reconnect {
+    fn new() -> Self {
+        Self {
+            next_attempt: None,
+            delay: RECONNECT_DELAY,
+        }
+    }
+
+    // A stream failed at now.
+    fn error(&mut self, now: Instant) {
+        if self.next_attempt.is_none() {
+            self.next_attempt = Some(now + self.delay);
+        }
+    }
 
-    let (producer, consumer) = mpsc::channel::<Clip>();
+    // Whether it's time to try reopening the streams.
+    fn due(&self, now: Instant) -> bool {
+        self.next_attempt.is_some_and(|at| now >= at)
+    }
 
-    let input_data_fn = move |data: &[f32], _: &cpal::InputCallbackInfo| {
-        if !input_state.recording() {
-            // We're not recording, save nothing.
+    // Reopening the streams at now didn't work; back off before trying again.
+    fn failed(&mut self, now: Instant) {
+        self.delay = (self.delay * 2).min(RECONNECT_MAX_DELAY);
+        self.next_attempt = Some(now + self.delay);
+    }
+
+    // The streams are back.
+    fn succeeded(&mut self) {
+        self.next_attempt = None;
+        self.delay = RECONNECT_DELAY;
+    }
+}
+
+// Opens the audio streams, and opens them again if anything goes wrong
+// with them.
+struct Audio {
+    host: cpal::Host,
+    input: Option<String>,
+    output: Option<String>,
+    buffer_size: Option<u32>,
+    preroll_ms: f32,
+    fade_ms: f32,
+    dc_block: bool,
+    gate_db: Option<f32>,
+    gate_attack_ms: f32,
+    gate_release_ms: f32,
+    // The audio threads can't do much about their own errors, so they send
+    // them here for the UI thread to deal with.
+    errors: mpsc::Sender<cpal::StreamError>,
+    stream_errors: mpsc::Receiver<cpal::StreamError>,
+    reconnect: Reconnect,
+}
+
+impl Audio {
+    fn new(config: &Config) -> Self {
+        let (errors, stream_errors) = mpsc::channel();
+        let host = open_host(config.backend);
+        println!("Audio backend: {}", host.id().name());
+        Self {
+            host,
+            input: config.input.clone(),
+            output: config.output.clone(),
+            buffer_size: config.buffer_size,
+            preroll_ms: config.preroll_ms.max(0.0),
+            fade_ms: config.fade_ms.max(0.0),
+            dc_block: config.dc_block,
+            gate_db: config.gate_db,
+            gate_attack_ms: config.gate_attack_ms.max(0.0),
+            gate_release_ms: config.gate_release_ms.max(0.0),
+            errors,
+            stream_errors,
+            reconnect: Reconnect::new(),
+        }
+    }
+
+    // Find the devices asked for on the command line, or the defaults. With
+    // fallback, a named device that's gone missing is replaced by the default.
+    fn open_devices(&self, fallback: bool) -> anyhow::Result<Devices> {
+        let input = open_device(&self.host, self.input.as_deref(), DeviceKind::Input, fallback)?;
+        let output = open_device(&self.host, self.output.as_deref(), DeviceKind::Output, fallback)?;
+        println!("Input device: {}", input.name()?);
+        println!("Output device: {}", output.name()?);
+
+        let input_supported = input.default_input_config()?;
+        let output_supported = output.default_output_config()?;
+        let mut input_config = input_supported.config();
+        let mut config = output_supported.config();
+        if let Some(frames) = self.buffer_size {
+            input_config.buffer_size = buffer_size(frames, input_supported.buffer_size(), DeviceKind::Input);
+            config.buffer_size = buffer_size(frames, output_supported.buffer_size(), DeviceKind::Output);
+        }
+        let input_format = input_supported.sample_format();
+        let format = output_supported.sample_format();
+        println!("Input config:  {:?} ({:?})", input_config, input_format);
+        println!("Output config: {:?} ({:?})", config, format);
+
+        Ok(Devices {
+            input,
+            input_config,
+            input_format,
+            output,
+            config,
+            format,
+        })
+    }
+
+    // Build the streams between devices and looper. They don't start until
+    // looper.play().
+    fn start(&self, looper: &mut Looper, devices: &Devices) -> anyhow::Result<()> {
+        let preroll_len = (self.preroll_ms / 1000.0 * devices.config.sample_rate.0 as f32) as usize;
+        let fade_len = (self.fade_ms / 1000.0 * devices.config.sample_rate.0 as f32) as usize;
+        let sample_rate = devices.config.sample_rate.0;
+        let gate = self.gate_db.map(|db| NoiseGate::new(db, self.gate_attack_ms, self.gate_release_ms, sample_rate));
+        let input_filter = InputFilter::new(devices.config.channels as usize, sample_rate, self.dc_block, gate);
+        let (input, output) = build_streams(looper, devices, preroll_len, fade_len, input_filter, &self.errors)?;
+        looper.input = Some(input);
+        looper.output = Some(output);
+        Ok(())
+    }
+
+    // Deal with any errors from the streams since last time, reopening them
+    // once it's time to.
+    fn check(&mut self, looper: &mut Looper, now: Instant) {
+        while let Ok(err) = self.stream_errors.try_recv() {
+            tracing::warn!(target: STATUS_TARGET, "Audio stream error: {}", err);
+            self.reconnect.error(now);
+        }
+        if !self.reconnect.due(now) {
             return;
         }
 
-        producer.send(Clip::new(
-                data.to_vec(),
-                input_state.get_write_index(data.len())
-        )).unwrap();
-    };
-    let input_stream = input.build_input_stream(&config, input_data_fn, err_fn)?;
-
-    // Setup output callback & stream.
-    let mut bank = SampleBank::new(vec![0.0; 44100 * 1000]);
-    let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        match consumer.try_recv() {
-            Ok(clip) => {
-                if output_state.is_recording.load(Ordering::SeqCst) {
-                    //println!("clip of length {} at idx {}", clip.samples.len(), clip.start);
-                    bank.write_at(clip.start, &clip.samples);
-                    // Update state to account for newly recorded samples.
-                    output_state.add_sample_count(clip.samples.len());
-                }
+        match self.restart(looper) {
+            Ok(()) => {
+                tracing::info!(target: STATUS_TARGET, "Reconnected.");
+                self.reconnect.succeeded();
             },
-            Err(_) => {
-                // No new clips
+            Err(e) => {
+                tracing::warn!(target: STATUS_TARGET, "Couldn't reconnect: {}", e);
+                self.reconnect.failed(now);
             },
         }
+        // Anything the old streams said on their way out is moot now.
+        while self.stream_errors.try_recv().is_ok() {}
+    }
 
-        if output_state.first_loop() {
-            // Bail; no playback yet.
-            return;
+    // Replace looper's streams with new ones, carrying on from wherever
+    // the loop was.
+    fn restart(&self, looper: &mut Looper) -> anyhow::Result<()> {
+        looper.input = None;
+        looper.output = None;
+
+        let devices = self.open_devices(true)?;
+        // Everything in the bank is at the old rate and channel count.
+        if devices.config.sample_rate.0 != looper.sample_rate
+            || devices.config.channels as usize != looper.state.channels
+        {
+            anyhow::bail!(
+                "output is now {} channel(s) at {} Hz, but the loop is {} channel(s) at {} Hz",
+                devices.config.channels, devices.config.sample_rate.0,
+                looper.state.channels, looper.sample_rate,
+            );
         }
+        self.start(looper, &devices)?;
 
-        // Load the new loop_len
-        let len = output_state.get_loop_len();
-        for sample in data {
-            // Sum up all samples at each corresponding index across loops.
-            let mut sum = 0.0;
-            for loop_offset in 0..output_state.get_loop_count() {
-                let sample_idx = output_state.get_playback() + len * loop_offset;
-                sum += bank.samples[sample_idx];
-            }
-            // TODO dynamic range compression!
-            *sample = sum;
+        // Only start the streams back up if they were running before.
+        if looper.tapped() || looper.metronome.enabled() || looper.state.monitoring() {
+            looper.play()?;
+        }
+        Ok(())
+    }
+}
 
-            output_state.advance_playback();
+// The device called name, or the default one if there's no name. With
+// fallback, use the default if the named one can't be found.
+fn open_device(
+    host: &cpal::Host,
+    name: Option<&str>,
+    kind: DeviceKind,
+    fallback: bool,
+) -> anyhow::Result<cpal::Device> {
+    if let Some(name) = name {
+        match find_device(host, name, kind) {
+            Ok(device) => return Ok(device),
+            Err(e) if !fallback => return Err(e),
+            Err(_) => println!("Can't find {} device \"{}\"; trying the default.", kind.name(), name),
         }
+    }
+
+    let device = match kind {
+        DeviceKind::Input => host.default_input_device(),
+        DeviceKind::Output => host.default_output_device(),
     };
-    let output_stream = output.build_output_stream(&config, output_data_fn, err_fn)?;
+    device.ok_or_else(|| anyhow::anyhow!("no {} device available.", kind.name()))
+}
 
-    looper.input = Some(input_stream);
-    looper.output = Some(output_stream);
+// Which UI to run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Ui {
+    Text,
+    Tui,
+    Headless,
+}
 
-    init_ui(looper);
+// TODO different implementations of this for different platforms.
+// This should be the only platform-specific feature.
+// Run the UI until it's time to quit, which is either when the user asks to
+// or when shutdown gets set: the full-screen one, the plain one that prints
+// as it goes, or none at all. Either of the first two runs the basic
+// Commands from keys.
+fn init_ui(
+    looper: &mut Looper,
+    audio: Audio,
+    keys: &KeyBindings,
+    shutdown: &AtomicBool,
+    ui: Ui,
+) -> anyhow::Result<()> {
+    match ui {
+        Ui::Text => run_text_ui(looper, audio, keys, shutdown),
+        Ui::Tui => run_tui(looper, audio, keys, shutdown),
+        Ui::Headless => run_headless(looper, audio, shutdown),
+    }
+}
 
+// No UI at all: just run whatever comes in from MIDI or OSC, and log what
+// happens, until we get a signal to stop. Nothing here touches stdin, so
+// this works without a terminal.
+fn run_headless(looper: &mut Looper, mut audio: Audio, shutdown: &AtomicBool) -> anyhow::Result<()> {
+    println!("Running headless. Send SIGINT or SIGTERM to quit.");
+    let mut dropouts = looper.dropouts.total();
+    while !shutdown.load(Ordering::Relaxed) {
+        while let Ok(command) = looper.commands.try_recv() {
+            run_command(looper, command);
+        }
+        while let Ok(event) = looper.events.try_recv() {
+            looper.handle_event(event);
+            if let Some(message) = describe_event(looper, event) {
+                tracing::info!(target: STATUS_TARGET, "{}", message);
+            }
+        }
+        audio.check(looper, Instant::now());
+        looper.flatten();
+        looper.make_room();
+        looper.log_recording();
+        if let Some(warning) = looper.dropouts.warning(dropouts) {
+            tracing::warn!(target: STATUS_TARGET, "{}", warning);
+            dropouts = looper.dropouts.total();
+        }
+        std::thread::sleep(UI_TICK);
+    }
     Ok(())
 }
 
-struct SampleBank {
-    samples: Vec<f32>,
+fn run_text_ui(
+    looper: &mut Looper,
+    mut audio: Audio,
+    keys: &KeyBindings,
+    shutdown: &AtomicBool,
+) -> anyhow::Result<()> {
+    // The layer that per-layer controls apply to.
+    let mut layer = 0;
+    // Likewise for the band of the EQ, and the effect to bypass or move.
+    let mut band = EqBand::Low;
+    let mut effect = Effect::Filter;
+
+    println!("Hit SPACE to start recording.");
+    if looper.tracks.len() > 1 {
+        println!("Hit TAB to switch to the next track.");
+    }
+    println!("Hit u to undo the last layer, or r to redo it.");
+    println!("Hit c to clear everything and start over, or C to throw away just the layer being recorded.");
+    println!("Hit p to stop or restart playback, b to play it backwards, or R to start it over from the top.");
+    println!("Hit , or . to play the loop at half or double speed.");
+    println!("Hit - or + to turn the whole mix down or up, or l to check the level.");
+    println!("Hit a to keep the mix's peak level steady as layers go on, or not.");
+    println!("Hit A to make the stereo image narrower or wider.");
+    println!("Hit {{ or }} to sweep the low-pass filter on the whole mix down or up.");
+    println!("Hit j to pick a band of the EQ (low, mid, or high), then J or K to cut or boost it.");
+    println!("Hit ; or ' to shift the whole mix's pitch down or up a semitone.");
+    println!("Hit m to turn the metronome on or off.");
+    println!("Hit n to hear the input along with the loop, or not.");
+    println!("Hit d to turn the delay on or off, ( or ) to shorten or lengthen it, or f for more or less feedback.");
+    println!("Hit v to turn the reverb on or off, V for a wetter or drier mix, or y for a longer or shorter tail.");
+    println!("Hit z to turn the bit-crusher on or off, Z for fewer bits, or h to hold each sample for longer.");
+    println!("Hit W for a wetter or drier mix of all the effects.");
+    println!("Hit F to pick an effect, then X to bypass it, or M or N to move it later or earlier in the chain.");
+    println!("Hit o to switch between layering, overdubbing, and replacing.");
+    println!("Hit t repeatedly to tap out a tempo for the metronome and the first loop.");
+    println!("Hit k to count in before recording the first loop.");
+    println!("Hit g to snap the first loop to the nearest beat, bar, or neither.");
+    println!("Hit e to export the loop to a WAV file, or i to start from one instead.");
+    println!("Hit E to save the whole session, or I to load one.");
+    println!("Hit w to stretch the loop longer or shorter, without changing its pitch.");
+    println!("Hit T to trim the silence off the start and end of the loop.");
+    println!("Hit D to double the loop's length, playing it twice over, or H to halve it.");
+    println!("Hit L to only play the first few layers, or all of them again.");
+    println!("Hit G to glue all the layers together into one (or u straight afterwards to split them up again).");
+    println!("Hit 1-9 to pick a layer, then [ or ] to turn it down or up.");
+    println!("Hit x to mute the layer, s to solo it, B to play it backwards, or < or > to pan it left or right.");
+    println!("Hit Y to have the layer fade away a little (or a lot) more every time round, until it's gone.");
+    println!("Hit q to quit.");
+    let mut clips = looper.meter.clip_count();
+    let mut dropouts = looper.dropouts.total();
+    while !shutdown.load(Ordering::Relaxed) {
+        while let Ok(command) = looper.commands.try_recv() {
+            run_command(looper, command);
+        }
+        while let Ok(event) = looper.events.try_recv() {
+            looper.handle_event(event);
+            if let Some(message) = describe_event(looper, event) {
+                tracing::info!(target: STATUS_TARGET, "{}", message);
+            }
+        }
+
+        let key = match read_key(UI_TICK)? {
+            Some(key) => key,
+            None => {
+                // Nothing pressed; check up on things in the meantime.
+                audio.check(looper, Instant::now());
+                looper.flatten();
+                looper.make_room();
+                looper.log_recording();
+                let new_clips = looper.meter.clip_count();
+                if new_clips > clips {
+                    println!("WARNING: output clipped {} time(s)! Try turning it down.", new_clips - clips);
+                    clips = new_clips;
+                }
+                if let Some(warning) = looper.dropouts.warning(dropouts) {
+                    tracing::warn!(target: STATUS_TARGET, "{}", warning);
+                    dropouts = looper.dropouts.total();
+                }
+                continue;
+            },
+        };
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            // Raw mode swallows SIGINT, so handle Ctrl-C ourselves.
+            break;
+        }
+        if let Some(command) = keys.command(key.code) {
+            run_command(looper, command);
+            continue;
+        }
+
+        match key.code {
+            KeyCode::Tab => {
+                run_command(looper, Command::SelectTrack((looper.track + 1) % looper.tracks.len()));
+            },
+            KeyCode::Char('r') => {
+                if let Err(e) = looper.redo() {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('R') => {
+                if let Err(e) = looper.retrigger() {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('C') => {
+                if let Err(e) = looper.cancel_layer() {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('n') => {
+                if let Err(e) = looper.toggle_monitoring() {
+                    println!("Couldn't start monitoring: {}", e);
+                }
+            },
+            KeyCode::Char('d') => {
+                looper.toggle_delay();
+            },
+            KeyCode::Char(c @ ('(' | ')')) => {
+                let step = if c == ')' { 25.0 } else { -25.0 };
+                looper.set_delay_time(looper.delay.time_ms() + step);
+            },
+            KeyCode::Char('f') => {
+                looper.next_delay_feedback();
+            },
+            KeyCode::Char('W') => {
+                looper.next_wet_mix();
+            },
+            KeyCode::Char('A') => {
+                looper.next_width();
+            },
+            KeyCode::Char('L') => {
+                looper.next_playback_layers();
+            },
+            KeyCode::Char('j') => {
+                band = band.next();
+                println!("eq_{}={:+.1} dB", band, looper.eq.gain_db(band));
+            },
+            KeyCode::Char(c @ ('J' | 'K')) => {
+                let step = if c == 'K' { 1.5 } else { -1.5 };
+                looper.set_band_gain(band, looper.eq.gain_db(band) + step);
+            },
+            KeyCode::Char('v') => {
+                looper.toggle_reverb();
+            },
+            KeyCode::Char('F') => {
+                effect = effect.next();
+                println!("effect={}", effect);
+            },
+            KeyCode::Char('X') => {
+                looper.toggle_bypass(effect);
+            },
+            KeyCode::Char(c @ ('M' | 'N')) => {
+                looper.move_effect(effect, c == 'M');
+            },
+            KeyCode::Char('V') => {
+                looper.next_reverb_mix();
+            },
+            KeyCode::Char('y') => {
+                looper.next_reverb_decay();
+            },
+            KeyCode::Char('z') => {
+                looper.toggle_crush();
+            },
+            KeyCode::Char('Z') => {
+                looper.next_crush_bits();
+            },
+            KeyCode::Char('h') => {
+                looper.next_crush_downsample();
+            },
+            KeyCode::Char('m') => {
+                if let Err(e) = looper.toggle_metronome() {
+                    println!("Couldn't start the metronome: {}", e);
+                }
+            },
+            KeyCode::Char(c @ '1'..='9') => {
+                layer = c as usize - '1' as usize;
+                println!("layer={} gain={:.1}", layer + 1, looper.layer_gain(layer));
+            },
+            KeyCode::Char(c @ ('[' | ']')) => {
+                let step = if c == ']' { 0.1 } else { -0.1 };
+                looper.set_layer_gain(layer, looper.layer_gain(layer) + step);
+                println!("layer={} gain={:.1}", layer + 1, looper.layer_gain(layer));
+            },
+            KeyCode::Char(c @ ('<' | '>')) => {
+                let step = if c == '>' { 0.25 } else { -0.25 };
+                looper.set_layer_pan(layer, looper.layer_pan(layer) + step);
+                println!("layer={} pan={:.2}", layer + 1, looper.layer_pan(layer));
+            },
+            KeyCode::Char('x') => {
+                looper.toggle_mute(layer);
+            },
+            KeyCode::Char('Y') => {
+                looper.next_layer_decay(layer);
+            },
+            KeyCode::Char('B') => {
+                if let Err(e) = looper.toggle_layer_reverse(layer) {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('s') => {
+                looper.toggle_solo(layer);
+            },
+            KeyCode::Char(c @ (',' | '.')) => {
+                let factor = if c == '.' { 2.0 } else { 0.5 };
+                if let Err(e) = looper.set_rate(looper.state.rate() * factor) {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('b') => {
+                if let Err(e) = looper.toggle_reverse() {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char(c @ ('-' | '=' | '+')) => {
+                let step = if c == '-' { -0.1 } else { 0.1 };
+                looper.set_master_gain(looper.state.master_gain() + step);
+            },
+            KeyCode::Char(c @ ('{' | '}')) => {
+                // A third of an octave at a time.
+                let factor = if c == '}' { 2.0_f32.powf(1.0 / 3.0) } else { 2.0_f32.powf(-1.0 / 3.0) };
+                looper.set_cutoff(looper.state.cutoff() * factor);
+            },
+            KeyCode::Char('a') => {
+                looper.toggle_normalize();
+            },
+            KeyCode::Char(c @ (';' | '\'')) => {
+                let step = if c == '\'' { 1.0 } else { -1.0 };
+                looper.set_pitch_semitones(looper.state.pitch_semitones() + step);
+            },
+            KeyCode::Char('l') => {
+                println!("peak={:.1} dBFS rms={:.1} dBFS", looper.meter.peak_dbfs(), looper.rms.rms_dbfs());
+            },
+            KeyCode::Char('o') => {
+                looper.next_record_mode();
+            },
+            KeyCode::Char('t') => {
+                looper.tap_tempo(Instant::now());
+            },
+            KeyCode::Char('k') => {
+                // Cycle between no count-in, one bar, and two bars.
+                let bar = looper.metronome.beats_per_bar;
+                looper.count_in_beats = (looper.count_in_beats + bar) % (bar * 3);
+                println!("count_in={} beat(s)", looper.count_in_beats);
+            },
+            KeyCode::Char('g') => {
+                looper.quantize = looper.quantize.next();
+                println!("quantize={:?}", looper.quantize);
+            },
+            KeyCode::Char('e') => {
+                let path = match prompt("Export to [loop.wav]: ")?.trim() {
+                    "" => "loop.wav".to_string(),
+                    path => path.to_string(),
+                };
+                if let Err(e) = looper.export_wav(Path::new(&path)) {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('i') => {
+                let path = prompt("Import from: ")?;
+                if let Err(e) = looper.import_wav(Path::new(path.trim())) {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('w') => {
+                let ratio = prompt("Stretch to how many times as long (e.g. 0.5 or 2): ")?;
+                match ratio.trim().parse::<f32>() {
+                    Ok(ratio) => {
+                        if let Err(e) = looper.stretch(ratio) {
+                            println!("{}", e);
+                        }
+                    },
+                    Err(e) => println!("That's not a number: {}", e),
+                }
+            },
+            KeyCode::Char('D') => {
+                if let Err(e) = looper.double_length() {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('G') => {
+                if let Err(e) = looper.freeze() {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('H') => {
+                let mode = match prompt("Keep the first half, or mix both halves together? [k/m]: ")?.trim() {
+                    "k" | "" => Some(HalveMode::KeepFirst),
+                    "m" => Some(HalveMode::Mix),
+                    _ => None,
+                };
+                match mode {
+                    Some(mode) => {
+                        if let Err(e) = looper.halve_length(mode) {
+                            println!("{}", e);
+                        }
+                    },
+                    None => println!("That's not k or m."),
+                }
+            },
+            KeyCode::Char('T') => {
+                let threshold = match prompt("Trim silence quieter than how many dBFS [-50]: ")?.trim() {
+                    "" => Ok(-50.0),
+                    db => db.parse::<f32>(),
+                };
+                match threshold {
+                    Ok(db) => {
+                        if let Err(e) = looper.trim_silence(db) {
+                            println!("{}", e);
+                        }
+                    },
+                    Err(e) => println!("That's not a number: {}", e),
+                }
+            },
+            KeyCode::Char('E') => {
+                let path = match prompt("Save session to [session.zip]: ")?.trim() {
+                    "" => "session.zip".to_string(),
+                    path => path.to_string(),
+                };
+                if let Err(e) = looper.save_session(Path::new(&path)) {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('I') => {
+                let path = prompt("Load session from: ")?;
+                if let Err(e) = looper.load_session(Path::new(path.trim())) {
+                    println!("{}", e);
+                }
+            },
+            KeyCode::Char('q') | KeyCode::Esc => {
+                break;
+            },
+            _ => {},
+        }
+    }
+    Ok(())
 }
 
-impl SampleBank {
-    fn new(samples: Vec<f32>) -> Self {
-        Self {
-            samples: samples,
+// Run command, telling the user if it didn't work.
+fn run_command(looper: &mut Looper, command: Command) {
+    if let Err(e) = looper.run(command) {
+        println!("{}", e);
+    }
+}
+
+// What to tell the user about something that happened to the looper, if
+// anything.
+fn describe_event(looper: &Looper, event: Event) -> Option<String> {
+    match event {
+        Event::RecordingStarted => Some("RECORDING.".to_string()),
+        Event::RecordingStopped => Some("STOPPED RECORDING.".to_string()),
+        Event::LoopClosed { track, len } => Some(format!(
+            "{}SET FIRST LOOP LENGTH: {:.2}s ({} at {} BPM).",
+            track_prefix(looper, track), len as f32 / looper.sample_rate as f32,
+            musical_len(looper, len), looper.metronome.bpm()
+        )),
+        Event::LayerAdded { track, index } => Some(format!(
+            "{}RECORDED LAYER {}.", track_prefix(looper, track), index + 1
+        )),
+        // Far too often to mention.
+        Event::PlaybackWrapped { .. } => None,
+        Event::MaxLayers { track } => Some(format!(
+            "{}REACHED THE MOST LAYERS THERE'S ROOM FOR ({}).",
+            track_prefix(looper, track), looper.tracks[track].state.get_loop_count()
+        )),
+        Event::MaxRecording { track } => Some(format!(
+            "{}WARNING: RECORDED AS LONG AS THERE'S ROOM FOR. Undo a layer to make room.",
+            track_prefix(looper, track)
+        )),
+        Event::ClipDropped { track } => Some(format!(
+            "{}WARNING: COULDN'T RECORD SOME INPUT.", track_prefix(looper, track)
+        )),
+        Event::LayerFaded { track, index } => Some(format!(
+            "{}LAYER {} FADED AWAY.", track_prefix(looper, track), index + 1
+        )),
+    }
+}
+
+// How long frames frames are in bars and beats, at the metronome's tempo.
+fn musical_len(looper: &Looper, frames: usize) -> String {
+    let (bars, beats) = looper.metronome.bars_and_beats(frames);
+    let bars = match bars {
+        1 => "1 bar".to_string(),
+        _ => format!("{} bars", bars),
+    };
+    if beats == 0.0 {
+        bars
+    } else {
+        format!("{} {:.2} beats", bars, beats)
+    }
+}
+
+// Which track a message is about, if there's more than one to tell apart.
+fn track_prefix(looper: &Looper, track: usize) -> String {
+    if looper.tracks.len() > 1 {
+        format!("TRACK {}: ", track + 1)
+    } else {
+        String::new()
+    }
+}
+
+// How often the full-screen UI redraws, so playback moves along smoothly.
+const TUI_TICK: Duration = Duration::from_millis(33);
+
+// Full-screen UI showing what the looper's up to as it happens. It only has
+// the basic controls; everything else is in the plain UI.
+fn run_tui(looper: &mut Looper, audio: Audio, keys: &KeyBindings, shutdown: &AtomicBool) -> anyhow::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    // Put the terminal back even if something goes wrong.
+    let result = tui_loop(&mut terminal, looper, audio, keys, shutdown);
+    ratatui::restore();
+    result
+}
+
+fn tui_loop(
+    terminal: &mut ratatui::DefaultTerminal,
+    looper: &mut Looper,
+    mut audio: Audio,
+    keys: &KeyBindings,
+    shutdown: &AtomicBool,
+) -> anyhow::Result<()> {
+    let mut message = "Hit SPACE to start recording.".to_string();
+    while !shutdown.load(Ordering::Relaxed) {
+        while let Ok(command) = looper.commands.try_recv() {
+            if let Err(e) = looper.run(command) {
+                message = e.to_string();
+            }
         }
+        while let Ok(event) = looper.events.try_recv() {
+            looper.handle_event(event);
+            if let Some(text) = describe_event(looper, event) {
+                message = text;
+            }
+        }
+        terminal.draw(|frame| draw_tui(frame, looper, &message))?;
+
+        let key = match next_key(TUI_TICK)? {
+            Some(key) => key,
+            None => {
+                audio.check(looper, Instant::now());
+                looper.flatten();
+                looper.make_room();
+                looper.log_recording();
+                continue;
+            },
+        };
+        let command = match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => break,
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Tab => Command::SelectTrack((looper.track + 1) % looper.tracks.len()),
+            code => match keys.command(code) {
+                Some(command) => command,
+                None => continue,
+            },
+        };
+        if let Err(e) = looper.run(command) {
+            message = e.to_string();
+        }
+        // The looper prints as it goes, which scribbles all over the
+        // screen, so redraw it from scratch.
+        terminal.clear()?;
+    }
+    Ok(())
+}
+
+fn draw_tui(frame: &mut ratatui::Frame, looper: &Looper, message: &str) {
+    use ratatui::layout::{Constraint, Layout};
+    use ratatui::widgets::{Block, Gauge, Paragraph};
+
+    let state = looper.snapshot();
+    let status = if state.counting_in {
+        "COUNTING IN"
+    } else if state.recording {
+        "RECORDING"
+    } else if state.loop_count == 0 {
+        "IDLE"
+    } else if state.playing {
+        "PLAYING"
+    } else {
+        "STOPPED"
+    };
+    // The first loop doesn't have a length until it's closed, so until then
+    // show how much of it there is so far.
+    let loop_len = match state.loop_count {
+        0 => state.total_samples / looper.state.channels,
+        _ => state.loop_len,
+    };
+    let loop_secs = loop_len as f32 / looper.sample_rate as f32;
+    let peak = looper.meter.peak_dbfs();
+
+    let [status_area, playback_area, meter_area, message_area, help_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(3),
+        Constraint::Length(3),
+        Constraint::Length(1),
+        Constraint::Length(1),
+    ]).areas(frame.area());
+    let track = if looper.tracks.len() > 1 {
+        format!("track: {}/{}   ", looper.track + 1, looper.tracks.len())
+    } else {
+        String::new()
+    };
+    frame.render_widget(
+        Paragraph::new(format!(
+            "{}{}   layers: {}   loop: {:.2}s ({})   dropouts: {}",
+            track, status, state.loop_count, loop_secs, musical_len(looper, loop_len), looper.dropouts.total()
+        )),
+        status_area,
+    );
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title("Playback"))
+            .ratio(loop_progress(state.playback, state.loop_len)),
+        playback_area,
+    );
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title("Level"))
+            .ratio(meter_fraction(peak))
+            .label(format!("{:.1} dBFS peak, {:.1} dBFS RMS", peak, looper.rms.rms_dbfs())),
+        meter_area,
+    );
+    frame.render_widget(Paragraph::new(message), message_area);
+    frame.render_widget(Paragraph::new("SPACE tap   u undo   c clear   p play/stop   q quit"), help_area);
+}
+
+// How far through the loop playback is, from 0 up to (but not including) 1.
+fn loop_progress(playback: usize, loop_len: usize) -> f64 {
+    if loop_len == 0 {
+        return 0.0;
     }
+    (playback.min(loop_len - 1) as f64 / loop_len as f64).clamp(0.0, 1.0)
+}
+
+// Quietest level the level meter shows, in dBFS.
+const METER_FLOOR_DB: f32 = -60.0;
+
+// How full the level meter should be for a peak of db dBFS.
+fn meter_fraction(db: f32) -> f64 {
+    ((db - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0) as f64
+}
 
-    // Write new samples contiguously to this SampleBank, starting at idx
-    fn write_at(&mut self, mut idx: usize, samples: &Vec<f32>) {
-        for sample in samples {
-            self.samples[idx] = *sample;
-            idx += 1;
+// Puts the terminal in raw mode for as long as it's alive, so we get
+// keypresses as they happen instead of a line at a time. Dropping it (including
+// while unwinding from a panic) puts the terminal back the way it was.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> anyhow::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// How often the UI checks in on the looper while waiting for a keypress.
+const UI_TICK: Duration = Duration::from_millis(100);
+
+// Wait up to timeout for a single key to be pressed. The terminal is only in
+// raw mode while we wait, so everything else can keep printing normally.
+fn read_key(timeout: Duration) -> anyhow::Result<Option<KeyEvent>> {
+    let _raw = RawMode::enable()?;
+    next_key(timeout)
+}
+
+// Wait up to timeout for a single key to be pressed, with the terminal in
+// whatever mode it's already in.
+fn next_key(timeout: Duration) -> anyhow::Result<Option<KeyEvent>> {
+    while event::poll(timeout)? {
+        if let event::Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                return Ok(Some(key));
+            }
         }
     }
+    Ok(None)
 }
 
-// TODO different implementations of this for different platforms.
-// This should be the only platform-specific feature.
-fn init_ui(mut looper: Looper) {
+// Ask for a whole line of input, e.g. a file name.
+fn prompt(message: &str) -> anyhow::Result<String> {
+    print!("{}", message);
+    std::io::stdout().flush()?;
+
     let mut line = String::new();
-    println!("Hit ENTER to start recording.");
-    loop {
-        let _ = std::io::stdin().read_line(&mut line).unwrap();
-        looper.tap().expect("tap failed!");
-    }
-}
-
-#[derive(Clone)]
-struct State {
-    // Where we are in the playback, relative to the start of each loop layer.
-    // This will always be a number between 0 and loop_len.
-    playback: Arc<AtomicUsize>,
-    // Number of samples in the current loop (i.e. in every loop layer).
-    // This determines when playback resets, as well as how far ahead we're
-    // allowed to write into SampleBank.
-    loop_len: Arc<AtomicUsize>,
-    // The number of partially or completely recorded loops.
-    loop_count: Arc<AtomicUsize>,
-    // Total samples across all loop layers.
-    total_samples: Arc<AtomicUsize>,
-    // Whether we're currently recording new samples,
-    // i.e. writing to SampleBank.
-    is_recording: Arc<AtomicBool>,
-}
-
-impl State {
-    fn new() -> Self {
-        Self {
-            playback: Arc::new(0.into()),
-            loop_len: Arc::new(0.into()),
-            loop_count: Arc::new(0.into()),
-            total_samples: Arc::new(0.into()),
-            is_recording: Arc::new(false.into()),
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line)
+}
+
+// Which of cpal's hosts to open the devices with.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    // Whatever cpal picks for this system, e.g. ALSA on Linux.
+    Default,
+    Jack,
+}
+
+impl Backend {
+    // The host to use, or None for the default one. JACK is only there when
+    // built with the jack feature.
+    fn host_id(self) -> Option<cpal::HostId> {
+        match self {
+            Backend::Default => None,
+            #[cfg(all(feature = "jack", any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd")))]
+            Backend::Jack => Some(cpal::HostId::Jack),
+            #[cfg(not(all(feature = "jack", any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd"))))]
+            Backend::Jack => None,
         }
     }
+}
 
-    fn recording(&self) -> bool {
-        self.is_recording.load(Ordering::SeqCst)
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Backend::Default => "default",
+            Backend::Jack => "jack",
+        };
+        write!(f, "{}", name)
     }
+}
 
-    fn toggle_recording(&self) {
-        let rec = self.recording();
-        self.is_recording.store(!rec, Ordering::SeqCst);
+// The host for backend, or the default one (with a warning) if that isn't
+// available, e.g. because the JACK server isn't running.
+fn open_host(backend: Backend) -> cpal::Host {
+    if backend == Backend::Default {
+        return cpal::default_host();
     }
+    let host = match backend.host_id() {
+        Some(id) => cpal::host_from_id(id).map_err(anyhow::Error::from),
+        None => Err(anyhow::anyhow!("not built with --features {}", backend)),
+    };
+    host.unwrap_or_else(|e| {
+        println!("WARNING: couldn't use the {} backend ({}), so using the default one instead.", backend, e);
+        cpal::default_host()
+    })
+}
 
-    fn first_loop(&self) -> bool {
-        self.get_loop_count() == 0
+#[derive(Clone, Copy)]
+enum DeviceKind {
+    Input,
+    Output,
+}
+
+impl DeviceKind {
+    fn name(self) -> &'static str {
+        match self {
+            DeviceKind::Input => "input",
+            DeviceKind::Output => "output",
+        }
     }
+}
+
+// Every device of the given kind on host, along with its name.
+fn enumerate_devices(
+    host: &cpal::Host,
+    kind: DeviceKind,
+) -> anyhow::Result<Vec<(String, cpal::Device)>> {
+    let devices: Box<dyn Iterator<Item = cpal::Device>> = match kind {
+        DeviceKind::Input => Box::new(host.input_devices()?),
+        DeviceKind::Output => Box::new(host.output_devices()?),
+    };
+    Ok(devices
+        .filter_map(|device| device.name().ok().map(|name| (name, device)))
+        .collect())
+}
 
-    fn began_recording(&self) -> bool {
-        self.recording() || !self.first_loop()
+// Find the device called name (see match_device_name()) on host, failing
+// with a list of what's available if there isn't one.
+fn find_device(
+    host: &cpal::Host,
+    name: &str,
+    kind: DeviceKind,
+) -> anyhow::Result<cpal::Device> {
+    let mut devices = enumerate_devices(host, kind)?;
+    let names: Vec<&str> = devices.iter().map(|(name, _)| name.as_str()).collect();
+
+    match match_device_name(&names, name) {
+        Some(idx) => Ok(devices.swap_remove(idx).1),
+        None => anyhow::bail!(
+            "No {} device matches \"{}\". Available {} devices:\n  {}",
+            kind.name(), name, kind.name(), names.join("\n  ")
+        ),
     }
+}
 
-    fn get_playback(&self) -> usize {
-        self.playback.load(Ordering::SeqCst)
+// Check a requested buffer size (in frames) against what a device supports,
+// returning None if it's out of range. If the device doesn't say what it
+// supports, we just have to try it.
+fn check_buffer_size(frames: u32, supported: &cpal::SupportedBufferSize) -> Option<u32> {
+    match supported {
+        cpal::SupportedBufferSize::Range { min, max } if frames < *min || frames > *max => None,
+        _ => Some(frames),
     }
+}
 
-    fn get_loop_len(&self) -> usize {
-        self.loop_len.load(Ordering::SeqCst)
+// The buffer size to ask a device for, given the one requested on the
+// command line. Falls back to the default if the device can't do it.
+fn buffer_size(
+    frames: u32,
+    supported: &cpal::SupportedBufferSize,
+    kind: DeviceKind,
+) -> cpal::BufferSize {
+    match check_buffer_size(frames, supported) {
+        Some(frames) => cpal::BufferSize::Fixed(frames),
+        None => {
+            println!(
+                "WARNING: {} device doesn't support a buffer size of {} ({:?}); using the default.",
+                kind.name(), frames, supported
+            );
+            cpal::BufferSize::Default
+        },
     }
+}
+
+// Everything --list-devices shows about a device.
+struct DeviceInfo {
+    name: String,
+    is_default: bool,
+    configs: Vec<ConfigInfo>,
+}
+
+// One range of configurations a device supports.
+struct ConfigInfo {
+    channels: u16,
+    min_sample_rate: u32,
+    max_sample_rate: u32,
+    sample_format: cpal::SampleFormat,
+}
+
+impl DeviceInfo {
+    fn new(name: String, device: &cpal::Device, kind: DeviceKind, default_name: Option<&str>) -> Self {
+        let configs: Vec<cpal::SupportedStreamConfigRange> = match kind {
+            DeviceKind::Input => device.supported_input_configs()
+                .map(|configs| configs.collect())
+                .unwrap_or_default(),
+            DeviceKind::Output => device.supported_output_configs()
+                .map(|configs| configs.collect())
+                .unwrap_or_default(),
+        };
 
-    fn get_loop_count(&self) -> usize {
-        self.loop_count.load(Ordering::SeqCst)
+        Self {
+            is_default: default_name == Some(name.as_str()),
+            name,
+            configs: configs.iter().map(|config| ConfigInfo {
+                channels: config.channels(),
+                min_sample_rate: config.min_sample_rate().0,
+                max_sample_rate: config.max_sample_rate().0,
+                sample_format: config.sample_format(),
+            }).collect(),
+        }
     }
+}
 
-    fn get_total_samples(&self) -> usize {
-        self.total_samples.load(Ordering::SeqCst)
+// Describe a device and its supported configs, one per line.
+fn format_device(device: &DeviceInfo) -> String {
+    let mut formatted = format!("    {}", device.name);
+    if device.is_default {
+        formatted.push_str(" (default)");
     }
+    formatted.push('\n');
 
-    // Get the current index at which we should start writing new Clips.
-    // Takes an offset (number of samples) to subtract from the playback idx
-    // on subsequent loops. The first time through the loop, we don't worry
-    // about the offset because we don't need to account for playback: we're
-    // we're just tacking onto the end of whatever's been recorded so far.
-    fn get_write_index(&self, offset: usize) -> usize {
-        if self.first_loop() {
-            self.get_total_samples()
+    for config in &device.configs {
+        let rate = if config.min_sample_rate == config.max_sample_rate {
+            format!("{} Hz", config.min_sample_rate)
         } else {
-            self.get_loop_count() * self.get_loop_len()
-                + self.get_playback() - offset
+            format!("{}-{} Hz", config.min_sample_rate, config.max_sample_rate)
+        };
+        formatted.push_str(&format!(
+            "      {} ch, {}, {:?}\n",
+            config.channels, rate, config.sample_format
+        ));
+    }
+    formatted
+}
+
+// Print every input and output device on every available host.
+fn list_devices() -> anyhow::Result<()> {
+    for host_id in cpal::available_hosts() {
+        let host = cpal::host_from_id(host_id)?;
+        println!("{}:", host_id.name());
+
+        for kind in [DeviceKind::Input, DeviceKind::Output].iter() {
+            let default = match kind {
+                DeviceKind::Input => host.default_input_device(),
+                DeviceKind::Output => host.default_output_device(),
+            };
+            let default_name = default.and_then(|device| device.name().ok());
+
+            println!("  {} devices:", kind.name());
+            for (name, device) in enumerate_devices(&host, *kind)? {
+                let info = DeviceInfo::new(name, &device, *kind, default_name.as_deref());
+                print!("{}", format_device(&info));
+            }
         }
     }
+    Ok(())
+}
+
+// Pick the device name matching wanted: an exact match if there is one,
+// otherwise the only name containing it, ignoring case. Returns the index of
+// the match in names.
+fn match_device_name(names: &[&str], wanted: &str) -> Option<usize> {
+    if let Some(idx) = names.iter().position(|name| *name == wanted) {
+        return Some(idx);
+    }
 
-    fn inc_loop_count(&mut self) {
-        let count = self.get_loop_count();
-        self.loop_count.store(count + 1, Ordering::SeqCst);
+    let wanted = wanted.to_lowercase();
+    let mut matches = names.iter()
+        .enumerate()
+        .filter(|(_, name)| name.to_lowercase().contains(&wanted));
+    match (matches.next(), matches.next()) {
+        (Some((idx, _)), None) => Some(idx),
+        _ => None,
     }
+}
+
+// A MIDI message that triggers a Command: a Note On, or a control change
+// going to 64 or more (i.e. a pedal or button being pressed), on any
+// channel.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+enum MidiTrigger {
+    Note(u8),
+    Cc(u8),
+}
 
-    fn add_sample_count(&mut self, n: usize) {
-        let new_total = self.get_total_samples() + n;
-        self.total_samples.store(new_total, Ordering::SeqCst);
+impl std::str::FromStr for MidiTrigger {
+    type Err = anyhow::Error;
 
-        if self.first_loop() {
-            self.loop_len.store(self.get_loop_len() + n, Ordering::SeqCst);
+    // Parse note:N or cc:N.
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (kind, number) = s.split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected note:N or cc:N, not \"{}\"", s))?;
+        let number: u8 = number.parse()?;
+        if number > 127 {
+            anyhow::bail!("MIDI note and controller numbers only go up to 127");
+        }
+        match kind {
+            "note" => Ok(MidiTrigger::Note(number)),
+            "cc" => Ok(MidiTrigger::Cc(number)),
+            _ => anyhow::bail!("expected note:N or cc:N, not \"{}\"", s),
         }
     }
+}
 
-    fn advance_playback(&mut self) {
-        if !self.began_recording() {
-            return;
+impl std::convert::TryFrom<String> for MidiTrigger {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+// Which MIDI messages trigger which Commands.
+struct MidiMap {
+    tap: MidiTrigger,
+    undo: MidiTrigger,
+    clear: MidiTrigger,
+    play: MidiTrigger,
+    // Whether to follow clock and start/stop.
+    clock: bool,
+}
+
+// The Command a raw MIDI message triggers, if any. stamp is when it
+// arrived, in microseconds, by the MIDI input's clock.
+fn midi_command(stamp: u64, message: &[u8], map: &MidiMap) -> Option<Command> {
+    let trigger = match *message {
+        [MIDI_CLOCK] if map.clock => return Some(Command::ClockPulse(Duration::from_micros(stamp))),
+        // Continue carries on from wherever the other device was, which
+        // isn't necessarily anywhere near the start of the loop, so it
+        // starts from the top too.
+        [MIDI_START] | [MIDI_CONTINUE] if map.clock => return Some(Command::ClockStart),
+        [MIDI_STOP] if map.clock => return Some(Command::ClockStop),
+        [status, note, velocity] if status & 0xf0 == 0x90 && velocity > 0 => MidiTrigger::Note(note),
+        [status, controller, value] if status & 0xf0 == 0xb0 && value >= 64 => MidiTrigger::Cc(controller),
+        _ => return None,
+    };
+
+    [
+        (map.tap, Command::Tap),
+        (map.undo, Command::Undo),
+        (map.clear, Command::Clear),
+        (map.play, Command::TogglePlayback),
+    ]
+        .iter()
+        .find(|(mapped, _)| *mapped == trigger)
+        .map(|&(_, command)| command)
+}
+
+// Listen to the MIDI input called name (see match_device_name()), sending
+// the commands it triggers to commands. Commands only get sent for as long
+// as the returned connection is kept around.
+fn connect_midi(
+    name: &str,
+    map: MidiMap,
+    commands: mpsc::Sender<Command>,
+) -> anyhow::Result<midir::MidiInputConnection<()>> {
+    let midi = midir::MidiInput::new("looper_proto")?;
+    let ports = midi.ports();
+    let names: Vec<String> = ports.iter()
+        .map(|port| midi.port_name(port).unwrap_or_default())
+        .collect();
+    let name_refs: Vec<&str> = names.iter().map(String::as_str).collect();
+    let idx = match_device_name(&name_refs, name).ok_or_else(|| anyhow::anyhow!(
+        "No MIDI input matches \"{}\". Available MIDI inputs:\n  {}",
+        name, name_refs.join("\n  ")
+    ))?;
+    println!("MIDI input: {}", names[idx]);
+
+    midi.connect(&ports[idx], "looper_proto", move |stamp, message, _| {
+        if let Some(command) = midi_command(stamp, message, &map) {
+            let _ = commands.send(command);
         }
+    }, ())
+        .map_err(|e| anyhow::anyhow!("couldn't connect to MIDI input: {}", e))
+}
+
+// MIDI real-time messages.
+const MIDI_CLOCK: u8 = 0xf8;
+const MIDI_START: u8 = 0xfa;
+const MIDI_CONTINUE: u8 = 0xfb;
+const MIDI_STOP: u8 = 0xfc;
+
+// How often to check whether the next clock tick's due. Ticks come every
+// 20ms or so at 120 bpm, so this keeps them within a millisecond.
+const CLOCK_POLL_INTERVAL: Duration = Duration::from_millis(1);
 
-        let mut playback = self.get_playback();
-        playback += 1;
+// Send MIDI clock to the MIDI output called name (see match_device_name())
+// in the background, following state's loop: Start once it starts playing,
+// Stop once it's stopped or cleared, and in between, as many ticks per loop
+// as there are whole beats in it at the metronome's tempo (see
+// clock_beats()).
+fn send_midi_clock(name: &str, state: State, metronome: Metronome, sample_rate: u32) -> anyhow::Result<()> {
+    let midi = midir::MidiOutput::new("looper_proto")?;
+    let ports = midi.ports();
+    let names: Vec<String> = ports.iter()
+        .map(|port| midi.port_name(port).unwrap_or_default())
+        .collect();
+    let name_refs: Vec<&str> = names.iter().map(String::as_str).collect();
+    let idx = match_device_name(&name_refs, name).ok_or_else(|| anyhow::anyhow!(
+        "No MIDI output matches \"{}\". Available MIDI outputs:\n  {}",
+        name, name_refs.join("\n  ")
+    ))?;
+    println!("MIDI clock output: {}", names[idx]);
+    let mut output = midi.connect(&ports[idx], "looper_proto")
+        .map_err(|e| anyhow::anyhow!("couldn't connect to MIDI output: {}", e))?;
 
-        if playback >= self.get_loop_len() {
-            playback = 0;
-            if self.recording() {
-                // We went past the end of the current loop while recording.
-                self.inc_loop_count();
+    std::thread::spawn(move || {
+        let mut playing = false;
+        let mut len = 0;
+        let mut beats = 1;
+        // The last tick sent, if any since starting.
+        let mut last_tick: Option<usize> = None;
+        loop {
+            std::thread::sleep(CLOCK_POLL_INTERVAL);
+            let was_playing = playing;
+            playing = state.get_loop_len() > 0 && state.playing();
+            if playing != was_playing {
+                let _ = output.send(&[if playing { MIDI_START } else { MIDI_STOP }]);
+                tracing::debug!(playing, "MIDI transport");
+                last_tick = None;
             }
+            if !playing {
+                continue;
+            }
+
+            if state.get_loop_len() != len {
+                len = state.get_loop_len();
+                beats = clock_beats(len, sample_rate, metronome.bpm());
+                tracing::debug!(beats, bpm = clock_bpm(len, sample_rate, beats), "MIDI clock tempo");
+            }
+            let tick = clock_tick(state.phase(), beats);
+            let due = match last_tick {
+                // Start always goes with a first tick, on the downbeat.
+                None => 1,
+                // Every tick since the last one, whichever way playback's
+                // going; if it's jumped more than a beat, just carry on
+                // from where it is now.
+                Some(last) => {
+                    let ticks = beats * CLOCK_TICKS_PER_BEAT;
+                    let ahead = (tick + ticks - last) % ticks;
+                    let passed = ahead.min(ticks - ahead);
+                    if passed > CLOCK_TICKS_PER_BEAT { 1 } else { passed }
+                },
+            };
+            for _ in 0..due {
+                let _ = output.send(&[MIDI_CLOCK]);
+            }
+            last_tick = Some(tick);
         }
+    });
+    Ok(())
+}
 
-        self.playback.store(playback, Ordering::SeqCst);
+// The Command an OSC message asks for, if any.
+fn osc_command(message: &rosc::OscMessage) -> Option<Command> {
+    match (message.addr.as_str(), message.args.as_slice()) {
+        ("/looper/tap", _) => Some(Command::Tap),
+        ("/looper/undo", _) => Some(Command::Undo),
+        ("/looper/clear", _) => Some(Command::Clear),
+        ("/looper/play", _) => Some(Command::TogglePlayback),
+        ("/looper/gain", [rosc::OscType::Float(gain)]) => Some(Command::SetMasterGain(*gain)),
+        ("/looper/gain", [rosc::OscType::Double(gain)]) => Some(Command::SetMasterGain(*gain as f32)),
+        // Tracks count from one, like everywhere the user sees them.
+        ("/looper/track", [rosc::OscType::Int(track)]) if *track > 0 => {
+            Some(Command::SelectTrack(*track as usize - 1))
+        },
+        _ => None,
     }
 }
 
-struct Clip {
-    samples: Vec<f32>,
-    start: usize,
+// Every Command in packet, bundles and all.
+fn osc_commands(packet: &rosc::OscPacket, commands: &mut Vec<Command>) {
+    match packet {
+        rosc::OscPacket::Message(message) => commands.extend(osc_command(message)),
+        rosc::OscPacket::Bundle(bundle) => {
+            for packet in &bundle.content {
+                osc_commands(packet, commands);
+            }
+        },
+    }
 }
 
-impl Clip {
-    fn new(samples: Vec<f32>, start: usize) -> Self {
-        Self {
-            samples: samples,
-            start: start,
+// How often to send OSC status updates.
+const OSC_STATUS_INTERVAL: Duration = Duration::from_millis(50);
+
+// Listen for OSC commands on port in the background, sending them on to
+// commands, and send the loop's status back to reply (or whoever sent the
+// last command).
+fn listen_osc(
+    port: u16,
+    mut reply: Option<std::net::SocketAddr>,
+    commands: mpsc::Sender<Command>,
+    state: State,
+) -> anyhow::Result<()> {
+    let socket = std::net::UdpSocket::bind(("0.0.0.0", port))?;
+    socket.set_read_timeout(Some(OSC_STATUS_INTERVAL))?;
+    println!("Listening for OSC on port {}.", port);
+    let fixed_reply = reply.is_some();
+
+    std::thread::spawn(move || {
+        let mut buf = [0; rosc::decoder::MTU];
+        let mut received = vec![];
+        loop {
+            if let Ok((len, from)) = socket.recv_from(&mut buf) {
+                if let Ok((_, packet)) = rosc::decoder::decode_udp(&buf[..len]) {
+                    osc_commands(&packet, &mut received);
+                    if !fixed_reply {
+                        reply = Some(from);
+                    }
+                }
+                for command in received.drain(..) {
+                    if commands.send(command).is_err() {
+                        // The looper's gone.
+                        return;
+                    }
+                }
+            }
+
+            if let Some(to) = reply {
+                for packet in osc_status(&state) {
+                    if let Ok(bytes) = rosc::encoder::encode(&packet) {
+                        let _ = socket.send_to(&bytes, to);
+                    }
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+// Join (or start) an Ableton Link session in the background, and follow it
+// just like an incoming MIDI clock (see Looper::run()): pulses straight from
+// the session's timeline, so the tempo comes out exact, and Start at the
+// next bar once its transport starts (from any peer that syncs start/stop).
+// Link's quantum is a bar, and the first loop gets rounded to whole bars,
+// so loops line up with everyone else's.
+#[cfg(feature = "link")]
+fn join_link(looper: &mut Looper) {
+    let quantum = looper.metronome.beats_per_bar.max(1) as f64;
+    let bar_ticks = looper.metronome.beats_per_bar.max(1) as i64 * CLOCK_TICKS_PER_BEAT as i64;
+    looper.quantize = looper_proto::Quantize::Bar;
+    let link = rusty_link::AblLink::new(looper.metronome.bpm() as f64);
+    link.enable(true);
+    link.enable_start_stop_sync(true);
+    println!("Joined Link session ({} other peers).", link.num_peers());
+    let commands = looper.command_sender();
+
+    std::thread::spawn(move || {
+        let mut session = rusty_link::SessionState::new();
+        let mut playing = false;
+        // Whether the transport's started, but the next bar hasn't come
+        // round yet.
+        let mut starting = false;
+        let mut last_tick: Option<i64> = None;
+        loop {
+            std::thread::sleep(CLOCK_POLL_INTERVAL);
+            link.capture_app_session_state(&mut session);
+            if session.is_playing() != playing {
+                playing = session.is_playing();
+                starting = playing;
+                if !playing && commands.send(Command::ClockStop).is_err() {
+                    return;
+                }
+            }
+
+            let ticks = link_ticks(last_tick, session.beat_at_time(link.clock_micros(), quantum));
+            last_tick = Some(*ticks.end());
+            for tick in ticks {
+                if starting && tick.rem_euclid(bar_ticks) == 0 {
+                    starting = false;
+                    if commands.send(Command::ClockStart).is_err() {
+                        return;
+                    }
+                }
+                let at = session.time_at_beat(tick as f64 / CLOCK_TICKS_PER_BEAT as f64, quantum);
+                let pulse = Command::ClockPulse(Duration::from_micros(at.max(0) as u64));
+                if commands.send(pulse).is_err() {
+                    return;
+                }
+            }
         }
+    });
+}
+
+// Which MIDI clock ticks to send now that a Link session's timeline is at
+// beat, given the last one sent (if any): every one since then, up to the
+// one beat falls in. Going backwards means someone's moved the timeline, so
+// that just carries on from wherever it is now, like after a jump of more
+// than a beat. Nothing left to send comes out empty.
+#[cfg(any(feature = "link", test))]
+fn link_ticks(last: Option<i64>, beat: f64) -> std::ops::RangeInclusive<i64> {
+    let tick = (beat * CLOCK_TICKS_PER_BEAT as f64).floor() as i64;
+    match last {
+        Some(last) if tick >= last && tick - last <= CLOCK_TICKS_PER_BEAT as i64 => last + 1..=tick,
+        _ => tick..=tick,
     }
 }
 
-struct Looper {
-    pub state: State,
-    pub input: Option<cpal::Stream>,
-    pub output: Option<cpal::Stream>,
+#[cfg(not(feature = "link"))]
+fn join_link(_looper: &mut Looper) {
+    println!("WARNING: not built with --features link, so not joining a Link session.");
+}
 
-    pub tap_count: usize,
+// OSC messages describing where the loop's at.
+fn osc_status(state: &State) -> [rosc::OscPacket; 2] {
+    [
+        rosc::OscPacket::Message(rosc::OscMessage {
+            addr: "/looper/loop_count".to_string(),
+            args: vec![rosc::OscType::Int(state.get_loop_count() as i32)],
+        }),
+        rosc::OscPacket::Message(rosc::OscMessage {
+            addr: "/looper/playback".to_string(),
+            args: vec![rosc::OscType::Float(state.phase())],
+        }),
+    ]
 }
 
-impl Looper {
-    fn new() -> Self {
-        Self {
-            state: State::new(),
-            input: None,
-            output: None,
-            tap_count: 0,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use looper_proto::MockAudio;
+
+    #[test]
+    fn device_names_match() {
+        let names = ["Built-in Audio", "USB Audio CODEC", "USB Audio CODEC 2", "Scarlett 2i2 USB"];
+        // Exact matches win, even when other names contain them.
+        assert_eq!(match_device_name(&names, "USB Audio CODEC"), Some(1));
+        // Otherwise, the only one containing it, whatever the case.
+        assert_eq!(match_device_name(&names, "scarlett"), Some(3));
+        assert_eq!(match_device_name(&names, "built-in"), Some(0));
+        assert_eq!(match_device_name(&names, "CODEC 2"), Some(2));
+        // Anything that could be more than one is no match at all.
+        assert_eq!(match_device_name(&names, "usb"), None);
+        assert_eq!(match_device_name(&names, "Focusrite"), None);
+        assert_eq!(match_device_name(&[], "anything"), None);
+    }
+
+    #[test]
+    fn format_device_lists_its_configs() {
+        let device = DeviceInfo {
+            name: "USB Audio CODEC".to_string(),
+            is_default: true,
+            configs: vec![
+                ConfigInfo { channels: 2, min_sample_rate: 44100, max_sample_rate: 48000, sample_format: cpal::SampleFormat::I16 },
+                ConfigInfo { channels: 1, min_sample_rate: 48000, max_sample_rate: 48000, sample_format: cpal::SampleFormat::F32 },
+            ],
+        };
+        assert_eq!(
+            format_device(&device),
+            "    USB Audio CODEC (default)\n      2 ch, 44100-48000 Hz, I16\n      1 ch, 48000 Hz, F32\n"
+        );
+
+        let device = DeviceInfo { name: "Dummy".to_string(), is_default: false, configs: vec![] };
+        assert_eq!(format_device(&device), "    Dummy\n");
+    }
+
+    #[test]
+    fn buffer_size_validation() {
+        let range = cpal::SupportedBufferSize::Range { min: 64, max: 4096 };
+        assert_eq!(check_buffer_size(64, &range), Some(64));
+        assert_eq!(check_buffer_size(256, &range), Some(256));
+        assert_eq!(check_buffer_size(4096, &range), Some(4096));
+        assert_eq!(check_buffer_size(32, &range), None);
+        assert_eq!(check_buffer_size(8192, &range), None);
+        // With nothing to go on, it's worth a try.
+        assert_eq!(check_buffer_size(12345, &cpal::SupportedBufferSize::Unknown), Some(12345));
+
+        assert_eq!(buffer_size(128, &range, DeviceKind::Output), cpal::BufferSize::Fixed(128));
+        assert_eq!(buffer_size(16, &range, DeviceKind::Input), cpal::BufferSize::Default);
+    }
+
+    #[test]
+    fn reconnect_backs_off() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut reconnect = Reconnect::new();
+        assert!(!reconnect.due(start));
+
+        // A burst of errors only makes for one attempt, RECONNECT_DELAY after
+        // the first of them.
+        reconnect.error(start);
+        reconnect.error(start + ms(10));
+        reconnect.error(start + ms(100));
+        assert!(!reconnect.due(start + ms(499)));
+        assert!(reconnect.due(start + RECONNECT_DELAY));
+
+        // Every failure doubles how long it waits, up to the most it'll wait.
+        let mut now = start + RECONNECT_DELAY;
+        let mut delay = RECONNECT_DELAY;
+        for _ in 0..6 {
+            reconnect.failed(now);
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            // More errors in the meantime don't bring it forward.
+            reconnect.error(now + ms(1));
+            assert!(!reconnect.due(now + delay - ms(1)));
+            now += delay;
+            assert!(reconnect.due(now));
         }
+        assert_eq!(delay, RECONNECT_MAX_DELAY);
+
+        // Once it works, it's back to waiting RECONNECT_DELAY after the next
+        // error.
+        reconnect.succeeded();
+        assert!(!reconnect.due(now + Duration::from_secs(60)));
+        reconnect.error(now);
+        assert!(reconnect.due(now + RECONNECT_DELAY));
+        assert!(!reconnect.due(now + RECONNECT_DELAY - ms(1)));
     }
 
-    fn tap(&mut self) -> anyhow::Result<()> {
-        match self.tap_count {
-            0 => {
-                println!("RECORDING.");
-                self.state.toggle_recording();
-                // Play input/output streams.
-                self.output.as_ref().unwrap().play()?;
-                self.input.as_ref().unwrap().play()?;
-            },
-            1 => {
-                println!("SET FIRST LOOP LENGTH.");
-                self.state.inc_loop_count();
-            },
-            _ => {
-                self.state.toggle_recording();
-                println!("recording={}", self.state.recording());
-            },
+    // A looper with a one-loop recording of input on it, through MockAudio.
+    fn record_loop(input: &[f32]) -> (Looper, MockAudio) {
+        let mut looper = LooperBuilder::new().sample_rate(8000).channels(1).build();
+        let mut audio = MockAudio::new(&mut looper);
+        let mut output = vec![0.0; input.len()];
+        looper.run(Command::Tap).unwrap();
+        audio.run(input, &mut output);
+        looper.run(Command::Tap).unwrap();
+        audio.run(&[], &mut []);
+        looper.run(Command::Tap).unwrap();
+        audio.run(&[], &mut []);
+        (looper, audio)
+    }
+
+    #[test]
+    fn shutdown_stops_then_exports() {
+        let dir = std::env::temp_dir().join(format!("looper_proto_main_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (mut looper, _audio) = record_loop(&[0.1; 256]);
+        assert_eq!(looper.state.get_loop_len(), 256);
+
+        // Setting the flag (as Ctrl-C does) gets the UI out of its loop...
+        let shutdown = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(UI_TICK * 3);
+                shutdown.store(true, Ordering::Relaxed);
+            });
+            let audio = Audio::new(&Config::default());
+            init_ui(&mut looper, audio, &KeyBindings::default(), &shutdown, Ui::Headless).unwrap();
+        });
+
+        // ...and then the loop gets exported.
+        let path = dir.join("exit.wav");
+        finish(&mut looper, false, Some(&path)).unwrap();
+        assert!(looper.input.is_none() && looper.output.is_none());
+        let reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.duration(), 256);
+
+        // Not after a panic, though.
+        let path = dir.join("panicked.wav");
+        assert!(finish(&mut looper, true, Some(&path)).is_err());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn status_messages_print_as_they_are() {
+        let printed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer = printed.clone();
+        let subscriber = tracing_subscriber::registry().with(status_layer(move || Printed(writer.clone())));
+        tracing::subscriber::with_default(subscriber, || {
+            let (mut looper, _audio) = record_loop(&[0.1; 256]);
+            // Undoing the only loop there is starts over.
+            looper.run(Command::Undo).unwrap();
+            // Logs aren't status messages.
+            tracing::info!("something else");
+            tracing::warn!(target: "looper_proto", "or this");
+        });
+        let printed = String::from_utf8(printed.lock().unwrap().clone()).unwrap();
+        assert_eq!(printed, "CLEARED. Hit SPACE to start recording.\n");
+    }
+
+    // Somewhere for status_layer() to write to, as if it were stdout.
+    struct Printed(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Printed {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
         }
-        self.tap_count += 1;
-        Ok(())
     }
-}
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {}", err);
+    #[test]
+    fn panics_shut_everything_down() {
+        let (mut looper, _audio) = record_loop(&[0.1; 256]);
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let panicked = Arc::new(AtomicBool::new(false));
+        set_panic_hook(shutdown.clone(), panicked.clone());
+
+        // A panic on another thread (such as an audio callback's) gets the
+        // UI out of its loop, just like Ctrl-C.
+        let audio_thread = std::thread::spawn(|| {
+            std::thread::sleep(UI_TICK * 3);
+            panic!("simulated panic in an audio callback");
+        });
+        let audio = Audio::new(&Config::default());
+        init_ui(&mut looper, audio, &KeyBindings::default(), &shutdown, Ui::Headless).unwrap();
+        assert!(audio_thread.join().is_err());
+        // Back to the default hook, for any other tests.
+        drop(std::panic::take_hook());
+        assert!(shutdown.load(Ordering::Relaxed));
+        assert!(panicked.load(Ordering::Relaxed));
+
+        // Then the streams get stopped, and it's reported as a failure.
+        assert!(finish(&mut looper, panicked.load(Ordering::Relaxed), None).is_err());
+        assert!(looper.input.is_none() && looper.output.is_none());
+    }
+
+    #[test]
+    fn progress_bar_fractions() {
+        // No loop yet, so nothing to show.
+        assert_eq!(loop_progress(0, 0), 0.0);
+        assert_eq!(loop_progress(10, 0), 0.0);
+        assert_eq!(loop_progress(0, 400), 0.0);
+        assert_eq!(loop_progress(100, 400), 0.25);
+        assert_eq!(loop_progress(399, 400), 399.0 / 400.0);
+        // Playback can be a little past the end just as the loop's been
+        // shortened, but the bar never quite fills up.
+        assert_eq!(loop_progress(500, 400), 399.0 / 400.0);
+
+        assert_eq!(meter_fraction(0.0), 1.0);
+        assert_eq!(meter_fraction(-30.0), 0.5);
+        assert_eq!(meter_fraction(METER_FLOOR_DB), 0.0);
+        assert_eq!(meter_fraction(f32::NEG_INFINITY), 0.0);
+        assert_eq!(meter_fraction(6.0), 1.0);
+    }
+
+    #[test]
+    fn midi_messages_map_to_commands() {
+        let mut map = MidiMap {
+            tap: MidiTrigger::Cc(80),
+            undo: MidiTrigger::Note(62),
+            clear: MidiTrigger::Cc(82),
+            play: MidiTrigger::Note(60),
+            clock: false,
+        };
+        let cases: [(&[u8], Option<Command>); 10] = [
+            // Pressed, on any channel.
+            (&[0xb0, 80, 127], Some(Command::Tap)),
+            (&[0xb5, 80, 64], Some(Command::Tap)),
+            (&[0x93, 62, 100], Some(Command::Undo)),
+            (&[0xbf, 82, 90], Some(Command::Clear)),
+            (&[0x90, 60, 1], Some(Command::TogglePlayback)),
+            // Released, or not mapped to anything.
+            (&[0xb0, 80, 63], None),
+            (&[0x90, 62, 0], None),
+            (&[0x80, 62, 64], None),
+            (&[0xb0, 62, 127], None),
+            // Clock, which isn't being followed.
+            (&[MIDI_CLOCK], None),
+        ];
+        for (message, command) in cases {
+            assert_eq!(midi_command(0, message, &map), command, "{:x?}", message);
+        }
+
+        map.clock = true;
+        assert_eq!(midi_command(1500, &[MIDI_CLOCK], &map), Some(Command::ClockPulse(Duration::from_micros(1500))));
+        assert_eq!(midi_command(0, &[MIDI_START], &map), Some(Command::ClockStart));
+        assert_eq!(midi_command(0, &[MIDI_CONTINUE], &map), Some(Command::ClockStart));
+        assert_eq!(midi_command(0, &[MIDI_STOP], &map), Some(Command::ClockStop));
+        assert_eq!(midi_command(0, &[], &map), None);
+
+        // How triggers get spelled out on the command line.
+        assert_eq!("note:60".parse::<MidiTrigger>().unwrap(), MidiTrigger::Note(60));
+        assert_eq!("cc:83".parse::<MidiTrigger>().unwrap(), MidiTrigger::Cc(83));
+        assert!("cc:128".parse::<MidiTrigger>().is_err());
+        assert!("pc:1".parse::<MidiTrigger>().is_err());
+        assert!("60".parse::<MidiTrigger>().is_err());
+    }
+
+    #[test]
+    fn osc_messages_map_to_commands() {
+        use rosc::{OscMessage, OscPacket, OscType};
+        let message = |addr: &str, args: Vec<OscType>| OscMessage { addr: addr.to_string(), args };
+        let cases = [
+            (message("/looper/tap", vec![]), Some(Command::Tap)),
+            // TouchOSC buttons send their value along, which doesn't matter.
+            (message("/looper/tap", vec![OscType::Float(1.0)]), Some(Command::Tap)),
+            (message("/looper/undo", vec![]), Some(Command::Undo)),
+            (message("/looper/clear", vec![]), Some(Command::Clear)),
+            (message("/looper/play", vec![]), Some(Command::TogglePlayback)),
+            (message("/looper/gain", vec![OscType::Float(0.5)]), Some(Command::SetMasterGain(0.5))),
+            (message("/looper/gain", vec![OscType::Double(0.25)]), Some(Command::SetMasterGain(0.25))),
+            (message("/looper/track", vec![OscType::Int(2)]), Some(Command::SelectTrack(1))),
+            // Missing or wrong arguments, and anything else.
+            (message("/looper/gain", vec![]), None),
+            (message("/looper/gain", vec![OscType::String("loud".to_string())]), None),
+            (message("/looper/track", vec![OscType::Int(0)]), None),
+            (message("/looper/tapp", vec![]), None),
+            (message("/other/tap", vec![]), None),
+        ];
+        for (message, command) in &cases {
+            assert_eq!(osc_command(message), *command, "{:?}", message);
+        }
+
+        // Bundles (within bundles) get unpacked, in order.
+        let bundle = |content| OscPacket::Bundle(rosc::OscBundle {
+            timetag: rosc::OscTime { seconds: 0, fractional: 0 },
+            content,
+        });
+        let packet = bundle(vec![
+            OscPacket::Message(message("/looper/undo", vec![])),
+            bundle(vec![
+                OscPacket::Message(message("/nope", vec![])),
+                OscPacket::Message(message("/looper/tap", vec![])),
+            ]),
+            OscPacket::Message(message("/looper/clear", vec![])),
+        ]);
+        let mut commands = vec![];
+        osc_commands(&packet, &mut commands);
+        assert_eq!(commands, [Command::Undo, Command::Tap, Command::Clear]);
+
+        // And what goes back the other way.
+        let (looper, _audio) = record_loop(&[0.1; 256]);
+        let [OscPacket::Message(count), OscPacket::Message(playback)] = osc_status(&looper.state) else {
+            panic!("the status should be two messages");
+        };
+        assert_eq!((count.addr.as_str(), count.args.as_slice()), ("/looper/loop_count", &[OscType::Int(1)][..]));
+        assert_eq!(playback.addr, "/looper/playback");
+        assert_eq!(playback.args, [OscType::Float(looper.state.phase())]);
+    }
+
+    #[test]
+    fn config_file_then_command_line() {
+        let config = Config::parse(r#"
+            input = "USB Audio"
+            output = "USB Audio"
+            buffer_size = 256
+            bpm = 96
+            master_gain = 0.8
+            taps = ["record", "stop"]
+            midi_port = "FS-1"
+            midi_tap = "note:60"
+
+            [keys]
+            tap = "t"
+            undo = "z"
+        "#).unwrap();
+        assert_eq!(config, Config {
+            input: Some("USB Audio".to_string()),
+            output: Some("USB Audio".to_string()),
+            buffer_size: Some(256),
+            bpm: 96.0,
+            master_gain: 0.8,
+            taps: vec![TapAction::Record, TapAction::Stop],
+            midi_port: Some("FS-1".to_string()),
+            midi_tap: MidiTrigger::Note(60),
+            keys: KeyBindings { tap: 't', undo: 'z', ..KeyBindings::default() },
+            ..Config::default()
+        });
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        // Typos don't get quietly ignored.
+        assert!(Config::parse("bmp = 96").is_err());
+        assert!(Config::parse("midi_tap = \"note:200\"").is_err());
+
+        // The command line wins over the file, but only for what it sets.
+        let args = Args::parse_from(["looper_proto", "--bpm", "100", "--input", "Built-in", "--sync"]);
+        let merged = config.clone().merge(&args);
+        assert_eq!(merged.bpm, 100.0);
+        assert_eq!(merged.input.as_deref(), Some("Built-in"));
+        assert!(merged.sync);
+        assert_eq!(merged.output, config.output);
+        assert_eq!(merged.master_gain, 0.8);
+        assert_eq!(merged.taps, config.taps);
+        assert_eq!(config.clone().merge(&Args::parse_from(["looper_proto"])), config);
+    }
+
+    #[test]
+    fn backend_names_and_hosts() {
+        use clap::ValueEnum;
+        // From the command line, or the config file.
+        assert_eq!(Args::parse_from(["looper_proto"]).backend, None);
+        let args = Args::parse_from(["looper_proto", "--backend", "jack"]);
+        assert_eq!(args.backend, Some(Backend::Jack));
+        assert_eq!(Config::default().merge(&args).backend, Backend::Jack);
+        assert!(Args::try_parse_from(["looper_proto", "--backend", "pulse"]).is_err());
+        assert_eq!(Config::parse("backend = \"jack\"").unwrap().backend, Backend::Jack);
+        assert_eq!(Config::parse("backend = \"default\"").unwrap().backend, Backend::Default);
+        assert!(Config::parse("backend = \"pulse\"").is_err());
+        assert_eq!(Config::default().backend, Backend::Default);
+        // Named the same way everywhere.
+        for backend in Backend::value_variants() {
+            assert_eq!(Backend::from_str(&backend.to_string(), false), Ok(*backend));
+        }
+
+        // JACK's only there when it's been built in, and anything that isn't
+        // there falls back to the default host.
+        let jack = cfg!(all(feature = "jack", any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd")));
+        assert_eq!(Backend::Default.host_id(), None);
+        assert_eq!(Backend::Jack.host_id().is_some(), jack);
+        let default = cpal::default_host().id();
+        assert_eq!(open_host(Backend::Default).id(), default);
+        if !jack {
+            assert_eq!(open_host(Backend::Jack).id(), default);
+        }
+    }
+
+    #[test]
+    fn headless_runs_off_commands() {
+        let mut looper = LooperBuilder::new().sample_rate(8000).channels(1).build();
+        let _audio = MockAudio::new(&mut looper);
+        let commands = looper.command_sender();
+        let shutdown = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            // Standing in for MIDI or OSC.
+            scope.spawn(|| {
+                commands.send(Command::Tap).unwrap();
+                std::thread::sleep(UI_TICK * 5);
+                shutdown.store(true, Ordering::Relaxed);
+            });
+            let audio = Audio::new(&Config::default());
+            init_ui(&mut looper, audio, &KeyBindings::default(), &shutdown, Ui::Headless).unwrap();
+        });
+        assert!(looper.snapshot().recording, "the tap should have started recording");
+        assert_eq!(looper.taps, 1);
+    }
+
+    #[test]
+    fn loop_secs_in_frames() {
+        assert_eq!(secs_to_frames(4.0, 44100), 176400);
+        assert_eq!(secs_to_frames(0.5, 48000), 24000);
+        // To the nearest frame.
+        assert_eq!(secs_to_frames(0.00001, 44100), 0);
+        assert_eq!(secs_to_frames(1.0 / 3.0, 8000), 2667);
+        assert_eq!(secs_to_frames(-1.0, 44100), 0);
+    }
+
+    #[test]
+    fn loop_lengths_in_bars_and_beats() {
+        let mut looper = LooperBuilder::new().sample_rate(44100).build();
+        // The defaults are 120 bpm in four.
+        assert_eq!(musical_len(&looper, 22050 * 4), "1 bar");
+        assert_eq!(musical_len(&looper, 22050 * 8), "2 bars");
+        assert_eq!(musical_len(&looper, 22050 * 5), "1 bar 1.00 beats");
+        assert_eq!(musical_len(&looper, 22050 * 2 + 11025), "0 bars 2.50 beats");
+        // To the nearest hundredth of a beat.
+        assert_eq!(musical_len(&looper, 22050 * 8 - 1), "2 bars");
+        assert_eq!(musical_len(&looper, 22050 * 8 + 2205), "2 bars 0.10 beats");
+        looper.metronome = Metronome::new(90.0, 4, 44100);
+        assert_eq!(musical_len(&looper, 44100 * 8 / 3), "1 bar");
+    }
+
+    #[test]
+    fn link_beats_become_clock_pulses() {
+        // However often the timeline gets polled, every tick goes out once,
+        // in order.
+        let mut last = None;
+        let mut ticks = vec![];
+        for poll in 0..30 {
+            let sent = link_ticks(last, poll as f64 * 0.3);
+            last = Some(*sent.end());
+            ticks.extend(sent);
+        }
+        assert_eq!(ticks, (0..=208).collect::<Vec<i64>>());
+        // Nothing new within a tick.
+        assert!(link_ticks(Some(24), 1.02).is_empty());
+        // Someone moving the timeline back, or too far forward, starts over
+        // from there.
+        assert_eq!(link_ticks(Some(48), 1.5), 36..=36);
+        assert_eq!(link_ticks(Some(48), 4.0), 96..=96);
+        assert_eq!(link_ticks(None, 2.0), 48..=48);
+
+        // Fed to the looper as pulses at a 100 bpm session's times (25 ms
+        // apart), with Start on the first bar, it follows the session's
+        // tempo and starts recording on the downbeat.
+        let mut looper = LooperBuilder::new().sample_rate(44100).build();
+        let _audio = MockAudio::new(&mut looper);
+        looper.run(Command::ClockStart).unwrap();
+        assert!(!looper.snapshot().recording);
+        for &tick in &ticks[..48] {
+            looper.run(Command::ClockPulse(Duration::from_millis(25 * tick as u64))).unwrap();
+        }
+        assert!(looper.snapshot().recording);
+        assert!((looper.metronome.bpm() - 100.0).abs() < 1e-3);
+    }
 }
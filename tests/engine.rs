@@ -0,0 +1,116 @@
+// Drives the looper end to end through its public API, the way a frontend
+// other than src/main.rs would, with MockAudio standing in for the sound
+// card.
+use looper_proto::{Command, Event, Looper, MockAudio};
+
+const BUFFER: usize = 64;
+const LEN: usize = BUFFER * 4;
+
+struct Engine {
+    looper: Looper,
+    audio: MockAudio,
+}
+
+impl Engine {
+    fn new() -> Self {
+        let mut looper = Looper::new();
+        let audio = MockAudio::new(&mut looper);
+        Self { looper, audio }
+    }
+
+    fn tap(&mut self) {
+        self.looper.run(Command::Tap).unwrap();
+    }
+
+    // Run input through a buffer at a time, and return what came out, along
+    // with whatever events came up.
+    fn run(&mut self, input: &[f32]) -> (Vec<f32>, Vec<Event>) {
+        let mut output = vec![0.0; input.len()];
+        for (input, output) in input.chunks(BUFFER).zip(output.chunks_mut(BUFFER)) {
+            self.audio.run(input, output);
+        }
+        if input.is_empty() {
+            self.audio.run(&[], &mut []);
+        }
+        let events: Vec<Event> = std::iter::from_fn(|| self.looper.events.try_recv().ok()).collect();
+        for &event in &events {
+            self.looper.handle_event(event);
+        }
+        self.looper.make_room();
+        (output, events)
+    }
+
+    fn silence(&mut self, frames: usize) -> Vec<f32> {
+        self.run(&vec![0.0; frames]).0
+    }
+}
+
+fn ramp() -> Vec<f32> {
+    (0..LEN).map(|idx| idx as f32 / LEN as f32 * 0.25).collect()
+}
+
+// Whether output is the loop, played from wherever playback was.
+fn plays(output: &[f32], input: &[f32], start: usize) -> bool {
+    output.iter().enumerate().all(|(idx, sample)| {
+        (sample - input[(start + idx) % input.len()]).abs() < 1e-6
+    })
+}
+
+#[test]
+fn records_and_plays_a_loop() {
+    let mut engine = Engine::new();
+    let input = ramp();
+    engine.tap();
+    let (_, events) = engine.run(&input);
+    assert_eq!(events, vec![Event::RecordingStarted]);
+    engine.tap();
+    let (_, events) = engine.run(&[]);
+    assert!(events.contains(&Event::LoopClosed { track: 0, len: LEN }));
+    engine.tap();
+    let (_, events) = engine.run(&[]);
+    assert!(events.contains(&Event::RecordingStopped));
+
+    let state = engine.looper.snapshot();
+    assert_eq!(state.loop_len, LEN);
+    assert_eq!(state.loop_count, 1);
+    assert!(!state.recording);
+    let output = engine.silence(LEN * 2);
+    assert!(plays(&output, &input, state.playback));
+}
+
+#[test]
+fn overdubs_undo_and_clear() {
+    let mut engine = Engine::new();
+    let input = ramp();
+    engine.tap();
+    engine.run(&input);
+    engine.tap();
+    engine.run(&[]);
+    engine.tap();
+    engine.run(&[]);
+
+    // A second layer plays along with the first. Each buffer of input goes
+    // with the buffer of output before it, which is what was being played
+    // along to, so it takes one more to fill the layer.
+    engine.tap();
+    engine.run(&vec![0.1; LEN + BUFFER]);
+    engine.tap();
+    engine.run(&[]);
+    let state = engine.looper.snapshot();
+    assert_eq!(state.loop_count, 2);
+    let doubled: Vec<f32> = input.iter().map(|sample| sample + 0.1).collect();
+    assert!(plays(&engine.silence(LEN), &doubled, state.playback));
+
+    // Until it's undone.
+    engine.looper.run(Command::Undo).unwrap();
+    let state = engine.looper.snapshot();
+    assert_eq!(state.loop_count, 1);
+    assert!(plays(&engine.silence(LEN), &input, state.playback));
+
+    // Then there's nothing left at all.
+    engine.looper.run(Command::Clear).unwrap();
+    let state = engine.looper.snapshot();
+    assert_eq!(state.loop_count, 0);
+    assert_eq!(state.loop_len, 0);
+    assert!(engine.silence(LEN).iter().all(|&sample| sample == 0.0));
+}